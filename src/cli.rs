@@ -1,36 +1,56 @@
 use clap::{Parser, Subcommand, ValueEnum};
 use duct::cmd;
 use flate2::{Compression, read::GzDecoder, write::GzEncoder};
-use mailparse::{self, MailAddr, MailHeaderMap};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
     io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
     path::{Path, PathBuf},
 };
 use tar::{Archive, Builder};
 
 use crate::{
-    envcfg::EnvConfig,
+    envcfg::{EnvConfig, upsert_env_setting},
     fsops::{
         io_atom::{create_dir_all, create_file, write_atomic},
         layout::MailLayout,
+        scan::{FLAT_LISTS, SkippedSidecar, StoredMessage, find_message, load_message},
     },
-    model::{address::Address, message::MessageSidecar},
-    ops::install as ops_install,
+    model::{
+        address::Address,
+        message::{MessageSidecar, OutboundState, OutboundStatus, RecipientState},
+        rules::{MessageContext, Rule},
+        settings::ListSettings,
+    },
+    ops::{dkim as ops_dkim, install as ops_install},
     pipeline::{
-        inbound::determine_route,
+        auth::{Authenticator, MessageOrigin},
+        compose::{forward_draft, own_addresses, reply_draft, write_draft},
+        inbound::{
+            deliver_message, deliver_message_from, determine_authenticated_route,
+            envelope_fallback_sender,
+        },
         outbox::{DispatchResult, OutboxPipeline},
-        smtp_in::InboundPipeline,
+        render::{RemoteContent, RenderPolicy},
+        search::{SearchIndex, SearchQuery},
+        smtp_in::{InboundPipeline, MalformedMessageError, render_message},
+        thread::ThreadIndex,
+    },
+    ruleset::{
+        eval::Route,
+        loader::{LoadedList, LoadedRules, RulesetLoader},
+        sieve::SieveOutcome,
     },
-    ruleset::loader::{LoadedRules, RulesetLoader},
     util::{
-        dkim,
+        dkim::{self, DkimKeyType},
         logging::{self, LogLevel, Logger},
+        time::parse_interval,
     },
 };
 use anyhow::{Context, Result, anyhow, bail};
+use time::OffsetDateTime;
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "owl", version, about = "File-first mail system")]
@@ -62,11 +82,19 @@ pub enum Commands {
     },
     #[command(about = "Reload routing rules without restarting the daemon")]
     Reload,
+    #[command(about = "Edit, list and explain routing rules")]
+    Rules {
+        #[command(subcommand)]
+        action: RulesAction,
+    },
     #[command(about = "List messages in quarantine or a specific list")]
     Triage {
         #[arg(long, help = "Filter by sender address")]
         address: Option<String>,
-        #[arg(long, help = "List to triage (quarantine, accepted, spam, banned)")]
+        #[arg(
+            long,
+            help = "List to triage (quarantine, accepted, spam, banned, outbox, sent)"
+        )]
         list: Option<String>,
     },
     #[command(about = "Show sender directories for one list or all lists")]
@@ -93,11 +121,55 @@ pub enum Commands {
         #[arg(long, help = "Remove the pinned flag instead of setting it")]
         unset: bool,
     },
+    #[command(about = "Show the conversation a message belongs to")]
+    Thread {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+    },
+    #[command(about = "Start a reply draft quoting a stored message")]
+    Reply {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+        #[arg(long, help = "Copy every other To/Cc recipient")]
+        all: bool,
+    },
+    #[command(about = "Start a forward draft quoting a stored message")]
+    Forward {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+    },
+    #[command(about = "Search received mail by words and field qualifiers")]
+    Search {
+        #[arg(
+            required_unless_present = "reindex",
+            help = "Words plus from:, list:, before:YYYY-MM-DD and has:attachment qualifiers"
+        )]
+        query: Vec<String>,
+        #[arg(long, help = "Rebuild the index from the whole mail tree first")]
+        reindex: bool,
+    },
+    #[command(about = "Regenerate a received message's HTML and text views")]
+    Render {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+        #[arg(long, help = "Load remote images and CSS for this message")]
+        allow_remote: bool,
+    },
     #[command(about = "Queue a draft for delivery")]
     Send {
         #[arg(help = "Draft file path or ULID")]
         draft: String,
     },
+    #[command(about = "Inspect and manage queued outbound mail")]
+    Outbox {
+        #[command(subcommand)]
+        action: OutboxAction,
+    },
+    #[command(about = "Rotate DKIM keys and show the records to publish")]
+    Dkim {
+        #[command(subcommand)]
+        action: DkimAction,
+    },
     #[command(about = "Create a tarball of the mail root")]
     Backup {
         #[arg(help = "Output path for the backup tarball")]
@@ -117,6 +189,20 @@ pub enum Commands {
         #[arg(help = "Path to maildir, mbox, or tar.gz archive")]
         source: PathBuf,
     },
+    #[command(about = "Deliver a raw message from stdin (MTA pipe transport)")]
+    Deliver {
+        #[arg(long, help = "Envelope sender (MAIL FROM)")]
+        sender: String,
+        #[arg(long, help = "Envelope recipient (RCPT TO)")]
+        recipient: String,
+        #[arg(
+            long,
+            help = "Address of the connecting client, for SPF (empty or unparseable means unknown)"
+        )]
+        client_ip: Option<String>,
+        #[arg(long, help = "HELO/EHLO name the client gave, for SPF")]
+        helo: Option<String>,
+    },
     #[command(about = "Render structured logs")]
     Logs {
         #[arg(value_enum, default_value_t = LogAction::Show, help = "Action to perform on logs")]
@@ -126,6 +212,83 @@ pub enum Commands {
     Configure,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum OutboxAction {
+    #[command(about = "Show queued messages with their delivery state")]
+    List,
+    #[command(about = "Dispatch a queued message now, skipping its backoff")]
+    Retry {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+    },
+    #[command(about = "Move a queued message back to drafts/ for editing")]
+    Cancel {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+    },
+    #[command(about = "Send a failed message or a sent/ copy again")]
+    Resend {
+        #[arg(help = "Message ULID")]
+        ulid: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RulesAction {
+    #[command(about = "Append a pattern to a list's .rules")]
+    Add {
+        #[arg(help = "List to add to (accepted, spam or banned)")]
+        list: String,
+        #[arg(help = "Rule line, e.g. @example.org or to:+shop")]
+        pattern: String,
+    },
+    #[command(about = "Remove a pattern from a list's .rules")]
+    Rm {
+        #[arg(help = "List to remove from (accepted, spam or banned)")]
+        list: String,
+        #[arg(help = "Rule line to remove")]
+        pattern: String,
+    },
+    #[command(about = "Show every list's rules and list_status")]
+    Ls,
+    #[command(about = "Explain which rule routes mail from an address")]
+    Test {
+        #[arg(help = "Sender address")]
+        address: String,
+        #[arg(long, help = "Envelope recipient, for to: rules")]
+        recipient: Option<String>,
+        #[arg(long, help = "Subject, for subject: rules")]
+        subject: Option<String>,
+        #[arg(
+            long = "header",
+            value_name = "NAME:VALUE",
+            help = "Extra header, for header: rules (repeatable)"
+        )]
+        headers: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DkimAction {
+    #[command(about = "Start a rotation to a new selector, or finish one whose overlap has passed")]
+    Rotate {
+        #[arg(long, help = "New selector (defaults to <current>-YYYYMMDD)")]
+        selector: Option<String>,
+        #[arg(
+            long,
+            help = "How long to keep signing with the old selector (defaults to dkim_rotation_overlap)"
+        )]
+        overlap: Option<String>,
+        #[arg(
+            long,
+            help = "Rotate the RSA key (dkim_rsa_selector) instead of the Ed25519 one"
+        )]
+        rsa: bool,
+    },
+    #[command(about = "Show selectors, key ages and DNS TXT records")]
+    Status,
+}
+
 #[derive(ValueEnum, Clone, Debug, Default)]
 pub enum RestartTarget {
     #[default]
@@ -156,20 +319,48 @@ pub fn run(cli: OwlCli, env: EnvConfig) -> Result<String> {
         Commands::Update => update(&env_path, &env, &logger),
         Commands::Restart { target } => restart(&env_path, target, &logger),
         Commands::Reload => reload(&env_path),
+        Commands::Rules { action } => rules(&env_path, &env, action, cli.json),
         Commands::Triage { address, list } => triage(&env_path, &env, address, list, cli.json),
         Commands::ListSenders { list } => list_senders(&env_path, list),
         Commands::MoveSender { from, to, address } => {
             move_sender(&env_path, &env, from, to, address)
         }
         Commands::Pin { address, unset } => pin_address(&env_path, &env, address, unset),
+        Commands::Thread { ulid } => show_thread(&env_path, &logger, &ulid, cli.json),
+        Commands::Reply { ulid, all } => reply(&env_path, &ulid, all),
+        Commands::Forward { ulid } => forward(&env_path, &ulid),
+        Commands::Search { query, reindex } => {
+            search(&env_path, &logger, &query.join(" "), reindex, cli.json)
+        }
+        Commands::Render { ulid, allow_remote } => {
+            render_message_views(&env_path, &env, &logger, &ulid, allow_remote)
+        }
         Commands::Send { draft } => send_draft(&env_path, &env, &logger, &draft),
+        Commands::Outbox { action } => outbox(&env_path, &env, &logger, action, cli.json),
+        Commands::Dkim { action } => dkim_keys(&env_path, &env, &logger, action, cli.json),
         Commands::Backup { path } => backup_mail(&env_path, &path),
         Commands::ExportSender {
             list,
             address,
             path,
         } => export_sender(&env_path, &env, &list, &address, &path),
-        Commands::Import { source } => import_archive(&env_path, &source),
+        Commands::Import { source } => import_archive(&env_path, &logger, &source),
+        Commands::Deliver {
+            sender,
+            recipient,
+            client_ip,
+            helo,
+        } => deliver(
+            &env_path,
+            &env,
+            &logger,
+            &sender,
+            &recipient,
+            client_ip.as_deref(),
+            helo.as_deref(),
+            &mut io::stdin().lock(),
+            Authenticator::system(),
+        ),
         Commands::Logs { action } => logs(&root, log_level, action, cli.json),
         Commands::Configure => configure(&env_path, &env, &logger),
     }
@@ -184,17 +375,24 @@ fn install(env_path: &Path, env: &EnvConfig, logger: &Logger) -> Result<String>
         "install.ensure",
         Some(&format!("root={}", root.display())),
     )?;
-    let dkim_material = dkim::ensure_ed25519_keypair(&layout.dkim_dir(), &env.dkim_selector)?;
-    logger.log(
-        LogLevel::Minimal,
-        "install.dkim.ready",
-        Some(&format!(
-            "selector={} public_key={} dns={}",
-            env.dkim_selector,
-            dkim_material.public_key,
-            dkim_material.dns_record_path.display()
-        )),
+    let dkim_materials = dkim::ensure_keypairs(
+        &layout.dkim_dir(),
+        &env.dkim_selector,
+        env.dkim_rsa_selector.as_deref(),
     )?;
+    for material in &dkim_materials {
+        logger.log(
+            LogLevel::Minimal,
+            "install.dkim.ready",
+            Some(&format!(
+                "selector={} type={} public_key={} dns={}",
+                material.selector,
+                material.key_type.dns_key_type(),
+                material.public_key,
+                material.dns_record_path.display()
+            )),
+        )?;
+    }
     ops_install::provision(&layout, env, logger)?;
     if !env_path.exists() {
         write_atomic(env_path, env.to_env_string().as_bytes())
@@ -432,11 +630,248 @@ fn reload(env_path: &Path) -> Result<String> {
     let accepted = loaded.accepted.rules.rules().len();
     let spam = loaded.spam.rules.rules().len();
     let banned = loaded.banned.rules.rules().len();
+    let sieve = if loaded.sieve.is_some() {
+        " sieve=on"
+    } else {
+        ""
+    };
     Ok(format!(
-        "reloaded rules: accepted={accepted} spam={spam} banned={banned}"
+        "reloaded rules: accepted={accepted} spam={spam} banned={banned}{sieve}"
     ))
 }
 
+/// Lists that own a `.rules` file, in routing precedence.
+const RULE_LISTS: [&str; 3] = ["banned", "spam", "accepted"];
+
+fn rules_list(name: &str) -> Result<&'static str> {
+    let list = validate_list_name(name)?;
+    if !RULE_LISTS.contains(&list) {
+        bail!("{list} has no .rules");
+    }
+    Ok(list)
+}
+
+fn loaded_list<'a>(loaded: &'a LoadedRules, list: &str) -> &'a LoadedList {
+    match list {
+        "banned" => &loaded.banned,
+        "spam" => &loaded.spam,
+        _ => &loaded.accepted,
+    }
+}
+
+fn rules(env_path: &Path, env: &EnvConfig, action: RulesAction, json: bool) -> Result<String> {
+    let root = mail_root(env_path);
+    match action {
+        RulesAction::Add { list, pattern } => {
+            let list = rules_list(&list)?;
+            let rule = Rule::parse(&pattern)?;
+            let path = root.join(list).join(".rules");
+            let data = fs::read_to_string(&path).unwrap_or_default();
+            if data
+                .lines()
+                .any(|line| Rule::parse(line).ok().as_ref() == Some(&rule))
+            {
+                return Ok(format!("{rule} already in {list}/.rules"));
+            }
+            set_rules_entry(&path, &pattern)?;
+            Ok(format!("added {rule} to {list}/.rules"))
+        }
+        RulesAction::Rm { list, pattern } => {
+            let list = rules_list(&list)?;
+            let rule = Rule::parse(&pattern)?;
+            let path = root.join(list).join(".rules");
+            let data = fs::read_to_string(&path).unwrap_or_default();
+            let kept: Vec<&str> = data
+                .lines()
+                .filter(|line| Rule::parse(line).ok().as_ref() != Some(&rule))
+                .collect();
+            if kept.len() == data.lines().count() {
+                bail!("{rule} not found in {list}/.rules");
+            }
+            let mut rendered = kept.join("\n");
+            if !rendered.is_empty() {
+                rendered.push('\n');
+            }
+            write_atomic(&path, rendered.as_bytes())?;
+            Ok(format!("removed {rule} from {list}/.rules"))
+        }
+        RulesAction::Ls => rules_ls(&RulesetLoader::new(&root).load()?, json),
+        RulesAction::Test {
+            address,
+            recipient,
+            subject,
+            headers,
+        } => {
+            let loaded = RulesetLoader::new(&root).load()?;
+            let sender = Address::parse(&address, env.keep_plus_tags)?;
+            let recipients: Vec<String> = recipient.into_iter().collect();
+            let mut fields = Vec::new();
+            for raw in &headers {
+                let Some((name, value)) = raw.split_once(':') else {
+                    bail!("--header {raw} must be name:value");
+                };
+                fields.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            // The message a real delivery would see: From and the envelope
+            // sender are the address unless --header says otherwise.
+            if !fields
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("from"))
+            {
+                fields.insert(0, ("From".to_string(), address.trim().to_string()));
+            }
+            if let Some(subject) = subject {
+                fields.push(("Subject".to_string(), subject));
+            }
+            let message = fields.iter().fold(
+                MessageContext::new(&sender)
+                    .with_envelope_sender(&address)
+                    .with_recipients(&recipients),
+                |message, (name, value)| message.with_header(name, value),
+            );
+            rules_test(&loaded, &message, json)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RulesListing {
+    list: &'static str,
+    list_status: String,
+    rules: Vec<String>,
+}
+
+fn rules_ls(loaded: &LoadedRules, json: bool) -> Result<String> {
+    let listings: Vec<RulesListing> = RULE_LISTS
+        .iter()
+        .map(|list| {
+            let loaded = loaded_list(loaded, list);
+            RulesListing {
+                list,
+                list_status: loaded.settings.list_status.clone(),
+                rules: loaded.rules.rules().iter().map(Rule::to_string).collect(),
+            }
+        })
+        .collect();
+    if json {
+        return Ok(serde_json::to_string(&listings)?);
+    }
+    let mut lines = Vec::new();
+    for listing in &listings {
+        lines.push(format!(
+            "{} (list_status={}):",
+            listing.list, listing.list_status
+        ));
+        if listing.rules.is_empty() {
+            lines.push("  (none)".to_string());
+        }
+        for rule in &listing.rules {
+            lines.push(format!("  {rule}"));
+        }
+    }
+    if loaded.sieve.is_some() {
+        lines.push(".sieve: evaluated before these rules".to_string());
+    }
+    Ok(lines.join("\n"))
+}
+
+#[derive(Debug, Serialize)]
+struct RuleCheck {
+    list: &'static str,
+    rule: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RulesExplanation {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sieve: Option<String>,
+    lists: Vec<RuleCheck>,
+    matched_list: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    list_status: Option<String>,
+    route: &'static str,
+}
+
+fn rules_test(loaded: &LoadedRules, message: &MessageContext, json: bool) -> Result<String> {
+    let (route, _) = determine_authenticated_route(message, loaded, None)?;
+    let sieve = loaded.sieve.as_ref().map(|script| script.evaluate(message));
+    let lists: Vec<RuleCheck> = match sieve {
+        Some(SieveOutcome::FileInto(_) | SieveOutcome::Discard) => Vec::new(),
+        _ => RULE_LISTS
+            .iter()
+            .map(|list| RuleCheck {
+                list,
+                rule: loaded_list(loaded, list)
+                    .rules
+                    .evaluate(message)
+                    .map(|rule| rule.to_string()),
+            })
+            .collect(),
+    };
+    let matched_list = match sieve {
+        Some(SieveOutcome::FileInto(Route::Quarantine) | SieveOutcome::Discard) => None,
+        Some(SieveOutcome::FileInto(route)) => Some(route.as_str()),
+        _ => lists
+            .iter()
+            .find(|check| check.rule.is_some())
+            .map(|check| check.list),
+    };
+    let explanation = RulesExplanation {
+        address: message.sender().canonical().to_string(),
+        sieve: sieve.map(|outcome| match outcome {
+            SieveOutcome::Keep => "keep".to_string(),
+            SieveOutcome::FileInto(route) => format!("fileinto {}", route.as_str()),
+            SieveOutcome::Discard => "discard".to_string(),
+        }),
+        list_status: matched_list
+            .map(|list| loaded_list(loaded, list).settings.list_status.clone()),
+        lists,
+        matched_list,
+        route: route.as_str(),
+    };
+    if json {
+        return Ok(serde_json::to_string(&explanation)?);
+    }
+
+    let mut lines = vec![explanation.address.clone()];
+    match (&explanation.sieve, sieve) {
+        (Some(action), Some(SieveOutcome::Keep)) => {
+            lines.push(format!("  .sieve: {action}, so .rules decide"))
+        }
+        (Some(action), Some(SieveOutcome::Discard)) => {
+            lines.push(format!("  .sieve: {action}, which routes to banned"))
+        }
+        (Some(action), _) => lines.push(format!("  .sieve: {action}")),
+        (None, _) => {}
+    }
+    for check in &explanation.lists {
+        let line = match (&check.rule, explanation.matched_list) {
+            (None, _) => format!("  {}: no match", check.list),
+            (Some(rule), Some(matched)) if matched == check.list => {
+                format!("  {}: matched {rule}", check.list)
+            }
+            (Some(rule), matched) => format!(
+                "  {}: {rule} also matches, but {} comes first",
+                check.list,
+                matched.unwrap_or("-")
+            ),
+        };
+        lines.push(line);
+    }
+    if let (Some(list), Some(status)) = (explanation.matched_list, &explanation.list_status) {
+        lines.push(format!("  {list} list_status={status}"));
+        let require_auth = &loaded_list(loaded, list).settings.require_auth;
+        if route == Route::Accepted && require_auth != "none" {
+            lines.push(format!(
+                "  {list} require_auth={require_auth}: quarantined unless authenticated"
+            ));
+        }
+    }
+    lines.push(format!("  route: {}", explanation.route));
+    Ok(lines.join("\n"))
+}
+
 fn logs(root: &Path, level: LogLevel, action: LogAction, json: bool) -> Result<String> {
     if level == LogLevel::Off {
         return Ok(if json {
@@ -502,11 +937,20 @@ struct TriageEntry {
     address: String,
     ulid: String,
     subject: String,
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    #[serde(default)]
+    date: String,
     status: String,
     read: bool,
     pinned: bool,
     rspamd: Option<RspamdView>,
     outbound: Option<OutboundView>,
+    /// Why `require_auth` sent this message to quarantine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    demoted: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -521,6 +965,55 @@ struct OutboundView {
     attempts: u32,
     last_error: Option<String>,
     next_attempt_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<RecipientState>,
+}
+
+impl OutboundView {
+    fn from_state(state: &OutboundState) -> Self {
+        Self {
+            status: format!("{:?}", state.status),
+            attempts: state.attempts,
+            last_error: state.last_error.clone(),
+            next_attempt_at: state.next_attempt_at.clone(),
+            recipients: state.recipients.clone(),
+        }
+    }
+
+    /// `delivered=1/3 pending=a@x failed=b@y` while any recipient still lacks
+    /// the message; `None` once everyone has it.
+    fn delivery_summary(&self) -> Option<String> {
+        let waiting = |status: OutboundStatus| {
+            self.recipients
+                .iter()
+                .filter(|rcpt| rcpt.status == status)
+                .map(|rcpt| rcpt.address.as_str())
+                .collect::<Vec<_>>()
+        };
+        let pending = waiting(OutboundStatus::Pending);
+        let failed = waiting(OutboundStatus::Failed);
+        if pending.is_empty() && failed.is_empty() {
+            return None;
+        }
+        let delivered = self.recipients.len() - pending.len() - failed.len();
+        let mut parts = vec![format!("delivered={delivered}/{}", self.recipients.len())];
+        if !pending.is_empty() {
+            parts.push(format!("pending={}", pending.join(",")));
+        }
+        if !failed.is_empty() {
+            parts.push(format!("failed={}", failed.join(",")));
+        }
+        Some(parts.join(" "))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct OutboxEntry {
+    ulid: String,
+    subject: String,
+    to: Vec<String>,
+    #[serde(flatten)]
+    outbound: OutboundView,
 }
 
 fn triage(
@@ -541,6 +1034,9 @@ fn triage(
         None => None,
     };
     let lists = match list {
+        Some(name) if matches!(name.to_ascii_lowercase().as_str(), "outbox" | "sent") => {
+            vec![name.to_ascii_lowercase()]
+        }
         Some(name) => vec![validate_list_name(&name)?.to_string()],
         None => vec!["quarantine".to_string()],
     };
@@ -551,8 +1047,50 @@ fn triage(
             "spam" => layout.spam(),
             "banned" => layout.banned(),
             "quarantine" => layout.quarantine(),
+            "outbox" => layout.outbox(),
+            "sent" => layout.sent(),
             other => bail!("unsupported list for triage: {other}"),
         };
+        // Outbound folders are flat; list their messages by recipient.
+        if matches!(list_name.as_str(), "outbox" | "sent") {
+            for message in sidecar_files(&base_dir)? {
+                let yaml = fs::read_to_string(&message)?;
+                let sidecar: MessageSidecar = serde_yaml::from_str(&yaml)?;
+                let headers = &sidecar.headers_cache;
+                if let Some(filter) = &filter_address
+                    && !headers
+                        .to
+                        .iter()
+                        .chain(&headers.cc)
+                        .chain(&headers.bcc)
+                        .any(|rcpt| {
+                            let email = rcpt
+                                .rsplit_once('<')
+                                .map_or(rcpt.as_str(), |(_, rest)| rest.trim_end_matches('>'));
+                            Address::parse(email, env.keep_plus_tags)
+                                .is_ok_and(|addr| addr.canonical() == filter)
+                        })
+                {
+                    continue;
+                }
+                entries.push(TriageEntry {
+                    list: list_name.clone(),
+                    address: headers.to.join(", "),
+                    ulid: sidecar.ulid.clone(),
+                    subject: headers.subject.clone(),
+                    to: headers.to.clone(),
+                    cc: headers.cc.clone(),
+                    date: headers.date.clone(),
+                    status: sidecar.status_shadow.clone(),
+                    read: sidecar.read,
+                    pinned: sidecar.pinned,
+                    rspamd: None,
+                    outbound: sidecar.outbound.as_ref().map(OutboundView::from_state),
+                    demoted: None,
+                });
+            }
+            continue;
+        }
         let mut senders = Vec::new();
         if let Some(filter) = &filter_address {
             senders.push(filter.clone());
@@ -582,22 +1120,21 @@ fn triage(
                     score: summary.score,
                     symbols: summary.symbols.clone(),
                 });
-                let outbound = sidecar.outbound.as_ref().map(|out| OutboundView {
-                    status: format!("{:?}", out.status),
-                    attempts: out.attempts,
-                    last_error: out.last_error.clone(),
-                    next_attempt_at: out.next_attempt_at.clone(),
-                });
+                let outbound = sidecar.outbound.as_ref().map(OutboundView::from_state);
                 entries.push(TriageEntry {
                     list: list_name.clone(),
                     address: sender.clone(),
                     ulid: sidecar.ulid.clone(),
                     subject: sidecar.headers_cache.subject.clone(),
+                    to: sidecar.headers_cache.to.clone(),
+                    cc: sidecar.headers_cache.cc.clone(),
+                    date: sidecar.headers_cache.date.clone(),
                     status: sidecar.status_shadow.clone(),
                     read: sidecar.read,
                     pinned: sidecar.pinned,
                     rspamd,
                     outbound,
+                    demoted: sidecar.auth.as_ref().and_then(|auth| auth.demoted.clone()),
                 });
             }
         }
@@ -625,11 +1162,17 @@ fn triage(
                 if let Some(rspamd) = &entry.rspamd {
                     extra.push(format!("rspamd={:.1}", rspamd.score));
                 }
+                if let Some(reason) = &entry.demoted {
+                    extra.push(format!("demoted: {reason}"));
+                }
                 if let Some(outbound) = &entry.outbound {
                     extra.push(format!(
                         "outbound={} attempts={}",
                         outbound.status, outbound.attempts
                     ));
+                    if let Some(summary) = outbound.delivery_summary() {
+                        extra.push(summary);
+                    }
                     if let Some(err) = &outbound.last_error {
                         extra.push(format!("last_error={err}"));
                     }
@@ -718,6 +1261,13 @@ fn move_sender(
         }
     }
 
+    let index = SearchIndex::new(&layout);
+    if index.exists() {
+        for path in sidecar_files(&dest_dir)? {
+            index.add(&load_message(to_list, path)?)?;
+        }
+    }
+
     Ok(format!(
         "moved {} from {from_list} to {to_list}",
         sender.canonical()
@@ -810,81 +1360,449 @@ fn sidecar_files(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn send_draft(env_path: &Path, env: &EnvConfig, logger: &Logger, draft: &str) -> Result<String> {
-    let root = mail_root(env_path);
-    let layout = MailLayout::new(&root);
-    let pipeline = OutboxPipeline::new(layout.clone(), env.clone(), logger.clone());
-    let draft_path = resolve_draft_path(&layout, draft)?;
-    if !draft_path.exists() {
-        bail!("draft {} not found", draft_path.display());
+fn reply(env_path: &Path, ulid: &str, all: bool) -> Result<String> {
+    let layout = MailLayout::new(mail_root(env_path));
+    let message = stored_message(&layout, ulid)?;
+    let identities = own_addresses(&RulesetLoader::new(layout.root()).load()?);
+    let (meta, body) = reply_draft(&message, all, &identities)?;
+    create_dir_all(&layout.drafts())?;
+    let path = write_draft(&layout, &meta, &body)?;
+    Ok(format!("draft created: {}", path.display()))
+}
+
+fn forward(env_path: &Path, ulid: &str) -> Result<String> {
+    let layout = MailLayout::new(mail_root(env_path));
+    let message = stored_message(&layout, ulid)?;
+    let (meta, body) = forward_draft(&message)?;
+    create_dir_all(&layout.drafts())?;
+    let path = write_draft(&layout, &meta, &body)?;
+    Ok(format!("draft created: {}", path.display()))
+}
+
+fn search(
+    env_path: &Path,
+    logger: &Logger,
+    query: &str,
+    reindex: bool,
+    json: bool,
+) -> Result<String> {
+    let layout = MailLayout::new(mail_root(env_path));
+    let index = SearchIndex::new(&layout);
+    let mut lines = Vec::new();
+    if reindex || !index.exists() {
+        let (count, skipped) = index.rebuild(&layout)?;
+        log_skipped(logger, &skipped)?;
+        if !json {
+            lines.push(format!("indexed {count} messages"));
+        }
     }
-    let message_path = pipeline.queue_draft(&draft_path)?;
-    if draft_path.starts_with(layout.drafts()) {
-        let _ = fs::remove_file(&draft_path);
+    let query = SearchQuery::parse(query)?;
+    if query.is_empty() {
+        if json {
+            return Ok("[]".to_string());
+        }
+        return Ok(lines.join("\n"));
     }
-    let results = pipeline.dispatch_pending()?;
-    let sent = results
-        .iter()
-        .filter(|result| matches!(result, DispatchResult::Sent(_)))
-        .count();
-    let retried = results
-        .iter()
-        .filter(|result| matches!(result, DispatchResult::Retry(_)))
-        .count();
-    let ulid = message_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .unwrap_or_default()
-        .to_string();
-    Ok(format!(
-        "queued {ulid} -> sent={sent} retry={retried} outbox={}",
-        layout.outbox().display()
-    ))
+    let hits = index.search(&query)?;
+    if json {
+        return Ok(serde_json::to_string(&hits)?);
+    }
+    if hits.is_empty() {
+        lines.push("no matches".to_string());
+    }
+    for hit in &hits {
+        lines.push(format!(
+            "[{list}] {from} :: {subject} ({ulid}) {date}",
+            list = hit.list,
+            from = hit.from,
+            subject = hit.subject,
+            ulid = hit.ulid,
+            date = hit.received_at
+        ));
+    }
+    Ok(lines.join("\n"))
 }
 
-fn resolve_draft_path(layout: &MailLayout, draft: &str) -> Result<PathBuf> {
-    let candidate = PathBuf::from(draft);
-    if candidate.is_absolute() || candidate.exists() {
-        return Ok(candidate);
+fn render_message_views(
+    env_path: &Path,
+    env: &EnvConfig,
+    logger: &Logger,
+    ulid: &str,
+    allow_remote: bool,
+) -> Result<String> {
+    if allow_remote && !env.load_external_per_message {
+        bail!("remote content is disabled (load_external_per_message=false)");
     }
-    let drafts_dir = layout.drafts();
-    let with_suffix = if draft.ends_with(".md") {
-        drafts_dir.join(draft)
+    let layout = MailLayout::new(mail_root(env_path));
+    let mut message = stored_message(&layout, ulid)?;
+    if FLAT_LISTS.contains(&message.list.as_str()) {
+        bail!("message {ulid} is outbound; only received mail can be re-rendered");
+    }
+    let remote = if allow_remote {
+        RemoteContent::Allow
     } else {
-        drafts_dir.join(format!("{draft}.md"))
+        RemoteContent::Block
     };
-    if with_suffix.exists() {
-        return Ok(with_suffix);
+    let body = fs::read(message.message_path())
+        .with_context(|| format!("reading {}", message.message_path().display()))?;
+    let policy = RenderPolicy::from_mode(&message.sidecar.render.mode);
+    let stored_attachments = list_has_attachments(&message.list);
+    let rendered = render_message(&body, env, policy, remote, stored_attachments)?;
+    write_atomic(&message.html_path(), rendered.html.as_bytes())?;
+    if let Some(plain) = message.plain_path() {
+        write_atomic(&plain, rendered.plain.as_bytes())?;
     }
-    Err(anyhow!("draft {draft} not found"))
+    message.sidecar.render.blocked_remote = rendered.blocked_remote;
+    message.sidecar.render.remote_allowed = allow_remote;
+    let yaml = serde_yaml::to_string(&message.sidecar)?;
+    write_atomic(&message.sidecar_path, yaml.as_bytes())?;
+    let index = SearchIndex::new(&layout);
+    if index.exists() {
+        index.add(&message)?;
+    }
+    let detail = format!(
+        "ulid={} remote_allowed={allow_remote}",
+        message.sidecar.ulid
+    );
+    logger.log(LogLevel::Minimal, "render.regenerated", Some(&detail))?;
+    let blocked = message.sidecar.render.blocked_remote.len();
+    Ok(if allow_remote {
+        format!("rendered {ulid} with remote content allowed")
+    } else {
+        format!("rendered {ulid} ({blocked} remote resources blocked)")
+    })
 }
 
-fn backup_mail(env_path: &Path, target: &Path) -> Result<String> {
-    let root = mail_root(env_path);
-    if let Some(parent) = target.parent()
-        && !parent.as_os_str().is_empty()
-    {
-        create_dir_all(parent)?;
+fn stored_message(layout: &MailLayout, ulid: &str) -> Result<StoredMessage> {
+    find_message(layout, ulid)?.ok_or_else(|| anyhow!("message {ulid} not found"))
+}
+
+/// Record sidecars a mailbox scan could not read, so damaged files show up
+/// in `owl logs` instead of silently vanishing from search and threads.
+fn log_skipped(logger: &Logger, skipped: &[SkippedSidecar]) -> Result<()> {
+    for entry in skipped {
+        logger.log(
+            LogLevel::Minimal,
+            "scan.skipped",
+            Some(&format!(
+                "path={} error={:#}",
+                entry.path.display(),
+                entry.error
+            )),
+        )?;
     }
-    let file = create_file(target).with_context(|| format!("creating {}", target.display()))?;
-    let encoder = GzEncoder::new(file, Compression::default());
-    let mut builder = Builder::new(encoder);
-    builder.append_dir_all(".", &root)?;
-    let encoder = builder.into_inner()?;
-    encoder.finish()?;
-    Ok(format!("backup written to {}", target.display()))
+    Ok(())
 }
 
-fn export_sender(
+fn show_thread(env_path: &Path, logger: &Logger, ulid: &str, json: bool) -> Result<String> {
+    let layout = MailLayout::new(mail_root(env_path));
+    let index = ThreadIndex::build(&layout)?;
+    log_skipped(logger, index.skipped())?;
+    let Some(thread) = index.thread_for(ulid) else {
+        bail!("message {ulid} not found");
+    };
+    if json {
+        return Ok(serde_json::to_string(&thread)?);
+    }
+    let mut lines = vec![format!(
+        "thread {} ({} messages)",
+        thread.thread_id,
+        thread.messages.len()
+    )];
+    for entry in &thread.messages {
+        let marker = if entry.ulid.eq_ignore_ascii_case(ulid) {
+            "*"
+        } else {
+            " "
+        };
+        lines.push(format!(
+            "{marker} [{list}] {from} :: {subject} ({ulid}) {date}",
+            list = entry.list,
+            from = entry.from,
+            subject = entry.subject,
+            ulid = entry.ulid,
+            date = entry.date
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+fn outbox(
     env_path: &Path,
     env: &EnvConfig,
-    list: &str,
-    address: &str,
-    target: &Path,
+    logger: &Logger,
+    action: OutboxAction,
+    json: bool,
 ) -> Result<String> {
-    let list_name = validate_list_name(list)?;
-    let parsed = Address::parse(address, env.keep_plus_tags)?;
-    let canonical = parsed.canonical().to_string();
+    let layout = MailLayout::new(mail_root(env_path));
+    let pipeline = OutboxPipeline::new(layout, env.clone(), logger.clone());
+    let outcome = match action {
+        OutboxAction::List => return outbox_list(&pipeline, json),
+        OutboxAction::Retry { ulid } => pipeline.retry(&ulid)?,
+        OutboxAction::Resend { ulid } => pipeline.resend(&ulid)?,
+        OutboxAction::Cancel { ulid } => {
+            let path = pipeline.cancel(&ulid)?;
+            return Ok(format!("draft restored: {}", path.display()));
+        }
+    };
+    Ok(match outcome {
+        DispatchResult::Sent(ulid) => format!("sent {ulid}"),
+        DispatchResult::Retry(ulid) => format!("deferred {ulid}; will retry"),
+        DispatchResult::Failed(ulid) => format!("failed {ulid}"),
+    })
+}
+
+fn outbox_list(pipeline: &OutboxPipeline, json: bool) -> Result<String> {
+    let entries: Vec<OutboxEntry> = pipeline
+        .entries()?
+        .into_iter()
+        .map(|sidecar| {
+            let outbound = sidecar.outbound.unwrap_or_default();
+            OutboxEntry {
+                ulid: sidecar.ulid,
+                subject: sidecar.headers_cache.subject,
+                to: sidecar.headers_cache.to,
+                outbound: OutboundView::from_state(&outbound),
+            }
+        })
+        .collect();
+    if json {
+        return Ok(serde_json::to_string(&entries)?);
+    }
+    if entries.is_empty() {
+        return Ok("outbox empty".into());
+    }
+    let mut lines = Vec::new();
+    for entry in &entries {
+        lines.push(format!(
+            "{ulid} [{status}] attempts={attempts} next={next} :: {subject} -> {to}",
+            ulid = entry.ulid,
+            status = entry.outbound.status,
+            attempts = entry.outbound.attempts,
+            next = entry.outbound.next_attempt_at.as_deref().unwrap_or("-"),
+            subject = entry.subject,
+            to = entry.to.join(", ")
+        ));
+        if let Some(summary) = entry.outbound.delivery_summary() {
+            lines.push(format!("    {summary}"));
+        }
+        if let Some(error) = &entry.outbound.last_error {
+            lines.push(format!("    last_error: {error}"));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+fn dkim_keys(
+    env_path: &Path,
+    env: &EnvConfig,
+    logger: &Logger,
+    action: DkimAction,
+    json: bool,
+) -> Result<String> {
+    let layout = MailLayout::new(mail_root(env_path));
+    let now = OffsetDateTime::now_utc();
+    match action {
+        DkimAction::Rotate {
+            selector,
+            overlap,
+            rsa,
+        } => {
+            if let Some(finished) = finish_dkim_rotation(&layout, env_path, logger, now)? {
+                return Ok(finished);
+            }
+            let overlap = overlap.unwrap_or_else(|| env.dkim_rotation_overlap.clone());
+            let duration = parse_interval(&overlap)
+                .ok_or_else(|| anyhow!("invalid DKIM rotation overlap: {overlap}"))?;
+            let key_type = if rsa {
+                DkimKeyType::Rsa
+            } else {
+                DkimKeyType::Ed25519
+            };
+            let (rotation, material) = ops_dkim::begin_rotation(
+                &layout,
+                env,
+                key_type,
+                selector.as_deref(),
+                duration,
+                now,
+            )?;
+            logger.log(
+                LogLevel::Minimal,
+                "dkim.rotation.started",
+                Some(&format!(
+                    "from={} to={} switch_at={}",
+                    rotation.from, rotation.to, rotation.switch_at
+                )),
+            )?;
+            let record = fs::read_to_string(&material.dns_record_path)
+                .with_context(|| format!("reading {}", material.dns_record_path.display()))?;
+            Ok(format!(
+                "publish {}._domainkey TXT {}
+signing stays on {} until {}; run `owl dkim rotate` again after that to update .env and archive the old key",
+                rotation.to,
+                ops_dkim::txt_strings(record.trim()),
+                rotation.from,
+                rotation.switch_at
+            ))
+        }
+        DkimAction::Status => {
+            let status = ops_dkim::status(&layout, env, now)?;
+            if json {
+                return Ok(serde_json::to_string(&status)?);
+            }
+            if status.selectors.is_empty() {
+                return Ok("no DKIM keys; run `owl install`".into());
+            }
+            let mut lines = Vec::new();
+            for entry in &status.selectors {
+                lines.push(format!(
+                    "{} [{}] {} age={}",
+                    entry.selector,
+                    entry.state.as_str(),
+                    entry.key_type,
+                    entry
+                        .age_days
+                        .map(|days| format!("{days}d"))
+                        .unwrap_or_else(|| "-".into())
+                ));
+                if let Some(record) = &entry.record {
+                    lines.push(format!(
+                        "    {} TXT {}",
+                        entry.record_name,
+                        ops_dkim::txt_strings(record)
+                    ));
+                }
+            }
+            if let Some(rotation) = &status.rotation {
+                lines.push(format!(
+                    "rotation: {} {} -> {} at {}",
+                    rotation.key_type.dns_key_type(),
+                    rotation.from,
+                    rotation.to,
+                    rotation.switch_at
+                ));
+            }
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+/// Finish a DKIM rotation whose overlap has passed; `None` when there is
+/// nothing to do.
+fn finish_dkim_rotation(
+    layout: &MailLayout,
+    env_path: &Path,
+    logger: &Logger,
+    now: OffsetDateTime,
+) -> Result<Option<String>> {
+    let Some((rotation, archived)) = ops_dkim::finish_due_rotation(layout, env_path, now)? else {
+        return Ok(None);
+    };
+    logger.log(
+        LogLevel::Minimal,
+        "dkim.rotation.finished",
+        Some(&format!(
+            "from={} to={} archived={}",
+            rotation.from,
+            rotation.to,
+            archived.display()
+        )),
+    )?;
+    Ok(Some(format!(
+        "{} switched from {} to {}; old key archived at {}",
+        rotation.key_type.env_key(),
+        rotation.from,
+        rotation.to,
+        archived.display()
+    )))
+}
+
+fn send_draft(env_path: &Path, env: &EnvConfig, logger: &Logger, draft: &str) -> Result<String> {
+    let root = mail_root(env_path);
+    let layout = MailLayout::new(&root);
+    let draft_path = resolve_draft_path(&layout, draft)?;
+    if !draft_path.exists() {
+        bail!("draft {} not found", draft_path.display());
+    }
+    // A finished rotation rewrites dkim_selector in .env, so sign with the
+    // reloaded config rather than the one read before it.
+    let env = match finish_dkim_rotation(&layout, env_path, logger, OffsetDateTime::now_utc())? {
+        Some(_) => load_env(env_path)?,
+        None => env.clone(),
+    };
+    let pipeline = OutboxPipeline::new(layout.clone(), env, logger.clone());
+    let message_path = pipeline.queue_draft(&draft_path)?;
+    if draft_path.starts_with(layout.drafts()) {
+        let _ = fs::remove_file(&draft_path);
+    }
+    let results = pipeline.dispatch_pending()?;
+    let sent = results
+        .iter()
+        .filter(|result| matches!(result, DispatchResult::Sent(_)))
+        .count();
+    let retried = results
+        .iter()
+        .filter(|result| matches!(result, DispatchResult::Retry(_)))
+        .count();
+    let failed = results
+        .iter()
+        .filter(|result| matches!(result, DispatchResult::Failed(_)))
+        .count();
+    let ulid = message_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_string();
+    Ok(format!(
+        "queued {ulid} -> sent={sent} retry={retried} failed={failed} outbox={}",
+        layout.outbox().display()
+    ))
+}
+
+fn resolve_draft_path(layout: &MailLayout, draft: &str) -> Result<PathBuf> {
+    let candidate = PathBuf::from(draft);
+    if candidate.is_absolute() || candidate.exists() {
+        return Ok(candidate);
+    }
+    let drafts_dir = layout.drafts();
+    let with_suffix = if draft.ends_with(".md") {
+        drafts_dir.join(draft)
+    } else {
+        drafts_dir.join(format!("{draft}.md"))
+    };
+    if with_suffix.exists() {
+        return Ok(with_suffix);
+    }
+    Err(anyhow!("draft {draft} not found"))
+}
+
+fn backup_mail(env_path: &Path, target: &Path) -> Result<String> {
+    let root = mail_root(env_path);
+    if let Some(parent) = target.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        create_dir_all(parent)?;
+    }
+    let file = create_file(target).with_context(|| format!("creating {}", target.display()))?;
+    let encoder = GzEncoder::new(file, Compression::default());
+    let mut builder = Builder::new(encoder);
+    builder.append_dir_all(".", &root)?;
+    let encoder = builder.into_inner()?;
+    encoder.finish()?;
+    Ok(format!("backup written to {}", target.display()))
+}
+
+fn export_sender(
+    env_path: &Path,
+    env: &EnvConfig,
+    list: &str,
+    address: &str,
+    target: &Path,
+) -> Result<String> {
+    let list_name = validate_list_name(list)?;
+    let parsed = Address::parse(address, env.keep_plus_tags)?;
+    let canonical = parsed.canonical().to_string();
     let root = mail_root(env_path);
     let layout = MailLayout::new(&root);
     let sender_dir = layout.root().join(list_name).join(&canonical);
@@ -929,7 +1847,7 @@ fn collect_attachments(dir: &Path) -> Result<HashSet<String>> {
     Ok(attachments)
 }
 
-fn import_archive(env_path: &Path, source: &Path) -> Result<String> {
+fn import_archive(env_path: &Path, logger: &Logger, source: &Path) -> Result<String> {
     if !source.exists() {
         bail!("source {} not found", source.display());
     }
@@ -944,7 +1862,7 @@ fn import_archive(env_path: &Path, source: &Path) -> Result<String> {
     }
 
     let outcome = if source.is_dir() {
-        Outcome::Messages(import_maildir(&layout, &env, source)?)
+        Outcome::Messages(import_maildir(&layout, &env, logger, source)?)
     } else {
         let lower_name = source
             .file_name()
@@ -961,7 +1879,7 @@ fn import_archive(env_path: &Path, source: &Path) -> Result<String> {
                 .map(|name| name.ends_with(".mbox"))
                 .unwrap_or(false)
         {
-            Outcome::Messages(import_mbox(&layout, &env, source)?)
+            Outcome::Messages(import_mbox(&layout, &env, logger, source)?)
         } else {
             let file =
                 File::open(source).with_context(|| format!("opening {}", source.display()))?;
@@ -983,6 +1901,12 @@ fn import_archive(env_path: &Path, source: &Path) -> Result<String> {
         }
     };
 
+    let index = SearchIndex::new(&layout);
+    if index.exists() {
+        let (_, skipped) = index.rebuild(&layout)?;
+        log_skipped(logger, &skipped)?;
+    }
+
     let summary = match outcome {
         Outcome::Archive => format!("imported {} into {}", source.display(), root.display()),
         Outcome::Messages(count) => format!(
@@ -995,8 +1919,13 @@ fn import_archive(env_path: &Path, source: &Path) -> Result<String> {
     Ok(summary)
 }
 
-fn import_maildir(layout: &MailLayout, env: &EnvConfig, dir: &Path) -> Result<usize> {
-    let (pipeline, rules) = inbound_context(layout, env)?;
+fn import_maildir(
+    layout: &MailLayout,
+    env: &EnvConfig,
+    logger: &Logger,
+    dir: &Path,
+) -> Result<usize> {
+    let (pipeline, rules) = inbound_context(layout, env, logger)?;
     let mut count = 0;
     for leaf in ["cur", "new"] {
         let path = dir.join(leaf);
@@ -1008,7 +1937,7 @@ fn import_maildir(layout: &MailLayout, env: &EnvConfig, dir: &Path) -> Result<us
             if entry.file_type()?.is_file() {
                 let body = fs::read(entry.path())
                     .with_context(|| format!("reading {}", entry.path().display()))?;
-                deliver_imported_message(&pipeline, &rules, env, &body)?;
+                deliver_message(&pipeline, &rules, env, &body, IMPORT_FALLBACK_SENDER)?;
                 count += 1;
             }
         }
@@ -1016,10 +1945,15 @@ fn import_maildir(layout: &MailLayout, env: &EnvConfig, dir: &Path) -> Result<us
     Ok(count)
 }
 
-fn import_mbox(layout: &MailLayout, env: &EnvConfig, path: &Path) -> Result<usize> {
+fn import_mbox(
+    layout: &MailLayout,
+    env: &EnvConfig,
+    logger: &Logger,
+    path: &Path,
+) -> Result<usize> {
     let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
     let mut reader = BufReader::new(file);
-    let (pipeline, rules) = inbound_context(layout, env)?;
+    let (pipeline, rules) = inbound_context(layout, env, logger)?;
     let mut current = Vec::new();
     let mut count = 0;
     loop {
@@ -1027,14 +1961,14 @@ fn import_mbox(layout: &MailLayout, env: &EnvConfig, path: &Path) -> Result<usiz
         let read = reader.read_until(b'\n', &mut line)?;
         if read == 0 {
             if !current.is_empty() {
-                deliver_imported_message(&pipeline, &rules, env, &current)?;
+                deliver_message(&pipeline, &rules, env, &current, IMPORT_FALLBACK_SENDER)?;
                 count += 1;
             }
             break;
         }
         if line.starts_with(b"From ") {
             if !current.is_empty() {
-                deliver_imported_message(&pipeline, &rules, env, &current)?;
+                deliver_message(&pipeline, &rules, env, &current, IMPORT_FALLBACK_SENDER)?;
                 count += 1;
                 current.clear();
             }
@@ -1045,52 +1979,88 @@ fn import_mbox(layout: &MailLayout, env: &EnvConfig, path: &Path) -> Result<usiz
     Ok(count)
 }
 
-fn inbound_context(layout: &MailLayout, env: &EnvConfig) -> Result<(InboundPipeline, LoadedRules)> {
-    let pipeline = InboundPipeline::new(layout.clone(), env.clone())?;
+fn inbound_context(
+    layout: &MailLayout,
+    env: &EnvConfig,
+    logger: &Logger,
+) -> Result<(InboundPipeline, LoadedRules)> {
+    let pipeline = InboundPipeline::new(layout.clone(), env.clone())?.with_logger(logger.clone());
     let loader = RulesetLoader::new(layout.root());
     let rules = loader.load()?;
     Ok((pipeline, rules))
 }
 
-fn deliver_imported_message(
-    pipeline: &InboundPipeline,
-    rules: &LoadedRules,
-    env: &EnvConfig,
-    body: &[u8],
-) -> Result<()> {
-    let parsed = mailparse::parse_mail(body).map_err(|err| anyhow!(err.to_string()))?;
-    let subject = parsed
-        .headers
-        .get_first_value("Subject")
-        .unwrap_or_else(|| "no subject".to_string());
-    let sender = if let Some(from_value) = parsed.headers.get_first_value("From") {
-        let addresses =
-            mailparse::addrparse(from_value.as_str()).map_err(|err| anyhow!(err.to_string()))?;
-        if let Some(addr) = first_mailbox(&addresses) {
-            Address::parse(&addr, env.keep_plus_tags)
-        } else {
-            Address::parse("unknown@import.invalid", env.keep_plus_tags)
-        }
-    } else {
-        Address::parse("unknown@import.invalid", env.keep_plus_tags)
-    }?;
-    let route = determine_route(&sender, rules, env)?;
-    pipeline.deliver_to_route(route, &sender, &subject, body)?;
-    Ok(())
-}
+const IMPORT_FALLBACK_SENDER: &str = "unknown@import.invalid";
 
-fn first_mailbox(addrs: &[MailAddr]) -> Option<String> {
-    for addr in addrs {
-        match addr {
-            MailAddr::Single(info) => return Some(info.addr.clone()),
-            MailAddr::Group(group) => {
-                if let Some(first) = group.addrs.first() {
-                    return Some(first.addr.clone());
-                }
+#[allow(clippy::too_many_arguments)]
+fn deliver(
+    env_path: &Path,
+    env: &EnvConfig,
+    logger: &Logger,
+    sender: &str,
+    recipient: &str,
+    client_ip: Option<&str>,
+    helo: Option<&str>,
+    input: &mut dyn io::Read,
+    authenticator: Authenticator,
+) -> Result<String> {
+    // Received headers in piped mail are not trusted: without --client-ip
+    // SPF is recorded as none. MTAs pass an empty value for local mail.
+    let origin = MessageOrigin {
+        client_ip: client_ip.and_then(|ip| ip.trim().parse::<IpAddr>().ok()),
+        helo: helo
+            .map(str::trim)
+            .filter(|helo| !helo.is_empty())
+            .map(str::to_string),
+        mail_from: sender.to_string(),
+        recipients: vec![recipient.to_string()],
+    };
+    let recipient = Address::parse(recipient, env.keep_plus_tags)
+        .map_err(MalformedMessageError::new)
+        .with_context(|| format!("invalid recipient {recipient}"))?;
+    let envelope_sender = envelope_fallback_sender(sender);
+    let mut body = Vec::new();
+    input
+        .read_to_end(&mut body)
+        .context("reading message from stdin")?;
+    let layout = MailLayout::new(mail_root(env_path));
+    // Only imports skip authentication; piped mail is checked like LMTP.
+    let (pipeline, rules) = inbound_context(&layout, env, logger)?;
+    let pipeline = pipeline.with_authenticator(authenticator);
+    match deliver_message_from(&pipeline, &rules, env, &body, &origin) {
+        Ok((route, path)) => {
+            let route = route.as_str();
+            logger.log(
+                LogLevel::Minimal,
+                "deliver.stored",
+                Some(&format!(
+                    "sender={envelope_sender} recipient={} route={route} bytes={}",
+                    recipient.canonical(),
+                    body.len()
+                )),
+            )?;
+            if let Err(err) = SearchIndex::new(&layout).add_delivered(&path) {
+                let _ = logger.log(
+                    LogLevel::Minimal,
+                    "deliver.index_error",
+                    Some(&err.to_string()),
+                );
             }
+            Ok(format!("delivered to {route}: {}", path.display()))
+        }
+        Err(err) => {
+            // Keep the delivery error intact so the exit code stays accurate.
+            let _ = logger.log(
+                LogLevel::Minimal,
+                "deliver.failed",
+                Some(&format!(
+                    "sender={envelope_sender} recipient={} error={err}",
+                    recipient.canonical()
+                )),
+            );
+            Err(err)
         }
     }
-    None
 }
 
 fn resolve_env_path(raw: &str) -> Result<PathBuf> {
@@ -1254,40 +2224,6 @@ fn setup_env(
     Ok(())
 }
 
-fn upsert_env_setting(env_path: &Path, key: &str, value: &str) -> Result<()> {
-    let mut lines = Vec::new();
-    let mut found = false;
-    if env_path.exists() {
-        let data = fs::read_to_string(env_path)
-            .with_context(|| format!("reading {}", env_path.display()))?;
-        for line in data.lines() {
-            if line.trim_start().starts_with('#') || line.trim().is_empty() {
-                lines.push(line.to_string());
-                continue;
-            }
-            if let Some((k, _)) = line.split_once('=')
-                && k.trim() == key
-            {
-                lines.push(format!("{key}={value}"));
-                found = true;
-                continue;
-            }
-            lines.push(line.to_string());
-        }
-    }
-    if !found {
-        lines.push(format!("{key}={value}"));
-    }
-    let rendered = if lines.is_empty() {
-        format!("{key}={value}\n")
-    } else {
-        format!("{}\n", lines.join("\n"))
-    };
-    write_atomic(env_path, rendered.as_bytes())
-        .with_context(|| format!("writing {}", env_path.display()))?;
-    Ok(())
-}
-
 fn set_rules_entry(path: &Path, entry: &str) -> Result<()> {
     if let Some(parent) = path.parent() {
         create_dir_all(parent)?;
@@ -1322,6 +2258,11 @@ fn set_settings_file(
     if let Some(parent) = path.parent() {
         create_dir_all(parent)?;
     }
+    // The wizard does not ask about authentication; keep what is configured.
+    let require_auth = fs::read_to_string(path)
+        .ok()
+        .and_then(|data| ListSettings::parse(&data).ok())
+        .map_or_else(|| "none".to_string(), |settings| settings.require_auth);
     let settings = format!(
         "list_status={list_status}\n\
 delete_after={delete_after}\n\
@@ -1329,7 +2270,8 @@ from={from_value}\n\
 reply_to={reply_to}\n\
 signature={signature}\n\
 body_format={body_format}\n\
-collapse_signatures=true\n"
+collapse_signatures=true\n\
+require_auth={require_auth}\n"
     );
     write_atomic(path, settings.as_bytes())?;
     Ok(())
@@ -1401,6 +2343,8 @@ mod tests {
         create_dir_all(&sender_dir).unwrap();
         let subject = "Greetings";
         let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+        let mut headers = crate::model::message::HeadersCache::new("Alice", subject);
+        headers.to = vec!["me@example.org".into()];
         let mut sidecar = MessageSidecar::new(
             ulid,
             crate::model::filename::message_filename(subject, ulid),
@@ -1408,12 +2352,16 @@ mod tests {
             "strict",
             crate::model::filename::html_filename(subject, ulid),
             "deadbeef",
-            crate::model::message::HeadersCache::new("Alice", subject),
+            headers,
         );
         sidecar.set_rspamd(crate::model::message::RspamdSummary {
             score: 4.5,
             symbols: vec!["VIOLATION".into()],
         });
+        sidecar.auth = Some(crate::model::message::AuthResults {
+            demoted: Some("accepted list requires dmarc: spoofed".into()),
+            ..Default::default()
+        });
         let sidecar_path = sender_dir.join(crate::model::filename::sidecar_filename(subject, ulid));
         write_atomic(
             &sidecar_path,
@@ -1425,11 +2373,275 @@ mod tests {
         assert!(output.contains("alice@example.org"));
         assert!(output.contains("Greetings"));
         assert!(output.contains("rspamd=4.5"));
+        assert!(output.contains("demoted: accepted list requires dmarc: spoofed"));
 
         let json = triage(&env_path, &env, None, None, true).unwrap();
         let parsed: Vec<TriageEntry> = serde_json::from_str(&json).unwrap();
         assert_eq!(parsed.len(), 1);
         assert_eq!(parsed[0].address, "alice@example.org");
+        assert_eq!(parsed[0].to, vec!["me@example.org".to_string()]);
+    }
+
+    #[test]
+    fn thread_shows_inbound_and_sent_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let mut inbound_headers =
+            crate::model::message::HeadersCache::new("alice@example.org", "Plans");
+        inbound_headers.message_id = Some("<plans@example.org>".into());
+        let inbound_ulid = "01ARZ3NDEKTSV4RRFFQ69G5FA1";
+        let inbound = MessageSidecar::new(
+            inbound_ulid,
+            crate::model::filename::message_filename("Plans", inbound_ulid),
+            "accepted",
+            "strict",
+            crate::model::filename::html_filename("Plans", inbound_ulid),
+            "hash",
+            inbound_headers,
+        );
+        let sender_dir = layout.accepted().join("alice@example.org");
+        create_dir_all(&sender_dir).unwrap();
+        write_atomic(
+            &sender_dir.join(crate::model::filename::sidecar_filename(
+                "Plans",
+                inbound_ulid,
+            )),
+            serde_yaml::to_string(&inbound).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let mut reply_headers =
+            crate::model::message::HeadersCache::new("me@example.org", "Re: Plans");
+        reply_headers.message_id = Some("<reply@example.org>".into());
+        reply_headers.in_reply_to = Some("<plans@example.org>".into());
+        let reply_ulid = "01ARZ3NDEKTSV4RRFFQ69G5FA2";
+        let reply = MessageSidecar::new(
+            reply_ulid,
+            crate::model::filename::outbox_message_filename(reply_ulid),
+            "sent",
+            "strict",
+            crate::model::filename::outbox_html_filename(reply_ulid),
+            "hash",
+            reply_headers,
+        );
+        write_atomic(
+            &layout
+                .sent()
+                .join(crate::model::filename::outbox_sidecar_filename(reply_ulid)),
+            serde_yaml::to_string(&reply).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let cli = OwlCli {
+            env: env_path.to_string_lossy().into(),
+            command: Some(Commands::Thread {
+                ulid: reply_ulid.into(),
+            }),
+            json: false,
+        };
+        let output = run(cli, EnvConfig::default()).unwrap();
+        assert!(output.starts_with("thread <plans@example.org> (2 messages)"));
+        assert!(output.contains("  [accepted] alice@example.org :: Plans"));
+        assert!(output.contains(&format!(
+            "* [sent] me@example.org :: Re: Plans ({reply_ulid})"
+        )));
+
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let json = show_thread(&env_path, &logger, inbound_ulid, true).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["thread_id"], "<plans@example.org>");
+        assert_eq!(value["messages"][1]["list"], "sent");
+
+        let err = show_thread(&env_path, &logger, "01ARZ3NDEKTSV4RRFFQ69G5FZZ", false).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[test]
+    fn reply_and_forward_write_drafts() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        let mut headers = crate::model::message::HeadersCache::new("alice@example.org", "Plans");
+        headers.message_id = Some("<plans@example.org>".into());
+        let sidecar = MessageSidecar::new(
+            ulid,
+            crate::model::filename::message_filename("Plans", ulid),
+            "accepted",
+            "strict",
+            crate::model::filename::html_filename("Plans", ulid),
+            "hash",
+            headers,
+        );
+        let sender_dir = layout.accepted().join("alice@example.org");
+        create_dir_all(&sender_dir).unwrap();
+        write_atomic(
+            &sender_dir.join(&sidecar.filename),
+            b"From: alice@example.org\r\nTo: me@example.org\r\nSubject: Plans\r\n\r\nLunch?\r\n",
+        )
+        .unwrap();
+        write_atomic(
+            &sender_dir.join(crate::model::filename::sidecar_filename("Plans", ulid)),
+            serde_yaml::to_string(&sidecar).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        fs::write(layout.accepted().join(".rules"), "alice@example.org\n").unwrap();
+        fs::write(
+            layout.accepted().join(".settings"),
+            "from=Team <team@example.org>\n",
+        )
+        .unwrap();
+
+        let output = reply(&env_path, &ulid.to_lowercase(), false).unwrap();
+        let path = PathBuf::from(output.trim_start_matches("draft created: "));
+        assert!(path.starts_with(layout.drafts()));
+        let draft = fs::read_to_string(&path).unwrap();
+        assert!(draft.contains("subject: 'Re: Plans'"));
+        assert!(!draft.contains("from:"));
+        assert!(draft.contains("in_reply_to: <plans@example.org>"));
+        assert!(draft.contains("> Lunch?"));
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let queued = OutboxPipeline::new(layout.clone(), EnvConfig::default(), logger)
+            .queue_draft(&path)
+            .unwrap();
+        let message = fs::read_to_string(queued).unwrap();
+        assert!(message.contains("From: Team <team@example.org>"));
+
+        let output = forward(&env_path, ulid).unwrap();
+        let path = PathBuf::from(output.trim_start_matches("draft created: "));
+        let draft = fs::read_to_string(&path).unwrap();
+        assert!(draft.contains("subject: 'Fwd: Plans'"));
+        assert!(draft.contains("to: []"));
+
+        let err = reply(&env_path, "01ARZ3NDEKTSV4RRFFQ69G5FZZ", true).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[test]
+    fn search_builds_index_and_follows_moved_senders() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let pipeline = InboundPipeline::new(layout.clone(), env.clone()).unwrap();
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let body =
+            b"From: alice@example.org\r\nSubject: Garden party\r\n\r\nBring the lanterns.\r\n";
+        pipeline
+            .deliver_to_route(
+                crate::ruleset::eval::Route::Accepted,
+                &sender,
+                "Garden party",
+                body,
+            )
+            .unwrap();
+        let ulid = find_ulid(&layout);
+
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let output = search(
+            &env_path,
+            &logger,
+            "lanterns from:alice@example.org",
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(output.starts_with("indexed 1 messages"));
+        assert!(output.contains(&format!(
+            "[accepted] alice@example.org :: Garden party ({ulid})"
+        )));
+        assert!(
+            search(&env_path, &logger, "lanterns list:spam", false, false)
+                .unwrap()
+                .contains("no matches")
+        );
+
+        move_sender(
+            &env_path,
+            &env,
+            "accepted".into(),
+            "spam".into(),
+            "alice@example.org".into(),
+        )
+        .unwrap();
+        let json = search(&env_path, &logger, "lanterns list:spam", false, true).unwrap();
+        let hits: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(hits[0]["ulid"], ulid.as_str());
+        assert_eq!(hits[0]["list"], "spam");
+
+        assert!(search(&env_path, &logger, "before:yesterday", false, false).is_err());
+
+        fs::write(layout.spam().join("alice@example.org/.broken.yml"), "{").unwrap();
+        let output = search(&env_path, &logger, "lanterns", true, false).unwrap();
+        assert!(output.starts_with("indexed 1 messages"));
+        let log = fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("scan.skipped"));
+        assert!(log.contains(".broken.yml"));
+    }
+
+    #[test]
+    fn render_allows_remote_content_per_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let pipeline = InboundPipeline::new(layout.clone(), env.clone()).unwrap();
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let body = b"From: alice@example.org\r\nSubject: News\r\nContent-Type: text/html\r\n\r\n<p>Hi<img src=\"https://cdn.example.org/logo.png\"></p>\r\n";
+        let path = pipeline
+            .deliver_to_route(crate::ruleset::eval::Route::Accepted, &sender, "News", body)
+            .unwrap();
+        let ulid = find_ulid(&layout);
+        let message = stored_message(&layout, &ulid).unwrap();
+        assert_eq!(
+            message.sidecar.render.blocked_remote,
+            vec!["https://cdn.example.org/logo.png".to_string()]
+        );
+        assert!(
+            !fs::read_to_string(message.html_path())
+                .unwrap()
+                .contains("cdn.example.org")
+        );
+
+        let output = render_message_views(&env_path, &env, &logger, &ulid, true).unwrap();
+        assert!(output.contains("remote content allowed"));
+        let message = stored_message(&layout, &ulid).unwrap();
+        assert!(message.sidecar.render.remote_allowed);
+        assert!(message.sidecar.render.blocked_remote.is_empty());
+        let html = fs::read_to_string(message.html_path()).unwrap();
+        assert!(html.contains("src=\"https://cdn.example.org/logo.png\""));
+        assert!(path.exists());
+
+        let output = render_message_views(&env_path, &env, &logger, &ulid, false).unwrap();
+        assert!(output.contains("1 remote resources blocked"));
+        assert!(
+            !stored_message(&layout, &ulid)
+                .unwrap()
+                .sidecar
+                .render
+                .remote_allowed
+        );
+
+        let disabled = EnvConfig {
+            load_external_per_message: false,
+            ..EnvConfig::default()
+        };
+        let err = render_message_views(&env_path, &disabled, &logger, &ulid, true).unwrap_err();
+        assert!(err.to_string().contains("load_external_per_message=false"));
+    }
+
+    fn find_ulid(layout: &MailLayout) -> String {
+        crate::fsops::scan::scan_messages(layout).unwrap().messages[0]
+            .sidecar
+            .ulid
+            .clone()
     }
 
     #[test]
@@ -1480,6 +2692,65 @@ mod tests {
         assert!(output.contains("rspamd=2.0"));
     }
 
+    #[test]
+    fn triage_summarizes_partial_outbound_delivery() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FD4";
+        let mut headers =
+            crate::model::message::HeadersCache::new("Owl <owl@example.org>", "Group");
+        headers.to = vec!["Bob <bob@example.org>".into(), "carol@down.example".into()];
+        let mut sidecar = MessageSidecar::new(
+            ulid,
+            crate::model::filename::outbox_message_filename(ulid),
+            "outbox",
+            "strict",
+            crate::model::filename::outbox_html_filename(ulid),
+            "hash",
+            headers,
+        );
+        let outbound = sidecar.outbound_state_mut();
+        outbound.attempts = 2;
+        outbound.last_error = Some("carol@down.example: 421 busy".into());
+        let mut bob = RecipientState::new("bob@example.org");
+        bob.status = OutboundStatus::Sent;
+        outbound.recipients = vec![bob, RecipientState::new("carol@down.example")];
+        write_atomic(
+            &layout
+                .outbox()
+                .join(crate::model::filename::outbox_sidecar_filename(ulid)),
+            serde_yaml::to_string(&sidecar).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let output = triage(&env_path, &env, None, Some("outbox".into()), false).unwrap();
+        assert!(output.starts_with("outbox:"));
+        assert!(output.contains("delivered=1/2 pending=carol@down.example"));
+        let output = triage(
+            &env_path,
+            &env,
+            Some("carol@down.example".into()),
+            Some("Outbox".into()),
+            true,
+        )
+        .unwrap();
+        let entries: Vec<TriageEntry> = serde_json::from_str(&output).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outbound.as_ref().unwrap().recipients.len(), 2);
+        let output = triage(
+            &env_path,
+            &env,
+            Some("dave@example.org".into()),
+            Some("outbox".into()),
+            false,
+        )
+        .unwrap();
+        assert_eq!(output, "no messages matched");
+    }
+
     #[test]
     fn triage_unknown_list_errors() {
         let dir = tempfile::tempdir().unwrap();
@@ -1611,6 +2882,184 @@ mod tests {
         assert_eq!(sidecar.outbound.unwrap().attempts, 1);
     }
 
+    #[test]
+    fn dkim_rotate_publishes_new_selector_and_status_lists_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig {
+            dkim_rsa_selector: None,
+            ..EnvConfig::default()
+        };
+        fs::write(&env_path, env.to_env_string()).unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        dkim::ensure_ed25519_keypair(&layout.dkim_dir(), "mail").unwrap();
+
+        let rotate = || DkimAction::Rotate {
+            selector: Some("next".into()),
+            overlap: Some("0s".into()),
+            rsa: false,
+        };
+        let output = dkim_keys(&env_path, &env, &logger, rotate(), false).unwrap();
+        assert!(output.starts_with("publish next._domainkey TXT \"v=DKIM1; k=ed25519; p="));
+        assert!(output.contains("signing stays on mail until"));
+
+        let status = dkim_keys(&env_path, &env, &logger, DkimAction::Status, false).unwrap();
+        assert!(status.contains("mail [retiring] ed25519 age=0d"));
+        assert!(status.contains("next [active] ed25519 age=0d"));
+        assert!(status.contains("rotation: ed25519 mail -> next at"));
+
+        let output = dkim_keys(&env_path, &env, &logger, rotate(), false).unwrap();
+        assert!(output.starts_with("dkim_selector switched from mail to next"));
+        let env = EnvConfig::from_file(&env_path).unwrap();
+        assert_eq!(env.dkim_selector, "next");
+        let json = dkim_keys(&env_path, &env, &logger, DkimAction::Status, true).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["selectors"][0]["selector"], "next");
+        assert_eq!(value["selectors"][1]["state"], "archived");
+        assert!(value["rotation"].is_null());
+    }
+
+    #[test]
+    fn dkim_rotate_rsa_replaces_the_rsa_selector() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        fs::write(&env_path, env.to_env_string()).unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        dkim::ensure_keypairs(&layout.dkim_dir(), "mail", Some("mail-rsa")).unwrap();
+
+        let rotate = || DkimAction::Rotate {
+            selector: Some("rsa-next".into()),
+            overlap: Some("0s".into()),
+            rsa: true,
+        };
+        let output = dkim_keys(&env_path, &env, &logger, rotate(), false).unwrap();
+        assert!(output.starts_with("publish rsa-next._domainkey TXT \"v=DKIM1; k=rsa; p="));
+        assert!(output.contains("signing stays on mail-rsa until"));
+
+        let status = dkim_keys(&env_path, &env, &logger, DkimAction::Status, false).unwrap();
+        assert!(status.contains("mail [active] ed25519"));
+        assert!(status.contains("mail-rsa [retiring] rsa"));
+        assert!(status.contains("rsa-next [active] rsa"));
+        assert!(status.contains("rotation: rsa mail-rsa -> rsa-next at"));
+
+        let output = dkim_keys(&env_path, &env, &logger, rotate(), false).unwrap();
+        assert!(output.starts_with("dkim_rsa_selector switched from mail-rsa to rsa-next"));
+        let env = EnvConfig::from_file(&env_path).unwrap();
+        assert_eq!(env.dkim_selector, "mail");
+        assert_eq!(env.dkim_rsa_selector.as_deref(), Some("rsa-next"));
+    }
+
+    #[test]
+    fn send_draft_signs_with_rotated_selector_after_finishing_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig {
+            dkim_rsa_selector: None,
+            retry_backoff: vec!["1h".into()],
+            ..EnvConfig::default()
+        };
+        fs::write(&env_path, env.to_env_string()).unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        dkim::ensure_ed25519_keypair(&layout.dkim_dir(), "mail").unwrap();
+        let rotate = DkimAction::Rotate {
+            selector: Some("next".into()),
+            overlap: Some("0s".into()),
+            rsa: false,
+        };
+        dkim_keys(&env_path, &env, &logger, rotate, false).unwrap();
+
+        let ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Hi\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nHello world!\n",
+        )
+        .unwrap();
+        send_draft(&env_path, &env, &logger, &draft_path.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            EnvConfig::from_file(&env_path).unwrap().dkim_selector,
+            "next"
+        );
+        assert!(!layout.dkim_private_key("mail").exists());
+        let message = fs::read_to_string(
+            layout
+                .outbox()
+                .join(crate::model::filename::outbox_message_filename(&ulid)),
+        )
+        .unwrap();
+        assert!(message.contains("s=next;"));
+        assert!(!message.contains("s=mail;"));
+    }
+
+    #[test]
+    fn outbox_lists_and_cancels_queued_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig {
+            retry_backoff: vec!["1h".into()],
+            ..EnvConfig::default()
+        };
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        assert_eq!(
+            outbox(&env_path, &env, &logger, OutboxAction::List, false).unwrap(),
+            "outbox empty"
+        );
+        let ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Hi\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nHello world!\n",
+        )
+        .unwrap();
+        send_draft(&env_path, &env, &logger, &draft_path.to_string_lossy()).unwrap();
+
+        let output = outbox(&env_path, &env, &logger, OutboxAction::List, false).unwrap();
+        assert!(output.contains(&format!("{ulid} [Pending] attempts=1")));
+        assert!(output.contains("last_error:"));
+        let json = outbox(&env_path, &env, &logger, OutboxAction::List, true).unwrap();
+        let entries: Vec<OutboxEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].subject, "Hi");
+        assert!(entries[0].outbound.next_attempt_at.is_some());
+
+        let err = outbox(
+            &env_path,
+            &env,
+            &logger,
+            OutboxAction::Resend { ulid: ulid.clone() },
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("use retry"));
+
+        let output = outbox(
+            &env_path,
+            &env,
+            &logger,
+            OutboxAction::Cancel { ulid: ulid.clone() },
+            false,
+        )
+        .unwrap();
+        assert!(output.starts_with("draft restored: "));
+        let draft = fs::read_to_string(&draft_path).unwrap();
+        assert!(draft.contains("subject: Hi"));
+        assert!(draft.contains("Hello world!"));
+        assert_eq!(
+            outbox(&env_path, &env, &logger, OutboxAction::List, false).unwrap(),
+            "outbox empty"
+        );
+    }
+
     #[test]
     fn send_draft_reports_missing_file() {
         let dir = tempfile::tempdir().unwrap();
@@ -1802,100 +3251,342 @@ mod tests {
             },
         )
         .unwrap();
-        assert_eq!(disabled_json, "[]");
+        assert_eq!(disabled_json, "[]");
+    }
+
+    #[test]
+    #[serial]
+    fn import_maildir_consumes_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = dir.path().join("maildir");
+        fs::create_dir_all(maildir.join("cur")).unwrap();
+        fs::write(
+            maildir.join("cur/msg1"),
+            sample_email("Alice <alice@example.org>", "Status"),
+        )
+        .unwrap();
+
+        let root = dir.path().join("mail");
+        fs::create_dir_all(&root).unwrap();
+        let env_path = root.join(".env");
+        fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
+
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let output = import_archive(&env_path, &logger, &maildir).unwrap();
+        assert!(output.contains("1 messages"));
+
+        let layout = MailLayout::new(&root);
+        let sender_dir = layout.quarantine().join("alice@example.org");
+        let entries: Vec<_> = fs::read_dir(&sender_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert!(
+            entries
+                .iter()
+                .any(|path| path.extension().map(|ext| ext == "eml").unwrap_or(false))
+        );
+        let sidecar_path = entries
+            .iter()
+            .find(|path| path.extension().map(|ext| ext == "yml").unwrap_or(false))
+            .unwrap();
+        let sidecar: MessageSidecar =
+            serde_yaml::from_str(&fs::read_to_string(sidecar_path).unwrap()).unwrap();
+        assert_eq!(sidecar.status_shadow, "quarantine");
+        assert_eq!(sidecar.headers_cache.subject, "Status");
+    }
+
+    #[test]
+    #[serial]
+    fn import_mbox_consumes_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("mail");
+        fs::create_dir_all(&root).unwrap();
+        let env_path = root.join(".env");
+        fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
+
+        let mbox_path = dir.path().join("import.mbox");
+        let mut mbox = Vec::new();
+        mbox.extend_from_slice(b"From alice@example.org Sat Jan  1 00:00:00 2022\n");
+        mbox.extend_from_slice(&sample_email("Alice <alice@example.org>", "Hello"));
+        mbox.push(b'\n');
+        mbox.extend_from_slice(b"From bob@example.org Sat Jan  1 01:00:00 2022\n");
+        mbox.extend_from_slice(&sample_email("Bob <bob@example.org>", "Update"));
+        fs::write(&mbox_path, &mbox).unwrap();
+
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let output = import_archive(&env_path, &logger, &mbox_path).unwrap();
+        assert!(output.contains("2 messages"));
+
+        let layout = MailLayout::new(&root);
+        assert!(layout.quarantine().join("alice@example.org").exists());
+        assert!(layout.quarantine().join("bob@example.org").exists());
+    }
+
+    #[test]
+    #[serial]
+    fn import_mail_without_from_uses_fallback_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = dir.path().join("maildir");
+        fs::create_dir_all(maildir.join("cur")).unwrap();
+        fs::write(maildir.join("cur/msg1"), b"Subject: Notice\n\nHello\n").unwrap();
+
+        let root = dir.path().join("mail");
+        fs::create_dir_all(&root).unwrap();
+        let env_path = root.join(".env");
+        fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
+
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        import_archive(&env_path, &logger, &maildir).unwrap();
+
+        let layout = MailLayout::new(&root);
+        let fallback_dir = layout.quarantine().join("unknown@import.invalid");
+        assert!(fallback_dir.exists());
+    }
+
+    #[test]
+    #[serial]
+    fn deliver_routes_stdin_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        fs::write(layout.accepted().join(".rules"), "alice@example.org\n").unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(sample_email("Alice <alice@example.org>", "Hi"));
+        let output = deliver(
+            &env_path,
+            &env,
+            &logger,
+            "bounce@example.org",
+            "me@example.org",
+            None,
+            None,
+            &mut input,
+            static_authenticator(),
+        )
+        .unwrap();
+        assert!(output.starts_with("delivered to accepted"));
+        assert!(layout.accepted().join("alice@example.org").exists());
+        let log = fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("deliver.stored"));
+        assert!(log.contains("route=accepted"));
     }
 
     #[test]
     #[serial]
-    fn import_maildir_consumes_messages() {
-        with_fake_render_env(|| {
-            let dir = tempfile::tempdir().unwrap();
-            let maildir = dir.path().join("maildir");
-            fs::create_dir_all(maildir.join("cur")).unwrap();
-            fs::write(
-                maildir.join("cur/msg1"),
-                sample_email("Alice <alice@example.org>", "Status"),
-            )
+    fn deliver_quarantines_spoofed_accepted_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        fs::write(layout.accepted().join(".rules"), "alice@example.org\n").unwrap();
+        fs::write(
+            layout.accepted().join(".settings"),
+            "list_status=accepted\nrequire_auth=dmarc\n",
+        )
+        .unwrap();
+        let resolver = crate::util::dns::StaticResolver::new()
+            .with_txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all")
+            .with_txt("_dmarc.example.org", "v=DMARC1; p=reject");
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(
+            b"From: Alice <alice@example.org>\r\nSubject: Pay now\r\n\r\nBody\r\n".to_vec(),
+        );
+        let output = deliver(
+            &env_path,
+            &EnvConfig::default(),
+            &logger,
+            "alice@example.org",
+            "me@example.org",
+            Some("203.0.113.7"),
+            Some("forger"),
+            &mut input,
+            Authenticator::new(std::sync::Arc::new(resolver)),
+        )
+        .unwrap();
+        assert!(output.starts_with("delivered to quarantine"));
+        let sender_dir = layout.quarantine().join("alice@example.org");
+        let sidecar = fs::read_dir(&sender_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "yml"))
             .unwrap();
-
-            let root = dir.path().join("mail");
-            fs::create_dir_all(&root).unwrap();
-            let env_path = root.join(".env");
-            fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
-
-            let output = import_archive(&env_path, &maildir).unwrap();
-            assert!(output.contains("1 messages"));
-
-            let layout = MailLayout::new(&root);
-            let sender_dir = layout.quarantine().join("alice@example.org");
-            let entries: Vec<_> = fs::read_dir(&sender_dir)
+        let sidecar: MessageSidecar =
+            serde_yaml::from_str(&fs::read_to_string(sidecar).unwrap()).unwrap();
+        let auth = sidecar.auth.unwrap();
+        assert_eq!(auth.spf.result.as_str(), "fail");
+        assert_eq!(auth.dmarc.result.as_str(), "fail");
+        assert!(
+            auth.demoted
+                .as_deref()
                 .unwrap()
-                .map(|entry| entry.unwrap().path())
-                .collect();
-            assert!(
-                entries
-                    .iter()
-                    .any(|path| path.extension().map(|ext| ext == "eml").unwrap_or(false))
-            );
-            let sidecar_path = entries
-                .iter()
-                .find(|path| path.extension().map(|ext| ext == "yml").unwrap_or(false))
-                .unwrap();
-            let sidecar: MessageSidecar =
-                serde_yaml::from_str(&fs::read_to_string(sidecar_path).unwrap()).unwrap();
-            assert_eq!(sidecar.status_shadow, "quarantine");
-            assert_eq!(sidecar.headers_cache.subject, "Status");
-        });
+                .starts_with("accepted list requires dmarc:")
+        );
+        assert!(!layout.accepted().join("alice@example.org").exists());
     }
 
     #[test]
     #[serial]
-    fn import_mbox_consumes_messages() {
-        with_fake_render_env(|| {
-            let dir = tempfile::tempdir().unwrap();
-            let root = dir.path().join("mail");
-            fs::create_dir_all(&root).unwrap();
-            let env_path = root.join(".env");
-            fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
-
-            let mbox_path = dir.path().join("import.mbox");
-            let mut mbox = Vec::new();
-            mbox.extend_from_slice(b"From alice@example.org Sat Jan  1 00:00:00 2022\n");
-            mbox.extend_from_slice(&sample_email("Alice <alice@example.org>", "Hello"));
-            mbox.push(b'\n');
-            mbox.extend_from_slice(b"From bob@example.org Sat Jan  1 01:00:00 2022\n");
-            mbox.extend_from_slice(&sample_email("Bob <bob@example.org>", "Update"));
-            fs::write(&mbox_path, &mbox).unwrap();
-
-            let output = import_archive(&env_path, &mbox_path).unwrap();
-            assert!(output.contains("2 messages"));
-
-            let layout = MailLayout::new(&root);
-            assert!(layout.quarantine().join("alice@example.org").exists());
-            assert!(layout.quarantine().join("bob@example.org").exists());
-        });
+    fn deliver_does_not_trust_received_headers_for_spf() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let resolver = crate::util::dns::StaticResolver::new()
+            .with_txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all");
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(
+            b"Received: from mx (mx [192.0.2.5]) by owl.test\r\n\
+              From: Alice <alice@example.org>\r\nSubject: Hi\r\n\r\nBody\r\n"
+                .to_vec(),
+        );
+        deliver(
+            &env_path,
+            &EnvConfig::default(),
+            &logger,
+            "alice@example.org",
+            "me@example.org",
+            Some(""),
+            None,
+            &mut input,
+            Authenticator::new(std::sync::Arc::new(resolver)),
+        )
+        .unwrap();
+        let message = crate::fsops::scan::scan_messages(&layout)
+            .unwrap()
+            .messages
+            .remove(0);
+        let spf = message.sidecar.auth.unwrap().spf;
+        assert_eq!(spf.result.as_str(), "none");
+        assert!(spf.client_ip.is_none());
     }
 
     #[test]
     #[serial]
-    fn import_mail_without_from_uses_fallback_sender() {
-        with_fake_render_env(|| {
-            let dir = tempfile::tempdir().unwrap();
-            let maildir = dir.path().join("maildir");
-            fs::create_dir_all(maildir.join("cur")).unwrap();
-            fs::write(maildir.join("cur/msg1"), b"Subject: Notice\n\nHello\n").unwrap();
+    fn deliver_falls_back_to_envelope_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(b"Subject: Notice\n\nHello\n".to_vec());
+        deliver(
+            &env_path,
+            &EnvConfig::default(),
+            &logger,
+            "<Carol@Example.org>",
+            "me@example.org",
+            None,
+            None,
+            &mut input,
+            static_authenticator(),
+        )
+        .unwrap();
+        let layout = MailLayout::new(dir.path());
+        assert!(layout.quarantine().join("carol@example.org").exists());
 
-            let root = dir.path().join("mail");
-            fs::create_dir_all(&root).unwrap();
-            let env_path = root.join(".env");
-            fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
+        let mut bounce = io::Cursor::new(b"Subject: Bounce\n\nReturned\n".to_vec());
+        deliver(
+            &env_path,
+            &EnvConfig::default(),
+            &logger,
+            "<>",
+            "me@example.org",
+            None,
+            None,
+            &mut bounce,
+            static_authenticator(),
+        )
+        .unwrap();
+        assert!(
+            layout
+                .quarantine()
+                .join(crate::pipeline::inbound::NULL_SENDER_FALLBACK)
+                .exists()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn deliver_reports_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig {
+            max_size_quarantine: "16".into(),
+            ..EnvConfig::default()
+        };
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(sample_email("Dave <dave@example.org>", "Large"));
+        let err = deliver(
+            &env_path,
+            &env,
+            &logger,
+            "dave@example.org",
+            "me@example.org",
+            None,
+            None,
+            &mut input,
+            static_authenticator(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            crate::util::sysexits::delivery_exit_code(&err),
+            crate::util::sysexits::EX_DATAERR
+        );
+        let log = fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("deliver.failed"));
+    }
 
-            import_archive(&env_path, &maildir).unwrap();
+    #[test]
+    fn deliver_rejects_invalid_recipient() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let mut input = io::Cursor::new(Vec::new());
+        let err = deliver(
+            &env_path,
+            &EnvConfig::default(),
+            &logger,
+            "a@example.org",
+            "not-an-address",
+            None,
+            None,
+            &mut input,
+            static_authenticator(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid recipient"));
+        assert_eq!(
+            crate::util::sysexits::delivery_exit_code(&err),
+            crate::util::sysexits::EX_DATAERR
+        );
+    }
 
-            let layout = MailLayout::new(&root);
-            let fallback_dir = layout.quarantine().join("unknown@import.invalid");
-            assert!(fallback_dir.exists());
-        });
+    #[test]
+    fn deliver_rejects_unparseable_from_as_data_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        for from in ["\"unterminated <a@example.org>", "no-at-sign"] {
+            let mut input = io::Cursor::new(sample_email(from, "Broken"));
+            let err = deliver(
+                &env_path,
+                &EnvConfig::default(),
+                &logger,
+                "a@example.org",
+                "me@example.org",
+                None,
+                None,
+                &mut input,
+                static_authenticator(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                crate::util::sysexits::delivery_exit_code(&err),
+                crate::util::sysexits::EX_DATAERR,
+                "{from}: {err:#}"
+            );
+        }
     }
 
     #[test]
@@ -1916,68 +3607,66 @@ mod tests {
     #[test]
     #[serial]
     fn import_archive_supports_tgz_and_tar() {
-        with_fake_render_env(|| {
-            let dir = tempfile::tempdir().unwrap();
-            let root = dir.path().join("mail");
-            fs::create_dir_all(&root).unwrap();
-            let env_path = root.join(".env");
-            fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("mail");
+        fs::create_dir_all(&root).unwrap();
+        let env_path = root.join(".env");
+        fs::write(&env_path, EnvConfig::default().to_env_string()).unwrap();
 
-            let tgz_path = dir.path().join("archive.tgz");
-            {
-                let file = fs::File::create(&tgz_path).unwrap();
-                let encoder = GzEncoder::new(file, Compression::default());
-                let mut builder = Builder::new(encoder);
-                let mut header = tar::Header::new_gnu();
-                header.set_mode(0o644);
-                header.set_uid(0);
-                header.set_gid(0);
-                header.set_mtime(0);
-                header.set_size(2);
-                header.set_cksum();
-                builder
-                    .append_data(&mut header, "marker.txt", &b"hi"[..])
-                    .unwrap();
-                builder.into_inner().unwrap().finish().unwrap();
-            }
+        let tgz_path = dir.path().join("archive.tgz");
+        {
+            let file = fs::File::create(&tgz_path).unwrap();
+            let encoder = GzEncoder::new(file, Compression::default());
+            let mut builder = Builder::new(encoder);
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(2);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "marker.txt", &b"hi"[..])
+                .unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
 
-            let tar_path = dir.path().join("archive.tar");
-            {
-                let file = fs::File::create(&tar_path).unwrap();
-                let mut builder = Builder::new(file);
-                let mut header = tar::Header::new_gnu();
-                header.set_mode(0o644);
-                header.set_uid(0);
-                header.set_gid(0);
-                header.set_mtime(0);
-                header.set_size(2);
-                header.set_cksum();
-                builder
-                    .append_data(&mut header, "note.txt", &b"ok"[..])
-                    .unwrap();
-                builder.finish().unwrap();
-            }
+        let tar_path = dir.path().join("archive.tar");
+        {
+            let file = fs::File::create(&tar_path).unwrap();
+            let mut builder = Builder::new(file);
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(2);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "note.txt", &b"ok"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
 
-            let tgz_cli = OwlCli {
-                env: env_path.to_string_lossy().into(),
-                command: Some(Commands::Import {
-                    source: tgz_path.clone(),
-                }),
-                json: false,
-            };
-            run(tgz_cli, EnvConfig::default()).unwrap();
-            assert!(root.join("marker.txt").exists());
+        let tgz_cli = OwlCli {
+            env: env_path.to_string_lossy().into(),
+            command: Some(Commands::Import {
+                source: tgz_path.clone(),
+            }),
+            json: false,
+        };
+        run(tgz_cli, EnvConfig::default()).unwrap();
+        assert!(root.join("marker.txt").exists());
 
-            let tar_cli = OwlCli {
-                env: env_path.to_string_lossy().into(),
-                command: Some(Commands::Import {
-                    source: tar_path.clone(),
-                }),
-                json: false,
-            };
-            run(tar_cli, EnvConfig::default()).unwrap();
-            assert!(root.join("note.txt").exists());
-        });
+        let tar_cli = OwlCli {
+            env: env_path.to_string_lossy().into(),
+            command: Some(Commands::Import {
+                source: tar_path.clone(),
+            }),
+            json: false,
+        };
+        run(tar_cli, EnvConfig::default()).unwrap();
+        assert!(root.join("note.txt").exists());
     }
 
     #[test]
@@ -1997,86 +3686,15 @@ mod tests {
         assert!(err.to_string().contains("unsupported import format"));
     }
 
+    fn static_authenticator() -> Authenticator {
+        Authenticator::new(std::sync::Arc::new(crate::util::dns::StaticResolver::new()))
+    }
+
     fn sample_email(from: &str, subject: &str) -> Vec<u8> {
         format!("From: {from}\r\nTo: you@example.org\r\nSubject: {subject}\r\n\r\nBody\r\n")
             .into_bytes()
     }
 
-    #[test]
-    fn first_mailbox_extracts_from_groups() {
-        let group = MailAddr::Group(mailparse::GroupInfo {
-            group_name: "Team".into(),
-            addrs: vec![mailparse::SingleInfo {
-                display_name: Some("Helper".into()),
-                addr: "helper@example.org".into(),
-            }],
-        });
-        let single = MailAddr::Single(mailparse::SingleInfo {
-            display_name: Some("Lead".into()),
-            addr: "lead@example.org".into(),
-        });
-        assert_eq!(
-            first_mailbox(std::slice::from_ref(&group)),
-            Some("helper@example.org".into())
-        );
-        assert_eq!(first_mailbox(&[single]), Some("lead@example.org".into()));
-        assert_eq!(
-            first_mailbox(&[
-                group,
-                MailAddr::Group(mailparse::GroupInfo {
-                    group_name: String::new(),
-                    addrs: vec![],
-                })
-            ]),
-            Some("helper@example.org".into())
-        );
-        assert_eq!(first_mailbox(&[]), None);
-    }
-
-    fn with_fake_render_env<T>(f: impl FnOnce() -> T) -> T {
-        let dir = tempfile::tempdir().unwrap();
-        write_exec(&dir, "sanitize-html", "#!/bin/sh\n/bin/cat\n");
-        write_exec(&dir, "lynx", "#!/bin/sh\n/bin/cat\n");
-        let original = std::env::var_os("PATH");
-        let mut new_path = std::ffi::OsString::from(dir.path());
-        if let Some(ref orig) = original {
-            new_path.push(":");
-            new_path.push(orig);
-        }
-        unsafe {
-            std::env::set_var("PATH", &new_path);
-        }
-        struct PathGuard {
-            original: Option<std::ffi::OsString>,
-        }
-        impl Drop for PathGuard {
-            fn drop(&mut self) {
-                match self.original.take() {
-                    Some(path) => unsafe { std::env::set_var("PATH", path) },
-                    None => unsafe { std::env::remove_var("PATH") },
-                }
-            }
-        }
-        let _guard = PathGuard { original };
-        f()
-    }
-
-    #[test]
-    #[serial]
-    fn with_fake_render_env_handles_missing_path() {
-        let original = std::env::var_os("PATH");
-        unsafe { std::env::remove_var("PATH") };
-        let path_inside = with_fake_render_env(|| std::env::var_os("PATH"));
-        assert!(
-            path_inside.is_some(),
-            "helper should populate PATH during call"
-        );
-        match original {
-            Some(value) => unsafe { std::env::set_var("PATH", value) },
-            None => unsafe { std::env::remove_var("PATH") },
-        }
-    }
-
     fn write_exec(dir: &tempfile::TempDir, name: &str, body: &str) {
         let path = dir.path().join(name);
         fs::write(&path, body).unwrap();
@@ -2584,6 +4202,157 @@ mod tests {
         assert!(output.contains("banned=0"));
     }
 
+    #[test]
+    fn rules_commands_edit_list_and_explain() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let env = EnvConfig::default();
+        let run = |action: RulesAction, json: bool| rules(&env_path, &env, action, json);
+        let add = |list: &str, pattern: &str| RulesAction::Add {
+            list: list.into(),
+            pattern: pattern.into(),
+        };
+        let test = |address: &str, recipient: Option<&str>| RulesAction::Test {
+            address: address.into(),
+            recipient: recipient.map(str::to_string),
+            subject: None,
+            headers: Vec::new(),
+        };
+
+        assert_eq!(
+            run(add("accepted", "@Example.org"), false).unwrap(),
+            "added @example.org to accepted/.rules"
+        );
+        assert!(
+            run(add("accepted", "@example.org"), false)
+                .unwrap()
+                .contains("already in accepted/.rules")
+        );
+        run(add("spam", "alice@example.org"), false).unwrap();
+        run(add("banned", "to:+shop"), false).unwrap();
+        assert!(run(add("quarantine", "@example.org"), false).is_err());
+        assert!(run(add("spam", "not a rule"), false).is_err());
+
+        let listing = run(RulesAction::Ls, false).unwrap();
+        assert!(listing.contains("spam (list_status=rejected):\n  alice@example.org"));
+        let json: serde_json::Value =
+            serde_json::from_str(&run(RulesAction::Ls, true).unwrap()).unwrap();
+        assert_eq!(json[2]["list"], "accepted");
+        assert_eq!(json[2]["rules"][0], "@example.org");
+
+        let output = run(test("Alice@example.org", None), false).unwrap();
+        assert_eq!(
+            output,
+            "alice@example.org\n  banned: no match\n  spam: matched alice@example.org\n  accepted: @example.org also matches, but spam comes first\n  spam list_status=rejected\n  route: spam"
+        );
+        let output = run(test("alice@example.org", Some("me+shop@example.org")), true).unwrap();
+        let explanation: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(explanation["matched_list"], "banned");
+        assert_eq!(explanation["lists"][0]["rule"], "to:+shop");
+        assert_eq!(explanation["route"], "banned");
+
+        fs::write(
+            dir.path().join("accepted/.settings"),
+            "list_status=rejected\n",
+        )
+        .unwrap();
+        let output = run(test("bob@example.org", None), false).unwrap();
+        assert!(output.ends_with("accepted list_status=rejected\n  route: spam"));
+
+        fs::write(
+            dir.path().join(".sieve"),
+            "require \"fileinto\";\nif address :localpart \"from\" \"\" { keep; } else { fileinto \"quarantine\"; }\n",
+        )
+        .unwrap();
+        let output = run(test("bob@example.org", None), false).unwrap();
+        assert_eq!(
+            output,
+            "bob@example.org\n  .sieve: fileinto quarantine\n  route: quarantine"
+        );
+        fs::write(
+            dir.path().join(".sieve"),
+            "require \"fileinto\";\nif address :is \"from\" \"bob@x.example\" { fileinto \"accepted\"; }\n",
+        )
+        .unwrap();
+        let output = run(test("bob@x.example", None), false).unwrap();
+        assert!(output.starts_with("bob@x.example\n  .sieve: fileinto accepted\n"));
+        let output = run(test("carol@x.example", None), false).unwrap();
+        assert!(output.contains(".sieve: keep, so .rules decide"));
+        fs::remove_file(dir.path().join(".sieve")).unwrap();
+
+        assert_eq!(
+            run(
+                RulesAction::Rm {
+                    list: "spam".into(),
+                    pattern: "ALICE@example.org".into()
+                },
+                false
+            )
+            .unwrap(),
+            "removed alice@example.org from spam/.rules"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("spam/.rules")).unwrap(),
+            ""
+        );
+        assert!(
+            run(
+                RulesAction::Rm {
+                    list: "spam".into(),
+                    pattern: "alice@example.org".into()
+                },
+                false
+            )
+            .is_err()
+        );
+
+        run(add("spam", "subject:/(?i)winner/"), false).unwrap();
+        run(add("banned", "header:X-Mailer:/^bulk/"), false).unwrap();
+        let output = run(
+            RulesAction::Test {
+                address: "dave@example.org".into(),
+                recipient: None,
+                subject: Some("You are a WINNER".into()),
+                headers: Vec::new(),
+            },
+            false,
+        )
+        .unwrap();
+        assert!(output.ends_with("route: spam"));
+        let output = run(
+            RulesAction::Test {
+                address: "dave@example.org".into(),
+                recipient: None,
+                subject: None,
+                headers: vec!["X-Mailer: bulk-o-matic".into()],
+            },
+            true,
+        )
+        .unwrap();
+        let explanation: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(explanation["route"], "banned");
+        let bad_header = RulesAction::Test {
+            address: "dave@example.org".into(),
+            recipient: None,
+            subject: None,
+            headers: vec!["X-Mailer".into()],
+        };
+        assert!(run(bad_header, false).is_err());
+    }
+
+    #[test]
+    fn reload_reports_sieve_script_and_its_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join(".env");
+        let sieve = dir.path().join(".sieve");
+        fs::write(&sieve, "require \"fileinto\";\nfileinto \"spam\";\n").unwrap();
+        assert!(reload(&env_path).unwrap().ends_with("banned=0 sieve=on"));
+
+        fs::write(&sieve, "require \"fileinto\";\nfileinto \"Junk\";\n").unwrap();
+        let err = format!("{:#}", reload(&env_path).unwrap_err());
+        assert!(err.contains("line 2: fileinto \"Junk\" is not a list"));
+    }
+
     #[test]
     fn mail_root_defaults_to_current_directory() {
         let root = mail_root(Path::new(".env"));
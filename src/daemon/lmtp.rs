@@ -0,0 +1,1112 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    envcfg::EnvConfig,
+    fsops::layout::MailLayout,
+    model::address::Address,
+    pipeline::{
+        auth::{Authenticator, MessageOrigin, received_client_ip},
+        inbound::{deliver_message_from, envelope_fallback_sender},
+        search::SearchIndex,
+        smtp_in::{InboundPipeline, MalformedMessageError, SizeLimitError},
+    },
+    ruleset::loader::RulesetLoader,
+    util::logging::{LogLevel, Logger},
+};
+
+const MAX_COMMAND_LINE: u64 = 4096;
+const DATA_CHUNK: u64 = 64 * 1024;
+const SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+const MAX_SESSIONS: usize = 32;
+
+/// Where the LMTP listener binds, parsed from the `lmtp_listen` setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LmtpListen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl LmtpListen {
+    /// Accepts `unix:/path/to/socket` or a loopback `host:port`. LMTP has no
+    /// authentication, so TCP listeners are restricted to localhost.
+    pub fn parse(input: &str) -> Result<Self> {
+        let value = input.trim();
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("lmtp_listen unix socket path is empty");
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        let addr = value
+            .to_socket_addrs()
+            .with_context(|| format!("invalid lmtp_listen address {value}"))?
+            .next()
+            .with_context(|| format!("lmtp_listen address {value} did not resolve"))?;
+        if !addr.ip().is_loopback() {
+            bail!("lmtp_listen must be a loopback address, got {value}");
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+                stream.set_write_timeout(Some(SESSION_TIMEOUT))?;
+                Ok(Box::new(stream))
+            }
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+                stream.set_write_timeout(Some(SESSION_TIMEOUT))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+trait Connection: Read + Write + Send {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>>;
+}
+
+impl Connection for UnixStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl Connection for TcpStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+struct LmtpContext {
+    layout: MailLayout,
+    env: EnvConfig,
+    pipeline: InboundPipeline,
+    logger: Logger,
+}
+
+/// Background LMTP (RFC 2033) listener feeding messages into
+/// [`InboundPipeline`], checking sender authentication with `authenticator`.
+/// Dropping the server stops the accept loop.
+pub struct LmtpServer {
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+    socket_path: Option<PathBuf>,
+}
+
+impl LmtpServer {
+    pub fn spawn(
+        listen: &LmtpListen,
+        layout: MailLayout,
+        env: EnvConfig,
+        logger: Logger,
+        authenticator: Authenticator,
+    ) -> Result<Self> {
+        let pipeline = InboundPipeline::new(layout.clone(), env.clone())?
+            .with_authenticator(authenticator)
+            .with_logger(logger.clone());
+        let (listener, local_addr, socket_path) = match listen {
+            LmtpListen::Unix(path) => {
+                // Only a socket left by an earlier run is fair game; anything
+                // else at the configured path is likely a typo.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if !metadata.file_type().is_socket() {
+                        bail!("refusing to replace {}: not a socket", path.display());
+                    }
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("removing stale socket {}", path.display()))?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("binding {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                (Listener::Unix(listener), None, Some(path.clone()))
+            }
+            LmtpListen::Tcp(addr) => {
+                let listener =
+                    TcpListener::bind(addr).with_context(|| format!("binding {addr}"))?;
+                listener.set_nonblocking(true)?;
+                let local = listener.local_addr()?;
+                (Listener::Tcp(listener), Some(local), None)
+            }
+        };
+        let bound = match (&local_addr, &socket_path) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(path)) => format!("unix:{}", path.display()),
+            (None, None) => String::new(),
+        };
+        logger.log(
+            LogLevel::Minimal,
+            "lmtp.listen",
+            Some(&format!("address={bound}")),
+        )?;
+
+        let context = Arc::new(LmtpContext {
+            layout,
+            env,
+            pipeline,
+            logger,
+        });
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_flag = Arc::clone(&shutdown);
+        let thread = thread::spawn(move || accept_loop(listener, context, shutdown_flag));
+        Ok(Self {
+            shutdown,
+            thread: Some(thread),
+            local_addr,
+            socket_path,
+        })
+    }
+
+    /// Bound TCP address; useful when listening on port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for LmtpServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.thread.take() {
+            let _ = handle.join();
+        }
+        if let Some(path) = self.socket_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Decrements the live session count when a session thread finishes.
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn accept_loop(listener: Listener, context: Arc<LmtpContext>, shutdown: Arc<AtomicBool>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    while !shutdown.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok(mut stream) => {
+                if active.fetch_add(1, Ordering::SeqCst) >= MAX_SESSIONS {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    let _ = reply(&mut stream, "421 4.3.2 too many sessions, try again later");
+                    let _ = context.logger.log(
+                        LogLevel::Minimal,
+                        "lmtp.session.refused",
+                        Some(&format!("limit={MAX_SESSIONS}")),
+                    );
+                    continue;
+                }
+                let slot = SessionSlot(Arc::clone(&active));
+                let session_context = Arc::clone(&context);
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(err) = run_session(stream, &session_context) {
+                        let _ = session_context.logger.log(
+                            LogLevel::Minimal,
+                            "lmtp.session.error",
+                            Some(&err.to_string()),
+                        );
+                    }
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(err) => {
+                let _ = context.logger.log(
+                    LogLevel::Minimal,
+                    "lmtp.accept.error",
+                    Some(&err.to_string()),
+                );
+                thread::sleep(ACCEPT_POLL);
+            }
+        }
+    }
+}
+
+fn run_session(stream: Box<dyn Connection>, context: &LmtpContext) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone_box()?);
+    let mut writer = stream;
+    serve(&mut reader, &mut writer, context)
+}
+
+#[derive(Default)]
+struct Transaction {
+    sender: Option<String>,
+    recipients: Vec<Address>,
+    /// Original client as forwarded by Postfix (`XFORWARD ADDR=... HELO=...`).
+    client_ip: Option<IpAddr>,
+    helo: Option<String>,
+}
+
+fn serve(reader: &mut impl BufRead, writer: &mut impl Write, context: &LmtpContext) -> Result<()> {
+    let max_size = context.pipeline.max_message_size();
+    reply(writer, "220 owl LMTP ready")?;
+    let mut greeted = false;
+    let mut transaction = Transaction::default();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader
+            .by_ref()
+            .take(MAX_COMMAND_LINE)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            reply(writer, "500 5.5.2 line too long")?;
+            if !discard_line(reader)? {
+                return Ok(());
+            }
+            continue;
+        }
+        let command = String::from_utf8_lossy(&line);
+        let command = command.trim_end_matches(['\r', '\n']);
+        let (verb, argument) = command.split_once(' ').unwrap_or((command, ""));
+        match verb.to_ascii_uppercase().as_str() {
+            "LHLO" => {
+                greeted = true;
+                transaction = Transaction::default();
+                write!(
+                    writer,
+                    "250-owl\r\n250-PIPELINING\r\n250-ENHANCEDSTATUSCODES\r\n250-8BITMIME\r\n250-XFORWARD NAME ADDR PROTO HELO SOURCE\r\n250 SIZE {max_size}\r\n"
+                )?;
+                writer.flush()?;
+            }
+            "HELO" | "EHLO" => reply(writer, "500 5.5.1 use LHLO")?,
+            "XFORWARD" if !greeted => reply(writer, "503 5.5.1 send LHLO first")?,
+            "XFORWARD" if transaction.sender.is_some() => {
+                reply(writer, "503 5.5.1 XFORWARD not allowed in a transaction")?
+            }
+            "XFORWARD" => {
+                apply_xforward(&mut transaction, argument);
+                reply(writer, "250 2.0.0 OK")?;
+            }
+            "MAIL" if !greeted => reply(writer, "503 5.5.1 send LHLO first")?,
+            "MAIL" if transaction.sender.is_some() => {
+                reply(writer, "503 5.5.1 nested MAIL command")?
+            }
+            "MAIL" => match parse_path(argument, "FROM:") {
+                Some((path, params)) => {
+                    if declared_size(params).is_some_and(|size| size > max_size) {
+                        reply(
+                            writer,
+                            "552 5.3.4 message size exceeds fixed maximum message size",
+                        )?;
+                    } else {
+                        transaction.sender = Some(path);
+                        reply(writer, "250 2.1.0 OK")?;
+                    }
+                }
+                None => reply(writer, "501 5.5.4 syntax: MAIL FROM:<address>")?,
+            },
+            "RCPT" if transaction.sender.is_none() => reply(writer, "503 5.5.1 need MAIL first")?,
+            "RCPT" => match parse_path(argument, "TO:") {
+                Some((path, _)) => match Address::parse(&path, context.env.keep_plus_tags) {
+                    Ok(address) => {
+                        transaction.recipients.push(address);
+                        reply(writer, "250 2.1.5 OK")?;
+                    }
+                    Err(_) => reply(writer, "550 5.1.3 bad recipient address syntax")?,
+                },
+                None => reply(writer, "501 5.5.4 syntax: RCPT TO:<address>")?,
+            },
+            "DATA" if transaction.recipients.is_empty() => {
+                reply(writer, "503 5.5.1 no valid recipients")?
+            }
+            "DATA" => {
+                reply(writer, "354 start mail input; end with <CRLF>.<CRLF>")?;
+                let (body, oversized) = read_data(reader, max_size)?;
+                let finished = std::mem::take(&mut transaction);
+                let recipients = finished.recipients;
+                // Without XFORWARD the topmost Received header is only
+                // believed when the operator vouches that the MTA wrote it.
+                let client_ip = finished.client_ip.or_else(|| {
+                    context
+                        .env
+                        .lmtp_trust_received
+                        .then(|| received_client_ip(&body))
+                        .flatten()
+                });
+                let origin = MessageOrigin {
+                    client_ip,
+                    helo: finished.helo,
+                    mail_from: finished.sender.unwrap_or_default(),
+                    recipients: recipients
+                        .iter()
+                        .map(|rcpt| rcpt.original().to_string())
+                        .collect(),
+                };
+                let status = if oversized {
+                    "552 5.3.4 message exceeds size limit".to_string()
+                } else {
+                    deliver(context, &origin, &recipients, &body)
+                };
+                for recipient in &recipients {
+                    reply(writer, &format!("{status} <{}>", recipient.canonical()))?;
+                }
+            }
+            "RSET" => {
+                transaction = Transaction::default();
+                reply(writer, "250 2.0.0 OK")?;
+            }
+            "NOOP" => reply(writer, "250 2.0.0 OK")?,
+            "VRFY" => reply(writer, "252 2.5.2 cannot verify")?,
+            "QUIT" => {
+                reply(writer, "221 2.0.0 bye")?;
+                return Ok(());
+            }
+            _ => reply(writer, "500 5.5.2 command not recognized")?,
+        }
+    }
+}
+
+/// Skip the rest of an oversized command line without buffering it.
+/// Returns `false` when the connection closed before the line ended.
+fn discard_line(reader: &mut impl BufRead) -> io::Result<bool> {
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => {
+                reader.consume(index + 1);
+                return Ok(true);
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Read a dot-terminated DATA section, undoing dot-stuffing. Content past
+/// `max_size` is consumed but discarded so the session stays in sync.
+fn read_data(reader: &mut impl BufRead, max_size: u64) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    let mut oversized = false;
+    let mut at_line_start = true;
+    let mut chunk = Vec::new();
+    loop {
+        chunk.clear();
+        let read = reader
+            .by_ref()
+            .take(DATA_CHUNK)
+            .read_until(b'\n', &mut chunk)?;
+        if read == 0 {
+            bail!("connection closed during DATA");
+        }
+        let line_start = at_line_start;
+        at_line_start = chunk.ends_with(b"\n");
+        let mut content: &[u8] = &chunk;
+        if line_start {
+            if content == b".\r\n" || content == b".\n" {
+                return Ok((body, oversized));
+            }
+            if content.starts_with(b".") {
+                content = &content[1..];
+            }
+        }
+        if oversized || (body.len() + content.len()) as u64 > max_size {
+            oversized = true;
+            body.clear();
+            continue;
+        }
+        body.extend_from_slice(content);
+    }
+}
+
+fn deliver(
+    context: &LmtpContext,
+    origin: &MessageOrigin,
+    recipients: &[Address],
+    body: &[u8],
+) -> String {
+    let fallback = envelope_fallback_sender(&origin.mail_from);
+    let rcpt_list = recipients
+        .iter()
+        .map(|rcpt| rcpt.canonical())
+        .collect::<Vec<_>>()
+        .join(",");
+    let result = RulesetLoader::new(context.layout.root())
+        .load()
+        .and_then(|rules| {
+            deliver_message_from(&context.pipeline, &rules, &context.env, body, origin)
+        });
+    match result {
+        Ok((route, path)) => {
+            let _ = context.logger.log(
+                LogLevel::Minimal,
+                "lmtp.delivered",
+                Some(&format!(
+                    "sender={fallback} recipients={rcpt_list} route={} bytes={}",
+                    route.as_str(),
+                    body.len()
+                )),
+            );
+            if let Err(err) = SearchIndex::new(&context.layout).add_delivered(&path) {
+                let _ = context.logger.log(
+                    LogLevel::Minimal,
+                    "lmtp.index_error",
+                    Some(&err.to_string()),
+                );
+            }
+            format!("250 2.0.0 delivered to {}", route.as_str())
+        }
+        Err(err) => {
+            let _ = context.logger.log(
+                LogLevel::Minimal,
+                "lmtp.failed",
+                Some(&format!(
+                    "sender={fallback} recipients={rcpt_list} error={err}"
+                )),
+            );
+            if err.chain().any(|cause| cause.is::<SizeLimitError>()) {
+                "552 5.3.4 message exceeds size limit".to_string()
+            } else if err.chain().any(|cause| cause.is::<MalformedMessageError>()) {
+                "554 5.6.0 malformed message".to_string()
+            } else {
+                "451 4.3.0 temporary delivery failure".to_string()
+            }
+        }
+    }
+}
+
+/// Record the XFORWARD (Postfix) attributes owl uses for SPF. Values are
+/// xtext; `[UNAVAILABLE]` and `[TEMPUNAVAIL]` leave the attribute unset.
+/// Any client may send XFORWARD, so only the MTA must be able to connect.
+fn apply_xforward(transaction: &mut Transaction, argument: &str) {
+    for attribute in argument.split_whitespace() {
+        let Some((name, value)) = attribute.split_once('=') else {
+            continue;
+        };
+        let value = decode_xtext(value);
+        if value.starts_with('[') && value.ends_with(']') {
+            continue;
+        }
+        match name.to_ascii_uppercase().as_str() {
+            "ADDR" => {
+                let literal = value
+                    .strip_prefix("IPv6:")
+                    .or_else(|| value.strip_prefix("ipv6:"))
+                    .unwrap_or(&value);
+                transaction.client_ip = literal.parse().ok();
+            }
+            "HELO" => transaction.helo = Some(value),
+            _ => {}
+        }
+    }
+}
+
+fn decode_xtext(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(index) = rest.find('+') {
+        out.push_str(&rest[..index]);
+        let hex = rest.get(index + 1..index + 3);
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                out.push(char::from(byte));
+                rest = &rest[index + 3..];
+            }
+            None => {
+                out.push('+');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Split `FROM:<addr> PARAMS` into the bare path and the parameter string.
+fn parse_path<'a>(argument: &'a str, keyword: &str) -> Option<(String, &'a str)> {
+    let argument = argument.trim_start();
+    let prefix = argument.get(..keyword.len())?;
+    if !prefix.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let rest = argument[keyword.len()..].trim_start();
+    let rest = rest.strip_prefix('<')?;
+    let (path, params) = rest.split_once('>')?;
+    Some((path.trim().to_string(), params.trim()))
+}
+
+fn declared_size(params: &str) -> Option<u64> {
+    params.split_whitespace().find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+fn reply(writer: &mut impl Write, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::dns::StaticResolver;
+    use serial_test::serial;
+
+    fn context(root: &std::path::Path, env: EnvConfig) -> LmtpContext {
+        let layout = MailLayout::new(root);
+        layout.ensure().unwrap();
+        LmtpContext {
+            pipeline: InboundPipeline::new(layout.clone(), env.clone())
+                .unwrap()
+                .with_authenticator(static_authenticator()),
+            logger: Logger::new(root, LogLevel::Minimal).unwrap(),
+            layout,
+            env,
+        }
+    }
+
+    fn static_authenticator() -> Authenticator {
+        Authenticator::new(Arc::new(StaticResolver::new()))
+    }
+
+    fn converse(context: &LmtpContext, input: &str) -> Vec<String> {
+        let mut reader = io::Cursor::new(input.as_bytes().to_vec());
+        let mut output = Vec::new();
+        serve(&mut reader, &mut output, context).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn read_reply(reader: &mut impl BufRead) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn parse_listen_accepts_unix_and_loopback() {
+        assert_eq!(
+            LmtpListen::parse("unix:/run/owl/lmtp.sock").unwrap(),
+            LmtpListen::Unix(PathBuf::from("/run/owl/lmtp.sock"))
+        );
+        assert_eq!(
+            LmtpListen::parse("127.0.0.1:2424").unwrap(),
+            LmtpListen::Tcp("127.0.0.1:2424".parse().unwrap())
+        );
+        assert!(matches!(
+            LmtpListen::parse("[::1]:2424").unwrap(),
+            LmtpListen::Tcp(_)
+        ));
+    }
+
+    #[test]
+    fn parse_listen_rejects_public_and_invalid() {
+        let err = LmtpListen::parse("0.0.0.0:2424").unwrap_err();
+        assert!(err.to_string().contains("loopback"));
+        assert!(LmtpListen::parse("unix:").is_err());
+        assert!(LmtpListen::parse("not an address").is_err());
+    }
+
+    #[test]
+    fn parse_path_and_size_params() {
+        assert_eq!(
+            parse_path("FROM:<a@example.org> SIZE=42", "FROM:"),
+            Some(("a@example.org".to_string(), "SIZE=42"))
+        );
+        assert_eq!(parse_path("from: <>", "FROM:"), Some((String::new(), "")));
+        assert_eq!(parse_path("TO:a@example.org", "TO:"), None);
+        assert_eq!(parse_path("X", "FROM:"), None);
+        assert_eq!(declared_size("BODY=8BITMIME size=100"), Some(100));
+        assert_eq!(declared_size("BODY=8BITMIME"), None);
+    }
+
+    #[test]
+    #[serial]
+    fn session_delivers_with_per_recipient_replies() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path(), EnvConfig::default());
+        std::fs::write(ctx.layout.accepted().join(".rules"), "alice@example.org\n").unwrap();
+        let replies = converse(
+            &ctx,
+            "LHLO client\r\nMAIL FROM:<alice@example.org>\r\nRCPT TO:<me@example.org>\r\n\
+             RCPT TO:<bogus>\r\nRCPT TO:<you@example.org>\r\nDATA\r\n\
+             From: Alice <alice@example.org>\r\nSubject: Over LMTP\r\n\r\n..dotted\r\n.\r\nQUIT\r\n",
+        );
+        assert_eq!(replies[0], "220 owl LMTP ready");
+        assert!(replies.iter().any(|line| line.starts_with("250 SIZE ")));
+        assert!(replies.contains(&"550 5.1.3 bad recipient address syntax".to_string()));
+        assert!(replies.contains(&"250 2.0.0 delivered to accepted <me@example.org>".to_string()));
+        assert!(replies.contains(&"250 2.0.0 delivered to accepted <you@example.org>".to_string()));
+        assert_eq!(replies.last().unwrap(), "221 2.0.0 bye");
+
+        let sender_dir = ctx.layout.accepted().join("alice@example.org");
+        let eml = std::fs::read_dir(&sender_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "eml"))
+            .unwrap();
+        let stored = std::fs::read_to_string(eml).unwrap();
+        assert!(stored.ends_with("\r\n.dotted\r\n"));
+        let log = std::fs::read_to_string(ctx.logger.log_path()).unwrap();
+        assert!(log.contains("lmtp.delivered"));
+    }
+
+    #[test]
+    #[serial]
+    fn session_records_sender_authentication_from_xforward() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(dir.path(), EnvConfig::default());
+        let resolver = StaticResolver::new()
+            .with_txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all")
+            .with_txt("_dmarc.example.org", "v=DMARC1; p=reject");
+        ctx.pipeline = InboundPipeline::new(ctx.layout.clone(), ctx.env.clone())
+            .unwrap()
+            .with_authenticator(Authenticator::new(Arc::new(resolver)));
+        // Alice is a contact, but her list insists on DKIM.
+        std::fs::write(ctx.layout.accepted().join(".rules"), "alice@example.org\n").unwrap();
+        std::fs::write(
+            ctx.layout.accepted().join(".settings"),
+            "list_status=accepted\nrequire_auth=dkim\n",
+        )
+        .unwrap();
+        let replies = converse(
+            &ctx,
+            "LHLO client\r\nXFORWARD NAME=mx.example.org ADDR=192.0.2.9\r\n\
+             XFORWARD HELO=mx.example.org PROTO=ESMTP\r\nMAIL FROM:<bounce@example.org>\r\n\
+             XFORWARD ADDR=192.0.2.10\r\nRCPT TO:<me@example.org>\r\nDATA\r\n\
+             From: Alice <alice@example.org>\r\nSubject: Checked\r\n\r\nhi\r\n.\r\nQUIT\r\n",
+        );
+        assert!(replies.contains(&"503 5.5.1 XFORWARD not allowed in a transaction".to_string()));
+        assert!(
+            replies.contains(&"250 2.0.0 delivered to quarantine <me@example.org>".to_string())
+        );
+
+        let sender_dir = ctx.layout.quarantine().join("alice@example.org");
+        let sidecar = std::fs::read_dir(&sender_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "yml"))
+            .unwrap();
+        let sidecar: crate::model::message::MessageSidecar =
+            serde_yaml::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        assert_eq!(sidecar.delivered_to, vec!["me@example.org"]);
+        let auth = sidecar.auth.unwrap();
+        assert!(auth.dkim.is_empty());
+        assert_eq!(auth.spf.result.as_str(), "pass");
+        assert_eq!(auth.spf.client_ip.as_deref(), Some("192.0.2.9"));
+        assert_eq!(auth.dmarc.result.as_str(), "pass");
+        assert!(auth.dmarc.spf_aligned);
+        assert_eq!(
+            auth.demoted.as_deref(),
+            Some(
+                "accepted list requires dkim: no valid DKIM signature aligned with example.org (dkim=none)"
+            )
+        );
+        assert_eq!(sidecar.status_shadow, "quarantine");
+    }
+
+    #[test]
+    #[serial]
+    fn session_trusts_received_header_only_when_configured() {
+        for trust in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let env = EnvConfig {
+                lmtp_trust_received: trust,
+                ..EnvConfig::default()
+            };
+            let mut ctx = context(dir.path(), env);
+            let resolver =
+                StaticResolver::new().with_txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all");
+            ctx.pipeline = InboundPipeline::new(ctx.layout.clone(), ctx.env.clone())
+                .unwrap()
+                .with_authenticator(Authenticator::new(Arc::new(resolver)));
+            converse(
+                &ctx,
+                "LHLO client\r\nMAIL FROM:<bounce@example.org>\r\nRCPT TO:<me@example.org>\r\n\
+                 DATA\r\nReceived: from mx.example.org (mx.example.org [192.0.2.9]) by owl\r\n\
+                 From: Alice <alice@example.org>\r\nSubject: Hop\r\n\r\nhi\r\n.\r\nQUIT\r\n",
+            );
+
+            let sender_dir = ctx.layout.quarantine().join("alice@example.org");
+            let sidecar = std::fs::read_dir(&sender_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension().is_some_and(|ext| ext == "yml"))
+                .unwrap();
+            let sidecar: crate::model::message::MessageSidecar =
+                serde_yaml::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+            let spf = sidecar.auth.unwrap().spf;
+            if trust {
+                assert_eq!(spf.result.as_str(), "pass");
+                assert_eq!(spf.client_ip.as_deref(), Some("192.0.2.9"));
+            } else {
+                assert_eq!(spf.result.as_str(), "none");
+                assert!(spf.client_ip.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn xforward_decodes_xtext_and_skips_unavailable() {
+        let mut transaction = Transaction::default();
+        apply_xforward(
+            &mut transaction,
+            "ADDR=IPv6:2001:db8::1 HELO=[UNAVAILABLE] NAME=x+2Dy",
+        );
+        assert_eq!(transaction.client_ip, Some("2001:db8::1".parse().unwrap()));
+        assert!(transaction.helo.is_none());
+        assert_eq!(decode_xtext("a+2Bb+zz"), "a+b+zz");
+    }
+
+    #[test]
+    fn session_rejects_declared_oversize_up_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = EnvConfig {
+            max_size_quarantine: "1K".into(),
+            max_size_approved_default: "2K".into(),
+            ..EnvConfig::default()
+        };
+        let ctx = context(dir.path(), env);
+        let replies = converse(
+            &ctx,
+            "LHLO client\r\nMAIL FROM:<a@example.org> SIZE=4096\r\nRCPT TO:<me@example.org>\r\nQUIT\r\n",
+        );
+        assert!(replies.contains(&"250 SIZE 2048".to_string()));
+        assert!(
+            replies
+                .contains(&"552 5.3.4 message size exceeds fixed maximum message size".to_string())
+        );
+        assert!(replies.contains(&"503 5.5.1 need MAIL first".to_string()));
+    }
+
+    #[test]
+    fn session_discards_oversized_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = EnvConfig {
+            max_size_quarantine: "16".into(),
+            max_size_approved_default: "16".into(),
+            ..EnvConfig::default()
+        };
+        let ctx = context(dir.path(), env);
+        let replies = converse(
+            &ctx,
+            "LHLO client\r\nMAIL FROM:<a@example.org>\r\nRCPT TO:<me@example.org>\r\nDATA\r\n\
+             Subject: this body is far too long\r\n\r\nbody\r\n.\r\nNOOP\r\n",
+        );
+        assert!(
+            replies.contains(&"552 5.3.4 message exceeds size limit <me@example.org>".to_string())
+        );
+        assert_eq!(replies.last().unwrap(), "250 2.0.0 OK");
+        assert!(!ctx.layout.quarantine().join("a@example.org").exists());
+    }
+
+    #[test]
+    #[serial]
+    fn session_reports_route_size_limit_per_recipient() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = EnvConfig {
+            max_size_quarantine: "16".into(),
+            max_size_approved_default: "1K".into(),
+            ..EnvConfig::default()
+        };
+        let ctx = context(dir.path(), env);
+        let replies = converse(
+            &ctx,
+            "LHLO c\r\nMAIL FROM:<a@example.org>\r\nRCPT TO:<me@example.org>\r\nDATA\r\n\
+             Subject: quarantined and large\r\n\r\nbody\r\n.\r\n",
+        );
+        assert!(
+            replies.contains(&"552 5.3.4 message exceeds size limit <me@example.org>".to_string())
+        );
+        let log = std::fs::read_to_string(ctx.logger.log_path()).unwrap();
+        assert!(log.contains("lmtp.failed"));
+    }
+
+    #[test]
+    #[serial]
+    fn session_rejects_unparseable_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path(), EnvConfig::default());
+        let replies = converse(
+            &ctx,
+            "LHLO c\r\nMAIL FROM:<a@example.org>\r\nRCPT TO:<me@example.org>\r\nDATA\r\n\
+             From: no-at-sign\r\nSubject: broken\r\n\r\nbody\r\n.\r\n",
+        );
+        assert!(replies.contains(&"554 5.6.0 malformed message <me@example.org>".to_string()));
+    }
+
+    #[test]
+    fn session_reports_temporary_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path(), EnvConfig::default());
+        std::fs::write(ctx.layout.accepted().join(".rules"), "/[invalid/\n").unwrap();
+        let replies = converse(
+            &ctx,
+            "LHLO c\r\nMAIL FROM:<>\r\nRCPT TO:<me@example.org>\r\nDATA\r\nSubject: x\r\n\r\nbody\r\n.\r\n",
+        );
+        assert!(
+            replies.contains(&"451 4.3.0 temporary delivery failure <me@example.org>".to_string())
+        );
+    }
+
+    #[test]
+    fn session_enforces_command_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path(), EnvConfig::default());
+        let long_line = format!("NOOP {}\r\n", "x".repeat(5000));
+        let replies = converse(
+            &ctx,
+            &format!(
+                "EHLO c\r\nMAIL FROM:<a@example.org>\r\nLHLO c\r\nMAIL FROM:a\r\n\
+                 MAIL FROM:<a@example.org>\r\nMAIL FROM:<b@example.org>\r\nDATA\r\nRCPT TO:b\r\n\
+                 RSET\r\nVRFY me\r\nBOGUS\r\n{long_line}NOOP\r\n"
+            ),
+        );
+        assert_eq!(
+            replies,
+            vec![
+                "220 owl LMTP ready",
+                "500 5.5.1 use LHLO",
+                "503 5.5.1 send LHLO first",
+                "250-owl",
+                "250-PIPELINING",
+                "250-ENHANCEDSTATUSCODES",
+                "250-8BITMIME",
+                "250-XFORWARD NAME ADDR PROTO HELO SOURCE",
+                "250 SIZE 52428800",
+                "501 5.5.4 syntax: MAIL FROM:<address>",
+                "250 2.1.0 OK",
+                "503 5.5.1 nested MAIL command",
+                "503 5.5.1 no valid recipients",
+                "501 5.5.4 syntax: RCPT TO:<address>",
+                "250 2.0.0 OK",
+                "252 2.5.2 cannot verify",
+                "500 5.5.2 command not recognized",
+                "500 5.5.2 line too long",
+                "250 2.0.0 OK",
+            ]
+        );
+    }
+
+    #[test]
+    fn session_discards_unterminated_long_line_and_closes() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path(), EnvConfig::default());
+        let input = format!("LHLO c\r\nNOOP {}", "x".repeat(1 << 20));
+        let replies = converse(&ctx, &input);
+        assert_eq!(replies.last().unwrap(), "500 5.5.2 line too long");
+    }
+
+    #[test]
+    fn discard_line_stops_after_newline() {
+        let mut reader =
+            BufReader::with_capacity(8, io::Cursor::new(b"xxxxxxxxxxxxxxxxxxxx\nNOOP\r\n"));
+        assert!(discard_line(&mut reader).unwrap());
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!(rest, "NOOP\r\n");
+        assert!(!discard_line(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn data_requires_terminator() {
+        let mut reader = io::Cursor::new(b"Subject: cut\r\n".to_vec());
+        let err = read_data(&mut reader, 1024).unwrap_err();
+        assert!(err.to_string().contains("closed during DATA"));
+    }
+
+    #[test]
+    fn data_keeps_long_lines_intact() {
+        let long = "a".repeat(DATA_CHUNK as usize + 10);
+        let input = format!("{long}\r\n.\r\n");
+        let mut reader = io::Cursor::new(input.into_bytes());
+        let (body, oversized) = read_data(&mut reader, u64::MAX).unwrap();
+        assert!(!oversized);
+        assert_eq!(body.len(), long.len() + 2);
+    }
+
+    #[test]
+    #[serial]
+    fn tcp_listener_serves_plain_socket_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let listen = LmtpListen::parse("127.0.0.1:0").unwrap();
+        let server = LmtpServer::spawn(
+            &listen,
+            layout.clone(),
+            EnvConfig::default(),
+            logger,
+            static_authenticator(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        assert_eq!(read_reply(&mut reader), "220 owl LMTP ready");
+        writer
+            .write_all(
+                b"LHLO test\r\nMAIL FROM:<carol@example.org>\r\nRCPT TO:<me@example.org>\r\nDATA\r\n",
+            )
+            .unwrap();
+        let mut saw_data_prompt = false;
+        while !saw_data_prompt {
+            saw_data_prompt = read_reply(&mut reader).starts_with("354");
+        }
+        writer
+            .write_all(b"From: carol@example.org\r\nSubject: Socket\r\n\r\nHi\r\n.\r\nQUIT\r\n")
+            .unwrap();
+        assert_eq!(
+            read_reply(&mut reader),
+            "250 2.0.0 delivered to quarantine <me@example.org>"
+        );
+        assert_eq!(read_reply(&mut reader), "221 2.0.0 bye");
+        drop(server);
+        assert!(layout.quarantine().join("carol@example.org").exists());
+    }
+
+    #[test]
+    fn unix_listener_replaces_stale_socket_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let socket = dir.path().join("lmtp.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        assert!(socket.exists());
+        let listen = LmtpListen::Unix(socket.clone());
+        let server = LmtpServer::spawn(
+            &listen,
+            layout,
+            EnvConfig::default(),
+            logger,
+            static_authenticator(),
+        )
+        .unwrap();
+        assert!(server.local_addr().is_none());
+
+        let stream = UnixStream::connect(&socket).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        assert_eq!(read_reply(&mut reader), "220 owl LMTP ready");
+        writer.write_all(b"QUIT\r\n").unwrap();
+        assert_eq!(read_reply(&mut reader), "221 2.0.0 bye");
+
+        drop(server);
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn listener_refuses_sessions_over_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let socket = dir.path().join("lmtp.sock");
+        let server = LmtpServer::spawn(
+            &LmtpListen::Unix(socket.clone()),
+            layout,
+            EnvConfig::default(),
+            logger.clone(),
+            static_authenticator(),
+        )
+        .unwrap();
+
+        let mut open = Vec::new();
+        for _ in 0..MAX_SESSIONS {
+            let stream = UnixStream::connect(&socket).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            assert_eq!(read_reply(&mut reader), "220 owl LMTP ready");
+            open.push(stream);
+        }
+        let extra = UnixStream::connect(&socket).unwrap();
+        let mut reader = BufReader::new(extra);
+        assert_eq!(
+            read_reply(&mut reader),
+            "421 4.3.2 too many sessions, try again later"
+        );
+        assert_eq!(read_reply(&mut reader), "");
+
+        drop(open);
+        drop(server);
+        let log = std::fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("lmtp.session.refused"));
+    }
+
+    #[test]
+    fn unix_listener_refuses_to_replace_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let path = dir.path().join("lmtp.sock");
+        std::fs::write(&path, "keep me").unwrap();
+        let err = LmtpServer::spawn(
+            &LmtpListen::Unix(path.clone()),
+            layout,
+            EnvConfig::default(),
+            logger,
+            static_authenticator(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("not a socket"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    }
+
+    #[test]
+    fn spawn_reports_bind_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let logger = Logger::new(dir.path(), LogLevel::Off).unwrap();
+        let listen = LmtpListen::Unix(dir.path().join("missing/dir/lmtp.sock"));
+        let err = LmtpServer::spawn(
+            &listen,
+            layout,
+            EnvConfig::default(),
+            logger,
+            static_authenticator(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("binding"));
+    }
+}
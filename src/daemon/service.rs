@@ -10,19 +10,23 @@ use crate::{
     envcfg::EnvConfig,
     fsops::layout::MailLayout,
     pipeline::{
+        auth::Authenticator,
         outbox::{MailTransport, OutboxPipeline},
         reconcile,
+        search::SearchIndex,
     },
     ruleset::loader::RulesetLoader,
     util::logging::{LogLevel, Logger},
 };
 
+use super::lmtp::{LmtpListen, LmtpServer};
 use super::watch::{WatchEvent, WatchEventKind, WatchList, WatchService};
 
 pub struct DaemonHandles {
     watch: Option<WatchService>,
     shutdown: Arc<AtomicBool>,
     retention: Option<JoinHandle<()>>,
+    lmtp: Option<LmtpServer>,
 }
 
 impl DaemonHandles {
@@ -31,8 +35,9 @@ impl DaemonHandles {
         if let Some(handle) = self.retention.take() {
             let _ = handle.join();
         }
-        // dropping watch stops threads
+        // dropping watch and lmtp stops their threads
         let _ = self.watch.take();
+        let _ = self.lmtp.take();
     }
 }
 
@@ -46,6 +51,16 @@ pub fn start_with_transport(
     logger: Logger,
     transport: Option<Arc<dyn MailTransport>>,
 ) -> Result<DaemonHandles> {
+    let lmtp = match env.lmtp_listen.as_deref() {
+        Some(listen) => Some(LmtpServer::spawn(
+            &LmtpListen::parse(listen)?,
+            layout.clone(),
+            env.clone(),
+            logger.clone(),
+            Authenticator::system(),
+        )?),
+        None => None,
+    };
     let shutdown = Arc::new(AtomicBool::new(false));
     let pipeline = if let Some(custom) = transport {
         Arc::new(OutboxPipeline::with_transport(
@@ -90,14 +105,26 @@ pub fn start_with_transport(
             match loader.load() {
                 Ok(rules) => {
                     let now = OffsetDateTime::now_utc();
-                    if let Err(err) =
-                        reconcile::enforce_retention(&layout_for_retention, &rules, now)
-                    {
-                        let _ = retention_logger.log(
-                            LogLevel::Minimal,
-                            "daemon.retention.error",
-                            Some(&err.to_string()),
-                        );
+                    match reconcile::enforce_retention(&layout_for_retention, &rules, now) {
+                        Ok(summaries) => {
+                            let index = SearchIndex::new(&layout_for_retention);
+                            for summary in summaries.values() {
+                                if let Err(err) = index.remove_sidecars(&summary.messages_removed) {
+                                    let _ = retention_logger.log(
+                                        LogLevel::Minimal,
+                                        "daemon.index.error",
+                                        Some(&err.to_string()),
+                                    );
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            let _ = retention_logger.log(
+                                LogLevel::Minimal,
+                                "daemon.retention.error",
+                                Some(&err.to_string()),
+                            );
+                        }
                     }
                 }
                 Err(err) => {
@@ -121,6 +148,7 @@ pub fn start_with_transport(
         watch: Some(watch),
         shutdown,
         retention: Some(retention),
+        lmtp,
     })
 }
 
@@ -167,7 +195,9 @@ fn handle_watch_event<F>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::model::{filename::outbox_message_filename, message::MessageSidecar};
+    use crate::model::filename::outbox_message_filename;
+    use crate::pipeline::outbox::{RecipientOutcome, RecipientResult, all_recipients};
+    use lettre::address::Envelope;
     use serial_test::serial;
     use std::sync::{
         Arc,
@@ -228,6 +258,38 @@ mod tests {
         handles.stop();
     }
 
+    #[test]
+    #[serial]
+    fn start_spawns_configured_lmtp_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let socket = dir.path().join("lmtp.sock");
+        let env = EnvConfig {
+            lmtp_listen: Some(format!("unix:{}", socket.display())),
+            ..EnvConfig::default()
+        };
+        let logger = Logger::new(layout.root(), LogLevel::Off).unwrap();
+        let handles = start(layout, env, logger).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&socket).is_ok());
+        handles.stop();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn start_rejects_non_loopback_lmtp_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let env = EnvConfig {
+            lmtp_listen: Some("0.0.0.0:2424".into()),
+            ..EnvConfig::default()
+        };
+        let logger = Logger::new(layout.root(), LogLevel::Off).unwrap();
+        let err = start(layout, env, logger).err().unwrap();
+        assert!(err.to_string().contains("loopback"));
+    }
+
     #[test]
     #[serial]
     fn start_logs_dispatch_errors() {
@@ -364,8 +426,8 @@ mod tests {
     struct SucceedingTransport;
 
     impl MailTransport for SucceedingTransport {
-        fn send(&self, _message: &[u8], _sidecar: &MessageSidecar) -> Result<()> {
-            Ok(())
+        fn send(&self, _message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>> {
+            Ok(all_recipients(envelope, RecipientOutcome::Delivered))
         }
     }
 
@@ -3,10 +3,34 @@ use std::{collections::HashMap, fs, path::Path, str::FromStr};
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
+use crate::{
+    fsops::io_atom::write_atomic,
+    util::dkim::{DEFAULT_OVERSIGN_HEADERS, DEFAULT_SIGNED_HEADERS},
+};
+
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct EnvConfig {
     pub dmarc_policy: String,
     pub dkim_selector: String,
+    /// Selector for the RSA key signed alongside Ed25519; `None` signs with
+    /// Ed25519 only.
+    #[serde(default)]
+    pub dkim_rsa_selector: Option<String>,
+    /// `header/body` canonicalization for outgoing signatures.
+    #[serde(default)]
+    pub dkim_canonicalization: String,
+    #[serde(default)]
+    pub dkim_signed_headers: Vec<String>,
+    /// Headers signed once more than they occur, so none can be added.
+    #[serde(default)]
+    pub dkim_oversign_headers: Vec<String>,
+    /// Signature lifetime (`x=`), e.g. `7d`; `None` never expires.
+    #[serde(default)]
+    pub dkim_expiry: Option<String>,
+    /// How long `owl dkim rotate` keeps signing with the old selector
+    /// while the new record propagates.
+    #[serde(default)]
+    pub dkim_rotation_overlap: String,
     pub letsencrypt_method: String,
     pub keep_plus_tags: bool,
     pub max_size_quarantine: String,
@@ -14,9 +38,21 @@ pub struct EnvConfig {
     pub contacts_dir: String,
     pub logging: String,
     pub render_mode: String,
+    /// `native` or `lynx`; how the `.txt` render is produced.
+    #[serde(default)]
+    pub plaintext_renderer: String,
+    /// Column the `.txt` render wraps at.
+    #[serde(default)]
+    pub plaintext_width: usize,
     pub load_external_per_message: bool,
     pub retry_backoff: Vec<String>,
     #[serde(default)]
+    pub retry_max_attempts: Option<u32>,
+    #[serde(default)]
+    pub retry_max_age: Option<String>,
+    #[serde(default)]
+    pub smtp_mode: String,
+    #[serde(default)]
     pub smtp_host: Option<String>,
     #[serde(default)]
     pub smtp_port: u16,
@@ -26,6 +62,12 @@ pub struct EnvConfig {
     pub smtp_password: Option<String>,
     #[serde(default)]
     pub smtp_starttls: bool,
+    #[serde(default)]
+    pub lmtp_listen: Option<String>,
+    /// Take the SPF client from the topmost `Received` header when an LMTP
+    /// client sends no XFORWARD; only safe when the MTA always writes one.
+    #[serde(default)]
+    pub lmtp_trust_received: bool,
 }
 
 impl Default for EnvConfig {
@@ -33,6 +75,18 @@ impl Default for EnvConfig {
         Self {
             dmarc_policy: "none".into(),
             dkim_selector: "mail".into(),
+            dkim_rsa_selector: Some("mail-rsa".into()),
+            dkim_canonicalization: "relaxed/relaxed".into(),
+            dkim_signed_headers: DEFAULT_SIGNED_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            dkim_oversign_headers: DEFAULT_OVERSIGN_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            dkim_expiry: None,
+            dkim_rotation_overlap: "7d".into(),
             letsencrypt_method: "http".into(),
             keep_plus_tags: false,
             max_size_quarantine: "25M".into(),
@@ -40,13 +94,20 @@ impl Default for EnvConfig {
             contacts_dir: "/home/pi/contacts".into(),
             logging: "minimal".into(),
             render_mode: "strict".into(),
+            plaintext_renderer: "native".into(),
+            plaintext_width: 78,
             load_external_per_message: true,
             retry_backoff: vec!["1m".into(), "5m".into(), "15m".into(), "1h".into()],
+            retry_max_attempts: None,
+            retry_max_age: None,
+            smtp_mode: "relay".into(),
             smtp_host: Some("127.0.0.1".into()),
             smtp_port: 25,
             smtp_username: None,
             smtp_password: None,
             smtp_starttls: true,
+            lmtp_listen: None,
+            lmtp_trust_received: false,
         }
     }
 }
@@ -79,6 +140,30 @@ impl EnvConfig {
                 .get("dkim_selector")
                 .cloned()
                 .unwrap_or_else(|| Self::default().dkim_selector),
+            dkim_rsa_selector: match map.get("dkim_rsa_selector") {
+                Some(value) => Some(value.clone()).filter(|v| !v.is_empty()),
+                None => Self::default().dkim_rsa_selector,
+            },
+            dkim_canonicalization: map
+                .get("dkim_canonicalization")
+                .filter(|v| !v.is_empty())
+                .cloned()
+                .unwrap_or_else(|| Self::default().dkim_canonicalization),
+            dkim_signed_headers: map
+                .get("dkim_signed_headers")
+                .map(|v| parse_header_list(v))
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| Self::default().dkim_signed_headers),
+            dkim_oversign_headers: match map.get("dkim_oversign_headers") {
+                Some(value) => parse_header_list(value),
+                None => Self::default().dkim_oversign_headers,
+            },
+            dkim_expiry: map.get("dkim_expiry").filter(|v| !v.is_empty()).cloned(),
+            dkim_rotation_overlap: map
+                .get("dkim_rotation_overlap")
+                .filter(|v| !v.is_empty())
+                .cloned()
+                .unwrap_or_else(|| Self::default().dkim_rotation_overlap),
             letsencrypt_method: map
                 .get("letsencrypt_method")
                 .cloned()
@@ -107,6 +192,16 @@ impl EnvConfig {
                 .get("render_mode")
                 .cloned()
                 .unwrap_or_else(|| Self::default().render_mode),
+            plaintext_renderer: map
+                .get("plaintext_renderer")
+                .filter(|v| !v.is_empty())
+                .cloned()
+                .unwrap_or_else(|| Self::default().plaintext_renderer),
+            plaintext_width: map
+                .get("plaintext_width")
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or_else(|| Self::default().plaintext_width),
             load_external_per_message: map
                 .get("load_external_per_message")
                 .map(|v| matches!(v.as_str(), "true" | "1" | "yes"))
@@ -121,6 +216,18 @@ impl EnvConfig {
                 })
                 .filter(|v: &Vec<String>| !v.is_empty())
                 .unwrap_or_else(|| Self::default().retry_backoff),
+            retry_max_attempts: map
+                .get("retry_max_attempts")
+                .and_then(|v| v.parse::<u32>().ok())
+                .filter(|v| *v > 0),
+            retry_max_age: map.get("retry_max_age").filter(|v| !v.is_empty()).cloned(),
+            smtp_mode: match map.get("smtp_mode").map(String::as_str) {
+                None | Some("") => Self::default().smtp_mode,
+                Some(mode @ ("relay" | "direct")) => mode.to_string(),
+                Some(other) => {
+                    anyhow::bail!("invalid smtp_mode {other}: expected relay or direct")
+                }
+            },
             smtp_host: map.get("smtp_host").cloned(),
             smtp_port: map
                 .get("smtp_port")
@@ -132,6 +239,11 @@ impl EnvConfig {
                 .get("smtp_starttls")
                 .map(|v| matches!(v.as_str(), "true" | "1" | "yes"))
                 .unwrap_or_else(|| Self::default().smtp_starttls),
+            lmtp_listen: map.get("lmtp_listen").filter(|v| !v.is_empty()).cloned(),
+            lmtp_trust_received: map
+                .get("lmtp_trust_received")
+                .map(|v| matches!(v.as_str(), "true" | "1" | "yes"))
+                .unwrap_or_else(|| Self::default().lmtp_trust_received),
         })
     }
 
@@ -140,6 +252,12 @@ impl EnvConfig {
             concat!(
                 "dmarc_policy={}\n",
                 "dkim_selector={}\n",
+                "dkim_rsa_selector={}\n",
+                "dkim_canonicalization={}\n",
+                "dkim_signed_headers={}\n",
+                "dkim_oversign_headers={}\n",
+                "dkim_expiry={}\n",
+                "dkim_rotation_overlap={}\n",
                 "letsencrypt_method={}\n",
                 "keep_plus_tags={}\n",
                 "max_size_quarantine={}\n",
@@ -147,14 +265,27 @@ impl EnvConfig {
                 "contacts_dir={}\n",
                 "logging={}\n",
                 "render_mode={}\n",
+                "plaintext_renderer={}\n",
+                "plaintext_width={}\n",
                 "load_external_per_message={}\n",
                 "retry_backoff={}\n",
+                "retry_max_attempts={}\n",
+                "retry_max_age={}\n",
+                "smtp_mode={}\n",
                 "smtp_host={}\n",
                 "smtp_port={}\n",
-                "smtp_starttls={}\n"
+                "smtp_starttls={}\n",
+                "lmtp_listen={}\n",
+                "lmtp_trust_received={}\n"
             ),
             self.dmarc_policy,
             self.dkim_selector,
+            self.dkim_rsa_selector.clone().unwrap_or_default(),
+            self.dkim_canonicalization,
+            self.dkim_signed_headers.join(","),
+            self.dkim_oversign_headers.join(","),
+            self.dkim_expiry.clone().unwrap_or_default(),
+            self.dkim_rotation_overlap,
             self.letsencrypt_method,
             bool_to_env(self.keep_plus_tags),
             self.max_size_quarantine,
@@ -162,11 +293,20 @@ impl EnvConfig {
             self.contacts_dir,
             self.logging,
             self.render_mode,
+            self.plaintext_renderer,
+            self.plaintext_width,
             bool_to_env(self.load_external_per_message),
             self.retry_backoff.join(","),
+            self.retry_max_attempts
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            self.retry_max_age.clone().unwrap_or_default(),
+            self.smtp_mode,
             self.smtp_host.clone().unwrap_or_else(|| "127.0.0.1".into()),
             self.smtp_port,
-            bool_to_env(self.smtp_starttls)
+            bool_to_env(self.smtp_starttls),
+            self.lmtp_listen.clone().unwrap_or_default(),
+            bool_to_env(self.lmtp_trust_received)
         )
     }
 }
@@ -179,6 +319,50 @@ impl FromStr for EnvConfig {
     }
 }
 
+/// Set `key=value` in the `.env` at `env_path`, keeping comments and the
+/// order of other keys; the key is appended when missing.
+pub fn upsert_env_setting(env_path: &Path, key: &str, value: &str) -> Result<()> {
+    let mut lines = Vec::new();
+    let mut found = false;
+    if env_path.exists() {
+        let data = fs::read_to_string(env_path)
+            .with_context(|| format!("reading {}", env_path.display()))?;
+        for line in data.lines() {
+            if line.trim_start().starts_with('#') || line.trim().is_empty() {
+                lines.push(line.to_string());
+                continue;
+            }
+            if let Some((k, _)) = line.split_once('=')
+                && k.trim() == key
+            {
+                lines.push(format!("{key}={value}"));
+                found = true;
+                continue;
+            }
+            lines.push(line.to_string());
+        }
+    }
+    if !found {
+        lines.push(format!("{key}={value}"));
+    }
+    let rendered = if lines.is_empty() {
+        format!("{key}={value}\n")
+    } else {
+        format!("{}\n", lines.join("\n"))
+    };
+    write_atomic(env_path, rendered.as_bytes())
+        .with_context(|| format!("writing {}", env_path.display()))?;
+    Ok(())
+}
+
+fn parse_header_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
 fn bool_to_env(value: bool) -> &'static str {
     if value { "true" } else { "false" }
 }
@@ -276,6 +460,87 @@ mod tests {
         assert!(env.contains("smtp_host=127.0.0.1"));
     }
 
+    #[test]
+    fn lmtp_listen_roundtrips_and_blank_disables() {
+        let cfg: EnvConfig = "lmtp_listen=unix:/run/owl/lmtp.sock\n".parse().unwrap();
+        assert_eq!(cfg.lmtp_listen.as_deref(), Some("unix:/run/owl/lmtp.sock"));
+        let reparsed: EnvConfig = cfg.to_env_string().parse().unwrap();
+        assert_eq!(reparsed.lmtp_listen, cfg.lmtp_listen);
+
+        let blank: EnvConfig = "lmtp_listen=\n".parse().unwrap();
+        assert!(blank.lmtp_listen.is_none());
+        assert!(
+            EnvConfig::default()
+                .to_env_string()
+                .contains("lmtp_listen=\n")
+        );
+    }
+
+    #[test]
+    fn retry_limits_roundtrip_and_default_to_unlimited() {
+        let cfg: EnvConfig = "retry_max_attempts=8\nretry_max_age=5d\n".parse().unwrap();
+        assert_eq!(cfg.retry_max_attempts, Some(8));
+        assert_eq!(cfg.retry_max_age.as_deref(), Some("5d"));
+        let reparsed: EnvConfig = cfg.to_env_string().parse().unwrap();
+        assert_eq!(reparsed.retry_max_attempts, Some(8));
+        assert_eq!(reparsed.retry_max_age, cfg.retry_max_age);
+
+        let unlimited: EnvConfig = "retry_max_attempts=0\nretry_max_age=\n".parse().unwrap();
+        assert!(unlimited.retry_max_attempts.is_none());
+        assert!(unlimited.retry_max_age.is_none());
+        assert_eq!(EnvConfig::default().retry_max_attempts, None);
+    }
+
+    #[test]
+    fn dkim_signing_options_parse_and_round_trip() {
+        let cfg: EnvConfig = "dkim_selector=mail\n".parse().unwrap();
+        assert_eq!(cfg.dkim_canonicalization, "relaxed/relaxed");
+        assert!(cfg.dkim_signed_headers.contains(&"message-id".to_string()));
+        assert!(cfg.dkim_oversign_headers.contains(&"from".to_string()));
+        assert!(cfg.dkim_expiry.is_none());
+
+        let custom: EnvConfig = "dkim_canonicalization=simple/simple\ndkim_signed_headers=From, Subject\ndkim_oversign_headers=\ndkim_expiry=7d\n"
+            .parse()
+            .unwrap();
+        assert_eq!(custom.dkim_signed_headers, ["from", "subject"]);
+        assert!(custom.dkim_oversign_headers.is_empty());
+        assert_eq!(custom.dkim_expiry.as_deref(), Some("7d"));
+        let reparsed: EnvConfig = custom.to_env_string().parse().unwrap();
+        assert_eq!(reparsed.dkim_canonicalization, "simple/simple");
+        assert_eq!(reparsed.dkim_signed_headers, custom.dkim_signed_headers);
+        assert!(reparsed.dkim_oversign_headers.is_empty());
+        assert_eq!(reparsed.dkim_expiry, custom.dkim_expiry);
+    }
+
+    #[test]
+    fn dkim_rsa_selector_defaults_and_can_be_disabled() {
+        let cfg: EnvConfig = "dkim_selector=mail\n".parse().unwrap();
+        assert_eq!(cfg.dkim_rsa_selector.as_deref(), Some("mail-rsa"));
+        let custom: EnvConfig = "dkim_rsa_selector=legacy\n".parse().unwrap();
+        let reparsed: EnvConfig = custom.to_env_string().parse().unwrap();
+        assert_eq!(reparsed.dkim_rsa_selector.as_deref(), Some("legacy"));
+        let disabled: EnvConfig = "dkim_rsa_selector=\n".parse().unwrap();
+        assert!(disabled.dkim_rsa_selector.is_none());
+        let reparsed: EnvConfig = disabled.to_env_string().parse().unwrap();
+        assert!(reparsed.dkim_rsa_selector.is_none());
+    }
+
+    #[test]
+    fn smtp_mode_defaults_to_relay() {
+        assert_eq!(EnvConfig::default().smtp_mode, "relay");
+        let cfg: EnvConfig = "smtp_mode=direct\n".parse().unwrap();
+        assert_eq!(cfg.smtp_mode, "direct");
+        let reparsed: EnvConfig = cfg.to_env_string().parse().unwrap();
+        assert_eq!(reparsed.smtp_mode, "direct");
+        let blank: EnvConfig = "smtp_mode=\n".parse().unwrap();
+        assert_eq!(blank.smtp_mode, "relay");
+        let err = "smtp_mode=drect\n".parse::<EnvConfig>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid smtp_mode drect: expected relay or direct"
+        );
+    }
+
     #[test]
     fn retry_backoff_spec_default() {
         // Per spec: default is "1m,5m,15m,1h"
@@ -351,6 +616,22 @@ mod tests {
         assert_eq!(full.logging, "verbose_full");
     }
 
+    #[test]
+    fn plaintext_renderer_settings() {
+        let cfg = EnvConfig::default();
+        assert_eq!(cfg.plaintext_renderer, "native");
+        assert_eq!(cfg.plaintext_width, 78);
+        let cfg: EnvConfig = "plaintext_renderer=lynx\nplaintext_width=100\n"
+            .parse()
+            .unwrap();
+        assert_eq!(cfg.plaintext_renderer, "lynx");
+        assert_eq!(cfg.plaintext_width, 100);
+        let cfg: EnvConfig = "plaintext_width=0\n".parse().unwrap();
+        assert_eq!(cfg.plaintext_width, 78);
+        let round_trip: EnvConfig = cfg.to_env_string().parse().unwrap();
+        assert_eq!(round_trip.plaintext_width, 78);
+    }
+
     #[test]
     fn render_mode_spec_values() {
         // Per spec: strict | moderate
@@ -402,6 +683,16 @@ mod tests {
         assert!(!f.load_external_per_message);
     }
 
+    #[test]
+    fn lmtp_trust_received_defaults_off() {
+        let cfg: EnvConfig = "".parse().unwrap();
+        assert!(!cfg.lmtp_trust_received);
+
+        let t: EnvConfig = "lmtp_trust_received=yes\n".parse().unwrap();
+        assert!(t.lmtp_trust_received);
+        assert!(t.to_env_string().contains("lmtp_trust_received=true\n"));
+    }
+
     #[test]
     fn smtp_port_custom_value() {
         let cfg: EnvConfig = "smtp_port=587\n".parse().unwrap();
@@ -55,6 +55,14 @@ impl MailLayout {
         self.logs_dir().join("owl.log")
     }
 
+    pub fn search_index(&self) -> PathBuf {
+        self.root.join("index")
+    }
+
+    pub fn thread_keys(&self) -> PathBuf {
+        self.search_index().join("threads")
+    }
+
     pub fn attachments(&self, list: &str) -> PathBuf {
         self.root.join(list).join("attachments")
     }
@@ -110,7 +118,7 @@ fn default_settings(list: &str) -> Vec<u8> {
         _ => "accepted",
     };
     format!(
-        "list_status={status}\ndelete_after=never\nfrom=\nreply_to=\nsignature=\nbody_format=both\ncollapse_signatures=true\n"
+        "list_status={status}\ndelete_after=never\nfrom=\nreply_to=\nsignature=\nbody_format=both\ncollapse_signatures=true\nrequire_auth=none\n"
     )
     .into_bytes()
 }
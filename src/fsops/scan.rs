@@ -0,0 +1,206 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{fsops::layout::MailLayout, model::message::MessageSidecar};
+
+/// Lists whose messages live in per-sender subdirectories.
+pub const SENDER_LISTS: [&str; 4] = ["quarantine", "accepted", "spam", "banned"];
+/// Lists that keep messages flat, named by ULID.
+pub const FLAT_LISTS: [&str; 2] = ["outbox", "sent"];
+
+/// A message found on disk together with its parsed sidecar.
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub list: String,
+    pub dir: PathBuf,
+    pub sidecar_path: PathBuf,
+    pub sidecar: MessageSidecar,
+}
+
+impl StoredMessage {
+    pub fn message_path(&self) -> PathBuf {
+        self.dir.join(&self.sidecar.filename)
+    }
+
+    pub fn html_path(&self) -> PathBuf {
+        self.dir.join(&self.sidecar.render.html)
+    }
+
+    pub fn plain_path(&self) -> Option<PathBuf> {
+        self.sidecar
+            .render
+            .plain
+            .as_ref()
+            .map(|plain| self.dir.join(plain))
+    }
+}
+
+/// A sidecar that could not be read or parsed, and why.
+#[derive(Debug)]
+pub struct SkippedSidecar {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// The messages found by [`scan_messages`], plus the sidecars it skipped.
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub messages: Vec<StoredMessage>,
+    pub skipped: Vec<SkippedSidecar>,
+}
+
+/// Load every sidecar under the mail root, in list order and then path order.
+/// Sidecars that cannot be read or parsed are returned in `skipped` rather
+/// than failing the scan, so one damaged file does not hide the rest of the
+/// mailbox.
+pub fn scan_messages(layout: &MailLayout) -> Result<Scan> {
+    let mut scan = Scan::default();
+    for list in SENDER_LISTS {
+        let base = layout.root().join(list);
+        if !base.exists() {
+            continue;
+        }
+        let mut senders = Vec::new();
+        for entry in fs::read_dir(&base).with_context(|| format!("reading {}", base.display()))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.file_name() != "attachments" {
+                senders.push(entry.path());
+            }
+        }
+        senders.sort();
+        for sender_dir in senders {
+            load_dir(list, &sender_dir, &mut scan)?;
+        }
+    }
+    for list in FLAT_LISTS {
+        load_dir(list, &layout.root().join(list), &mut scan)?;
+    }
+    Ok(scan)
+}
+
+/// Locate a single message by ULID anywhere under the mail root.
+pub fn find_message(layout: &MailLayout, ulid: &str) -> Result<Option<StoredMessage>> {
+    Ok(scan_messages(layout)?
+        .messages
+        .into_iter()
+        .find(|message| message.sidecar.ulid.eq_ignore_ascii_case(ulid)))
+}
+
+fn load_dir(list: &str, dir: &Path, scan: &mut Scan) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_file()
+            && path.extension().and_then(|ext| ext.to_str()) == Some("yml")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    for sidecar_path in paths {
+        match load_message(list, sidecar_path.clone()) {
+            Ok(message) => scan.messages.push(message),
+            Err(error) => scan.skipped.push(SkippedSidecar {
+                path: sidecar_path,
+                error,
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Load the message whose sidecar is at `sidecar_path`, filed in `list`.
+pub fn load_message(list: &str, sidecar_path: PathBuf) -> Result<StoredMessage> {
+    let yaml = fs::read_to_string(&sidecar_path)
+        .with_context(|| format!("reading {}", sidecar_path.display()))?;
+    let sidecar: MessageSidecar = serde_yaml::from_str(&yaml)
+        .with_context(|| format!("parsing {}", sidecar_path.display()))?;
+    Ok(StoredMessage {
+        list: list.to_string(),
+        dir: sidecar_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        sidecar_path,
+        sidecar,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::message::HeadersCache;
+
+    fn write_sidecar(dir: &Path, name: &str, ulid: &str, status: &str) {
+        fs::create_dir_all(dir).unwrap();
+        let mut sidecar = MessageSidecar::new(
+            ulid,
+            format!("{ulid}.eml"),
+            status,
+            "strict",
+            format!(".{ulid}.html"),
+            "hash",
+            HeadersCache::new("a@example.org", "Subject"),
+        );
+        sidecar.set_plain_render(format!(".{ulid}.txt"));
+        fs::write(dir.join(name), serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn scans_sender_and_flat_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        write_sidecar(
+            &layout.accepted().join("a@example.org"),
+            ".m1.yml",
+            "01A",
+            "accepted",
+        );
+        write_sidecar(
+            &layout.quarantine().join("b@example.org"),
+            ".m2.yml",
+            "01B",
+            "quarantine",
+        );
+        write_sidecar(&layout.sent(), ".01C.yml", "01C", "sent");
+        fs::create_dir_all(layout.attachments("accepted")).unwrap();
+        fs::write(
+            layout.attachments("accepted").join("x.yml"),
+            "not a sidecar",
+        )
+        .unwrap();
+        fs::write(layout.sent().join("01C.eml"), "raw").unwrap();
+
+        let messages = scan_messages(&layout).unwrap().messages;
+        let lists: Vec<_> = messages.iter().map(|m| m.list.as_str()).collect();
+        assert_eq!(lists, vec!["quarantine", "accepted", "sent"]);
+
+        let sent = find_message(&layout, "01c").unwrap().unwrap();
+        assert_eq!(sent.message_path(), layout.sent().join("01C.eml"));
+        assert_eq!(sent.html_path(), layout.sent().join(".01C.html"));
+        assert_eq!(sent.plain_path(), Some(layout.sent().join(".01C.txt")));
+        assert!(find_message(&layout, "01Z").unwrap().is_none());
+    }
+
+    #[test]
+    fn scan_skips_broken_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        fs::create_dir_all(layout.sent()).unwrap();
+        fs::write(layout.sent().join(".bad.yml"), "{ invalid").unwrap();
+        write_sidecar(&layout.sent(), ".01C.yml", "01C", "sent");
+        let scan = scan_messages(&layout).unwrap();
+        assert_eq!(scan.messages.len(), 1);
+        assert_eq!(scan.messages[0].sidecar.ulid, "01C");
+        assert_eq!(scan.skipped.len(), 1);
+        assert_eq!(scan.skipped[0].path, layout.sent().join(".bad.yml"));
+        assert!(scan.skipped[0].error.to_string().contains("parsing"));
+    }
+}
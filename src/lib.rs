@@ -2,6 +2,7 @@ pub mod cli;
 pub mod envcfg;
 
 pub mod daemon {
+    pub mod lmtp;
     pub mod service;
     pub mod watch;
 }
@@ -18,32 +19,43 @@ pub mod fsops {
     pub mod attach;
     pub mod io_atom;
     pub mod layout;
+    pub mod scan;
 }
 
 pub mod pipeline {
+    pub mod auth;
+    pub mod compose;
     pub mod inbound;
+    pub mod mx;
     pub mod outbox;
+    pub mod plaintext;
     pub mod reconcile;
     pub mod render;
+    pub mod search;
     pub mod smtp_in;
+    pub mod thread;
 }
 
 pub mod ruleset {
     pub mod eval;
     pub mod loader;
+    pub mod sieve;
 }
 
 pub mod util {
     pub mod dkim;
+    pub mod dns;
     pub mod idna;
     pub mod logging;
     pub mod regex;
     pub mod size;
+    pub mod sysexits;
     pub mod time;
     pub mod ulid;
 }
 
 pub mod ops {
+    pub mod dkim;
     pub mod install;
 }
 
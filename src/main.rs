@@ -1,10 +1,9 @@
 #[cfg(not(test))]
 use clap::Parser;
-#[cfg(test)]
-use owl::cli::Commands;
 use owl::{
-    cli::{OwlCli, run},
+    cli::{Commands, OwlCli, run},
     envcfg::EnvConfig,
+    util::sysexits,
 };
 use std::path::Path;
 
@@ -27,9 +26,23 @@ fn execute(mut cli: OwlCli) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Exit status for a failed command. `deliver` runs under an MTA pipe
+/// transport, so it reports sysexits codes; everything else exits with 1.
+fn failure_exit_code(command: Option<&Commands>, err: &anyhow::Error) -> i32 {
+    match command {
+        Some(Commands::Deliver { .. }) => sysexits::delivery_exit_code(err),
+        _ => 1,
+    }
+}
+
 #[cfg(not(test))]
-fn main() -> anyhow::Result<()> {
-    execute(OwlCli::parse())
+fn main() {
+    let cli = OwlCli::parse();
+    let command = cli.command.clone();
+    if let Err(err) = execute(cli) {
+        eprintln!("Error: {err:?}");
+        std::process::exit(failure_exit_code(command.as_ref(), &err));
+    }
 }
 
 #[cfg(test)]
@@ -139,6 +152,32 @@ mod tests {
         execute(cli).unwrap();
     }
 
+    #[test]
+    fn failure_exit_code_uses_sysexits_for_deliver() {
+        let deliver = Commands::Deliver {
+            sender: "a@example.org".into(),
+            recipient: "b@example.org".into(),
+            client_ip: None,
+            helo: None,
+        };
+        let size_err = anyhow::Error::new(owl::pipeline::smtp_in::SizeLimitError {
+            size: 2,
+            label: "quarantine".into(),
+            configured: "1".into(),
+        });
+        assert_eq!(
+            failure_exit_code(Some(&deliver), &size_err),
+            sysexits::EX_DATAERR
+        );
+        let io_err = anyhow::Error::new(std::io::Error::other("disk full"));
+        assert_eq!(
+            failure_exit_code(Some(&deliver), &io_err),
+            sysexits::EX_TEMPFAIL
+        );
+        assert_eq!(failure_exit_code(Some(&Commands::Reload), &size_err), 1);
+        assert_eq!(failure_exit_code(None, &io_err), 1);
+    }
+
     #[test]
     fn stub_main_is_callable() {
         super::main().unwrap();
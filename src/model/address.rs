@@ -32,6 +32,11 @@ impl Address {
         })
     }
 
+    /// The address as given, before lowercasing and `+tag` stripping.
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+
     pub fn canonical(&self) -> &str {
         &self.canonical
     }
@@ -47,6 +47,16 @@ pub fn outbox_html_filename(ulid: &str) -> String {
     format!(".{ulid}.html")
 }
 
+/// ULID a message file is named after, under either naming scheme above.
+pub fn ulid_from_filename(name: &str) -> Option<&str> {
+    let (stem, _) = name.trim_start_matches('.').rsplit_once('.')?;
+    let ulid = match stem.rsplit_once(" (") {
+        Some((_, rest)) => rest.strip_suffix(')')?,
+        None => stem,
+    };
+    (ulid.len() == 26 && ulid.chars().all(|c| c.is_ascii_alphanumeric())).then_some(ulid)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,6 +91,24 @@ mod tests {
         assert_eq!(outbox_html_filename(ulid), format!(".{ulid}.html"));
     }
 
+    #[test]
+    fn ulid_recovered_from_filenames() {
+        let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+        assert_eq!(
+            ulid_from_filename(&sidecar_filename("Re: (draft) plans", ulid)),
+            Some(ulid)
+        );
+        assert_eq!(
+            ulid_from_filename(&message_filename("Hi", ulid)),
+            Some(ulid)
+        );
+        assert_eq!(
+            ulid_from_filename(&outbox_sidecar_filename(ulid)),
+            Some(ulid)
+        );
+        assert_eq!(ulid_from_filename("notes.txt"), None);
+    }
+
     proptest! {
         #[test]
         fn slug_is_windows_safe(input in ".{0,256}") {
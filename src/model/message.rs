@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
-use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use time::{
+    OffsetDateTime,
+    format_description::well_known::{Rfc2822, Rfc3339},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct MessageSidecar {
@@ -16,10 +19,19 @@ pub struct MessageSidecar {
     pub render: RenderInfo,
     pub attachments: Vec<AttachmentMeta>,
     pub headers_cache: HeadersCache,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
     #[serde(default)]
     pub history: Vec<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub rspamd: Option<RspamdSummary>,
+    /// Inbound only: DKIM/SPF/DMARC results from delivery time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthResults>,
+    /// Inbound only: envelope recipients (`RCPT TO`) the message was
+    /// delivered for; empty for imports.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delivered_to: Vec<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub outbound: Option<OutboundState>,
 }
@@ -30,12 +42,27 @@ pub struct RenderInfo {
     pub html: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub plain: Option<String>,
+    /// Remote images and CSS `url()`s replaced by placeholders in the `.html`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_remote: Vec<String>,
+    /// Set once `owl render --allow-remote` let this message load them.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub remote_allowed: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct AttachmentMeta {
     pub sha256: String,
     pub name: String,
+    /// Embedded in the HTML through `cid:` rather than attached for download.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub inline: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -43,8 +70,19 @@ pub struct HeadersCache {
     pub from: String,
     pub to: Vec<String>,
     pub cc: Vec<String>,
+    /// Outbound only: envelope-only recipients, never written to the message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<String>,
     pub subject: String,
+    /// RFC 3339: the `Date` header when it parses, otherwise when owl
+    /// stored the message.
     pub date: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
 }
 
 impl MessageSidecar {
@@ -59,9 +97,11 @@ impl MessageSidecar {
         headers: HeadersCache,
     ) -> Self {
         let now = OffsetDateTime::now_utc().format(&Rfc3339).expect("rfc3339");
+        let ulid = ulid.into();
+        let thread_id = Some(headers.thread_root().unwrap_or_else(|| ulid.clone()));
         Self {
             schema: 1,
-            ulid: ulid.into(),
+            ulid,
             filename: filename.into(),
             status_shadow: status_shadow.into(),
             read: false,
@@ -74,11 +114,16 @@ impl MessageSidecar {
                 mode: mode.into(),
                 html: html.into(),
                 plain: None,
+                blocked_remote: Vec::new(),
+                remote_allowed: false,
             },
             attachments: Vec::new(),
             headers_cache: headers,
+            thread_id,
             history: Vec::new(),
             rspamd: None,
+            auth: None,
+            delivered_to: Vec::new(),
             outbound: None,
         }
     }
@@ -87,6 +132,23 @@ impl MessageSidecar {
         self.attachments.push(AttachmentMeta {
             sha256: sha256.into(),
             name: name.into(),
+            inline: false,
+            content_id: None,
+        });
+    }
+
+    /// Record an image the HTML references as `cid:<content_id>`.
+    pub fn add_inline_attachment(
+        &mut self,
+        sha256: impl Into<String>,
+        name: impl Into<String>,
+        content_id: impl Into<String>,
+    ) {
+        self.attachments.push(AttachmentMeta {
+            sha256: sha256.into(),
+            name: name.into(),
+            inline: true,
+            content_id: Some(content_id.into()),
         });
     }
 
@@ -121,10 +183,65 @@ impl HeadersCache {
             from: from.into(),
             to: Vec::new(),
             cc: Vec::new(),
+            bcc: Vec::new(),
             subject: subject.into(),
             date: OffsetDateTime::now_utc().format(&Rfc3339).expect("rfc3339"),
+            message_id: None,
+            in_reply_to: None,
+            references: Vec::new(),
         }
     }
+
+    /// Message-ID of the conversation root: the first References entry, else
+    /// the parent from In-Reply-To, else this message's own Message-ID.
+    pub fn thread_root(&self) -> Option<String> {
+        self.references
+            .first()
+            .or(self.in_reply_to.as_ref())
+            .or(self.message_id.as_ref())
+            .cloned()
+    }
+}
+
+/// Extract the `<msg-id>` tokens from a Message-ID, In-Reply-To or References
+/// header value. Values without angle brackets fall back to whitespace tokens
+/// that look like ids (contain `@`), so junk cannot link unrelated threads.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let id = &rest[start..start + len + 1];
+        if id.len() > 2 {
+            ids.push(id.to_string());
+        }
+        rest = &rest[start + len + 1..];
+    }
+    if ids.is_empty() {
+        ids = value
+            .split_whitespace()
+            .filter(|token| token.contains('@') && !token.contains(['<', '>']))
+            .map(str::to_string)
+            .collect();
+    }
+    ids
+}
+
+/// Normalise a `Date` header to the RFC 3339 form `headers_cache.date` uses,
+/// keeping the sender's offset. Looser forms only mailparse understands are
+/// converted to UTC; anything else yields `None`.
+pub fn normalize_date(value: &str) -> Option<String> {
+    let value = value.trim();
+    let parsed = OffsetDateTime::parse(value, &Rfc2822).ok().or_else(|| {
+        mailparse::dateparse(value)
+            .ok()
+            // mailparse reports text it cannot read as the epoch
+            .filter(|&seconds| seconds > 0)
+            .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+    })?;
+    parsed.format(&Rfc3339).ok()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
@@ -134,6 +251,86 @@ pub struct RspamdSummary {
     pub symbols: Vec<String>,
 }
 
+/// Outcome of one authentication check, named as in RFC 8601
+/// `Authentication-Results` headers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthVerdict {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    #[default]
+    None,
+    TempError,
+    PermError,
+}
+
+impl AuthVerdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthVerdict::Pass => "pass",
+            AuthVerdict::Fail => "fail",
+            AuthVerdict::SoftFail => "softfail",
+            AuthVerdict::Neutral => "neutral",
+            AuthVerdict::None => "none",
+            AuthVerdict::TempError => "temperror",
+            AuthVerdict::PermError => "permerror",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct AuthResults {
+    /// One entry per `DKIM-Signature` header checked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dkim: Vec<DkimCheck>,
+    #[serde(default)]
+    pub spf: SpfCheck,
+    #[serde(default)]
+    pub dmarc: DmarcCheck,
+    /// Why a message bound for `accepted/` was quarantined instead
+    /// (`require_auth` in the list's `.settings`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub demoted: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct DkimCheck {
+    pub result: AuthVerdict,
+    pub domain: String,
+    pub selector: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct SpfCheck {
+    pub result: AuthVerdict,
+    /// Envelope sender domain, or the HELO name for bounces.
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct DmarcCheck {
+    pub result: AuthVerdict,
+    /// Domain of the From header.
+    #[serde(default)]
+    pub domain: String,
+    /// Published `p=` (or `sp=`) policy when a record was found.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    #[serde(default)]
+    pub dkim_aligned: bool,
+    #[serde(default)]
+    pub spf_aligned: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct OutboundState {
     pub status: OutboundStatus,
@@ -142,6 +339,17 @@ pub struct OutboundState {
     pub last_error: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub next_attempt_at: Option<String>,
+    /// When the message was (re)queued, RFC 3339; `retry_max_age` counts
+    /// from here. Older sidecars fall back to `received_at`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued_at: Option<String>,
+    /// Per envelope recipient delivery state. Empty until the first attempt,
+    /// which fills it from the To/Cc/Bcc headers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<RecipientState>,
+    /// List signature appended when queued, so cancel removes exactly it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 impl Default for OutboundState {
@@ -151,6 +359,54 @@ impl Default for OutboundState {
             attempts: 0,
             last_error: None,
             next_attempt_at: None,
+            queued_at: None,
+            recipients: Vec::new(),
+            signature: None,
+        }
+    }
+}
+
+impl OutboundState {
+    /// Count recipients as `(delivered, pending, failed)`.
+    pub fn recipient_counts(&self) -> (usize, usize, usize) {
+        self.recipients
+            .iter()
+            .fold((0, 0, 0), |(sent, pending, failed), rcpt| {
+                match rcpt.status {
+                    OutboundStatus::Sent => (sent + 1, pending, failed),
+                    OutboundStatus::Pending => (sent, pending + 1, failed),
+                    OutboundStatus::Failed => (sent, pending, failed + 1),
+                }
+            })
+    }
+
+    /// Some recipients have the message and others do not (yet).
+    pub fn is_partial(&self) -> bool {
+        let (sent, pending, failed) = self.recipient_counts();
+        sent > 0 && pending + failed > 0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecipientState {
+    pub address: String,
+    pub status: OutboundStatus,
+    pub attempts: u32,
+    /// Last SMTP reply (or connection/DNS error) for a failed attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<String>,
+}
+
+impl RecipientState {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            status: OutboundStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: None,
         }
     }
 }
@@ -192,6 +448,84 @@ mod tests {
         assert_eq!(parsed.outbound.unwrap().attempts, 2);
     }
 
+    #[test]
+    fn headers_cache_threading_fields_are_optional() {
+        let yaml = "from: a\nto: []\ncc: []\nsubject: s\ndate: d\n";
+        let cache: HeadersCache = serde_yaml::from_str(yaml).unwrap();
+        assert!(cache.message_id.is_none());
+        assert!(cache.references.is_empty());
+        let rendered = serde_yaml::to_string(&cache).unwrap();
+        assert!(!rendered.contains("references"));
+        assert!(!rendered.contains("message_id"));
+    }
+
+    #[test]
+    fn outbound_recipients_are_optional_and_counted() {
+        let legacy: OutboundState = serde_yaml::from_str("status: Pending\nattempts: 3\n").unwrap();
+        assert!(legacy.recipients.is_empty());
+        assert_eq!(legacy.recipient_counts(), (0, 0, 0));
+        assert!(!legacy.is_partial());
+        assert!(
+            !serde_yaml::to_string(&legacy)
+                .unwrap()
+                .contains("recipients")
+        );
+
+        let mut state = OutboundState::default();
+        let mut sent = RecipientState::new("a@example.org");
+        sent.status = OutboundStatus::Sent;
+        let mut failed = RecipientState::new("b@example.org");
+        failed.status = OutboundStatus::Failed;
+        state.recipients = vec![sent, failed, RecipientState::new("c@example.org")];
+        assert_eq!(state.recipient_counts(), (1, 1, 1));
+        assert!(state.is_partial());
+        let parsed: OutboundState =
+            serde_yaml::from_str(&serde_yaml::to_string(&state).unwrap()).unwrap();
+        assert_eq!(parsed, state);
+    }
+
+    #[test]
+    fn thread_id_follows_root_of_conversation() {
+        let mut headers = HeadersCache::new("Alice", "Hello");
+        assert!(headers.thread_root().is_none());
+        let lonely = MessageSidecar::new(
+            "01A",
+            "a.eml",
+            "accepted",
+            "strict",
+            ".a.html",
+            "h",
+            headers.clone(),
+        );
+        assert_eq!(lonely.thread_id.as_deref(), Some("01A"));
+
+        headers.message_id = Some("<c@x>".into());
+        assert_eq!(headers.thread_root().as_deref(), Some("<c@x>"));
+        headers.in_reply_to = Some("<b@x>".into());
+        assert_eq!(headers.thread_root().as_deref(), Some("<b@x>"));
+        headers.references = vec!["<a@x>".into(), "<b@x>".into()];
+        assert_eq!(headers.thread_root().as_deref(), Some("<a@x>"));
+        let reply = MessageSidecar::new(
+            "01C", "c.eml", "accepted", "strict", ".c.html", "h", headers,
+        );
+        assert_eq!(reply.thread_id.as_deref(), Some("<a@x>"));
+    }
+
+    #[test]
+    fn parse_message_ids_extracts_tokens() {
+        assert_eq!(
+            parse_message_ids("<a@x>\r\n <b@y> junk <c@z>"),
+            vec!["<a@x>", "<b@y>", "<c@z>"]
+        );
+        assert_eq!(
+            parse_message_ids("bare@id other@id"),
+            vec!["bare@id", "other@id"]
+        );
+        assert_eq!(parse_message_ids("<> <unterminated"), Vec::<String>::new());
+        assert_eq!(parse_message_ids("junk <open@x"), Vec::<String>::new());
+        assert!(parse_message_ids("  ").is_empty());
+    }
+
     #[test]
     fn sidecar_defaults_flags_to_false() {
         // Per spec: read, starred, pinned default to false
@@ -504,11 +838,38 @@ mod tests {
         assert!(state.next_attempt_at.is_some());
     }
 
+    #[test]
+    fn inline_attachments_are_flagged() {
+        let mut sidecar = MessageSidecar::new(
+            "01ABC",
+            "Subject (01ABC).eml",
+            "accepted",
+            "strict",
+            ".Subject.html",
+            "deadbeef",
+            HeadersCache::new("Alice", "Hello"),
+        );
+        sidecar.add_attachment("aa", "contract.pdf");
+        sidecar.add_inline_attachment("bb", "logo.png", "logo@example.org");
+        let yaml = serde_yaml::to_string(&sidecar).unwrap();
+        assert_eq!(yaml.matches("inline: true").count(), 1);
+        assert!(yaml.contains("content_id: logo@example.org"));
+        let parsed: MessageSidecar = serde_yaml::from_str(&yaml).unwrap();
+        assert!(!parsed.attachments[0].inline);
+        assert!(parsed.attachments[1].inline);
+        assert_eq!(
+            parsed.attachments[1].content_id.as_deref(),
+            Some("logo@example.org")
+        );
+    }
+
     #[test]
     fn attachment_meta_format() {
         let attachment = AttachmentMeta {
             sha256: "a".repeat(64), // SHA256 is 64 hex chars
             name: "document.pdf".to_string(),
+            inline: false,
+            content_id: None,
         };
 
         // Per spec: attachments are content-addressed with sha256
@@ -645,10 +1006,28 @@ mod tests {
         assert!(!headers.date.is_empty());
         assert!(OffsetDateTime::parse(&headers.date, &Rfc3339).is_ok());
 
-        // Can update it to a specific RFC 2822 format
+        // Can update it from a Date header, normalized to RFC 3339
         let mut headers2 = headers.clone();
-        headers2.date = "Tue, 16 Sep 2025 23:12:33 -0700".to_string();
-        assert!(headers2.date.contains("2025"));
+        headers2.date = normalize_date("Wed, 2 Jan 2024 03:04:05 +0000").unwrap();
+        assert_eq!(headers2.date, "2024-01-02T03:04:05Z");
+        assert!(OffsetDateTime::parse(&headers2.date, &Rfc3339).is_ok());
+    }
+
+    #[test]
+    fn normalize_date_converts_header_dates_to_rfc3339() {
+        assert_eq!(
+            normalize_date("Tue, 16 Sep 2025 23:12:33 -0700").as_deref(),
+            Some("2025-09-16T23:12:33-07:00")
+        );
+        assert_eq!(
+            normalize_date(" 16 Sep 2025 23:12:33 GMT ").as_deref(),
+            Some("2025-09-16T23:12:33Z")
+        );
+        assert_eq!(
+            normalize_date("Tue, 16 Sep 2025 23:12:33 -0700 (PDT)").as_deref(),
+            Some("2025-09-16T23:12:33-07:00")
+        );
+        assert!(normalize_date("yesterday-ish").is_none());
     }
 
     #[test]
@@ -2,6 +2,7 @@ use crate::model::address::Address;
 use anyhow::{Result, bail};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -10,6 +11,93 @@ pub enum Rule {
     DomainSuffix(String),
     DomainExact(String),
     Regex(String),
+    /// `to:me+shop@example.org`: an envelope recipient, `+tag` included.
+    Recipient(String),
+    /// `to:+shop`: any envelope recipient carrying this `+tag`.
+    RecipientTag(String),
+    /// `list-id:announce.example.org`: the id inside the List-Id header.
+    ListId(String),
+    /// `subject:/…/`
+    Subject(String),
+    /// `header:X-Spam-Flag:/…/`
+    Header {
+        name: String,
+        pattern: String,
+    },
+}
+
+/// What rules look at: the canonical sender plus, when known, the envelope
+/// (recipients parsed with `+tag`s kept), the decoded message headers and
+/// the message size.
+#[derive(Debug, Clone)]
+pub struct MessageContext<'a> {
+    sender: &'a Address,
+    envelope_sender: Option<String>,
+    recipients: Vec<Address>,
+    headers: Vec<(String, String)>,
+    size: usize,
+}
+
+impl<'a> MessageContext<'a> {
+    pub fn new(sender: &'a Address) -> Self {
+        Self {
+            sender,
+            envelope_sender: None,
+            recipients: Vec::new(),
+            headers: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// `MAIL FROM` as given; empty for bounces.
+    pub fn with_envelope_sender(mut self, sender: &str) -> Self {
+        let sender = sender.trim().trim_start_matches('<').trim_end_matches('>');
+        self.envelope_sender = Some(sender.to_string());
+        self
+    }
+
+    /// Unparseable recipients are skipped; they can never match a rule.
+    pub fn with_recipients<S: AsRef<str>>(mut self, recipients: &[S]) -> Self {
+        self.recipients.extend(
+            recipients
+                .iter()
+                .filter_map(|recipient| Address::parse(recipient.as_ref(), true).ok()),
+        );
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn sender(&self) -> &Address {
+        self.sender
+    }
+
+    pub fn envelope_sender(&self) -> Option<&str> {
+        self.envelope_sender.as_deref()
+    }
+
+    pub fn recipients(&self) -> &[Address] {
+        &self.recipients
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn header_values<'b>(&'b self, name: &'b str) -> impl Iterator<Item = &'b str> {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 impl Rule {
@@ -23,6 +111,40 @@ impl Rule {
             Regex::new(body).map_err(|e| anyhow::anyhow!("invalid regex: {e}"))?;
             return Ok(Self::Regex(body.to_string()));
         }
+        if let Some(recipient) = trimmed.strip_prefix("to:") {
+            let recipient = recipient.trim();
+            if let Some(tag) = recipient.strip_prefix('+') {
+                if tag.is_empty() || tag.contains('@') {
+                    bail!("invalid recipient tag: {trimmed}");
+                }
+                return Ok(Self::RecipientTag(tag.to_ascii_lowercase()));
+            }
+            let address = Address::parse(recipient, true)?;
+            return Ok(Self::Recipient(address.canonical().to_string()));
+        }
+        if let Some(id) = trimmed.strip_prefix("list-id:") {
+            let id = list_id(id);
+            if id.is_empty() {
+                bail!("empty list id: {trimmed}");
+            }
+            return Ok(Self::ListId(id));
+        }
+        if let Some(pattern) = trimmed.strip_prefix("subject:") {
+            return Ok(Self::Subject(slashed_regex(pattern)?));
+        }
+        if let Some(rest) = trimmed.strip_prefix("header:") {
+            let Some((name, pattern)) = rest.split_once(':') else {
+                bail!("header rule needs a name and a pattern: {trimmed}");
+            };
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                bail!("invalid header name: {trimmed}");
+            }
+            return Ok(Self::Header {
+                name: name.to_string(),
+                pattern: slashed_regex(pattern)?,
+            });
+        }
         if let Some(addr) = trimmed.strip_prefix('@') {
             if let Some(domain) = addr.strip_prefix('=') {
                 return Ok(Self::DomainExact(domain.to_ascii_lowercase()));
@@ -35,7 +157,8 @@ impl Rule {
         bail!("unsupported rule: {trimmed}");
     }
 
-    pub fn matches(&self, address: &Address) -> bool {
+    pub fn matches(&self, message: &MessageContext) -> bool {
+        let address = message.sender();
         match self {
             Rule::ExactAddress(value) => address.canonical() == value,
             Rule::DomainSuffix(value) => address.domain().ends_with(value.trim_start_matches('.')),
@@ -44,10 +167,73 @@ impl Rule {
                 .ok()
                 .map(|re| re.is_match(address.canonical()))
                 .unwrap_or(false),
+            Rule::Recipient(value) => message
+                .recipients
+                .iter()
+                .any(|recipient| recipient.canonical() == value),
+            Rule::RecipientTag(value) => message.recipients.iter().any(|recipient| {
+                recipient
+                    .local()
+                    .split_once('+')
+                    .is_some_and(|(_, tag)| tag == value)
+            }),
+            Rule::ListId(value) => message
+                .header_values("List-Id")
+                .any(|header| list_id(header) == *value),
+            Rule::Subject(pattern) => regex_matches(pattern, message.header_values("Subject")),
+            Rule::Header { name, pattern } => regex_matches(pattern, message.header_values(name)),
+        }
+    }
+}
+
+/// Renders the `.rules` line the rule parses from.
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::ExactAddress(value) => write!(f, "{value}"),
+            Rule::DomainSuffix(value) => write!(f, "@{value}"),
+            Rule::DomainExact(value) => write!(f, "@={value}"),
+            Rule::Regex(value) => write!(f, "/{value}/"),
+            Rule::Recipient(value) => write!(f, "to:{value}"),
+            Rule::RecipientTag(value) => write!(f, "to:+{value}"),
+            Rule::ListId(value) => write!(f, "list-id:{value}"),
+            Rule::Subject(pattern) => write!(f, "subject:/{pattern}/"),
+            Rule::Header { name, pattern } => write!(f, "header:{name}:/{pattern}/"),
         }
     }
 }
 
+/// `/pattern/` with the slashes required, as in sender regex rules.
+fn slashed_regex(value: &str) -> Result<String> {
+    let value = value.trim();
+    let Some(body) = value
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    else {
+        bail!("expected /regex/, got: {value}");
+    };
+    Regex::new(body).map_err(|e| anyhow::anyhow!("invalid regex: {e}"))?;
+    Ok(body.to_string())
+}
+
+fn regex_matches<'a>(pattern: &str, mut values: impl Iterator<Item = &'a str>) -> bool {
+    Regex::new(pattern)
+        .ok()
+        .map(|re| values.any(|value| re.is_match(value)))
+        .unwrap_or(false)
+}
+
+/// The id between the angle brackets of a List-Id (RFC 2919), or the whole
+/// value when there are none, lowercased.
+fn list_id(value: &str) -> String {
+    let value = value.trim();
+    let id = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    id.trim().to_ascii_lowercase()
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct RuleSet {
     rules: Vec<Rule>,
@@ -66,9 +252,9 @@ impl RuleSet {
         Ok(Self { rules })
     }
 
-    pub fn evaluate(&self, address: &Address) -> Option<Rule> {
+    pub fn evaluate(&self, message: &MessageContext) -> Option<Rule> {
         for rule in &self.rules {
-            if rule.matches(address) {
+            if rule.matches(message) {
                 return Some(rule.clone());
             }
         }
@@ -103,14 +289,14 @@ mod tests {
     fn regex_rule_matches() {
         let rule = Rule::parse("/foo/").unwrap();
         let addr = Address::parse("foo@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn domain_suffix_trims_leading_dot() {
         let rule = Rule::parse("@.Example.Org").unwrap();
         let addr = Address::parse("user@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
@@ -118,10 +304,101 @@ mod tests {
         let data = "@example.org\ncarol@example.org";
         let set: RuleSet = data.parse().unwrap();
         let addr = Address::parse("carol@example.org", false).unwrap();
-        let matched = set.evaluate(&addr).unwrap();
+        let matched = set.evaluate(&MessageContext::new(&addr)).unwrap();
         assert!(matches!(matched, Rule::DomainSuffix(_)));
     }
 
+    #[test]
+    fn message_rules_parse() {
+        assert_eq!(
+            Rule::parse("to:Me+Shop@Example.org").unwrap(),
+            Rule::Recipient("me+shop@example.org".into())
+        );
+        assert_eq!(
+            Rule::parse("to:+Shop").unwrap(),
+            Rule::RecipientTag("shop".into())
+        );
+        assert_eq!(
+            Rule::parse("list-id:<News.Example.org>").unwrap(),
+            Rule::ListId("news.example.org".into())
+        );
+        assert_eq!(
+            Rule::parse("subject:/^\\[ci\\]/").unwrap(),
+            Rule::Subject("^\\[ci\\]".into())
+        );
+        assert_eq!(
+            Rule::parse("header:X-Spam-Flag:/YES/").unwrap(),
+            Rule::Header {
+                name: "X-Spam-Flag".into(),
+                pattern: "YES".into()
+            }
+        );
+        for invalid in [
+            "to:+",
+            "to:nobody",
+            "list-id:<>",
+            "subject:ci",
+            "subject:/[/",
+            "header:/x/",
+            "header:X Flag:/x/",
+        ] {
+            assert!(Rule::parse(invalid).is_err(), "{invalid} should not parse");
+        }
+    }
+
+    #[test]
+    fn message_rules_match_recipients_and_headers() {
+        let sender = Address::parse("bot@ci.example.org", false).unwrap();
+        let message = MessageContext::new(&sender)
+            .with_recipients(&["Me+Shop@Example.org", "not an address"])
+            .with_header("subject", "[ci] build failed")
+            .with_header("List-Id", "CI results <ci.example.org>")
+            .with_header("X-Priority", "1")
+            .with_header("X-Priority", "5");
+        for rule in [
+            "to:me+shop@example.org",
+            "to:+shop",
+            "list-id:ci.example.org",
+            "subject:/^\\[ci\\]/",
+            "header:x-priority:/^5$/",
+        ] {
+            assert!(Rule::parse(rule).unwrap().matches(&message), "{rule}");
+        }
+        for rule in [
+            "to:me@example.org",
+            "to:+news",
+            "list-id:example.org",
+            "subject:/^build/",
+            "header:X-Mailer:/.*/",
+        ] {
+            assert!(!Rule::parse(rule).unwrap().matches(&message), "{rule}");
+        }
+
+        // A bare sender context carries no recipients or headers.
+        let bare = MessageContext::new(&sender);
+        assert!(!Rule::parse("to:+shop").unwrap().matches(&bare));
+        assert!(!Rule::parse("subject:/.*/").unwrap().matches(&bare));
+    }
+
+    #[test]
+    fn rules_display_as_they_parse() {
+        for line in [
+            "carol@example.org",
+            "@example.org",
+            "@=example.org",
+            "/^admin@/",
+            "to:me+shop@example.org",
+            "to:+shop",
+            "list-id:news.example.org",
+            "subject:/^\\[ci\\]/",
+            "header:X-Spam-Flag:/YES/",
+        ] {
+            let rule = Rule::parse(line).unwrap();
+            assert_eq!(rule.to_string(), line);
+            assert_eq!(Rule::parse(&rule.to_string()).unwrap(), rule);
+        }
+    }
+
     #[test]
     fn rejects_empty_rule() {
         assert!(Rule::parse("   ").is_err());
@@ -131,21 +408,21 @@ mod tests {
     fn exact_address_rule_matches() {
         let rule = Rule::parse("carol@example.org").unwrap();
         let addr = Address::parse("carol@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn domain_exact_rule_matches() {
         let rule = Rule::parse("@=example.org").unwrap();
         let addr = Address::parse("bob@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn invalid_regex_is_safe() {
         let rule = Rule::Regex("[".into());
         let addr = Address::parse("carol@example.org", false).unwrap();
-        assert!(!rule.matches(&addr));
+        assert!(!rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
@@ -164,7 +441,10 @@ mod tests {
         // Per spec: @example.org matches subdomains
         let rule = Rule::parse("@example.org").unwrap();
         let addr = Address::parse("user@mail.example.org", false).unwrap();
-        assert!(rule.matches(&addr), "Domain suffix should match subdomains");
+        assert!(
+            rule.matches(&MessageContext::new(&addr)),
+            "Domain suffix should match subdomains"
+        );
     }
 
     #[test]
@@ -173,13 +453,13 @@ mod tests {
         let rule = Rule::parse("@=example.org").unwrap();
         let addr = Address::parse("user@mail.example.org", false).unwrap();
         assert!(
-            !rule.matches(&addr),
+            !rule.matches(&MessageContext::new(&addr)),
             "Domain exact should not match subdomains"
         );
 
         let exact_addr = Address::parse("user@example.org", false).unwrap();
         assert!(
-            rule.matches(&exact_addr),
+            rule.matches(&MessageContext::new(&exact_addr)),
             "Domain exact should match exact domain"
         );
     }
@@ -190,10 +470,10 @@ mod tests {
         // Note: addresses are canonicalized (+ stripped by default)
         let rule = Rule::parse(r"/^support.*@example\.org$/").unwrap();
         let addr = Address::parse("support-tickets@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
 
         let non_match = Address::parse("help@example.org", false).unwrap();
-        assert!(!rule.matches(&non_match));
+        assert!(!rule.matches(&MessageContext::new(&non_match)));
     }
 
     #[test]
@@ -221,7 +501,7 @@ mod tests {
         let data = "@example.org";
         let set: RuleSet = data.parse().unwrap();
         let addr = Address::parse("user@other.org", false).unwrap();
-        assert!(set.evaluate(&addr).is_none());
+        assert!(set.evaluate(&MessageContext::new(&addr)).is_none());
     }
 
     #[test]
@@ -230,38 +510,38 @@ mod tests {
         assert!(set.rules().is_empty());
 
         let addr = Address::parse("any@example.org", false).unwrap();
-        assert!(set.evaluate(&addr).is_none());
+        assert!(set.evaluate(&MessageContext::new(&addr)).is_none());
     }
 
     #[test]
     fn exact_address_case_insensitive() {
         let rule = Rule::parse("Alice@Example.Org").unwrap();
         let addr = Address::parse("alice@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn domain_suffix_case_insensitive() {
         let rule = Rule::parse("@Example.Org").unwrap();
         let addr = Address::parse("user@EXAMPLE.ORG", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn domain_exact_case_insensitive() {
         let rule = Rule::parse("@=Example.Org").unwrap();
         let addr = Address::parse("user@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
     fn regex_rule_with_anchors() {
         let rule = Rule::parse(r"/^admin@/").unwrap();
         let match_addr = Address::parse("admin@example.org", false).unwrap();
-        assert!(rule.matches(&match_addr));
+        assert!(rule.matches(&MessageContext::new(&match_addr)));
 
         let no_match = Address::parse("user@admin.org", false).unwrap();
-        assert!(!rule.matches(&no_match));
+        assert!(!rule.matches(&MessageContext::new(&no_match)));
     }
 
     #[test]
@@ -270,7 +550,7 @@ mod tests {
         let rule = Rule::parse(r"/ADMIN/").unwrap();
         let lowercase = Address::parse("admin@example.org", false).unwrap();
         // Address is canonicalized to lowercase, so this won't match
-        assert!(!rule.matches(&lowercase));
+        assert!(!rule.matches(&MessageContext::new(&lowercase)));
     }
 
     #[test]
@@ -280,9 +560,9 @@ mod tests {
         let support = Address::parse("support@example.org", false).unwrap();
         let other = Address::parse("user@example.org", false).unwrap();
 
-        assert!(rule.matches(&admin));
-        assert!(rule.matches(&support));
-        assert!(!rule.matches(&other));
+        assert!(rule.matches(&MessageContext::new(&admin)));
+        assert!(rule.matches(&MessageContext::new(&support)));
+        assert!(!rule.matches(&MessageContext::new(&other)));
     }
 
     #[test]
@@ -292,15 +572,15 @@ mod tests {
         let exact = Address::parse("user@example.org", false).unwrap();
 
         // Leading dot is trimmed, so it matches both
-        assert!(rule.matches(&subdomain));
-        assert!(rule.matches(&exact));
+        assert!(rule.matches(&MessageContext::new(&subdomain)));
+        assert!(rule.matches(&MessageContext::new(&exact)));
     }
 
     #[test]
     fn multiple_level_subdomain() {
         let rule = Rule::parse("@example.org").unwrap();
         let deep = Address::parse("user@a.b.c.example.org", false).unwrap();
-        assert!(rule.matches(&deep));
+        assert!(rule.matches(&MessageContext::new(&deep)));
     }
 
     #[test]
@@ -308,7 +588,7 @@ mod tests {
         // Plus tags are stripped during canonicalization by default
         let rule = Rule::parse("alice@example.org").unwrap();
         let with_tag = Address::parse("alice+tag@example.org", false).unwrap();
-        assert!(rule.matches(&with_tag));
+        assert!(rule.matches(&MessageContext::new(&with_tag)));
     }
 
     #[test]
@@ -317,7 +597,7 @@ mod tests {
         let set: RuleSet = data.parse().unwrap();
         let addr = Address::parse("alice@example.org", false).unwrap();
 
-        let matched = set.evaluate(&addr).unwrap();
+        let matched = set.evaluate(&MessageContext::new(&addr)).unwrap();
         // First rule (@example.org) should match
         assert!(matches!(matched, Rule::DomainSuffix(_)));
     }
@@ -344,7 +624,7 @@ mod tests {
             let full_domain = format!("{}.{}", subdomain, domain);
             let rule = Rule::parse(&format!("@{}", domain)).unwrap();
             let addr = Address::parse(&format!("user@{}", full_domain), false).unwrap();
-            prop_assert!(rule.matches(&addr));
+            prop_assert!(rule.matches(&MessageContext::new(&addr)));
         }
 
         #[test]
@@ -355,7 +635,7 @@ mod tests {
             let full_domain = format!("{}.{}", subdomain, domain);
             let rule = Rule::parse(&format!("@={}", domain)).unwrap();
             let addr = Address::parse(&format!("user@{}", full_domain), false).unwrap();
-            prop_assert!(!rule.matches(&addr));
+            prop_assert!(!rule.matches(&MessageContext::new(&addr)));
         }
 
         #[test]
@@ -366,7 +646,7 @@ mod tests {
             let email = format!("{}@{}", local, domain);
             let rule = Rule::parse(&email).unwrap();
             let addr = Address::parse(&email, false).unwrap();
-            prop_assert!(rule.matches(&addr));
+            prop_assert!(rule.matches(&MessageContext::new(&addr)));
         }
     }
 
@@ -397,7 +677,7 @@ mod tests {
         let rule = Rule::parse("//").unwrap();
         // Empty regex matches everything
         let addr = Address::parse("any@example.org", false).unwrap();
-        assert!(rule.matches(&addr));
+        assert!(rule.matches(&MessageContext::new(&addr)));
     }
 
     #[test]
@@ -407,9 +687,9 @@ mod tests {
         let match1 = Address::parse("aXb@example.org", false).unwrap();
         let match2 = Address::parse("a@b@example.org", false).unwrap();
 
-        assert!(rule.matches(&match1));
+        assert!(rule.matches(&MessageContext::new(&match1)));
         // Second @ would fail IDNA, so this won't parse correctly
-        assert!(match2.domain().contains("@") || !rule.matches(&match2));
+        assert!(match2.domain().contains("@") || !rule.matches(&MessageContext::new(&match2)));
     }
 
     #[test]
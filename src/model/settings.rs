@@ -10,6 +10,14 @@ pub struct ListSettings {
     pub signature: Option<String>,
     pub body_format: String,
     pub collapse_signatures: bool,
+    /// Sender authentication a message must pass to be delivered as
+    /// `accepted`: `dmarc`, `dkim` or `none`.
+    #[serde(default = "default_require_auth")]
+    pub require_auth: String,
+}
+
+fn default_require_auth() -> String {
+    "none".into()
 }
 
 impl Default for ListSettings {
@@ -22,6 +30,7 @@ impl Default for ListSettings {
             signature: None,
             body_format: "both".into(),
             collapse_signatures: true,
+            require_auth: default_require_auth(),
         }
     }
 }
@@ -49,6 +58,11 @@ impl ListSettings {
                 "collapse_signatures" => {
                     settings.collapse_signatures = matches!(value, "true" | "1" | "yes")
                 }
+                "require_auth" => match value {
+                    "dmarc" | "dkim" | "none" => settings.require_auth = value.to_string(),
+                    "" => settings.require_auth = default_require_auth(),
+                    other => bail!("invalid require_auth {other} (expected dmarc|dkim|none)"),
+                },
                 _ => bail!("unknown key {key}"),
             }
         }
@@ -93,6 +107,19 @@ mod tests {
         assert_eq!(settings.delete_after, "30d");
     }
 
+    #[test]
+    fn require_auth_accepts_known_modes() {
+        assert_eq!(ListSettings::default().require_auth, "none");
+        let settings = ListSettings::parse("require_auth=dmarc").unwrap();
+        assert_eq!(settings.require_auth, "dmarc");
+        assert_eq!(
+            ListSettings::parse("require_auth=").unwrap().require_auth,
+            "none"
+        );
+        let err = ListSettings::parse("require_auth=spf").unwrap_err();
+        assert!(err.to_string().contains("dmarc|dkim|none"));
+    }
+
     #[test]
     fn parse_unknown_key_fails() {
         assert!(ListSettings::parse("unknown=value").is_err());
@@ -244,6 +271,7 @@ mod tests {
             signature: Some("~/sig.txt".to_string()),
             body_format: "plain".to_string(),
             collapse_signatures: false,
+            require_auth: "none".to_string(),
         };
 
         // Serialize to string
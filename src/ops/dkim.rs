@@ -0,0 +1,553 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::{
+    envcfg::{EnvConfig, upsert_env_setting},
+    fsops::{io_atom::create_dir_all, layout::MailLayout},
+    util::dkim::{self, DkimKeyType, DkimMaterial, Rotation},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelectorState {
+    /// Signs outgoing mail.
+    Active,
+    /// Generated by a rotation that has not reached its switch time.
+    Pending,
+    /// Replaced by a due rotation that has not been finished yet.
+    Retiring,
+    /// Has a key in `dkim/` but is not configured.
+    Unused,
+    /// Moved to `dkim/archive/` by a finished rotation.
+    Archived,
+}
+
+impl SelectorState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SelectorState::Active => "active",
+            SelectorState::Pending => "pending",
+            SelectorState::Retiring => "retiring",
+            SelectorState::Unused => "unused",
+            SelectorState::Archived => "archived",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelectorStatus {
+    pub selector: String,
+    pub key_type: String,
+    pub state: SelectorState,
+    pub created_at: Option<String>,
+    pub age_days: Option<i64>,
+    /// Owner name of the TXT record, relative to the signing domain.
+    pub record_name: String,
+    pub record: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DkimStatus {
+    pub selectors: Vec<SelectorStatus>,
+    pub rotation: Option<Rotation>,
+}
+
+/// Generate a `key_type` key under a new selector and record the switch from
+/// `dkim_selector` (or `dkim_rsa_selector`) to it `overlap` from `now`.
+pub fn begin_rotation(
+    layout: &MailLayout,
+    env: &EnvConfig,
+    key_type: DkimKeyType,
+    selector: Option<&str>,
+    overlap: Duration,
+    now: OffsetDateTime,
+) -> Result<(Rotation, DkimMaterial)> {
+    let dir = layout.dkim_dir();
+    if let Some(pending) = Rotation::load(&dir)? {
+        bail!(
+            "DKIM rotation from {} to {} is pending until {}",
+            pending.from,
+            pending.to,
+            pending.switch_at
+        );
+    }
+    let current = match key_type {
+        DkimKeyType::Ed25519 => env.dkim_selector.as_str(),
+        DkimKeyType::Rsa => env.dkim_rsa_selector.as_deref().ok_or_else(|| {
+            anyhow!("dkim_rsa_selector is not set; there is no RSA key to rotate")
+        })?,
+    };
+    let selector = match selector {
+        Some(selector) => selector.trim().to_string(),
+        None => default_selector(current, now),
+    };
+    validate_selector(&selector)?;
+    if selector == env.dkim_selector || env.dkim_rsa_selector.as_deref() == Some(selector.as_str())
+    {
+        bail!("DKIM selector {selector} is already in use");
+    }
+    if layout.dkim_private_key(&selector).exists()
+        || archive_dir(layout)
+            .join(format!("{selector}.private"))
+            .exists()
+    {
+        bail!("DKIM selector {selector} already has a key");
+    }
+    let material = match key_type {
+        DkimKeyType::Ed25519 => dkim::ensure_ed25519_keypair(&dir, &selector)?,
+        DkimKeyType::Rsa => dkim::ensure_rsa_keypair(&dir, &selector)?,
+    };
+    let rotation = Rotation {
+        key_type,
+        from: current.to_string(),
+        to: selector,
+        started_at: now.format(&Rfc3339)?,
+        switch_at: (now + overlap).format(&Rfc3339)?,
+    };
+    rotation.save(&dir)?;
+    Ok((rotation, material))
+}
+
+/// Finish the pending rotation once its switch time has passed, returning
+/// it with the archived private key path.
+pub fn finish_due_rotation(
+    layout: &MailLayout,
+    env_path: &Path,
+    now: OffsetDateTime,
+) -> Result<Option<(Rotation, PathBuf)>> {
+    let Some(rotation) = Rotation::load(&layout.dkim_dir())? else {
+        return Ok(None);
+    };
+    if !rotation.is_due(now)? {
+        return Ok(None);
+    }
+    let archived = finish_rotation(layout, env_path, &rotation)?;
+    Ok(Some((rotation, archived)))
+}
+
+/// Point `dkim_selector` (or `dkim_rsa_selector`) at the new selector and
+/// move the old key files to `dkim/archive/`. `.env` is written first so an
+/// interrupted finish never leaves it naming an archived key.
+pub fn finish_rotation(
+    layout: &MailLayout,
+    env_path: &Path,
+    rotation: &Rotation,
+) -> Result<PathBuf> {
+    upsert_env_setting(env_path, rotation.key_type.env_key(), &rotation.to)?;
+    let archive = archive_dir(layout);
+    create_dir_all(&archive)?;
+    for path in [
+        layout.dkim_private_key(&rotation.from),
+        layout.dkim_public_key(&rotation.from),
+        layout.dkim_dns_record(&rotation.from),
+    ] {
+        if let Some(name) = path.file_name()
+            && path.exists()
+        {
+            let target = archive.join(name);
+            fs::rename(&path, &target)
+                .with_context(|| format!("archiving {} to {}", path.display(), target.display()))?;
+        }
+    }
+    let rotation_path = Rotation::path(&layout.dkim_dir());
+    fs::remove_file(&rotation_path)
+        .with_context(|| format!("removing {}", rotation_path.display()))?;
+    Ok(archive.join(format!("{}.private", rotation.from)))
+}
+
+/// Every selector with key files in `dkim/` or `dkim/archive/`.
+pub fn status(layout: &MailLayout, env: &EnvConfig, now: OffsetDateTime) -> Result<DkimStatus> {
+    let dir = layout.dkim_dir();
+    let rotation = Rotation::load(&dir)?;
+    let signing = dkim::signing_selector(&dir, DkimKeyType::Ed25519, &env.dkim_selector, now)?;
+    let rsa_signing = env
+        .dkim_rsa_selector
+        .as_deref()
+        .map(|configured| dkim::signing_selector(&dir, DkimKeyType::Rsa, configured, now))
+        .transpose()?;
+    let mut selectors = Vec::new();
+    for selector in selector_names(&dir)? {
+        let state = if selector == signing || rsa_signing.as_deref() == Some(&selector) {
+            SelectorState::Active
+        } else if rotation.as_ref().is_some_and(|r| r.to == selector) {
+            SelectorState::Pending
+        } else if rotation.as_ref().is_some_and(|r| r.from == selector) {
+            SelectorState::Retiring
+        } else {
+            SelectorState::Unused
+        };
+        selectors.push(selector_status(&dir, selector, state, now)?);
+    }
+    let archive = archive_dir(layout);
+    for selector in selector_names(&archive)? {
+        selectors.push(selector_status(
+            &archive,
+            selector,
+            SelectorState::Archived,
+            now,
+        )?);
+    }
+    Ok(DkimStatus {
+        selectors,
+        rotation,
+    })
+}
+
+/// `<base>-YYYYMMDD`, replacing a date suffix left by an earlier rotation.
+pub fn default_selector(current: &str, now: OffsetDateTime) -> String {
+    let base = match current.rsplit_once('-') {
+        Some((base, suffix))
+            if !base.is_empty()
+                && suffix.len() == 8
+                && suffix.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => current,
+    };
+    format!(
+        "{base}-{:04}{:02}{:02}",
+        now.year(),
+        u8::from(now.month()),
+        now.day()
+    )
+}
+
+/// A TXT value split into the quoted 255-byte strings DNS requires; RSA
+/// records are longer than one string.
+pub fn txt_strings(record: &str) -> String {
+    record
+        .as_bytes()
+        .chunks(255)
+        .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn archive_dir(layout: &MailLayout) -> PathBuf {
+    layout.dkim_dir().join("archive")
+}
+
+fn validate_selector(selector: &str) -> Result<()> {
+    let valid = !selector.is_empty()
+        && selector.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    if !valid {
+        bail!("invalid DKIM selector {selector:?}");
+    }
+    Ok(())
+}
+
+fn selector_names(dir: &Path) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    if !dir.exists() {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        let is_key = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "private" | "dns"));
+        if is_key && let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+            names.insert(stem.to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn selector_status(
+    dir: &Path,
+    selector: String,
+    state: SelectorState,
+    now: OffsetDateTime,
+) -> Result<SelectorStatus> {
+    let private = dir.join(format!("{selector}.private"));
+    let dns = dir.join(format!("{selector}.dns"));
+    let record = match fs::read_to_string(&dns) {
+        Ok(data) => Some(data.trim().to_string()),
+        Err(_) => None,
+    };
+    let key_type = record
+        .as_deref()
+        .and_then(|record| {
+            record
+                .split(';')
+                .filter_map(|tag| tag.split_once('='))
+                .find(|(name, _)| name.trim() == "k")
+                .map(|(_, value)| value.trim().to_string())
+        })
+        .unwrap_or_else(|| "rsa".into());
+    let created = fs::metadata(if private.exists() { &private } else { &dns })
+        .and_then(|meta| meta.modified())
+        .ok()
+        .map(OffsetDateTime::from);
+    Ok(SelectorStatus {
+        record_name: format!("{selector}._domainkey"),
+        selector,
+        key_type,
+        state,
+        created_at: created.map(|at| at.format(&Rfc3339)).transpose()?,
+        age_days: created.map(|at| (now - at).whole_days()),
+        record,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn setup() -> (tempfile::TempDir, MailLayout, PathBuf, EnvConfig) {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let env_path = dir.path().join(".env");
+        fs::write(&env_path, "# owl\ndkim_selector=mail\nlogging=off\n").unwrap();
+        let env = EnvConfig {
+            dkim_rsa_selector: None,
+            ..EnvConfig::default()
+        };
+        dkim::ensure_ed25519_keypair(&layout.dkim_dir(), "mail").unwrap();
+        (dir, layout, env_path, env)
+    }
+
+    #[test]
+    fn default_selector_replaces_date_suffix() {
+        let now = datetime!(2026-10-17 12:00 UTC);
+        assert_eq!(default_selector("mail", now), "mail-20261017");
+        assert_eq!(default_selector("mail-20250101", now), "mail-20261017");
+        assert_eq!(default_selector("owl-mail", now), "owl-mail-20261017");
+    }
+
+    #[test]
+    fn rotation_switches_signing_after_overlap_and_archives_old_key() {
+        let (_dir, layout, env_path, env) = setup();
+        let start = datetime!(2026-10-17 12:00 UTC);
+        let (rotation, material) = begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Ed25519,
+            Some("next"),
+            Duration::days(7),
+            start,
+        )
+        .unwrap();
+        assert_eq!(rotation.from, "mail");
+        assert_eq!(rotation.switch_at, "2026-10-24T12:00:00Z");
+        assert!(material.dns_record_path.exists());
+
+        let dir = layout.dkim_dir();
+        let err = begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Ed25519,
+            None,
+            Duration::days(7),
+            start,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("pending until"));
+        assert_eq!(
+            dkim::signing_selector(&dir, DkimKeyType::Ed25519, "mail", start).unwrap(),
+            "mail"
+        );
+        let before = finish_due_rotation(&layout, &env_path, start).unwrap();
+        assert!(before.is_none());
+
+        let later = start + Duration::days(8);
+        assert_eq!(
+            dkim::signing_selector(&dir, DkimKeyType::Ed25519, "mail", later).unwrap(),
+            "next"
+        );
+        let (_, archived) = finish_due_rotation(&layout, &env_path, later)
+            .unwrap()
+            .unwrap();
+        assert!(archived.exists());
+        assert!(!layout.dkim_private_key("mail").exists());
+        assert!(Rotation::load(&dir).unwrap().is_none());
+        let env_text = fs::read_to_string(&env_path).unwrap();
+        assert!(env_text.starts_with("# owl\ndkim_selector=next\n"));
+
+        let env = EnvConfig::from_file(&env_path).unwrap();
+        let err = begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Ed25519,
+            Some("mail"),
+            Duration::days(7),
+            later,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("already has a key"));
+    }
+
+    #[test]
+    fn status_reports_states_and_records() {
+        let (_dir, layout, _env_path, env) = setup();
+        let now = OffsetDateTime::now_utc();
+        begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Ed25519,
+            Some("next"),
+            Duration::days(7),
+            now,
+        )
+        .unwrap();
+        let status = status(&layout, &env, now).unwrap();
+        let states: Vec<_> = status
+            .selectors
+            .iter()
+            .map(|s| (s.selector.as_str(), s.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("mail", SelectorState::Active),
+                ("next", SelectorState::Pending)
+            ]
+        );
+        let next = &status.selectors[1];
+        assert_eq!(next.key_type, "ed25519");
+        assert_eq!(next.record_name, "next._domainkey");
+        assert_eq!(next.age_days, Some(0));
+        assert!(
+            next.record
+                .as_deref()
+                .unwrap()
+                .starts_with("v=DKIM1; k=ed25519; p=")
+        );
+        assert_eq!(status.rotation.unwrap().to, "next");
+
+        let due = status_after(&layout, &env, now + Duration::days(7));
+        assert_eq!(due[0].1, SelectorState::Retiring);
+        assert_eq!(due[1].1, SelectorState::Active);
+    }
+
+    #[test]
+    fn rsa_rotation_replaces_only_the_rsa_selector() {
+        let (_dir, layout, env_path, env) = setup();
+        let start = datetime!(2026-10-17 12:00 UTC);
+        let err = begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Rsa,
+            None,
+            Duration::days(7),
+            start,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("dkim_rsa_selector is not set"));
+
+        fs::write(
+            &env_path,
+            "dkim_selector=mail\ndkim_rsa_selector=mail-rsa\nlogging=off\n",
+        )
+        .unwrap();
+        let env = EnvConfig::from_file(&env_path).unwrap();
+        let dir = layout.dkim_dir();
+        dkim::ensure_rsa_keypair(&dir, "mail-rsa").unwrap();
+        let (rotation, material) = begin_rotation(
+            &layout,
+            &env,
+            DkimKeyType::Rsa,
+            Some("rsa-next"),
+            Duration::days(7),
+            start,
+        )
+        .unwrap();
+        assert_eq!(rotation.key_type, DkimKeyType::Rsa);
+        assert_eq!(rotation.from, "mail-rsa");
+        assert_eq!(material.key_type, DkimKeyType::Rsa);
+
+        let later = start + Duration::days(8);
+        let rsa = |now| dkim::signing_selector(&dir, DkimKeyType::Rsa, "mail-rsa", now).unwrap();
+        assert_eq!(rsa(start), "mail-rsa");
+        assert_eq!(rsa(later), "rsa-next");
+        assert_eq!(
+            dkim::signing_selector(&dir, DkimKeyType::Ed25519, "mail", later).unwrap(),
+            "mail"
+        );
+
+        let states = status_after(&layout, &env, start);
+        assert_eq!(
+            states,
+            vec![
+                ("mail".to_string(), SelectorState::Active),
+                ("mail-rsa".to_string(), SelectorState::Active),
+                ("rsa-next".to_string(), SelectorState::Pending),
+            ]
+        );
+        let states = status_after(&layout, &env, later);
+        assert_eq!(states[1].1, SelectorState::Retiring);
+        assert_eq!(states[2].1, SelectorState::Active);
+        let next = &status(&layout, &env, later).unwrap().selectors[2];
+        assert_eq!(next.key_type, "rsa");
+
+        finish_due_rotation(&layout, &env_path, later)
+            .unwrap()
+            .unwrap();
+        let env = EnvConfig::from_file(&env_path).unwrap();
+        assert_eq!(env.dkim_selector, "mail");
+        assert_eq!(env.dkim_rsa_selector.as_deref(), Some("rsa-next"));
+        assert!(layout.dkim_private_key("mail").exists());
+        assert!(!layout.dkim_private_key("mail-rsa").exists());
+        assert!(archive_dir(&layout).join("mail-rsa.private").exists());
+    }
+
+    #[test]
+    fn rejects_invalid_or_reused_selectors() {
+        let (_dir, layout, _env_path, env) = setup();
+        let now = OffsetDateTime::now_utc();
+        for selector in ["", "bad selector", "-x", "mail"] {
+            assert!(
+                begin_rotation(
+                    &layout,
+                    &env,
+                    DkimKeyType::Ed25519,
+                    Some(selector),
+                    Duration::days(1),
+                    now
+                )
+                .is_err()
+            );
+        }
+        assert!(Rotation::load(&layout.dkim_dir()).unwrap().is_none());
+    }
+
+    #[test]
+    fn txt_strings_splits_long_records() {
+        let record = "a".repeat(300);
+        let split = txt_strings(&record);
+        assert_eq!(
+            split,
+            format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45))
+        );
+        assert_eq!(txt_strings("v=DKIM1"), "\"v=DKIM1\"");
+    }
+
+    fn status_after(
+        layout: &MailLayout,
+        env: &EnvConfig,
+        now: OffsetDateTime,
+    ) -> Vec<(String, SelectorState)> {
+        status(layout, env, now)
+            .unwrap()
+            .selectors
+            .into_iter()
+            .map(|s| (s.selector, s.state))
+            .collect()
+    }
+}
@@ -0,0 +1,998 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Verifier};
+use ring::signature::{ED25519, UnparsedPublicKey};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    model::message::{AuthResults, AuthVerdict, DkimCheck, DmarcCheck, SpfCheck},
+    util::{
+        dkim::{
+            Canonicalization, DkimKeyType, canonical_signed_headers, canonicalize_body,
+            canonicalize_header, header_fields,
+        },
+        dns::{DnsResolver, SystemResolver, normalize},
+    },
+};
+
+/// Signatures beyond this many are ignored, so a message cannot make us do
+/// unbounded key lookups.
+const MAX_DKIM_SIGNATURES: usize = 5;
+/// RFC 7208 §4.6.4: DNS-querying terms allowed per SPF evaluation.
+const SPF_LOOKUP_LIMIT: usize = 10;
+/// RFC 8301: shorter RSA keys must not be considered valid.
+const MIN_RSA_BITS: u32 = 1024;
+/// Second-level labels treated as registries under two-letter TLDs when
+/// approximating the organizational domain (`example.co.uk`).
+const REGISTRY_LABELS: &[&str] = &[
+    "ac", "co", "com", "edu", "go", "gov", "ne", "net", "or", "org",
+];
+
+/// What the receiving MTA knew about where a message came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageOrigin {
+    pub client_ip: Option<IpAddr>,
+    pub helo: Option<String>,
+    /// Envelope sender (`MAIL FROM`); empty for bounces.
+    pub mail_from: String,
+    /// Envelope recipients (`RCPT TO`) as given, `+tag`s included.
+    pub recipients: Vec<String>,
+}
+
+/// A temporary or permanent error, with the reason kept for the sidecar.
+type Failure = (AuthVerdict, String);
+
+fn perm(reason: impl Into<String>) -> Failure {
+    (AuthVerdict::PermError, reason.into())
+}
+
+fn temp(reason: impl Into<String>) -> Failure {
+    (AuthVerdict::TempError, reason.into())
+}
+
+/// Checks DKIM signatures (RFC 6376), SPF (RFC 7208) and DMARC alignment
+/// (RFC 7489) for inbound mail.
+pub struct Authenticator {
+    resolver: Arc<dyn DnsResolver>,
+}
+
+impl Authenticator {
+    pub fn new(resolver: Arc<dyn DnsResolver>) -> Self {
+        Self { resolver }
+    }
+
+    pub fn system() -> Self {
+        Self::new(Arc::new(SystemResolver::new()))
+    }
+
+    /// Verify `message` as received from `origin`. `from_domain` is the
+    /// domain of the From header, which DMARC aligns against. SPF is `none`
+    /// when the origin has no client address.
+    pub fn authenticate(
+        &self,
+        message: &[u8],
+        origin: &MessageOrigin,
+        from_domain: &str,
+    ) -> AuthResults {
+        let message = to_crlf(message);
+        let (headers, body) = split_message(&message);
+        let headers = String::from_utf8_lossy(headers);
+        let dkim = self.verify_dkim(&headers, body);
+        let spf = self.check_spf(origin);
+        let dmarc = self.evaluate_dmarc(from_domain, &dkim, &spf);
+        AuthResults {
+            dkim,
+            spf,
+            dmarc,
+            demoted: None,
+        }
+    }
+
+    fn verify_dkim(&self, headers: &str, body: &[u8]) -> Vec<DkimCheck> {
+        header_fields(headers, "dkim-signature")
+            .into_iter()
+            .take(MAX_DKIM_SIGNATURES)
+            .map(|field| {
+                let tags = parse_tags(field.split_once(':').map_or("", |(_, value)| value));
+                let mut check = DkimCheck {
+                    result: AuthVerdict::Pass,
+                    domain: tags.get("d").map(|d| normalize(d)).unwrap_or_default(),
+                    selector: tags.get("s").cloned().unwrap_or_default(),
+                    reason: None,
+                };
+                if let Err((verdict, reason)) = self.verify_signature(headers, body, &field, &tags)
+                {
+                    check.result = verdict;
+                    check.reason = Some(reason);
+                }
+                check
+            })
+            .collect()
+    }
+
+    fn verify_signature(
+        &self,
+        headers: &str,
+        body: &[u8],
+        field: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), Failure> {
+        for required in ["v", "a", "b", "bh", "d", "h", "s"] {
+            if !tags.contains_key(required) {
+                return Err(perm(format!("missing {required}= tag")));
+            }
+        }
+        if tags["v"] != "1" {
+            return Err(perm(format!("unsupported version {}", tags["v"])));
+        }
+        let header_list: Vec<&str> = tags["h"].split(':').collect();
+        if !header_list
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("from"))
+        {
+            return Err(perm("From header not signed"));
+        }
+        let (header_canon, body_canon) =
+            Canonicalization::parse_pair(tags.get("c").map_or("simple/simple", String::as_str))
+                .map_err(|err| perm(err.to_string()))?;
+        let key_type = match tags["a"].to_ascii_lowercase().as_str() {
+            "rsa-sha256" => DkimKeyType::Rsa,
+            "ed25519-sha256" => DkimKeyType::Ed25519,
+            other => return Err(perm(format!("unsupported algorithm {other}"))),
+        };
+        if let Some(expiry) = tags.get("x")
+            && expiry
+                .parse::<i64>()
+                .is_ok_and(|x| x < OffsetDateTime::now_utc().unix_timestamp())
+        {
+            return Err((AuthVerdict::Fail, "signature expired".into()));
+        }
+
+        let key_name = format!("{}._domainkey.{}", tags["s"], tags["d"]);
+        let records = self
+            .resolver
+            .txt(&key_name)
+            .map_err(|err| temp(err.to_string()))?;
+        let Some(record) = records.first() else {
+            return Err(perm(format!("no key published at {key_name}")));
+        };
+        let key_tags = parse_tags(record);
+        let Some(encoded_key) = key_tags.get("p") else {
+            return Err(perm("key record has no p= tag"));
+        };
+        if encoded_key.is_empty() {
+            return Err((AuthVerdict::Fail, "key revoked".into()));
+        }
+        let published_type = key_tags.get("k").map_or("rsa", String::as_str);
+        if !published_type.eq_ignore_ascii_case(key_type.dns_key_type()) {
+            return Err(perm(format!("key type {published_type} does not match a=")));
+        }
+        let public_key = STANDARD
+            .decode(encoded_key)
+            .map_err(|_| perm("invalid key encoding"))?;
+
+        let mut canonical_body = canonicalize_body(body, body_canon);
+        if let Some(length) = tags.get("l") {
+            let length: usize = length.parse().map_err(|_| perm("invalid l= tag"))?;
+            if length > canonical_body.len() {
+                return Err(perm("l= exceeds body length"));
+            }
+            canonical_body.truncate(length);
+        }
+        if STANDARD.encode(Sha256::digest(&canonical_body)) != tags["bh"] {
+            return Err((AuthVerdict::Fail, "body hash mismatch".into()));
+        }
+
+        let mut signed = canonical_signed_headers(headers, &header_list, header_canon);
+        let signature_header = canonicalize_header(&without_signature(field), header_canon);
+        signed.extend_from_slice(signature_header.trim_end_matches("\r\n").as_bytes());
+        let signature = STANDARD
+            .decode(&tags["b"])
+            .map_err(|_| perm("invalid signature encoding"))?;
+        let verified = match key_type {
+            // RFC 8463: Ed25519 signs the SHA-256 digest of the data.
+            DkimKeyType::Ed25519 => UnparsedPublicKey::new(&ED25519, &public_key)
+                .verify(&Sha256::digest(&signed), &signature)
+                .is_ok(),
+            DkimKeyType::Rsa => verify_rsa(&public_key, &signed, &signature)?,
+        };
+        if !verified {
+            return Err((AuthVerdict::Fail, "signature did not verify".into()));
+        }
+        Ok(())
+    }
+
+    fn check_spf(&self, origin: &MessageOrigin) -> SpfCheck {
+        let helo = origin.helo.as_deref().map(normalize).unwrap_or_default();
+        let mail_from = origin
+            .mail_from
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        // Bounces are checked against the HELO identity (RFC 7208 §2.4).
+        let (sender, domain) = match mail_from.rsplit_once('@') {
+            Some((_, domain)) => (mail_from.to_string(), normalize(domain)),
+            None => (format!("postmaster@{helo}"), helo.clone()),
+        };
+        let mut check = SpfCheck {
+            result: AuthVerdict::None,
+            domain: domain.clone(),
+            client_ip: origin.client_ip.map(|ip| ip.to_string()),
+            reason: None,
+        };
+        let Some(ip) = origin.client_ip else {
+            check.reason = Some("client address unknown".into());
+            return check;
+        };
+        if domain.is_empty() {
+            check.reason = Some("no sender domain".into());
+            return check;
+        }
+        let mut session = SpfSession {
+            resolver: self.resolver.as_ref(),
+            ip,
+            sender,
+            helo,
+            lookups: 0,
+        };
+        match session.check_host(&domain) {
+            Ok(result) => check.result = result,
+            Err((verdict, reason)) => {
+                check.result = verdict;
+                check.reason = Some(reason);
+            }
+        }
+        check
+    }
+
+    /// Alignment is reported even when the From domain publishes no DMARC
+    /// record (relaxed mode); `result` stays `none` in that case.
+    fn evaluate_dmarc(&self, from_domain: &str, dkim: &[DkimCheck], spf: &SpfCheck) -> DmarcCheck {
+        let domain = normalize(from_domain);
+        let organizational = organizational_domain(&domain);
+        let found = match self.dmarc_record(&domain) {
+            Ok(None) if organizational != domain => self
+                .dmarc_record(&organizational)
+                .map(|record| record.map(|record| (record, true))),
+            other => other.map(|record| record.map(|record| (record, false))),
+        };
+        let tags = match &found {
+            Ok(Some((record, _))) => parse_tags(record),
+            _ => HashMap::new(),
+        };
+        let strict_dkim = tags.get("adkim").is_some_and(|mode| mode == "s");
+        let strict_spf = tags.get("aspf").is_some_and(|mode| mode == "s");
+        let mut check = DmarcCheck {
+            dkim_aligned: dkim.iter().any(|signature| {
+                signature.result == AuthVerdict::Pass
+                    && aligned(&signature.domain, &domain, strict_dkim)
+            }),
+            spf_aligned: spf.result == AuthVerdict::Pass
+                && aligned(&spf.domain, &domain, strict_spf),
+            domain,
+            ..DmarcCheck::default()
+        };
+        match found {
+            Ok(Some((_, inherited))) => {
+                let policy = if inherited {
+                    tags.get("sp").or_else(|| tags.get("p"))
+                } else {
+                    tags.get("p")
+                };
+                check.policy = Some(policy.cloned().unwrap_or_else(|| "none".into()));
+                check.result = if check.dkim_aligned || check.spf_aligned {
+                    AuthVerdict::Pass
+                } else {
+                    AuthVerdict::Fail
+                };
+            }
+            Ok(None) => {}
+            Err(_) => check.result = AuthVerdict::TempError,
+        }
+        check
+    }
+
+    /// The single `v=DMARC1` record at `_dmarc.<domain>`; several records
+    /// count as none (RFC 7489 §6.6.3).
+    fn dmarc_record(&self, domain: &str) -> anyhow::Result<Option<String>> {
+        let records = self.resolver.txt(&format!("_dmarc.{domain}"))?;
+        let mut dmarc = records.into_iter().filter(|record| {
+            record
+                .trim_start()
+                .strip_prefix("v=DMARC1")
+                .is_some_and(|rest| {
+                    rest.trim_start().is_empty() || rest.trim_start().starts_with(';')
+                })
+        });
+        match (dmarc.next(), dmarc.next()) {
+            (Some(record), None) => Ok(Some(record)),
+            _ => Ok(None),
+        }
+    }
+}
+
+struct SpfSession<'a> {
+    resolver: &'a dyn DnsResolver,
+    ip: IpAddr,
+    sender: String,
+    helo: String,
+    lookups: usize,
+}
+
+impl SpfSession<'_> {
+    fn check_host(&mut self, domain: &str) -> Result<AuthVerdict, Failure> {
+        let records = self
+            .resolver
+            .txt(domain)
+            .map_err(|err| temp(err.to_string()))?;
+        let spf: Vec<&String> = records
+            .iter()
+            .filter(|record| {
+                let lower = record.to_ascii_lowercase();
+                lower == "v=spf1" || lower.starts_with("v=spf1 ")
+            })
+            .collect();
+        let record = match spf.as_slice() {
+            [] => return Ok(AuthVerdict::None),
+            [record] => record,
+            _ => return Err(perm(format!("multiple SPF records for {domain}"))),
+        };
+
+        let mut redirect = None;
+        for term in record.split_whitespace().skip(1) {
+            if let Some((name, value)) = term.split_once('=')
+                && !name.is_empty()
+                && name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+            {
+                if name.eq_ignore_ascii_case("redirect") {
+                    redirect = Some(value.to_string());
+                }
+                continue;
+            }
+            let (verdict, mechanism) = match term.as_bytes()[0] {
+                b'+' => (AuthVerdict::Pass, &term[1..]),
+                b'-' => (AuthVerdict::Fail, &term[1..]),
+                b'~' => (AuthVerdict::SoftFail, &term[1..]),
+                b'?' => (AuthVerdict::Neutral, &term[1..]),
+                _ => (AuthVerdict::Pass, term),
+            };
+            if self.matches(mechanism, domain)? {
+                return Ok(verdict);
+            }
+        }
+        if let Some(target) = redirect {
+            self.count_lookup()?;
+            let target = self.expand(&target, domain)?;
+            return match self.check_host(&target)? {
+                AuthVerdict::None => {
+                    Err(perm(format!("redirect target {target} has no SPF record")))
+                }
+                verdict => Ok(verdict),
+            };
+        }
+        Ok(AuthVerdict::Neutral)
+    }
+
+    fn matches(&mut self, mechanism: &str, domain: &str) -> Result<bool, Failure> {
+        let split = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, rest) = mechanism.split_at(split);
+        let argument = rest.strip_prefix(':');
+        match name.to_ascii_lowercase().as_str() {
+            "all" => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = self.expand(
+                    argument.ok_or_else(|| perm("include needs a domain"))?,
+                    domain,
+                )?;
+                match self.check_host(&target)? {
+                    AuthVerdict::Pass => Ok(true),
+                    AuthVerdict::None => {
+                        Err(perm(format!("include target {target} has no SPF record")))
+                    }
+                    _ => Ok(false),
+                }
+            }
+            "a" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = self.target_and_cidr(argument.unwrap_or(rest), domain)?;
+                let addresses = self
+                    .resolver
+                    .addresses(&target)
+                    .map_err(|err| temp(err.to_string()))?;
+                Ok(addresses
+                    .iter()
+                    .any(|addr| in_network(self.ip, *addr, v4, v6)))
+            }
+            "mx" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = self.target_and_cidr(argument.unwrap_or(rest), domain)?;
+                let exchangers = self
+                    .resolver
+                    .mx(&target)
+                    .map_err(|err| temp(err.to_string()))?;
+                for mx in exchangers.iter().take(SPF_LOOKUP_LIMIT) {
+                    let addresses = self
+                        .resolver
+                        .addresses(&mx.exchange)
+                        .map_err(|err| temp(err.to_string()))?;
+                    if addresses
+                        .iter()
+                        .any(|addr| in_network(self.ip, *addr, v4, v6))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "ip4" | "ip6" => {
+                let argument = argument.ok_or_else(|| perm(format!("{name} needs a network")))?;
+                let (network, prefix) = argument.split_once('/').unwrap_or((argument, ""));
+                let network: IpAddr = network
+                    .parse()
+                    .map_err(|_| perm(format!("invalid network {argument}")))?;
+                let max = if network.is_ipv4() { 32 } else { 128 };
+                let prefix = if prefix.is_empty() {
+                    max
+                } else {
+                    prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|len| *len <= max)
+                        .ok_or_else(|| perm(format!("invalid prefix in {argument}")))?
+                };
+                Ok(in_network(self.ip, network, prefix, prefix))
+            }
+            "exists" => {
+                self.count_lookup()?;
+                let target = self.expand(
+                    argument.ok_or_else(|| perm("exists needs a domain"))?,
+                    domain,
+                )?;
+                let addresses = self
+                    .resolver
+                    .addresses(&target)
+                    .map_err(|err| temp(err.to_string()))?;
+                Ok(addresses.iter().any(IpAddr::is_ipv4))
+            }
+            // Deprecated (RFC 7208 §5.5); counted but never matched.
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            other => Err(perm(format!("unknown mechanism {other}"))),
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Failure> {
+        self.lookups += 1;
+        if self.lookups > SPF_LOOKUP_LIMIT {
+            return Err(perm("too many DNS lookups"));
+        }
+        Ok(())
+    }
+
+    /// Split `[domain][/v4-cidr][//v6-cidr]`, defaulting to the current domain.
+    fn target_and_cidr(&self, spec: &str, domain: &str) -> Result<(String, u8, u8), Failure> {
+        let (target, cidr) = spec.split_at(spec.find('/').unwrap_or(spec.len()));
+        let (v4, v6) = match cidr.split_once("//") {
+            Some((v4, v6)) => (v4, Some(v6)),
+            None => (cidr, None),
+        };
+        let parse = |value: &str, max: u8| {
+            value
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| perm(format!("invalid prefix in {spec}")))
+        };
+        let v4 = match v4.strip_prefix('/') {
+            Some(len) => parse(len, 32)?,
+            None => 32,
+        };
+        let v6 = match v6 {
+            Some(len) => parse(len, 128)?,
+            None => 128,
+        };
+        let target = if target.is_empty() {
+            domain.to_string()
+        } else {
+            self.expand(target, domain)?
+        };
+        Ok((target, v4, v6))
+    }
+
+    /// Expand RFC 7208 §7 macros such as `%{ir}.%{v}._spf.%{d2}`.
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Failure> {
+        let mut out = String::new();
+        let mut chars = spec.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                out.push(ch);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| perm("unterminated macro"))?;
+                    out.push_str(&self.macro_value(&rest[..end], domain)?);
+                    chars = rest[end + 1..].chars();
+                }
+                _ => return Err(perm(format!("invalid macro in {spec}"))),
+            }
+        }
+        Ok(out)
+    }
+
+    fn macro_value(&self, spec: &str, domain: &str) -> Result<String, Failure> {
+        let mut chars = spec.chars();
+        let letter = chars.next().ok_or_else(|| perm("empty macro"))?;
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.clone(),
+            'l' => self
+                .sender
+                .rsplit_once('@')
+                .map_or("postmaster", |(local, _)| local)
+                .to_string(),
+            'o' => self
+                .sender
+                .rsplit_once('@')
+                .map_or(domain, |(_, domain)| domain)
+                .to_string(),
+            'd' => domain.to_string(),
+            'i' => dotted_ip(self.ip),
+            'v' => if self.ip.is_ipv4() { "in-addr" } else { "ip6" }.to_string(),
+            'h' => self.helo.clone(),
+            'p' => "unknown".to_string(),
+            other => return Err(perm(format!("unknown macro letter {other}"))),
+        };
+        let rest = chars.as_str();
+        let digits = rest.len()
+            - rest
+                .trim_start_matches(|ch: char| ch.is_ascii_digit())
+                .len();
+        let (count, rest) = rest.split_at(digits);
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|ch| ".-+,/_=".contains(ch)) {
+            return Err(perm(format!("invalid macro transformer in %{{{spec}}}")));
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+        let mut parts: Vec<&str> = value.split(|ch| delimiters.contains(ch)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if !count.is_empty() {
+            let keep: usize = count
+                .parse()
+                .ok()
+                .filter(|keep| *keep > 0)
+                .ok_or_else(|| perm(format!("invalid macro transformer in %{{{spec}}}")))?;
+            parts = parts.split_off(parts.len().saturating_sub(keep));
+        }
+        Ok(parts.join("."))
+    }
+}
+
+fn verify_rsa(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, Failure> {
+    // DKIM publishes SubjectPublicKeyInfo; some signers use bare PKCS#1.
+    let key = PKey::public_key_from_der(public_key)
+        .or_else(|_| Rsa::public_key_from_der_pkcs1(public_key).and_then(PKey::from_rsa))
+        .map_err(|_| perm("invalid public key"))?;
+    if key.bits() < MIN_RSA_BITS {
+        return Err(perm(format!("RSA key too short ({} bits)", key.bits())));
+    }
+    let mut verifier =
+        Verifier::new(MessageDigest::sha256(), &key).map_err(|err| perm(err.to_string()))?;
+    verifier.update(data).map_err(|err| perm(err.to_string()))?;
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+/// Parse a DKIM/DMARC tag list, dropping folding whitespace from values.
+fn parse_tags(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|part| {
+            let (tag, value) = part.split_once('=')?;
+            let value: String = value.chars().filter(|ch| !ch.is_whitespace()).collect();
+            Some((tag.trim().to_string(), value))
+        })
+        .collect()
+}
+
+/// The signature header with its `b=` value emptied, as it was when signed.
+fn without_signature(field: &str) -> String {
+    let Some((name, value)) = field.split_once(':') else {
+        return field.to_string();
+    };
+    let parts: Vec<&str> = value
+        .split(';')
+        .map(|part| match part.split_once('=') {
+            Some((tag, _)) if tag.trim() == "b" => &part[..=tag.len()],
+            _ => part,
+        })
+        .collect();
+    format!("{name}:{}", parts.join(";"))
+}
+
+/// Whether `domain` aligns with the From domain, exactly in strict mode or
+/// by organizational domain in relaxed mode.
+fn aligned(domain: &str, from_domain: &str, strict: bool) -> bool {
+    let domain = normalize(domain);
+    if strict {
+        domain == from_domain
+    } else {
+        organizational_domain(&domain) == organizational_domain(from_domain)
+    }
+}
+
+/// Approximates the Public Suffix List: the last two labels, or three under
+/// common second-level registries such as `co.uk`. Private suffixes like
+/// `github.io` are not known, so their tenants share one organization.
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = normalize(domain);
+    let labels: Vec<&str> = domain.split('.').collect();
+    let count = labels.len();
+    let keep = if count >= 3
+        && labels[count - 1].len() == 2
+        && REGISTRY_LABELS.contains(&labels[count - 2])
+    {
+        3
+    } else {
+        2
+    };
+    labels[count.saturating_sub(keep)..].join(".")
+}
+
+/// The connecting client recorded by our MTA in the topmost `Received`
+/// header, e.g. `from mx.example.org (mx.example.org [192.0.2.1])`. Only
+/// trustworthy when our own MTA is known to have written that header.
+pub fn received_client_ip(message: &[u8]) -> Option<IpAddr> {
+    let message = to_crlf(message);
+    let headers = String::from_utf8_lossy(split_message(&message).0);
+    let received = header_fields(&headers, "received").into_iter().next()?;
+    let from_clause = received.split(" by ").next()?;
+    let start = from_clause.find('[')?;
+    let end = start + from_clause[start..].find(']')?;
+    let literal = &from_clause[start + 1..end];
+    let literal = literal
+        .strip_prefix("IPv6:")
+        .or_else(|| literal.strip_prefix("ipv6:"))
+        .unwrap_or(literal);
+    literal.parse().ok()
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, v4_prefix: u8, v6_prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(v4_prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(v6_prefix))
+                .unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// `%{i}`: dotted quad, or dot-separated nibbles for IPv6.
+fn dotted_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let hex = format!("{:032x}", u128::from(ip));
+            hex.chars().map(String::from).collect::<Vec<_>>().join(".")
+        }
+    }
+}
+
+fn to_crlf(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len());
+    let mut previous = 0u8;
+    for &byte in message {
+        if byte == b'\n' && previous != b'\r' {
+            out.push(b'\r');
+        }
+        out.push(byte);
+        previous = byte;
+    }
+    out
+}
+
+/// Header section (ending in CRLF) and body of a CRLF message.
+fn split_message(message: &[u8]) -> (&[u8], &[u8]) {
+    match message.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(index) => (&message[..index + 2], &message[index + 4..]),
+        None => (message, &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{
+        dkim::{DkimSigner, ensure_keypairs},
+        dns::StaticResolver,
+    };
+
+    const HEADERS: &str =
+        "From: Alice <alice@example.org>\r\nTo: me@owl.test\r\nSubject: Hello\r\n";
+    const BODY: &[u8] = b"Hi there,\r\nsee you.\r\n";
+
+    /// A message signed with Ed25519 and RSA keys for example.org, and a
+    /// resolver publishing both keys.
+    fn signed_message(dir: &std::path::Path) -> (Vec<u8>, StaticResolver) {
+        let materials = ensure_keypairs(dir, "mail", Some("mail-rsa")).unwrap();
+        let signer = DkimSigner::from_materials(&materials).unwrap();
+        let mut resolver = StaticResolver::new();
+        for material in &materials {
+            let record = std::fs::read_to_string(&material.dns_record_path).unwrap();
+            resolver = resolver.with_txt(
+                &format!("{}._domainkey.example.org", material.selector),
+                record.trim(),
+            );
+        }
+        let mut message = Vec::new();
+        for value in signer.sign("example.org", HEADERS, BODY).unwrap() {
+            message.extend_from_slice(format!("DKIM-Signature: {value}\r\n").as_bytes());
+        }
+        message.extend_from_slice(HEADERS.as_bytes());
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(BODY);
+        (message, resolver)
+    }
+
+    fn origin(ip: &str, mail_from: &str) -> MessageOrigin {
+        MessageOrigin {
+            client_ip: Some(ip.parse().unwrap()),
+            helo: Some("mx.example.org".into()),
+            mail_from: mail_from.into(),
+            ..MessageOrigin::default()
+        }
+    }
+
+    #[test]
+    fn verifies_dkim_signatures_and_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let (message, resolver) = signed_message(dir.path());
+        let auth = Authenticator::new(Arc::new(
+            resolver
+                .clone()
+                .with_txt("_dmarc.example.org", "v=DMARC1; p=reject"),
+        ));
+        let results = auth.authenticate(&message, &origin("192.0.2.1", ""), "example.org");
+        assert_eq!(results.dkim.len(), 2);
+        assert!(
+            results
+                .dkim
+                .iter()
+                .all(|check| check.result == AuthVerdict::Pass)
+        );
+        assert_eq!(results.dkim[1].selector, "mail-rsa");
+        assert_eq!(results.dmarc.result, AuthVerdict::Pass);
+        assert!(results.dmarc.dkim_aligned);
+        assert_eq!(results.dmarc.policy.as_deref(), Some("reject"));
+
+        let tampered = String::from_utf8(message.clone())
+            .unwrap()
+            .replace("see you.", "send money.");
+        let results = auth.authenticate(
+            tampered.as_bytes(),
+            &MessageOrigin::default(),
+            "example.org",
+        );
+        assert!(
+            results
+                .dkim
+                .iter()
+                .all(|check| check.result == AuthVerdict::Fail)
+        );
+        assert_eq!(
+            results.dkim[0].reason.as_deref(),
+            Some("body hash mismatch")
+        );
+        assert_eq!(results.dmarc.result, AuthVerdict::Fail);
+
+        // Bare-LF storage and header-only tweaks are handled too.
+        let lf = String::from_utf8(message.clone())
+            .unwrap()
+            .replace("\r\n", "\n");
+        let results = auth.authenticate(lf.as_bytes(), &MessageOrigin::default(), "example.org");
+        assert!(
+            results
+                .dkim
+                .iter()
+                .all(|check| check.result == AuthVerdict::Pass)
+        );
+        let spoofed = String::from_utf8(message)
+            .unwrap()
+            .replace("Subject: Hello", "Subject: Urgent");
+        let results =
+            auth.authenticate(spoofed.as_bytes(), &MessageOrigin::default(), "example.org");
+        assert_eq!(
+            results.dkim[0].reason.as_deref(),
+            Some("signature did not verify")
+        );
+    }
+
+    #[test]
+    fn dkim_key_problems_are_classified() {
+        let dir = tempfile::tempdir().unwrap();
+        let (message, _) = signed_message(dir.path());
+        let missing = Authenticator::new(Arc::new(
+            StaticResolver::new().fail("mail-rsa._domainkey.example.org"),
+        ));
+        let results = missing.authenticate(&message, &MessageOrigin::default(), "example.org");
+        assert_eq!(results.dkim[0].result, AuthVerdict::PermError);
+        assert_eq!(results.dkim[1].result, AuthVerdict::TempError);
+
+        let revoked = Authenticator::new(Arc::new(
+            StaticResolver::new().with_txt("mail._domainkey.example.org", "v=DKIM1; k=ed25519; p="),
+        ));
+        let results = revoked.authenticate(&message, &MessageOrigin::default(), "example.org");
+        assert_eq!(results.dkim[0].result, AuthVerdict::Fail);
+        assert_eq!(results.dkim[0].reason.as_deref(), Some("key revoked"));
+        assert_eq!(results.dmarc.result, AuthVerdict::None);
+    }
+
+    #[test]
+    fn spf_evaluates_mechanisms_includes_and_redirects() {
+        let resolver = StaticResolver::new()
+            .with_txt(
+                "example.org",
+                "v=spf1 ip4:192.0.2.0/24 include:_spf.provider.test mx -all",
+            )
+            .with_txt("_spf.provider.test", "v=spf1 ip6:2001:db8::/32 ~all")
+            .with_mx("example.org", 10, "mx.example.org")
+            .with_host("mx.example.org", "198.51.100.7".parse().unwrap())
+            .with_txt("soft.test", "v=spf1 a:relay.soft.test/28 ~all")
+            .with_host("relay.soft.test", "203.0.113.1".parse().unwrap())
+            .with_txt("redirect.test", "v=spf1 redirect=example.org")
+            .with_txt("macro.test", "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all")
+            .with_host(
+                "7.100.51.198.alice._spf.macro.test",
+                "127.0.0.2".parse().unwrap(),
+            )
+            .with_txt("double.test", "v=spf1 -all")
+            .with_txt("double.test", "v=spf1 +all")
+            .fail("broken.test");
+        let auth = Authenticator::new(Arc::new(resolver));
+        let spf = |ip: &str, mail_from: &str| auth.check_spf(&origin(ip, mail_from));
+
+        assert_eq!(spf("192.0.2.44", "a@example.org").result, AuthVerdict::Pass);
+        assert_eq!(
+            spf("2001:db8::1", "a@example.org").result,
+            AuthVerdict::Pass
+        );
+        assert_eq!(
+            spf("198.51.100.7", "a@example.org").result,
+            AuthVerdict::Pass
+        );
+        let rejected = spf("203.0.113.9", "<a@example.org>");
+        assert_eq!(rejected.result, AuthVerdict::Fail);
+        assert_eq!(rejected.domain, "example.org");
+        assert_eq!(rejected.client_ip.as_deref(), Some("203.0.113.9"));
+        assert_eq!(spf("203.0.113.14", "a@soft.test").result, AuthVerdict::Pass);
+        assert_eq!(
+            spf("203.0.113.99", "a@soft.test").result,
+            AuthVerdict::SoftFail
+        );
+        assert_eq!(
+            spf("192.0.2.1", "a@redirect.test").result,
+            AuthVerdict::Pass
+        );
+        assert_eq!(
+            spf("198.51.100.7", "alice@macro.test").result,
+            AuthVerdict::Pass
+        );
+        assert_eq!(
+            spf("198.51.100.8", "alice@macro.test").result,
+            AuthVerdict::Fail
+        );
+        assert_eq!(spf("192.0.2.1", "a@nothing.test").result, AuthVerdict::None);
+        assert_eq!(
+            spf("192.0.2.1", "a@double.test").result,
+            AuthVerdict::PermError
+        );
+        assert_eq!(
+            spf("192.0.2.1", "a@broken.test").result,
+            AuthVerdict::TempError
+        );
+        // Bounces fall back to the HELO identity.
+        assert_eq!(spf("198.51.100.7", "").domain, "mx.example.org");
+    }
+
+    #[test]
+    fn spf_stops_after_lookup_limit() {
+        let mut resolver = StaticResolver::new();
+        for n in 0..12 {
+            resolver = resolver.with_txt(
+                &format!("l{n}.test"),
+                &format!("v=spf1 include:l{}.test -all", n + 1),
+            );
+        }
+        let check =
+            Authenticator::new(Arc::new(resolver)).check_spf(&origin("192.0.2.1", "a@l0.test"));
+        assert_eq!(check.result, AuthVerdict::PermError);
+        assert_eq!(check.reason.as_deref(), Some("too many DNS lookups"));
+    }
+
+    #[test]
+    fn dmarc_uses_organizational_domain_and_alignment_modes() {
+        let resolver = StaticResolver::new()
+            .with_txt(
+                "_dmarc.example.co.uk",
+                "v=DMARC1; p=quarantine; sp=reject; aspf=s",
+            )
+            .with_txt("news.example.co.uk", "v=spf1 ip4:192.0.2.1 -all")
+            .with_txt("bounces.example.co.uk", "v=spf1 ip4:192.0.2.1 -all");
+        let auth = Authenticator::new(Arc::new(resolver));
+        let message = b"From: a@news.example.co.uk\r\nSubject: hi\r\n\r\nbody\r\n";
+
+        let aligned = auth.authenticate(
+            message,
+            &origin("192.0.2.1", "x@news.example.co.uk"),
+            "news.example.co.uk",
+        );
+        assert_eq!(aligned.spf.result, AuthVerdict::Pass);
+        assert_eq!(aligned.dmarc.result, AuthVerdict::Pass);
+        assert_eq!(aligned.dmarc.policy.as_deref(), Some("reject"));
+
+        // aspf=s: a passing SPF for a sibling domain does not align.
+        let sibling = auth.authenticate(
+            message,
+            &origin("192.0.2.1", "x@bounces.example.co.uk"),
+            "news.example.co.uk",
+        );
+        assert_eq!(sibling.spf.result, AuthVerdict::Pass);
+        assert!(!sibling.dmarc.spf_aligned);
+        assert_eq!(sibling.dmarc.result, AuthVerdict::Fail);
+
+        assert_eq!(organizational_domain("a.b.example.org."), "example.org");
+        assert_eq!(organizational_domain("mail.example.co.uk"), "example.co.uk");
+    }
+
+    #[test]
+    fn spf_is_none_without_client_address() {
+        let auth = Authenticator::new(Arc::new(
+            StaticResolver::new().with_txt("example.org", "v=spf1 ip4:203.0.113.7 -all"),
+        ));
+        let message = b"Received: from forger (forger [203.0.113.7]) by owl.test\r\nFrom: a@example.org\r\n\r\nHi\r\n";
+        let origin = MessageOrigin {
+            mail_from: "a@example.org".into(),
+            ..MessageOrigin::default()
+        };
+        let results = auth.authenticate(message, &origin, "example.org");
+        assert_eq!(results.spf.result, AuthVerdict::None);
+        assert_eq!(
+            results.spf.reason.as_deref(),
+            Some("client address unknown")
+        );
+    }
+
+    #[test]
+    fn received_client_ip_reads_topmost_header() {
+        let headers = "Received: from mx.example.org (mx.example.org [IPv6:2001:db8::5])\r\n\tby owl.test (Postfix) with ESMTPS\r\nReceived: from inner ([10.0.0.1]) by mx.example.org\r\nFrom: a@example.org\r\n";
+        assert_eq!(
+            received_client_ip(headers.as_bytes()),
+            Some("2001:db8::5".parse().unwrap())
+        );
+        assert_eq!(received_client_ip(b"From: a@example.org\r\n"), None);
+    }
+}
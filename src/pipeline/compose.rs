@@ -0,0 +1,458 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow};
+use lettre::message::Mailbox;
+use mailparse::{MailHeaderMap, ParsedMail, parse_mail};
+
+use crate::{
+    fsops::{io_atom::write_atomic, layout::MailLayout, scan::StoredMessage},
+    pipeline::{
+        outbox::{DraftFrontMatter, format_draft},
+        smtp_in::collect_mailboxes,
+    },
+    ruleset::loader::LoadedRules,
+    util::ulid,
+};
+
+/// Build a reply draft for a stored message.
+///
+/// Replies to inbound mail go to Reply-To (or From) and leave `from` unset so
+/// the `.settings` of the recipient's list supply it at send time; replies to
+/// our own `sent/` or `outbox/` copies keep their From and go back to the
+/// original recipients. `all` copies every other To/Cc recipient except our
+/// own addresses: `identities` (see [`own_addresses`]), the envelope
+/// recipients the message was delivered for, and the From of our own copies.
+pub fn reply_draft(
+    message: &StoredMessage,
+    all: bool,
+    identities: &[String],
+) -> Result<(DraftFrontMatter, String)> {
+    let original = Original::load(message)?;
+    let outgoing = matches!(message.list.as_str(), "sent" | "outbox");
+    let mut own: Vec<String> = identities
+        .iter()
+        .chain(&message.sidecar.delivered_to)
+        .map(|address| own_key(address))
+        .collect();
+    let (from, to) = if outgoing {
+        let from = original.from.first().cloned();
+        own.extend(from.iter().map(|address| own_key(address)));
+        (from, original.to.clone())
+    } else {
+        let target = if original.reply_to.is_empty() {
+            original.from.clone()
+        } else {
+            original.reply_to.clone()
+        };
+        (None, target)
+    };
+    let mut cc = Vec::new();
+    if all {
+        let source = if outgoing {
+            original.cc.clone()
+        } else {
+            original.to.iter().chain(&original.cc).cloned().collect()
+        };
+        let mut seen: Vec<String> = to.iter().map(|m| mailbox_key(m)).collect();
+        for candidate in source {
+            let key = mailbox_key(&candidate);
+            if !seen.contains(&key) && !own.contains(&own_key(&candidate)) {
+                seen.push(key);
+                cc.push(candidate);
+            }
+        }
+    }
+
+    let mut references = original.references.clone();
+    if let Some(id) = &original.message_id
+        && !references.contains(id)
+    {
+        references.push(id.clone());
+    }
+    let meta = DraftFrontMatter {
+        subject: prefixed_subject("Re:", &original.subject),
+        from,
+        to,
+        cc,
+        in_reply_to: original.message_id.clone(),
+        references,
+        ..DraftFrontMatter::default()
+    };
+    let sender = original
+        .from
+        .first()
+        .cloned()
+        .unwrap_or_else(|| message.sidecar.headers_cache.from.clone());
+    let mut body = format!("\n\nOn {}, {} wrote:\n", original.date, sender);
+    body.push_str(&quote(&original.text));
+    Ok((meta, body))
+}
+
+/// Build a forward draft with the original quoted inline, no recipients and
+/// no `from`, which the recipient's list settings fill in when sent.
+pub fn forward_draft(message: &StoredMessage) -> Result<(DraftFrontMatter, String)> {
+    let original = Original::load(message)?;
+    let mut references = original.references.clone();
+    if let Some(id) = &original.message_id
+        && !references.contains(id)
+    {
+        references.push(id.clone());
+    }
+    let meta = DraftFrontMatter {
+        subject: prefixed_subject("Fwd:", &original.subject),
+        references,
+        ..DraftFrontMatter::default()
+    };
+    let mut header_block = vec![
+        "---------- Forwarded message ----------".to_string(),
+        format!(
+            "From: {}",
+            original.from.first().cloned().unwrap_or_else(|| message
+                .sidecar
+                .headers_cache
+                .from
+                .clone())
+        ),
+        format!("Date: {}", original.date),
+        format!("Subject: {}", original.subject),
+    ];
+    if !original.to.is_empty() {
+        header_block.push(format!("To: {}", original.to.join(", ")));
+    }
+    if !original.cc.is_empty() {
+        header_block.push(format!("Cc: {}", original.cc.join(", ")));
+    }
+    let mut body = String::from("\n\n");
+    body.push_str(&quote(&header_block.join("\n")));
+    body.push_str(">\n");
+    body.push_str(&quote(&original.text));
+    Ok((meta, body))
+}
+
+/// Write a new `drafts/<ULID>.md` and return its path.
+pub fn write_draft(layout: &MailLayout, meta: &DraftFrontMatter, body: &str) -> Result<PathBuf> {
+    let path = layout.drafts().join(format!("{}.md", ulid::generate()));
+    write_atomic(&path, format_draft(meta, body)?.as_bytes())?;
+    Ok(path)
+}
+
+/// Prefix a subject unless it already carries the marker (case-insensitive).
+fn prefixed_subject(prefix: &str, subject: &str) -> String {
+    let trimmed = subject.trim();
+    let already = trimmed
+        .get(..prefix.len())
+        .is_some_and(|head| head.eq_ignore_ascii_case(prefix));
+    if already {
+        trimmed.to_string()
+    } else if trimmed.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{prefix} {trimmed}")
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::new();
+    for line in text.trim_end().lines() {
+        if line.is_empty() {
+            quoted.push_str(">\n");
+        } else {
+            quoted.push_str("> ");
+            quoted.push_str(line);
+            quoted.push('\n');
+        }
+    }
+    quoted
+}
+
+fn mailbox_key(value: &str) -> String {
+    Mailbox::from_str(value)
+        .map(|mailbox| mailbox.email.to_string())
+        .unwrap_or_else(|_| value.trim().to_string())
+        .to_ascii_lowercase()
+}
+
+/// [`mailbox_key`] without a `+tag`, so `me+lists@example.org` counts as
+/// the `me@example.org` a list's `.settings` names.
+fn own_key(value: &str) -> String {
+    let key = mailbox_key(value);
+    match key.split_once('@') {
+        Some((local, domain)) => {
+            let base = local.split_once('+').map_or(local, |(base, _)| base);
+            format!("{base}@{domain}")
+        }
+        None => key,
+    }
+}
+
+/// The addresses we send as: every `from` and `reply_to` in the lists'
+/// `.settings`.
+pub fn own_addresses(rules: &LoadedRules) -> Vec<String> {
+    [&rules.accepted, &rules.spam, &rules.banned]
+        .into_iter()
+        .flat_map(|list| [&list.settings.from, &list.settings.reply_to])
+        .flatten()
+        .filter(|address| !address.trim().is_empty())
+        .cloned()
+        .collect()
+}
+
+/// The parts of a stored message needed to pre-fill a draft.
+struct Original {
+    from: Vec<String>,
+    reply_to: Vec<String>,
+    to: Vec<String>,
+    cc: Vec<String>,
+    subject: String,
+    date: String,
+    message_id: Option<String>,
+    references: Vec<String>,
+    text: String,
+}
+
+impl Original {
+    fn load(message: &StoredMessage) -> Result<Self> {
+        let path = message.message_path();
+        let raw = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let parsed = parse_mail(&raw).map_err(|err| anyhow!(err.to_string()))?;
+        let headers = &message.sidecar.headers_cache;
+        let mut from = collect_mailboxes(&parsed, "From");
+        if from.is_empty() {
+            from.push(headers.from.clone());
+        }
+        let mut to = collect_mailboxes(&parsed, "To");
+        if to.is_empty() {
+            to = headers.to.clone();
+        }
+        let mut cc = collect_mailboxes(&parsed, "Cc");
+        if cc.is_empty() {
+            cc = headers.cc.clone();
+        }
+        let rendered = message
+            .plain_path()
+            .and_then(|plain| fs::read_to_string(plain).ok());
+        let text = match rendered {
+            Some(text) => text,
+            None => first_plain_part(&parsed)?.unwrap_or_default(),
+        };
+        Ok(Self {
+            from,
+            reply_to: collect_mailboxes(&parsed, "Reply-To"),
+            to,
+            cc,
+            subject: headers.subject.clone(),
+            date: parsed
+                .headers
+                .get_first_value("Date")
+                .map(|value| value.trim().to_string())
+                .unwrap_or_else(|| headers.date.clone()),
+            message_id: headers.message_id.clone(),
+            references: headers.references.clone(),
+            text,
+        })
+    }
+}
+
+/// Decoded body of the first `text/plain` leaf part, depth first.
+pub fn first_plain_part(part: &ParsedMail) -> Result<Option<String>> {
+    if part.subparts.is_empty() {
+        if part.ctype.mimetype.eq_ignore_ascii_case("text/plain") {
+            return Ok(Some(
+                part.get_body().map_err(|err| anyhow!(err.to_string()))?,
+            ));
+        }
+        return Ok(None);
+    }
+    for sub in &part.subparts {
+        if let Some(text) = first_plain_part(sub)? {
+            return Ok(Some(text));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::message::{HeadersCache, MessageSidecar};
+    use std::path::Path;
+
+    fn store(dir: &Path, list: &str, eml: &str, plain: Option<&str>) -> StoredMessage {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("01A.eml"), eml).unwrap();
+        let parsed = parse_mail(eml.as_bytes()).unwrap();
+        let mut headers = HeadersCache::new(
+            collect_mailboxes(&parsed, "From")
+                .first()
+                .cloned()
+                .unwrap_or_default(),
+            parsed
+                .headers
+                .get_first_value("Subject")
+                .unwrap_or_default(),
+        );
+        headers.message_id = Some("<b@example.org>".into());
+        headers.references = vec!["<a@example.org>".into()];
+        let mut sidecar = MessageSidecar::new(
+            "01A",
+            "01A.eml",
+            list,
+            "strict",
+            ".01A.html",
+            "hash",
+            headers,
+        );
+        if let Some(text) = plain {
+            fs::write(dir.join(".01A.txt"), text).unwrap();
+            sidecar.set_plain_render(".01A.txt");
+        }
+        StoredMessage {
+            list: list.into(),
+            dir: dir.to_path_buf(),
+            sidecar_path: dir.join(".01A.yml"),
+            sidecar,
+        }
+    }
+
+    const INBOUND: &str = "From: Alice <alice@example.org>\r\nReply-To: Help <help@example.org>\r\nTo: Owl <owl@example.net>, Bob <bob@example.org>\r\nCc: carol@example.org, ALICE@example.org\r\nSubject: Plans\r\nDate: Tue, 1 Oct 2024 10:00:00 +0000\r\n\r\nignored\r\n";
+
+    #[test]
+    fn reply_targets_reply_to_and_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = store(
+            &dir.path().join("accepted/alice@example.org"),
+            "accepted",
+            INBOUND,
+            Some("Line one\n\nLine two\n"),
+        );
+        let (meta, body) = reply_draft(&message, false, &[]).unwrap();
+        assert_eq!(meta.subject, "Re: Plans");
+        assert!(meta.from.is_none());
+        assert_eq!(meta.to, vec!["\"Help\" <help@example.org>"]);
+        assert!(meta.cc.is_empty());
+        assert_eq!(meta.in_reply_to.as_deref(), Some("<b@example.org>"));
+        assert_eq!(meta.references, vec!["<a@example.org>", "<b@example.org>"]);
+        assert!(body.contains("wrote:\n> Line one\n>\n> Line two\n"));
+    }
+
+    #[test]
+    fn reply_all_copies_other_recipients_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = store(
+            &dir.path().join("accepted/alice@example.org"),
+            "accepted",
+            &INBOUND.replace("Reply-To: Help <help@example.org>\r\n", ""),
+            None,
+        );
+        let identities = vec!["Owl <owl@example.net>".to_string()];
+        let (meta, body) = reply_draft(&message, true, &identities).unwrap();
+        assert_eq!(meta.to, vec!["\"Alice\" <alice@example.org>"]);
+        assert_eq!(
+            meta.cc,
+            vec!["\"Bob\" <bob@example.org>", "carol@example.org"]
+        );
+        assert!(body.contains("> ignored"));
+    }
+
+    #[test]
+    fn reply_all_drops_the_envelope_recipient_wherever_it_appears() {
+        let dir = tempfile::tempdir().unwrap();
+        let eml = "From: bob@example.org\r\nTo: Alice <alice@example.org>, Owl <owl+plans@example.net>\r\nCc: carol@example.org\r\nSubject: Plans\r\n\r\nhi\r\n";
+        let mut message = store(
+            &dir.path().join("accepted/bob@example.org"),
+            "accepted",
+            eml,
+            None,
+        );
+        message.sidecar.delivered_to = vec!["owl+plans@example.net".into()];
+        let (meta, _) = reply_draft(&message, true, &[]).unwrap();
+        assert_eq!(meta.to, vec!["bob@example.org"]);
+        assert_eq!(
+            meta.cc,
+            vec!["\"Alice\" <alice@example.org>", "carol@example.org"]
+        );
+    }
+
+    #[test]
+    fn reply_all_drops_our_settings_address_when_only_cced() {
+        let dir = tempfile::tempdir().unwrap();
+        let eml = "From: bob@example.org\r\nTo: alice@example.org, dave@example.org\r\nCc: carol@example.org, OWL@example.net, owl+news@example.net\r\nSubject: Plans\r\n\r\nhi\r\n";
+        let message = store(
+            &dir.path().join("accepted/bob@example.org"),
+            "accepted",
+            eml,
+            None,
+        );
+        let mut rules = LoadedRules::default();
+        rules.accepted.settings.from = Some("Owl <owl@example.net>".into());
+        let identities = own_addresses(&rules);
+        assert_eq!(identities, vec!["Owl <owl@example.net>"]);
+        let (meta, _) = reply_draft(&message, true, &identities).unwrap();
+        assert_eq!(
+            meta.cc,
+            vec!["alice@example.org", "dave@example.org", "carol@example.org"]
+        );
+    }
+
+    #[test]
+    fn reply_to_sent_copy_addresses_original_recipients() {
+        let dir = tempfile::tempdir().unwrap();
+        let eml = "From: owl@example.net\r\nTo: bob@example.org\r\nSubject: RE: Plans\r\nContent-Type: multipart/alternative; boundary=\"b\"\r\n\r\n--b\r\nContent-Type: text/plain\r\n\r\nplain text\r\n--b\r\nContent-Type: text/html\r\n\r\n<p>html</p>\r\n--b--\r\n";
+        let message = store(&dir.path().join("sent"), "sent", eml, None);
+        let (meta, body) = reply_draft(&message, false, &[]).unwrap();
+        assert_eq!(meta.subject, "RE: Plans");
+        assert_eq!(meta.from.as_deref(), Some("owl@example.net"));
+        assert_eq!(meta.to, vec!["bob@example.org"]);
+        assert!(body.contains("> plain text"));
+        assert!(!body.contains("html"));
+    }
+
+    #[test]
+    fn forward_quotes_headers_without_recipients() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = store(
+            &dir.path().join("accepted/alice@example.org"),
+            "accepted",
+            INBOUND,
+            Some("Body\n"),
+        );
+        let (meta, body) = forward_draft(&message).unwrap();
+        assert_eq!(meta.subject, "Fwd: Plans");
+        assert!(meta.from.is_none());
+        assert!(meta.to.is_empty());
+        assert!(meta.in_reply_to.is_none());
+        assert_eq!(meta.references.len(), 2);
+        assert!(body.contains("> ---------- Forwarded message ----------\n"));
+        assert!(body.contains("> From: \"Alice\" <alice@example.org>\n"));
+        assert!(body.contains("> Subject: Plans\n"));
+        assert!(body.ends_with("> Cc: carol@example.org, ALICE@example.org\n>\n> Body\n"));
+    }
+
+    #[test]
+    fn prefixed_subject_is_idempotent() {
+        assert_eq!(prefixed_subject("Re:", "Hello"), "Re: Hello");
+        assert_eq!(prefixed_subject("Re:", "re: Hello"), "re: Hello");
+        assert_eq!(prefixed_subject("Fwd:", "Re: Hello"), "Fwd: Re: Hello");
+        assert_eq!(prefixed_subject("Fwd:", ""), "Fwd:");
+    }
+
+    #[test]
+    fn write_draft_creates_ulid_named_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let meta = DraftFrontMatter {
+            subject: "Re: Plans".into(),
+            to: vec!["bob@example.org".into()],
+            ..DraftFrontMatter::default()
+        };
+        let path = write_draft(&layout, &meta, "> hi\n").unwrap();
+        assert_eq!(path.parent().unwrap(), layout.drafts());
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        assert_eq!(stem.len(), 26);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("---\nsubject: 'Re: Plans'\n"));
+    }
+}
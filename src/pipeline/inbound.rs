@@ -1,28 +1,203 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
+use mailparse::{MailAddr, MailHeaderMap};
 
 use crate::{
     envcfg::EnvConfig,
-    model::address::Address,
+    model::{
+        address::Address,
+        message::{AuthResults, AuthVerdict},
+        rules::MessageContext,
+    },
+    pipeline::{
+        auth::MessageOrigin,
+        smtp_in::{InboundPipeline, MalformedMessageError},
+    },
     ruleset::{
         eval::{Route, evaluate},
         loader::LoadedRules,
+        sieve::SieveOutcome,
     },
 };
 
-pub fn determine_route(sender: &Address, rules: &LoadedRules, _env: &EnvConfig) -> Result<Route> {
-    let route = evaluate(
-        sender,
-        &rules.accepted.rules,
-        &rules.spam.rules,
-        &rules.banned.rules,
-    );
-    let adjusted = match route {
-        Route::Accepted => map_status(&rules.accepted.settings.list_status)?,
-        Route::Spam => map_status(&rules.spam.settings.list_status)?,
-        Route::Banned => map_status(&rules.banned.settings.list_status)?,
-        Route::Quarantine => Route::Quarantine,
+/// Route by sender alone; [`determine_authenticated_route`] also sees the
+/// envelope and headers in the message context. `.sieve`, when present,
+/// decides first and the lists' `.rules` only see mail it keeps.
+pub fn determine_route(sender: &Address, rules: &LoadedRules) -> Result<Route> {
+    Ok(determine_authenticated_route(&MessageContext::new(sender), rules, None)?.0)
+}
+
+/// [`determine_route`], but mail that would land in `accepted/` goes to
+/// quarantine when `auth` misses the matched list's `require_auth`; the
+/// reason is returned alongside. Without `auth` (imports) nothing is demoted.
+pub fn determine_authenticated_route(
+    message: &MessageContext,
+    rules: &LoadedRules,
+    auth: Option<&AuthResults>,
+) -> Result<(Route, Option<String>)> {
+    let sieve = rules.sieve.as_ref().map(|script| script.evaluate(message));
+    let matched = match sieve {
+        Some(SieveOutcome::FileInto(route)) => route,
+        Some(SieveOutcome::Discard) => return Ok((Route::Banned, None)),
+        Some(SieveOutcome::Keep) | None => evaluate(
+            message,
+            &rules.accepted.rules,
+            &rules.spam.rules,
+            &rules.banned.rules,
+        ),
     };
-    Ok(adjusted)
+    let settings = match matched {
+        Route::Accepted => &rules.accepted.settings,
+        Route::Spam => &rules.spam.settings,
+        Route::Banned => &rules.banned.settings,
+        Route::Quarantine => return Ok((Route::Quarantine, None)),
+    };
+    let route = map_status(&settings.list_status)?;
+    if route == Route::Accepted
+        && let Some(auth) = auth
+        && let Some(shortfall) = auth_shortfall(&settings.require_auth, auth)
+    {
+        let reason = format!(
+            "{} list requires {}: {shortfall}",
+            matched.as_str(),
+            settings.require_auth
+        );
+        return Ok((Route::Quarantine, Some(reason)));
+    }
+    Ok((route, None))
+}
+
+/// Why `auth` falls short of a `require_auth` mode, if it does. `dkim`
+/// needs a valid signature aligned with the From domain; `dmarc` accepts an
+/// aligned SPF pass as well, whether or not the domain publishes a policy.
+pub fn auth_shortfall(require_auth: &str, auth: &AuthResults) -> Option<String> {
+    let dmarc = &auth.dmarc;
+    let dkim = if auth.dkim.is_empty() {
+        AuthVerdict::None.as_str().to_string()
+    } else {
+        auth.dkim
+            .iter()
+            .map(|check| check.result.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    match require_auth {
+        "dkim" if !dmarc.dkim_aligned => Some(format!(
+            "no valid DKIM signature aligned with {} (dkim={dkim})",
+            dmarc.domain
+        )),
+        "dmarc" if !dmarc.dkim_aligned && !dmarc.spf_aligned => Some(format!(
+            "neither DKIM nor SPF aligned with {} (dkim={dkim} spf={})",
+            dmarc.domain,
+            auth.spf.result.as_str()
+        )),
+        _ => None,
+    }
+}
+
+/// Sender used for bounces (`MAIL FROM:<>`) that carry no From header either.
+pub const NULL_SENDER_FALLBACK: &str = "mailer-daemon@deliver.invalid";
+
+/// Normalise an envelope sender for use as the delivery fallback, mapping the
+/// null reverse-path to [`NULL_SENDER_FALLBACK`].
+pub fn envelope_fallback_sender(sender: &str) -> &str {
+    match sender.trim().trim_start_matches('<').trim_end_matches('>') {
+        "" => NULL_SENDER_FALLBACK,
+        other => other,
+    }
+}
+
+/// Route and store one raw RFC 5322 message. The sender comes from the From
+/// header; `fallback_sender` is used when it is missing or has no mailbox.
+pub fn deliver_message(
+    pipeline: &InboundPipeline,
+    rules: &LoadedRules,
+    env: &EnvConfig,
+    body: &[u8],
+    fallback_sender: &str,
+) -> Result<(Route, PathBuf)> {
+    deliver(pipeline, rules, env, body, fallback_sender, None)
+}
+
+/// [`deliver_message`] for mail received over SMTP/LMTP: the envelope
+/// sender is the fallback, and the pipeline's authenticator (if any) checks
+/// the message against `origin`.
+pub fn deliver_message_from(
+    pipeline: &InboundPipeline,
+    rules: &LoadedRules,
+    env: &EnvConfig,
+    body: &[u8],
+    origin: &MessageOrigin,
+) -> Result<(Route, PathBuf)> {
+    let fallback = envelope_fallback_sender(&origin.mail_from);
+    deliver(pipeline, rules, env, body, fallback, Some(origin))
+}
+
+fn deliver(
+    pipeline: &InboundPipeline,
+    rules: &LoadedRules,
+    env: &EnvConfig,
+    body: &[u8],
+    fallback_sender: &str,
+    origin: Option<&MessageOrigin>,
+) -> Result<(Route, PathBuf)> {
+    let parsed = mailparse::parse_mail(body).map_err(MalformedMessageError::new)?;
+    let subject = parsed
+        .headers
+        .get_first_value("Subject")
+        .unwrap_or_else(|| "no subject".to_string());
+    let sender = if let Some(from_value) = parsed.headers.get_first_value("From") {
+        let addresses =
+            mailparse::addrparse(from_value.as_str()).map_err(MalformedMessageError::new)?;
+        if let Some(addr) = first_mailbox(&addresses) {
+            Address::parse(&addr, env.keep_plus_tags)
+        } else {
+            Address::parse(fallback_sender, env.keep_plus_tags)
+        }
+    } else {
+        Address::parse(fallback_sender, env.keep_plus_tags)
+    }
+    .map_err(MalformedMessageError::new)?;
+    let mut auth = match (pipeline.authenticator(), origin) {
+        (Some(authenticator), Some(origin)) => {
+            Some(authenticator.authenticate(body, origin, sender.domain()))
+        }
+        _ => None,
+    };
+    let mut message = MessageContext::new(&sender).with_size(body.len());
+    if let Some(origin) = origin {
+        message = message
+            .with_envelope_sender(&origin.mail_from)
+            .with_recipients(&origin.recipients);
+    }
+    let message = parsed.headers.iter().fold(message, |message, header| {
+        message.with_header(&header.get_key(), &header.get_value())
+    });
+    let (route, demoted) = determine_authenticated_route(&message, rules, auth.as_ref())?;
+    if let Some(auth) = auth.as_mut() {
+        auth.demoted = demoted;
+    }
+    let recipients = origin
+        .map(|origin| origin.recipients.as_slice())
+        .unwrap_or(&[]);
+    let path =
+        pipeline.deliver_with_auth(route, &sender, &subject, body, auth.as_ref(), recipients)?;
+    Ok((route, path))
+}
+
+pub fn first_mailbox(addrs: &[MailAddr]) -> Option<String> {
+    for addr in addrs {
+        match addr {
+            MailAddr::Single(info) => return Some(info.addr.clone()),
+            MailAddr::Group(group) => {
+                if let Some(first) = group.addrs.first() {
+                    return Some(first.addr.clone());
+                }
+            }
+        }
+    }
+    None
 }
 
 fn map_status(status: &str) -> Result<Route> {
@@ -44,7 +219,7 @@ mod tests {
         let sender = Address::parse("foo@bar.com", false).unwrap();
         let mut rules = LoadedRules::default();
         rules.banned.rules = RuleSet::parse("@bar.com").unwrap();
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Banned);
     }
 
@@ -54,7 +229,7 @@ mod tests {
         let mut rules = LoadedRules::default();
         rules.accepted.rules = RuleSet::parse("@example.com").unwrap();
         rules.accepted.settings.list_status = "banned".into();
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Banned);
     }
 
@@ -63,18 +238,79 @@ mod tests {
         let sender = Address::parse("foo@spam.test", false).unwrap();
         let mut rules = LoadedRules::default();
         rules.spam.rules = RuleSet::parse("@spam.test").unwrap();
-        let spam_route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let spam_route = determine_route(&sender, &rules).unwrap();
         assert_eq!(spam_route, Route::Spam);
         rules.spam.settings.list_status = "accepted".into();
-        let adjusted = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let adjusted = determine_route(&sender, &rules).unwrap();
         assert_eq!(adjusted, Route::Accepted);
     }
 
+    #[test]
+    fn require_auth_demotes_unauthenticated_accepted_mail() {
+        use crate::model::message::{DkimCheck, SpfCheck};
+
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let mut rules = LoadedRules::default();
+        rules.accepted.rules = RuleSet::parse("alice@example.org").unwrap();
+        rules.accepted.settings.require_auth = "dkim".into();
+        let mut auth = AuthResults {
+            spf: SpfCheck {
+                result: AuthVerdict::Pass,
+                domain: "example.org".into(),
+                ..SpfCheck::default()
+            },
+            ..AuthResults::default()
+        };
+        auth.dmarc.domain = "example.org".into();
+        auth.dmarc.spf_aligned = true;
+
+        let (route, reason) =
+            determine_authenticated_route(&MessageContext::new(&sender), &rules, Some(&auth))
+                .unwrap();
+        assert_eq!(route, Route::Quarantine);
+        assert_eq!(
+            reason.as_deref(),
+            Some(
+                "accepted list requires dkim: no valid DKIM signature aligned with example.org (dkim=none)"
+            )
+        );
+
+        // An aligned SPF pass satisfies dmarc but not dkim.
+        rules.accepted.settings.require_auth = "dmarc".into();
+        let (route, reason) =
+            determine_authenticated_route(&MessageContext::new(&sender), &rules, Some(&auth))
+                .unwrap();
+        assert_eq!((route, reason), (Route::Accepted, None));
+
+        auth.dmarc.spf_aligned = false;
+        auth.dkim.push(DkimCheck {
+            result: AuthVerdict::Fail,
+            ..DkimCheck::default()
+        });
+        let (route, reason) =
+            determine_authenticated_route(&MessageContext::new(&sender), &rules, Some(&auth))
+                .unwrap();
+        assert_eq!(route, Route::Quarantine);
+        assert!(reason.unwrap().ends_with("(dkim=fail spf=pass)"));
+
+        // Imports carry no results, and only accepted deliveries are demoted.
+        assert_eq!(
+            determine_authenticated_route(&MessageContext::new(&sender), &rules, None).unwrap(),
+            (Route::Accepted, None)
+        );
+        rules.accepted.settings.list_status = "rejected".into();
+        assert_eq!(
+            determine_authenticated_route(&MessageContext::new(&sender), &rules, Some(&auth))
+                .unwrap(),
+            (Route::Spam, None)
+        );
+    }
+
     #[test]
     fn unmatched_is_quarantine() {
         let sender = Address::parse("nobody@unknown.invalid", false).unwrap();
         let rules = LoadedRules::default();
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Quarantine);
     }
 
@@ -84,10 +320,135 @@ mod tests {
         let mut rules = LoadedRules::default();
         rules.accepted.rules = RuleSet::parse("@example.com").unwrap();
         rules.accepted.settings.list_status = "unknown".into();
-        let err = determine_route(&sender, &rules, &EnvConfig::default()).unwrap_err();
+        let err = determine_route(&sender, &rules).unwrap_err();
         assert!(err.to_string().contains("unknown list_status"));
     }
 
+    #[test]
+    fn delivery_routes_on_recipient_tags_and_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = crate::fsops::layout::MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let env = EnvConfig::default();
+        let pipeline = InboundPipeline::new(layout, env.clone()).unwrap();
+        let mut rules = LoadedRules::default();
+        rules.accepted.rules = RuleSet::parse("to:+shop\nlist-id:news.example.org").unwrap();
+        rules.spam.rules = RuleSet::parse("header:X-Spam-Flag:/^YES$/").unwrap();
+        let origin = |rcpt: &str| MessageOrigin {
+            mail_from: "shop@store.example".into(),
+            recipients: vec![rcpt.into()],
+            ..MessageOrigin::default()
+        };
+        let plain = b"From: shop@store.example\r\nSubject: Receipt\r\n\r\nThanks\r\n";
+
+        let (route, _) = deliver_message_from(
+            &pipeline,
+            &rules,
+            &env,
+            plain,
+            &origin("me+Shop@example.org"),
+        )
+        .unwrap();
+        assert_eq!(route, Route::Accepted);
+        let (route, _) =
+            deliver_message_from(&pipeline, &rules, &env, plain, &origin("me@example.org"))
+                .unwrap();
+        assert_eq!(route, Route::Quarantine);
+        // Without an envelope (imports) recipient rules cannot match.
+        let (route, _) =
+            deliver_message(&pipeline, &rules, &env, plain, "shop@store.example").unwrap();
+        assert_eq!(route, Route::Quarantine);
+
+        let list = b"From: editor@example.net\r\nList-Id: Weekly <news.example.org>\r\nSubject: Issue 4\r\n\r\nHi\r\n";
+        let (route, _) = deliver_message(&pipeline, &rules, &env, list, "x@example.net").unwrap();
+        assert_eq!(route, Route::Accepted);
+
+        let flagged =
+            b"From: shop@store.example\r\nX-Spam-Flag: YES\r\nSubject: Deal\r\n\r\nBuy\r\n";
+        let (route, _) = deliver_message_from(
+            &pipeline,
+            &rules,
+            &env,
+            flagged,
+            &origin("me+shop@example.org"),
+        )
+        .unwrap();
+        assert_eq!(route, Route::Spam);
+    }
+
+    #[test]
+    fn sieve_decides_before_rules() {
+        use crate::ruleset::sieve::SieveScript;
+
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let mut rules = LoadedRules::default();
+        rules.accepted.rules = RuleSet::parse("@example.org").unwrap();
+        rules.spam.settings.list_status = "banned".into();
+        let script = r#"
+            require ["fileinto", "envelope"];
+            if header :contains "subject" "deal" { fileinto "spam"; }
+            elsif envelope :is "from" "" { discard; }
+            elsif header :is "subject" "hold" { fileinto "quarantine"; }
+        "#;
+        rules.sieve = Some(SieveScript::parse(script).unwrap());
+        let route = |message: MessageContext| {
+            determine_authenticated_route(&message, &rules, None)
+                .unwrap()
+                .0
+        };
+
+        // fileinto goes through the list's list_status like a matched rule.
+        let deal = MessageContext::new(&sender).with_header("Subject", "Big DEAL");
+        assert_eq!(route(deal), Route::Banned);
+        let bounce = MessageContext::new(&sender).with_envelope_sender("<>");
+        assert_eq!(route(bounce), Route::Banned);
+        let hold = MessageContext::new(&sender).with_header("Subject", "hold");
+        assert_eq!(route(hold), Route::Quarantine);
+        // Implicit keep leaves the decision to `.rules`.
+        assert_eq!(route(MessageContext::new(&sender)), Route::Accepted);
+    }
+
+    #[test]
+    fn envelope_fallback_handles_null_sender() {
+        assert_eq!(envelope_fallback_sender("<>"), NULL_SENDER_FALLBACK);
+        assert_eq!(envelope_fallback_sender(""), NULL_SENDER_FALLBACK);
+        assert_eq!(
+            envelope_fallback_sender(" <bob@example.org> "),
+            "bob@example.org"
+        );
+    }
+
+    #[test]
+    fn first_mailbox_extracts_from_groups() {
+        let group = MailAddr::Group(mailparse::GroupInfo {
+            group_name: "Team".into(),
+            addrs: vec![mailparse::SingleInfo {
+                display_name: Some("Helper".into()),
+                addr: "helper@example.org".into(),
+            }],
+        });
+        let single = MailAddr::Single(mailparse::SingleInfo {
+            display_name: Some("Lead".into()),
+            addr: "lead@example.org".into(),
+        });
+        assert_eq!(
+            first_mailbox(std::slice::from_ref(&group)),
+            Some("helper@example.org".into())
+        );
+        assert_eq!(first_mailbox(&[single]), Some("lead@example.org".into()));
+        assert_eq!(
+            first_mailbox(&[
+                group,
+                MailAddr::Group(mailparse::GroupInfo {
+                    group_name: String::new(),
+                    addrs: vec![],
+                })
+            ]),
+            Some("helper@example.org".into())
+        );
+        assert_eq!(first_mailbox(&[]), None);
+    }
+
     #[test]
     fn map_status_all_valid_values() {
         assert_eq!(map_status("accepted").unwrap(), Route::Accepted);
@@ -117,7 +478,7 @@ mod tests {
         rules.accepted.rules = RuleSet::parse("@example.org").unwrap();
         rules.accepted.settings.list_status = "banned".into();
 
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Banned);
     }
 
@@ -129,7 +490,7 @@ mod tests {
         rules.spam.rules = RuleSet::parse("@spam.org").unwrap();
         rules.spam.settings.list_status = "accepted".into();
 
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Accepted);
     }
 
@@ -139,7 +500,7 @@ mod tests {
         let sender = Address::parse("unknown@nowhere.org", false).unwrap();
         let rules = LoadedRules::default();
 
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Quarantine);
     }
 
@@ -150,7 +511,7 @@ mod tests {
         // Domain should be punycoded in canonicalization
         rules.accepted.rules = RuleSet::parse("@xn--caf-dma.example.org").unwrap();
 
-        let route = determine_route(&sender, &rules, &EnvConfig::default()).unwrap();
+        let route = determine_route(&sender, &rules).unwrap();
         assert_eq!(route, Route::Accepted);
     }
 }
@@ -0,0 +1,488 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use lettre::{
+    Address,
+    address::Envelope,
+    transport::smtp::{
+        Error as SmtpError, SMTP_PORT,
+        client::{SmtpConnection, TlsParameters},
+        commands::{Data, Mail, Rcpt},
+        extension::{ClientId, Extension, MailBodyParameter, MailParameter},
+    },
+};
+
+use crate::{
+    pipeline::outbox::{MailTransport, RecipientOutcome, RecipientResult},
+    util::dns::{DnsResolver, SystemResolver},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delivers straight to each recipient domain's mail exchangers instead of
+/// relaying through `smtp_host`.
+pub struct MxTransport {
+    resolver: Arc<dyn DnsResolver>,
+    port: u16,
+    timeout: Duration,
+}
+
+impl MxTransport {
+    pub fn new(resolver: Arc<dyn DnsResolver>) -> Self {
+        Self {
+            resolver,
+            port: SMTP_PORT,
+            timeout: CONNECT_TIMEOUT,
+        }
+    }
+
+    pub fn system() -> Self {
+        Self::new(Arc::new(SystemResolver::new()))
+    }
+
+    /// Connect to exchangers on `port` instead of 25.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Attempt delivery to every envelope recipient, one SMTP transaction per
+    /// recipient domain, and report what happened to each address.
+    pub fn deliver(&self, message: &[u8], envelope: &Envelope) -> Vec<RecipientResult> {
+        let from = envelope.from().cloned();
+        let helo = ClientId::Domain(
+            from.as_ref()
+                .map(|addr| addr.domain().to_ascii_lowercase())
+                .unwrap_or_else(|| "localhost".into()),
+        );
+        let mut domains: BTreeMap<String, Vec<Address>> = BTreeMap::new();
+        for recipient in envelope.to() {
+            domains
+                .entry(recipient.domain().to_ascii_lowercase())
+                .or_default()
+                .push(recipient.clone());
+        }
+        let mut results = Vec::new();
+        for (domain, recipients) in domains {
+            results.extend(self.deliver_domain(
+                &domain,
+                &helo,
+                from.as_ref(),
+                &recipients,
+                message,
+            ));
+        }
+        results
+    }
+
+    fn deliver_domain(
+        &self,
+        domain: &str,
+        helo: &ClientId,
+        from: Option<&Address>,
+        recipients: &[Address],
+        message: &[u8],
+    ) -> Vec<RecipientResult> {
+        let all = |outcome: RecipientOutcome| {
+            recipients
+                .iter()
+                .map(|recipient| RecipientResult {
+                    recipient: recipient.to_string(),
+                    outcome: outcome.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let ascii = match crate::util::idna::to_ascii(domain) {
+            Ok(ascii) => ascii,
+            Err(err) => {
+                return all(RecipientOutcome::Rejected(format!(
+                    "invalid domain {domain}: {err}"
+                )));
+            }
+        };
+        let hosts = match self.exchangers(&ascii) {
+            Ok(hosts) => hosts,
+            Err(err) => return all(RecipientOutcome::Deferred(format!("{err:#}"))),
+        };
+        if hosts.is_empty() {
+            return all(RecipientOutcome::Rejected(format!(
+                "{domain} does not accept mail (null MX)"
+            )));
+        }
+
+        let mut last_error = format!("no usable mail exchanger for {domain}");
+        // Only a definite empty answer everywhere (NXDOMAIN included) is
+        // permanent; one lookup error or failed connection keeps it deferred.
+        let mut unresolvable = true;
+        for host in hosts {
+            let addrs = match self.resolver.addresses(&host) {
+                Ok(addrs) if addrs.is_empty() => {
+                    last_error = format!("{host}: no address records");
+                    continue;
+                }
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    unresolvable = false;
+                    last_error = format!("{err:#}");
+                    continue;
+                }
+            };
+            unresolvable = false;
+            for addr in addrs {
+                match self.transaction(addr, &host, helo, from, recipients, message) {
+                    Ok(results) => return results,
+                    Err(err) if err.is_permanent() => {
+                        return all(RecipientOutcome::Rejected(format!("{host}: {err}")));
+                    }
+                    Err(err) => last_error = format!("{host}: {err}"),
+                }
+            }
+        }
+        if unresolvable {
+            return all(RecipientOutcome::Rejected(format!(
+                "5.1.2 {domain} has no mail exchanger or address records"
+            )));
+        }
+        all(RecipientOutcome::Deferred(last_error))
+    }
+
+    /// Exchanger hostnames in preference order. Without MX records the domain
+    /// itself is the implicit exchanger (RFC 5321 §5.1); a null MX (RFC 7505)
+    /// yields an empty list.
+    fn exchangers(&self, domain: &str) -> Result<Vec<String>> {
+        let mut records = self.resolver.mx(domain)?;
+        if records.is_empty() {
+            return Ok(vec![domain.to_string()]);
+        }
+        if records.iter().all(|record| record.exchange.is_empty()) {
+            return Ok(Vec::new());
+        }
+        records.sort_by_key(|record| record.preference);
+        Ok(records
+            .into_iter()
+            .filter(|record| !record.exchange.is_empty())
+            .map(|record| record.exchange)
+            .collect())
+    }
+
+    /// One SMTP session: STARTTLS when offered, then MAIL, one RCPT per
+    /// recipient and DATA for those the server accepted. `Err` means the
+    /// session failed as a whole and the next exchanger may be tried.
+    fn transaction(
+        &self,
+        ip: IpAddr,
+        host: &str,
+        helo: &ClientId,
+        from: Option<&Address>,
+        recipients: &[Address],
+        message: &[u8],
+    ) -> Result<Vec<RecipientResult>, SmtpError> {
+        let addr = SocketAddr::new(ip, self.port);
+        let mut conn = SmtpConnection::connect(addr, Some(self.timeout), helo, None, None)?;
+        if conn.can_starttls() {
+            // Opportunistic: MX certificates rarely match and are not
+            // verified; if the upgrade fails, retry the session in plaintext.
+            let upgraded = TlsParameters::builder(host.to_string())
+                .dangerous_accept_invalid_certs(true)
+                .dangerous_accept_invalid_hostnames(true)
+                .build()
+                .and_then(|tls| conn.starttls(&tls, helo));
+            if upgraded.is_err() {
+                conn.abort();
+                conn = SmtpConnection::connect(addr, Some(self.timeout), helo, None, None)?;
+            }
+        }
+
+        per_recipient(&mut conn, host, from, recipients, message)
+    }
+}
+
+/// MAIL, one RCPT per recipient and DATA for those the server accepted, on an
+/// already greeted (and possibly upgraded and authenticated) connection.
+/// RCPT replies become per-recipient outcomes prefixed with `host`; `Err`
+/// means the transaction failed as a whole.
+pub(crate) fn per_recipient(
+    conn: &mut SmtpConnection,
+    host: &str,
+    from: Option<&Address>,
+    recipients: &[Address],
+    message: &[u8],
+) -> Result<Vec<RecipientResult>, SmtpError> {
+    let mut params = Vec::new();
+    if !message.is_ascii() && conn.server_info().supports_feature(Extension::EightBitMime) {
+        params.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    if let Err(err) = conn.command(Mail::new(from.cloned(), params)) {
+        conn.abort();
+        return Err(err);
+    }
+
+    let mut results = Vec::new();
+    let mut accepted = Vec::new();
+    for recipient in recipients {
+        let outcome = match conn.command(Rcpt::new(recipient.clone(), Vec::new())) {
+            Ok(_) => {
+                accepted.push(results.len());
+                RecipientOutcome::Delivered
+            }
+            Err(err) if err.is_permanent() => RecipientOutcome::Rejected(format!("{host}: {err}")),
+            Err(err) if err.is_transient() => RecipientOutcome::Deferred(format!("{host}: {err}")),
+            Err(err) => {
+                conn.abort();
+                return Err(err);
+            }
+        };
+        results.push(RecipientResult {
+            recipient: recipient.to_string(),
+            outcome,
+        });
+    }
+    if accepted.is_empty() {
+        let _ = conn.quit();
+        return Ok(results);
+    }
+
+    let data = conn.command(Data).and_then(|_| conn.message(message));
+    if let Err(err) = data {
+        conn.abort();
+        if !err.is_permanent() && !err.is_transient() {
+            return Err(err);
+        }
+        let outcome = if err.is_permanent() {
+            RecipientOutcome::Rejected(format!("{host}: {err}"))
+        } else {
+            RecipientOutcome::Deferred(format!("{host}: {err}"))
+        };
+        for idx in accepted {
+            results[idx].outcome = outcome.clone();
+        }
+        return Ok(results);
+    }
+    let _ = conn.quit();
+    Ok(results)
+}
+
+impl MailTransport for MxTransport {
+    fn send(&self, message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>> {
+        Ok(self.deliver(message, envelope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::dns::StaticResolver;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    /// Accept SMTP sessions on loopback until the test ends, rejecting
+    /// recipients whose local part starts with `nobody` (550) or `later`
+    /// (450). Every command line received is sent back over the channel.
+    fn smtp_sink() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    writer.write_all(b"220 sink ready\r\n").unwrap();
+                    let mut line = String::new();
+                    let mut in_data = false;
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        if in_data {
+                            if line == ".\r\n" {
+                                in_data = false;
+                                let _ = writer.write_all(b"250 queued\r\n");
+                            }
+                            line.clear();
+                            continue;
+                        }
+                        let _ = tx.send(line.trim_end().to_string());
+                        let upper = line.to_ascii_uppercase();
+                        let reply = if upper.starts_with("EHLO") {
+                            "250 sink"
+                        } else if upper.starts_with("RCPT TO:<NOBODY") {
+                            "550 5.1.1 no such user"
+                        } else if upper.starts_with("RCPT TO:<LATER") {
+                            "450 4.2.1 try again later"
+                        } else if upper.starts_with("DATA") {
+                            in_data = true;
+                            "354 go ahead"
+                        } else if upper.starts_with("QUIT") {
+                            let _ = writer.write_all(b"221 bye\r\n");
+                            break;
+                        } else {
+                            "250 ok"
+                        };
+                        if writer.write_all(format!("{reply}\r\n").as_bytes()).is_err() {
+                            break;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    fn envelope(to: &[&str]) -> Envelope {
+        Envelope::new(
+            Some("owl@example.org".parse().unwrap()),
+            to.iter().map(|addr| addr.parse().unwrap()).collect(),
+        )
+        .unwrap()
+    }
+
+    const MESSAGE: &[u8] = b"From: owl@example.org\r\nSubject: Hello\r\n\r\nHi\r\n";
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn tries_exchangers_in_preference_order() {
+        let (port, rx) = smtp_sink();
+        // 127.0.0.2 shares loopback but nothing listens there, so the
+        // preferred exchanger refuses the connection.
+        let resolver = StaticResolver::new()
+            .with_mx("b.example", 20, "backup.b.example")
+            .with_mx("b.example", 10, "primary.b.example")
+            .with_host("primary.b.example", "127.0.0.2".parse().unwrap())
+            .with_host("backup.b.example", localhost());
+        let transport = MxTransport::new(Arc::new(resolver)).with_port(port);
+        let results = transport.deliver(MESSAGE, &envelope(&["bob@b.example", "carol@B.example"]));
+        assert_eq!(results.len(), 2);
+        assert!(
+            results
+                .iter()
+                .all(|result| result.outcome == RecipientOutcome::Delivered)
+        );
+        let commands: Vec<String> = rx.try_iter().collect();
+        assert!(commands.contains(&"EHLO example.org".to_string()));
+        assert_eq!(
+            commands
+                .iter()
+                .filter(|line| line.starts_with("MAIL FROM"))
+                .count(),
+            1
+        );
+        assert!(
+            transport
+                .send(MESSAGE, &envelope(&["bob@b.example"]))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_address_records_without_mx() {
+        let (port, _rx) = smtp_sink();
+        let resolver = StaticResolver::new().with_host("c.example", localhost());
+        let transport = MxTransport::new(Arc::new(resolver)).with_port(port);
+        let results = transport.deliver(MESSAGE, &envelope(&["dave@c.example"]));
+        assert_eq!(results[0].outcome, RecipientOutcome::Delivered);
+    }
+
+    #[test]
+    fn reports_each_recipient_separately() {
+        let (port, _rx) = smtp_sink();
+        let resolver = StaticResolver::new()
+            .with_mx("d.example", 10, "mx.d.example")
+            .with_host("mx.d.example", localhost())
+            .with_mx("null.example", 0, ".")
+            .fail("broken.example");
+        let transport = MxTransport::new(Arc::new(resolver)).with_port(port);
+        let results = transport.deliver(
+            MESSAGE,
+            &envelope(&[
+                "erin@d.example",
+                "nobody@d.example",
+                "later@d.example",
+                "frank@null.example",
+                "gina@broken.example",
+            ]),
+        );
+        let outcome = |addr: &str| {
+            results
+                .iter()
+                .find(|result| result.recipient == addr)
+                .map(|result| result.outcome.clone())
+                .unwrap()
+        };
+        assert_eq!(outcome("erin@d.example"), RecipientOutcome::Delivered);
+        assert!(matches!(
+            outcome("nobody@d.example"),
+            RecipientOutcome::Rejected(err) if err.contains("550")
+        ));
+        assert!(matches!(
+            outcome("later@d.example"),
+            RecipientOutcome::Deferred(err) if err.contains("450")
+        ));
+        assert!(matches!(
+            outcome("frank@null.example"),
+            RecipientOutcome::Rejected(err) if err.contains("null MX")
+        ));
+        assert!(matches!(
+            outcome("gina@broken.example"),
+            RecipientOutcome::Deferred(err) if err.contains("SERVFAIL")
+        ));
+    }
+
+    #[test]
+    fn rejects_domains_without_exchanger_or_address_records() {
+        let resolver = StaticResolver::new()
+            .with_mx("f.example", 10, "mx.f.example")
+            .with_mx("g.example", 10, "mx.g.example")
+            .fail("mx.g.example");
+        let transport = MxTransport::new(Arc::new(resolver));
+        let results = transport.deliver(
+            MESSAGE,
+            &envelope(&["ivy@nxdomain.example", "jo@f.example", "kim@g.example"]),
+        );
+        let outcome = |addr: &str| {
+            results
+                .iter()
+                .find(|result| result.recipient == addr)
+                .map(|result| result.outcome.clone())
+                .unwrap()
+        };
+        assert!(matches!(
+            outcome("ivy@nxdomain.example"),
+            RecipientOutcome::Rejected(err) if err.starts_with("5.1.2 nxdomain.example")
+        ));
+        assert!(matches!(
+            outcome("jo@f.example"),
+            RecipientOutcome::Rejected(err) if err.starts_with("5.1.2 f.example")
+        ));
+        assert!(matches!(
+            outcome("kim@g.example"),
+            RecipientOutcome::Deferred(_)
+        ));
+    }
+
+    #[test]
+    fn defers_when_no_exchanger_answers() {
+        let resolver = StaticResolver::new()
+            .with_mx("e.example", 10, "mx.e.example")
+            .with_host("mx.e.example", "127.0.0.2".parse().unwrap());
+        let (port, _rx) = smtp_sink();
+        let transport = MxTransport::new(Arc::new(resolver)).with_port(port);
+        let results = transport.deliver(MESSAGE, &envelope(&["hal@e.example"]));
+        assert!(matches!(
+            &results[0].outcome,
+            RecipientOutcome::Deferred(err) if err.starts_with("mx.e.example:")
+        ));
+    }
+}
@@ -1,47 +1,57 @@
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
 use ::ulid::Ulid;
 use anyhow::{Context, Result, anyhow, bail};
-use lettre::Transport;
 use lettre::address::Envelope;
-use lettre::message::{Mailbox, Message, MultiPart};
-use lettre::transport::smtp::{SmtpTransport, authentication::Credentials};
+use lettre::message::{Attachment, Mailbox, Message, MultiPart, SinglePart, header::ContentType};
+use lettre::transport::smtp::{
+    Error as SmtpError,
+    authentication::{Credentials, DEFAULT_MECHANISMS},
+    client::{SmtpConnection, TlsParameters},
+    extension::ClientId,
+};
 use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd, html};
 use sha2::{Digest, Sha256};
-use time::{
-    Duration, OffsetDateTime,
-    format_description::well_known::{Rfc2822, Rfc3339},
-};
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
 
 use crate::{
     envcfg::EnvConfig,
     fsops::{
+        attach::AttachmentStore,
         io_atom::{create_dir_all, write_atomic},
         layout::MailLayout,
+        scan::SENDER_LISTS,
     },
     model::{
+        address::Address,
         filename::{outbox_html_filename, outbox_message_filename, outbox_sidecar_filename},
-        message::{HeadersCache, MessageSidecar, OutboundStatus},
+        message::{
+            HeadersCache, MessageSidecar, OutboundState, OutboundStatus, RecipientState,
+            normalize_date,
+        },
+        rules::MessageContext,
+        settings::ListSettings,
+    },
+    pipeline::{
+        compose::first_plain_part,
+        mx::{self, MxTransport},
+        smtp_in::{collect_mailboxes, plaintext_to_html},
+        thread::{assign_thread_id, record_thread},
+    },
+    ruleset::{
+        eval::{Route, evaluate},
+        loader::{LoadedRules, RulesetLoader},
     },
     util::{
-        dkim::{self, DkimSigner},
+        dkim::{self, Canonicalization, DkimKeyType, DkimSigner, SignOptions},
         logging::{LogLevel, Logger},
         time::parse_interval,
     },
 };
 
-const SIGNED_HEADERS: &[&str] = &[
-    "from",
-    "to",
-    "subject",
-    "date",
-    "mime-version",
-    "content-type",
-];
-
 pub struct OutboxPipeline {
     layout: MailLayout,
     env: EnvConfig,
@@ -53,7 +63,10 @@ pub struct OutboxPipeline {
 impl OutboxPipeline {
     pub fn new(layout: MailLayout, env: EnvConfig, logger: Logger) -> Self {
         let schedule = parse_retry_schedule(&env);
-        let transport = Arc::new(SmtpRelay::from_env(&env));
+        let transport: Arc<dyn MailTransport> = match env.smtp_mode.as_str() {
+            "direct" => Arc::new(MxTransport::system()),
+            _ => Arc::new(SmtpRelay::from_env(&env)),
+        };
         Self {
             layout,
             env,
@@ -80,23 +93,47 @@ impl OutboxPipeline {
     }
 
     pub fn queue_draft(&self, draft_path: &Path) -> Result<PathBuf> {
-        let draft = Draft::from_file(draft_path)?;
-        let material =
-            dkim::ensure_ed25519_keypair(&self.layout.dkim_dir(), &self.env.dkim_selector)?;
-        let signer = DkimSigner::from_material(&material)?;
+        let mut draft = Draft::from_file(draft_path)?;
+        let rules = RulesetLoader::new(self.layout.root()).load()?;
+        let settings = recipient_list_settings(&draft, &rules, self.env.keep_plus_tags);
+        if let Some(settings) = &settings {
+            draft.apply_settings(settings, self.layout.root())?;
+        }
+        let (from, domain) = draft.sender()?;
+        let dkim_dir = self.layout.dkim_dir();
+        let now = OffsetDateTime::now_utc();
+        let selector = dkim::signing_selector(
+            &dkim_dir,
+            DkimKeyType::Ed25519,
+            &self.env.dkim_selector,
+            now,
+        )?;
+        let rsa_selector = self
+            .env
+            .dkim_rsa_selector
+            .as_deref()
+            .map(|configured| dkim::signing_selector(&dkim_dir, DkimKeyType::Rsa, configured, now))
+            .transpose()?;
+        let materials = dkim::ensure_keypairs(&dkim_dir, &selector, rsa_selector.as_deref())?;
+        let signer = DkimSigner::from_materials(&materials)?.with_options(self.sign_options()?);
 
         create_dir_all(&self.layout.outbox())?;
 
-        let text_body = markdown_to_text(&draft.body);
-        let html_body = markdown_to_html(&draft.body);
+        let mut text_body = markdown_to_text(&draft.body);
+        let mut html_body = markdown_to_html(&draft.body);
+        if let Some(signature) = &draft.signature {
+            let block = format!("-- \n{signature}");
+            text_body = format!("{text_body}\n\n{block}");
+            html_body.push_str(&plaintext_to_html(&block));
+        }
 
         let mut builder = Message::builder()
-            .from(draft.from.clone())
+            .from(from.clone())
             .subject(&draft.subject);
 
         let timestamp = OffsetDateTime::now_utc();
         builder = builder.date(timestamp.into());
-        builder = builder.message_id(Some(format!("<{}@{}>", draft.ulid, draft.domain)));
+        builder = builder.message_id(Some(format!("<{}@{}>", draft.ulid, domain)));
 
         if let Some(reply_to) = &draft.reply_to {
             builder = builder.reply_to(reply_to.clone());
@@ -110,15 +147,55 @@ impl OutboxPipeline {
             builder = builder.cc(recipient.clone());
         }
 
-        let multipart = MultiPart::alternative_plain_html(text_body.clone(), html_body.clone());
-        let message = builder.multipart(multipart)?;
+        if let Some(parent) = &draft.in_reply_to {
+            builder = builder.in_reply_to(parent.clone());
+        }
+
+        if !draft.references.is_empty() {
+            builder = builder.references(draft.references.join(" "));
+        }
+
+        let body_format = settings
+            .as_ref()
+            .map(|settings| settings.body_format.as_str())
+            .unwrap_or("both");
+        let attachments = draft
+            .attachments
+            .iter()
+            .map(|entry| resolve_attachment(&self.layout, &draft.dir, entry))
+            .collect::<Result<Vec<_>>>()?;
+        let message = if attachments.is_empty() {
+            match body_format {
+                "plain" => builder.singlepart(SinglePart::plain(text_body.clone()))?,
+                "html" => builder.singlepart(SinglePart::html(html_body.clone()))?,
+                _ => builder.multipart(MultiPart::alternative_plain_html(
+                    text_body.clone(),
+                    html_body.clone(),
+                ))?,
+            }
+        } else {
+            let mut mixed = match body_format {
+                "plain" => MultiPart::mixed().singlepart(SinglePart::plain(text_body.clone())),
+                "html" => MultiPart::mixed().singlepart(SinglePart::html(html_body.clone())),
+                _ => MultiPart::mixed().multipart(MultiPart::alternative_plain_html(
+                    text_body.clone(),
+                    html_body.clone(),
+                )),
+            };
+            for attachment in &attachments {
+                mixed = mixed.singlepart(attachment.part()?);
+            }
+            builder.multipart(mixed)?
+        };
 
         let formatted = message.formatted();
         let (headers_raw, body_bytes) = split_headers_body(&formatted)?;
-        let dkim_value = signer.sign(&draft.domain, &headers_raw, body_bytes, SIGNED_HEADERS)?;
+        let dkim_values = signer.sign(&domain, &headers_raw, body_bytes)?;
 
         let mut final_message = Vec::new();
-        final_message.extend_from_slice(format!("DKIM-Signature: {dkim_value}\r\n").as_bytes());
+        for dkim_value in dkim_values {
+            final_message.extend_from_slice(format!("DKIM-Signature: {dkim_value}\r\n").as_bytes());
+        }
         final_message.extend_from_slice(headers_raw.as_bytes());
         final_message.extend_from_slice(b"\r\n\r\n");
         final_message.extend_from_slice(body_bytes);
@@ -137,12 +214,17 @@ impl OutboxPipeline {
         let sidecar_filename = outbox_sidecar_filename(&draft.ulid);
         let sidecar_path = self.layout.outbox().join(&sidecar_filename);
         let headers_cache = HeadersCache {
-            from: draft.from.to_string(),
+            from: from.to_string(),
             to: draft.to.iter().map(|m| m.to_string()).collect(),
             cc: draft.cc.iter().map(|m| m.to_string()).collect(),
+            bcc: draft.bcc.iter().map(|m| m.to_string()).collect(),
             subject: draft.subject.clone(),
             date: header_value(&headers_raw, "date")
-                .unwrap_or_else(|| timestamp.format(&Rfc2822).unwrap()),
+                .and_then(|value| normalize_date(&value))
+                .unwrap_or_else(|| timestamp.format(&Rfc3339).unwrap()),
+            message_id: header_value(&headers_raw, "message-id"),
+            in_reply_to: draft.in_reply_to.clone(),
+            references: draft.references.clone(),
         };
         let mut sidecar = MessageSidecar::new(
             draft.ulid.clone(),
@@ -153,18 +235,181 @@ impl OutboxPipeline {
             hash_hex,
             headers_cache,
         );
-        sidecar.outbound_state_mut();
+        sidecar.thread_id = Some(assign_thread_id(&self.layout, &sidecar, Some(&self.logger)));
+        if !attachments.is_empty() {
+            let store = AttachmentStore::new(self.layout.attachments("sent"));
+            for attachment in &attachments {
+                let stored = store.store(&attachment.name, &attachment.data)?;
+                sidecar.add_attachment(stored.sha256, attachment.name.clone());
+            }
+        }
+        let queued_at = sidecar.received_at.clone();
+        let outbound = sidecar.outbound_state_mut();
+        outbound.queued_at = Some(queued_at);
+        outbound.signature = draft.signature.clone();
         let yaml = serde_yaml::to_string(&sidecar)?;
         write_atomic(&sidecar_path, yaml.as_bytes())?;
+        record_thread(&self.layout, &sidecar, Some(&self.logger));
 
         Ok(message_path)
     }
 
     pub fn dispatch_pending(&self) -> Result<Vec<DispatchResult>> {
         let mut outcomes = Vec::new();
+        for (path, mut sidecar) in self.outbox_sidecars()? {
+            if sidecar.status_shadow != "outbox" {
+                continue;
+            }
+            let outbound = sidecar.outbound_state_mut();
+            if matches!(
+                outbound.status,
+                OutboundStatus::Sent | OutboundStatus::Failed
+            ) {
+                continue;
+            }
+            if let Some(next) = &outbound.next_attempt_at
+                && let Ok(next_time) = OffsetDateTime::parse(next, &Rfc3339)
+                && next_time > OffsetDateTime::now_utc()
+            {
+                continue;
+            }
+            if let Some(outcome) = self.attempt(sidecar, &path)? {
+                outcomes.push(outcome);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Every sidecar in `outbox/`, ordered by ULID.
+    pub fn entries(&self) -> Result<Vec<MessageSidecar>> {
+        Ok(self
+            .outbox_sidecars()?
+            .into_iter()
+            .map(|(_, sidecar)| sidecar)
+            .collect())
+    }
+
+    /// Dispatch a queued message now, ignoring its backoff.
+    pub fn retry(&self, ulid: &str) -> Result<DispatchResult> {
+        let (path, mut sidecar) = self.find_queued(ulid)?;
+        let outbound = sidecar.outbound_state_mut();
+        if outbound.status == OutboundStatus::Failed {
+            bail!("message {ulid} has failed permanently; use resend");
+        }
+        outbound.next_attempt_at = None;
+        for rcpt in &mut outbound.recipients {
+            rcpt.next_attempt_at = None;
+        }
+        self.attempt(sidecar, &path)?
+            .ok_or_else(|| anyhow!("message {ulid} is missing its .eml"))
+    }
+
+    /// Send a failed message, or a copy from `sent/`, again.
+    ///
+    /// Sent copies move back into `outbox/` first, so an undeliverable resend
+    /// is retried and listed like any other queued message. A partially
+    /// delivered message only goes to the recipients that did not get it.
+    pub fn resend(&self, ulid: &str) -> Result<DispatchResult> {
+        let (path, mut sidecar) = match self.find_queued(ulid) {
+            Ok((path, sidecar)) => {
+                if sidecar
+                    .outbound
+                    .as_ref()
+                    .is_none_or(|outbound| outbound.status != OutboundStatus::Failed)
+                {
+                    bail!("message {ulid} is still queued; use retry");
+                }
+                (path, sidecar)
+            }
+            Err(_) => self.requeue_sent(ulid)?,
+        };
+        let previous = sidecar.outbound.take().unwrap_or_default();
+        let mut outbound = OutboundState {
+            queued_at: Some(OffsetDateTime::now_utc().format(&Rfc3339)?),
+            signature: previous.signature.clone(),
+            ..OutboundState::default()
+        };
+        if previous.is_partial() {
+            outbound.recipients = previous
+                .recipients
+                .into_iter()
+                .map(|rcpt| match rcpt.status {
+                    OutboundStatus::Sent => rcpt,
+                    _ => RecipientState::new(rcpt.address),
+                })
+                .collect();
+        }
+        sidecar.outbound = Some(outbound);
+        self.attempt(sidecar, &path)?
+            .ok_or_else(|| anyhow!("message {ulid} is missing its .eml"))
+    }
+
+    /// Remove a queued message and turn it back into an editable draft.
+    pub fn cancel(&self, ulid: &str) -> Result<PathBuf> {
+        let (path, sidecar) = self.find_queued(ulid)?;
+        let message_path = self.layout.outbox().join(&sidecar.filename);
+        let raw = fs::read(&message_path)
+            .with_context(|| format!("reading {}", message_path.display()))?;
+        let parsed = mailparse::parse_mail(&raw).map_err(|err| anyhow!(err.to_string()))?;
+        let html_path = self.layout.outbox().join(&sidecar.render.html);
+        let body = match first_plain_part(&parsed)? {
+            Some(text) => strip_signature(
+                &text,
+                sidecar
+                    .outbound
+                    .as_ref()
+                    .and_then(|outbound| outbound.signature.as_deref()),
+            ),
+            None => fs::read_to_string(&html_path).unwrap_or_default(),
+        };
+        let headers = &sidecar.headers_cache;
+        let meta = DraftFrontMatter {
+            subject: headers.subject.clone(),
+            from: Some(headers.from.clone()),
+            to: headers.to.clone(),
+            cc: headers.cc.clone(),
+            bcc: headers.bcc.clone(),
+            reply_to: collect_mailboxes(&parsed, "Reply-To").into_iter().next(),
+            in_reply_to: headers.in_reply_to.clone(),
+            references: headers.references.clone(),
+            attachments: sidecar
+                .attachments
+                .iter()
+                .map(|attachment| format!("{}__{}", attachment.sha256, attachment.name))
+                .collect(),
+        };
+        create_dir_all(&self.layout.drafts())?;
+        // Never clobber a draft that reused the name since it was queued.
+        let mut draft_path = self.layout.drafts().join(format!("{}.md", sidecar.ulid));
+        if draft_path.exists() {
+            draft_path = self
+                .layout
+                .drafts()
+                .join(format!("{}.md", crate::util::ulid::generate()));
+        }
+        write_atomic(&draft_path, format_draft(&meta, &body)?.as_bytes())?;
+        fs::remove_file(&message_path)?;
+        if html_path.exists() {
+            fs::remove_file(&html_path)?;
+        }
+        fs::remove_file(&path)?;
+        self.logger.log(
+            LogLevel::Minimal,
+            "outbox.cancelled",
+            Some(&format!(
+                "ulid={} draft={}",
+                sidecar.ulid,
+                draft_path.display()
+            )),
+        )?;
+        Ok(draft_path)
+    }
+
+    fn outbox_sidecars(&self) -> Result<Vec<(PathBuf, MessageSidecar)>> {
+        let mut sidecars = Vec::new();
         let outbox_dir = self.layout.outbox();
         if !outbox_dir.exists() {
-            return Ok(outcomes);
+            return Ok(sidecars);
         }
         for entry in fs::read_dir(&outbox_dir)? {
             let entry = entry?;
@@ -176,72 +421,270 @@ impl OutboxPipeline {
                 continue;
             }
             let yaml = fs::read_to_string(&path)?;
-            let mut sidecar: MessageSidecar = serde_yaml::from_str(&yaml)?;
-            if sidecar.status_shadow != "outbox" {
-                continue;
+            let sidecar: MessageSidecar = serde_yaml::from_str(&yaml)?;
+            sidecars.push((path, sidecar));
+        }
+        sidecars.sort_by(|a, b| a.1.ulid.cmp(&b.1.ulid));
+        Ok(sidecars)
+    }
+
+    fn find_queued(&self, ulid: &str) -> Result<(PathBuf, MessageSidecar)> {
+        self.outbox_sidecars()?
+            .into_iter()
+            .find(|(_, sidecar)| {
+                sidecar.status_shadow == "outbox" && sidecar.ulid.eq_ignore_ascii_case(ulid)
+            })
+            .ok_or_else(|| anyhow!("message {ulid} not found in outbox"))
+    }
+
+    /// Move a `sent/` copy back into `outbox/` for another delivery.
+    fn requeue_sent(&self, ulid: &str) -> Result<(PathBuf, MessageSidecar)> {
+        let sent_dir = self.layout.sent();
+        let sidecar_path = sent_dir.join(outbox_sidecar_filename(&ulid.to_ascii_uppercase()));
+        if !sidecar_path.exists() {
+            bail!("message {ulid} not found in outbox or sent");
+        }
+        let mut sidecar: MessageSidecar = serde_yaml::from_str(
+            &fs::read_to_string(&sidecar_path)
+                .with_context(|| format!("reading {}", sidecar_path.display()))?,
+        )?;
+        let outbox_dir = self.layout.outbox();
+        create_dir_all(&outbox_dir)?;
+        fs::rename(
+            sent_dir.join(&sidecar.filename),
+            outbox_dir.join(&sidecar.filename),
+        )?;
+        let html_path = sent_dir.join(&sidecar.render.html);
+        if html_path.exists() {
+            fs::rename(&html_path, outbox_dir.join(&sidecar.render.html))?;
+        }
+        sidecar.status_shadow = "outbox".to_string();
+        sidecar.touch();
+        let path = outbox_dir.join(sidecar_path.file_name().unwrap());
+        write_atomic(&path, serde_yaml::to_string(&sidecar)?.as_bytes())?;
+        fs::remove_file(&sidecar_path)?;
+        Ok((path, sidecar))
+    }
+
+    /// Send one outbox message to its recipients that are due and record
+    /// each outcome in the sidecar. Returns `None` when the `.eml` is missing
+    /// or no recipient is due yet.
+    fn attempt(&self, mut sidecar: MessageSidecar, path: &Path) -> Result<Option<DispatchResult>> {
+        let mut outbound = sidecar.outbound.take().unwrap_or_default();
+        let message_path = self.layout.outbox().join(&sidecar.filename);
+        if !message_path.exists() {
+            self.logger.log(
+                LogLevel::Minimal,
+                "outbox.missing_eml",
+                Some(&format!("file={}", message_path.display())),
+            )?;
+            return Ok(None);
+        }
+        let eml = fs::read(&message_path)?;
+        let now = OffsetDateTime::now_utc();
+        let envelope = build_envelope(&sidecar);
+        if let Ok(envelope) = &envelope {
+            if outbound.recipients.is_empty() {
+                outbound.recipients = envelope
+                    .to()
+                    .iter()
+                    .map(|addr| RecipientState::new(addr.to_string()))
+                    .collect();
             }
-            let mut outbound = sidecar.outbound.take().unwrap_or_default();
-            if outbound.status == OutboundStatus::Sent {
-                sidecar.outbound = Some(outbound);
-                continue;
+            if !outbound.recipients.iter().any(|rcpt| {
+                rcpt.status == OutboundStatus::Pending
+                    && is_due(rcpt.next_attempt_at.as_deref(), now)
+            }) {
+                return Ok(None);
             }
-            if let Some(next) = &outbound.next_attempt_at
-                && let Ok(next_time) = OffsetDateTime::parse(next, &Rfc3339)
-                && next_time > OffsetDateTime::now_utc()
-            {
-                sidecar.outbound = Some(outbound);
-                continue;
+        }
+        outbound.attempts += 1;
+        let queued_at = outbound
+            .queued_at
+            .clone()
+            .unwrap_or_else(|| sidecar.received_at.clone());
+        let queued_at = queued_at.as_str();
+        match envelope {
+            Ok(envelope) => {
+                let mut results = Vec::new();
+                let mut targets = Vec::new();
+                for rcpt in outbound.recipients.iter().filter(|rcpt| {
+                    rcpt.status == OutboundStatus::Pending
+                        && is_due(rcpt.next_attempt_at.as_deref(), now)
+                }) {
+                    match lettre::Address::from_str(&rcpt.address) {
+                        Ok(address) => targets.push(address),
+                        Err(err) => results.push(RecipientResult {
+                            recipient: rcpt.address.clone(),
+                            outcome: RecipientOutcome::Rejected(format!(
+                                "invalid recipient address: {err}"
+                            )),
+                        }),
+                    }
+                }
+                if !targets.is_empty() {
+                    match Envelope::new(envelope.from().cloned(), targets.clone()) {
+                        Ok(targets) => match self.transport.send(&eml, &targets) {
+                            Ok(sent) => results.extend(sent),
+                            Err(err) => {
+                                let detail = err.to_string();
+                                let outcome = if err.is::<PermanentFailure>() {
+                                    RecipientOutcome::Rejected(detail)
+                                } else {
+                                    RecipientOutcome::Deferred(detail)
+                                };
+                                results.extend(all_recipients(&targets, outcome));
+                            }
+                        },
+                        // An envelope that cannot be built now never will be.
+                        Err(err) => results.extend(targets.iter().map(|address| RecipientResult {
+                            recipient: address.to_string(),
+                            outcome: RecipientOutcome::Rejected(format!("invalid envelope: {err}")),
+                        })),
+                    }
+                }
+                for result in results {
+                    let Some(rcpt) = outbound
+                        .recipients
+                        .iter_mut()
+                        .find(|rcpt| rcpt.address.eq_ignore_ascii_case(&result.recipient))
+                    else {
+                        continue;
+                    };
+                    rcpt.attempts += 1;
+                    match result.outcome {
+                        RecipientOutcome::Delivered => {
+                            rcpt.status = OutboundStatus::Sent;
+                            rcpt.last_error = None;
+                            rcpt.next_attempt_at = None;
+                        }
+                        RecipientOutcome::Deferred(err)
+                            if !self.retries_exhausted(queued_at, rcpt.attempts) =>
+                        {
+                            let delay = next_delay(rcpt.attempts, &self.retry_schedule);
+                            rcpt.status = OutboundStatus::Pending;
+                            rcpt.last_error = Some(err);
+                            rcpt.next_attempt_at = Some((now + delay).format(&Rfc3339)?);
+                        }
+                        RecipientOutcome::Deferred(err) | RecipientOutcome::Rejected(err) => {
+                            rcpt.status = OutboundStatus::Failed;
+                            rcpt.last_error = Some(err);
+                            rcpt.next_attempt_at = None;
+                        }
+                    }
+                }
+                let errors: Vec<String> = outbound
+                    .recipients
+                    .iter()
+                    .filter(|rcpt| rcpt.status != OutboundStatus::Sent)
+                    .filter_map(|rcpt| {
+                        let err = rcpt.last_error.as_ref()?;
+                        Some(format!("{}: {err}", rcpt.address))
+                    })
+                    .collect();
+                let (_, pending, failed) = outbound.recipient_counts();
+                outbound.status = if pending > 0 {
+                    OutboundStatus::Pending
+                } else if failed > 0 {
+                    OutboundStatus::Failed
+                } else {
+                    OutboundStatus::Sent
+                };
+                outbound.last_error = (!errors.is_empty()).then(|| errors.join("; "));
+                outbound.next_attempt_at = outbound
+                    .recipients
+                    .iter()
+                    .filter(|rcpt| rcpt.status == OutboundStatus::Pending)
+                    .filter_map(|rcpt| rcpt.next_attempt_at.as_deref())
+                    .filter_map(|next| OffsetDateTime::parse(next, &Rfc3339).ok())
+                    .min()
+                    .map(|next| next.format(&Rfc3339))
+                    .transpose()?;
+            }
+            Err(err) => {
+                outbound.status = OutboundStatus::Failed;
+                outbound.last_error = Some(format!("{err:#}"));
+                outbound.next_attempt_at = None;
             }
-            let message_path = outbox_dir.join(&sidecar.filename);
-            if !message_path.exists() {
-                self.logger.log(
-                    LogLevel::Minimal,
-                    "outbox.missing_eml",
-                    Some(&format!("file={}", message_path.display())),
-                )?;
+        }
+
+        let (sent, _, _) = outbound.recipient_counts();
+        let progress = format!("delivered={sent}/{}", outbound.recipients.len());
+        let outcome = match outbound.status {
+            OutboundStatus::Sent => {
+                sidecar.status_shadow = "sent".to_string();
+                sidecar.touch();
+                let detail = format!("ulid={} attempts={}", sidecar.ulid, outbound.attempts);
+                self.logger
+                    .log(LogLevel::Minimal, "outbox.sent", Some(&detail))?;
                 sidecar.outbound = Some(outbound);
-                continue;
+                self.finish_dispatch(&sidecar, &message_path, path)?;
+                return Ok(Some(DispatchResult::Sent(sidecar.ulid)));
             }
-            let eml = fs::read(&message_path)?;
-            outbound.attempts += 1;
-            let send_result = self.transport.send(&eml, &sidecar);
-            match send_result {
-                Ok(()) => {
-                    outbound.status = OutboundStatus::Sent;
-                    outbound.last_error = None;
-                    outbound.next_attempt_at = None;
-                    sidecar.status_shadow = "sent".to_string();
-                    sidecar.touch();
-                    let detail = format!("ulid={} attempts={}", sidecar.ulid, outbound.attempts);
-                    self.logger
-                        .log(LogLevel::Minimal, "outbox.sent", Some(&detail))?;
-                    sidecar.outbound = Some(outbound);
-                    self.finish_dispatch(&sidecar, &message_path, &path)?;
-                    outcomes.push(DispatchResult::Sent(sidecar.ulid.clone()));
-                }
-                Err(err) => {
-                    outbound.status = OutboundStatus::Pending;
-                    outbound.last_error = Some(err.to_string());
-                    let delay = next_delay(outbound.attempts, &self.retry_schedule);
-                    let next_attempt = OffsetDateTime::now_utc() + delay;
-                    outbound.next_attempt_at = Some(next_attempt.format(&Rfc3339)?);
-                    let detail = format!(
-                        "ulid={} attempts={} next={} error={}",
-                        sidecar.ulid,
-                        outbound.attempts,
-                        outbound.next_attempt_at.as_deref().unwrap_or("unknown"),
-                        err
-                    );
-                    self.logger
-                        .log(LogLevel::Minimal, "outbox.retry", Some(&detail))?;
-                    sidecar.outbound = Some(outbound);
-                    let yaml = serde_yaml::to_string(&sidecar)?;
-                    write_atomic(&path, yaml.as_bytes())?;
-                    outcomes.push(DispatchResult::Retry(sidecar.ulid.clone()));
-                }
+            OutboundStatus::Failed => {
+                let detail = format!(
+                    "ulid={} attempts={} {progress} error={}",
+                    sidecar.ulid,
+                    outbound.attempts,
+                    outbound.last_error.as_deref().unwrap_or("unknown")
+                );
+                self.logger
+                    .log(LogLevel::Minimal, "outbox.failed", Some(&detail))?;
+                DispatchResult::Failed(sidecar.ulid.clone())
+            }
+            OutboundStatus::Pending => {
+                let detail = format!(
+                    "ulid={} attempts={} {progress} next={} error={}",
+                    sidecar.ulid,
+                    outbound.attempts,
+                    outbound.next_attempt_at.as_deref().unwrap_or("unknown"),
+                    outbound.last_error.as_deref().unwrap_or("unknown")
+                );
+                self.logger
+                    .log(LogLevel::Minimal, "outbox.retry", Some(&detail))?;
+                DispatchResult::Retry(sidecar.ulid.clone())
+            }
+        };
+        sidecar.outbound = Some(outbound);
+        let yaml = serde_yaml::to_string(&sidecar)?;
+        write_atomic(path, yaml.as_bytes())?;
+        Ok(Some(outcome))
+    }
+
+    /// DKIM signing options from the `dkim_*` keys in `.env`.
+    fn sign_options(&self) -> Result<SignOptions> {
+        let (header_canonicalization, body_canonicalization) =
+            Canonicalization::parse_pair(&self.env.dkim_canonicalization)?;
+        let expiry = match self.env.dkim_expiry.as_deref() {
+            Some(value) => {
+                Some(parse_interval(value).ok_or_else(|| anyhow!("invalid dkim_expiry: {value}"))?)
             }
+            None => None,
+        };
+        Ok(SignOptions {
+            header_canonicalization,
+            body_canonicalization,
+            headers: self.env.dkim_signed_headers.clone(),
+            oversign: self.env.dkim_oversign_headers.clone(),
+            expiry,
+        })
+    }
+
+    /// Whether a temporary failure has used up `retry_max_attempts` or
+    /// outlived `retry_max_age` since the message was queued.
+    fn retries_exhausted(&self, queued_at: &str, attempts: u32) -> bool {
+        if self
+            .env
+            .retry_max_attempts
+            .is_some_and(|max| attempts >= max)
+        {
+            return true;
         }
-        Ok(outcomes)
+        let Some(max_age) = self.env.retry_max_age.as_deref().and_then(parse_interval) else {
+            return false;
+        };
+        OffsetDateTime::parse(queued_at, &Rfc3339)
+            .is_ok_and(|queued| queued + max_age <= OffsetDateTime::now_utc())
     }
 
     fn finish_dispatch(
@@ -291,6 +734,12 @@ fn parse_retry_schedule(env: &EnvConfig) -> Vec<Duration> {
     schedule
 }
 
+fn is_due(next_attempt_at: Option<&str>, now: OffsetDateTime) -> bool {
+    next_attempt_at
+        .and_then(|next| OffsetDateTime::parse(next, &Rfc3339).ok())
+        .is_none_or(|next| next <= now)
+}
+
 fn next_delay(attempts: u32, schedule: &[Duration]) -> Duration {
     if schedule.is_empty() {
         return Duration::minutes(1);
@@ -355,47 +804,103 @@ fn markdown_to_text(markdown: &str) -> String {
 struct Draft {
     ulid: String,
     subject: String,
-    from: Mailbox,
+    from: Option<Mailbox>,
     to: Vec<Mailbox>,
     cc: Vec<Mailbox>,
+    bcc: Vec<Mailbox>,
     reply_to: Option<Mailbox>,
+    in_reply_to: Option<String>,
+    references: Vec<String>,
     body: String,
-    domain: String,
+    signature: Option<String>,
+    attachments: Vec<String>,
+    dir: PathBuf,
 }
 
 pub trait MailTransport: Send + Sync {
-    fn send(&self, message: &[u8], sidecar: &MessageSidecar) -> Result<()>;
+    /// Deliver `message` to the envelope recipients and report each one.
+    /// `Err` means the attempt failed as a whole: a `PermanentFailure` rejects
+    /// every recipient, anything else defers them.
+    fn send(&self, message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>>;
 }
 
+const RELAY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Relays through `smtp_host`, one RCPT per recipient so the relay's
+/// replies are recorded per address.
+#[derive(Debug)]
 pub struct SmtpRelay {
-    inner: SmtpTransport,
+    host: String,
+    port: u16,
+    starttls: bool,
+    credentials: Option<Credentials>,
 }
 
 impl SmtpRelay {
     pub fn from_env(env: &EnvConfig) -> Self {
-        let host = env.smtp_host.as_deref().unwrap_or("127.0.0.1");
-        let mut builder = if env.smtp_starttls {
-            SmtpTransport::relay(host).unwrap_or_else(|_| SmtpTransport::builder_dangerous(host))
-        } else {
-            SmtpTransport::builder_dangerous(host)
+        let credentials = match (&env.smtp_username, &env.smtp_password) {
+            (Some(user), Some(pass)) => Some(Credentials::new(user.clone(), pass.clone())),
+            _ => None,
         };
-        builder = builder.port(env.smtp_port);
-        if let (Some(user), Some(pass)) = (&env.smtp_username, &env.smtp_password) {
-            builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
-        }
         Self {
-            inner: builder.build(),
+            host: env
+                .smtp_host
+                .clone()
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: env.smtp_port,
+            starttls: env.smtp_starttls,
+            credentials,
+        }
+    }
+
+    /// Connect, upgrade and authenticate; `Err` fails every recipient alike.
+    fn connect(&self) -> Result<SmtpConnection, SmtpError> {
+        let helo = ClientId::default();
+        let mut conn = SmtpConnection::connect(
+            (self.host.as_str(), self.port),
+            Some(RELAY_TIMEOUT),
+            &helo,
+            None,
+            None,
+        )?;
+        if self.starttls {
+            let upgraded =
+                TlsParameters::new(self.host.clone()).and_then(|tls| conn.starttls(&tls, &helo));
+            if let Err(err) = upgraded {
+                conn.abort();
+                return Err(err);
+            }
+        }
+        if let Some(credentials) = &self.credentials
+            && let Err(err) = conn.auth(DEFAULT_MECHANISMS, credentials)
+        {
+            conn.abort();
+            return Err(err);
         }
+        Ok(conn)
     }
 }
 
 impl MailTransport for SmtpRelay {
-    fn send(&self, message: &[u8], sidecar: &MessageSidecar) -> Result<()> {
-        let envelope = build_envelope(sidecar)?;
-        self.inner
-            .send_raw(&envelope, message)
-            .map_err(|err| anyhow!("smtp send failed: {err}"))
-            .map(|_| ())
+    fn send(&self, message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>> {
+        self.connect()
+            .and_then(|mut conn| {
+                mx::per_recipient(
+                    &mut conn,
+                    &self.host,
+                    envelope.from(),
+                    envelope.to(),
+                    message,
+                )
+            })
+            .map_err(|err| {
+                let detail = format!("smtp send failed: {err}");
+                if err.is_permanent() {
+                    PermanentFailure(detail).into()
+                } else {
+                    anyhow!(detail)
+                }
+            })
     }
 }
 
@@ -403,8 +908,44 @@ impl MailTransport for SmtpRelay {
 pub enum DispatchResult {
     Sent(String),
     Retry(String),
+    Failed(String),
+}
+
+/// What happened to one envelope recipient during a delivery attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecipientOutcome {
+    Delivered,
+    /// Temporary failure; worth retrying later.
+    Deferred(String),
+    /// Permanent failure; retrying will not help.
+    Rejected(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientResult {
+    pub recipient: String,
+    pub outcome: RecipientOutcome,
+}
+
+/// The same outcome for every recipient in `envelope`, for transports that
+/// deliver in a single all-or-nothing transaction.
+pub fn all_recipients(envelope: &Envelope, outcome: RecipientOutcome) -> Vec<RecipientResult> {
+    envelope
+        .to()
+        .iter()
+        .map(|addr| RecipientResult {
+            recipient: addr.to_string(),
+            outcome: outcome.clone(),
+        })
+        .collect()
 }
 
+/// A rejection that retrying cannot fix: an SMTP 5xx reply or a recipient
+/// that cannot be addressed at all.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct PermanentFailure(pub String);
+
 impl Draft {
     fn from_file(path: &Path) -> Result<Self> {
         let stem = path
@@ -426,17 +967,19 @@ impl Draft {
             from,
             to,
             cc,
+            bcc,
             reply_to,
+            in_reply_to,
+            references,
+            attachments,
         } = meta;
-        let from_raw = from.ok_or_else(|| anyhow!("draft front matter missing 'from'"))?;
-        let from = parse_mailbox(&from_raw)?;
-        let address = from.email.to_string();
-        let domain = address
-            .rsplit_once('@')
-            .map(|(_, domain)| domain.to_string())
-            .ok_or_else(|| anyhow!("from address missing domain"))?;
+        let from = match from {
+            Some(value) => Some(parse_mailbox(&value)?),
+            None => None,
+        };
         let to = parse_mailboxes(&to)?;
         let cc = parse_mailboxes(&cc)?;
+        let bcc = parse_mailboxes(&bcc)?;
         let reply_to = match reply_to {
             Some(value) => Some(parse_mailbox(&value)?),
             None => None,
@@ -448,11 +991,188 @@ impl Draft {
             from,
             to,
             cc,
+            bcc,
             reply_to,
+            in_reply_to,
+            references,
             body,
-            domain,
+            signature: None,
+            attachments,
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
         })
     }
+
+    /// Fill From/Reply-To from list settings, then append the signature.
+    fn apply_settings(&mut self, settings: &ListSettings, root: &Path) -> Result<()> {
+        if self.from.is_none()
+            && let Some(from) = settings.from.as_deref().filter(|v| !v.trim().is_empty())
+        {
+            self.from = Some(parse_mailbox(from)?);
+        }
+        if self.reply_to.is_none()
+            && let Some(reply_to) = settings
+                .reply_to
+                .as_deref()
+                .filter(|v| !v.trim().is_empty())
+        {
+            self.reply_to = Some(parse_mailbox(reply_to)?);
+        }
+        if let Some(raw) = settings
+            .signature
+            .as_deref()
+            .filter(|v| !v.trim().is_empty())
+        {
+            let path = signature_path(root, raw.trim())?;
+            let signature = fs::read_to_string(&path)
+                .with_context(|| format!("reading signature {}", path.display()))?;
+            let signature = signature.trim_end();
+            if !signature.is_empty() {
+                self.signature = Some(signature.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The resolved From mailbox and the domain used for Message-ID and DKIM.
+    fn sender(&self) -> Result<(&Mailbox, String)> {
+        let from = self
+            .from
+            .as_ref()
+            .ok_or_else(|| anyhow!("draft front matter missing 'from'"))?;
+        let address = from.email.to_string();
+        let domain = address
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_string())
+            .ok_or_else(|| anyhow!("from address missing domain"))?;
+        Ok((from, domain))
+    }
+}
+
+/// Settings of the first To/Cc recipient that routes into a list.
+fn recipient_list_settings(
+    draft: &Draft,
+    rules: &LoadedRules,
+    keep_plus_tags: bool,
+) -> Option<ListSettings> {
+    for recipient in draft.to.iter().chain(&draft.cc) {
+        let Ok(address) = Address::parse(recipient.email.as_ref(), keep_plus_tags) else {
+            continue;
+        };
+        let list = match evaluate(
+            &MessageContext::new(&address),
+            &rules.accepted.rules,
+            &rules.spam.rules,
+            &rules.banned.rules,
+        ) {
+            Route::Accepted => &rules.accepted,
+            Route::Spam => &rules.spam,
+            Route::Banned => &rules.banned,
+            Route::Quarantine => continue,
+        };
+        return Some(list.settings.clone());
+    }
+    None
+}
+
+struct OutgoingAttachment {
+    name: String,
+    data: Vec<u8>,
+}
+
+impl OutgoingAttachment {
+    fn part(&self) -> Result<SinglePart> {
+        let mime = mime_guess::from_path(&self.name).first_or_octet_stream();
+        let content_type = ContentType::parse(mime.essence_str())
+            .map_err(|err| anyhow!("invalid content type for {}: {err}", self.name))?;
+        Ok(Attachment::new(self.name.clone()).body(self.data.clone(), content_type))
+    }
+}
+
+/// Load a draft attachment: a path relative to the draft first, then a
+/// `<sha256>__name` blob from any list's attachment store. For drafts kept
+/// in `drafts/`, paths must stay inside it, so a draft cannot mail out
+/// `.env` or keys.
+fn resolve_attachment(
+    layout: &MailLayout,
+    draft_dir: &Path,
+    entry: &str,
+) -> Result<OutgoingAttachment> {
+    let draft_dir = if draft_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        draft_dir
+    };
+    let base = draft_dir
+        .canonicalize()
+        .with_context(|| format!("resolving {}", draft_dir.display()))?;
+    let drafts = layout.drafts().canonicalize().ok();
+    let contained = drafts.as_ref().filter(|drafts| base.starts_with(drafts));
+    let relative = Path::new(entry);
+    if contained.is_some()
+        && !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("attachment {entry} must be a path inside drafts/");
+    }
+    let path = base.join(relative);
+    if path.is_file() {
+        if let Some(drafts) = contained {
+            let resolved = path
+                .canonicalize()
+                .with_context(|| format!("resolving {}", path.display()))?;
+            if !resolved.starts_with(drafts) {
+                bail!("attachment {entry} must be a path inside drafts/");
+            }
+        }
+        let data = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("attachment {entry} has no file name"))?
+            .to_string();
+        return Ok(OutgoingAttachment { name, data });
+    }
+    if let Some((digest, name)) = entry.split_once("__")
+        && digest.len() == 64
+        && digest.chars().all(|c| c.is_ascii_hexdigit())
+        && !entry.contains('/')
+    {
+        for list in SENDER_LISTS.iter().chain(&["sent"]) {
+            let store = AttachmentStore::new(layout.attachments(list));
+            if let Ok(data) = store.load(entry) {
+                return Ok(OutgoingAttachment {
+                    name: name.to_string(),
+                    data,
+                });
+            }
+        }
+    }
+    bail!("attachment {entry} not found")
+}
+
+/// Drop the `-- ` block `apply_settings` appended, the inverse of
+/// `queue_draft`. Text that does not end with exactly that signature, such as
+/// a `-- ` the author wrote, is kept.
+fn strip_signature(text: &str, signature: Option<&str>) -> String {
+    let text = text.replace("\r\n", "\n");
+    let text = text.trim_end();
+    let Some(signature) = signature else {
+        return text.to_string();
+    };
+    let block = format!("\n-- \n{}", signature.replace("\r\n", "\n").trim_end());
+    match text.strip_suffix(&block) {
+        Some(body) => body.trim_end().to_string(),
+        None => text.to_string(),
+    }
+}
+
+fn signature_path(root: &Path, raw: &str) -> Result<PathBuf> {
+    if let Some(rest) = raw.strip_prefix("~/") {
+        let home = std::env::var("HOME").context("$HOME is not set")?;
+        return Ok(PathBuf::from(home).join(rest));
+    }
+    Ok(root.join(raw))
 }
 
 fn parse_mailbox(value: &str) -> Result<Mailbox> {
@@ -480,7 +1200,7 @@ fn split_front_matter(contents: &str) -> Result<(String, String)> {
     bail!("draft missing closing front matter delimiter")
 }
 
-fn build_envelope(sidecar: &MessageSidecar) -> Result<Envelope> {
+pub(crate) fn build_envelope(sidecar: &MessageSidecar) -> Result<Envelope> {
     let from_mailbox = Mailbox::from_str(&sidecar.headers_cache.from)
         .map_err(|err| anyhow!("invalid from address: {err}"))?;
     let mut recipients = Vec::new();
@@ -489,6 +1209,7 @@ fn build_envelope(sidecar: &MessageSidecar) -> Result<Envelope> {
         .to
         .iter()
         .chain(sidecar.headers_cache.cc.iter())
+        .chain(sidecar.headers_cache.bcc.iter())
     {
         let parsed =
             Mailbox::from_str(entry).map_err(|err| anyhow!("invalid recipient {entry}: {err}"))?;
@@ -501,15 +1222,34 @@ fn build_envelope(sidecar: &MessageSidecar) -> Result<Envelope> {
         .map_err(|err| anyhow!("failed to build envelope: {err}"))
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct DraftFrontMatter {
-    subject: String,
-    from: Option<String>,
-    to: Vec<String>,
-    #[serde(default)]
-    cc: Vec<String>,
-    #[serde(default)]
-    reply_to: Option<String>,
+/// YAML front matter at the top of a `drafts/<ULID>.md` file.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DraftFrontMatter {
+    pub subject: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+    /// Envelope-only recipients; recorded in the sidecar but never in headers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    /// Files relative to the draft, or `<sha256>__name` blobs already in an
+    /// attachment store.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+}
+
+/// Render front matter and a Markdown body into draft file contents.
+pub fn format_draft(meta: &DraftFrontMatter, body: &str) -> Result<String> {
+    let yaml = serde_yaml::to_string(meta)?;
+    Ok(format!("---\n{yaml}---\n{body}"))
 }
 
 #[cfg(test)]
@@ -709,97 +1449,887 @@ mod tests {
     }
 
     #[test]
-    fn dispatch_moves_successful_message() {
+    fn queue_draft_applies_recipient_list_settings() {
         let (_dir, layout, env, logger) = test_env();
-        let transport = Arc::new(RecordingTransport::success());
-        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        fs::write(layout.accepted().join(".rules"), "bob@example.org\n").unwrap();
+        fs::write(
+            layout.accepted().join(".settings"),
+            "from=Team <team@example.org>\nreply_to=help@example.org\nsignature=sig.txt\nbody_format=plain\n",
+        )
+        .unwrap();
+        fs::write(layout.root().join("sig.txt"), "Owl team\n").unwrap();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
         let draft_ulid = crate::util::ulid::generate();
         let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
         fs::write(
             &draft_path,
-            "---\nsubject: Dispatch\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nBody\n",
+            "---\nsubject: Hi\nto:\n  - Bob <BOB@example.org>\n---\nHello\n",
         )
         .unwrap();
+
         pipeline.queue_draft(&draft_path).unwrap();
-        let outcomes = pipeline.dispatch_pending().unwrap();
-        assert!(matches!(outcomes.first(), Some(DispatchResult::Sent(_))));
-        let sent_path = layout.sent().join(outbox_message_filename(&draft_ulid));
-        assert!(sent_path.exists());
-        assert!(
-            !layout
-                .outbox()
-                .join(outbox_message_filename(&draft_ulid))
-                .exists()
-        );
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        assert!(message.contains("From: Team <team@example.org>"));
+        assert!(message.contains("Reply-To: help@example.org"));
+        assert!(message.contains("Message-ID: <"));
+        assert!(message.contains("@example.org>"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(!message.contains("multipart"));
+        assert!(message.contains("Hello\r\n\r\n-- \r\nOwl team"));
+        let html =
+            fs::read_to_string(layout.outbox().join(outbox_html_filename(&draft_ulid))).unwrap();
+        assert!(html.contains("<pre>-- \nOwl team</pre>"));
     }
 
     #[test]
-    fn dispatch_records_retry_on_failure() {
+    fn queue_draft_explicit_headers_override_settings() {
         let (_dir, layout, env, logger) = test_env();
-        let transport = Arc::new(RecordingTransport::fail());
-        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        fs::write(layout.spam().join(".rules"), "@spam.example\n").unwrap();
+        fs::write(
+            layout.spam().join(".settings"),
+            "from=Nobody <nobody@example.org>\nbody_format=html\n",
+        )
+        .unwrap();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
         let draft_ulid = crate::util::ulid::generate();
         let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
         fs::write(
             &draft_path,
-            "---\nsubject: Retry\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nBody\n",
+            "---\nsubject: Hi\nfrom: Owl <owl@example.net>\nto:\n  - x@spam.example\n---\nHello\n",
         )
         .unwrap();
+
         pipeline.queue_draft(&draft_path).unwrap();
-        let outcomes = pipeline.dispatch_pending().unwrap();
-        assert!(matches!(outcomes.first(), Some(DispatchResult::Retry(_))));
-        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&draft_ulid));
-        let sidecar: MessageSidecar =
-            serde_yaml::from_str(&fs::read_to_string(sidecar_path).unwrap()).unwrap();
-        let outbound = sidecar.outbound.unwrap();
-        assert_eq!(outbound.attempts, 1);
-        assert!(outbound.last_error.unwrap().contains("forced"));
-        assert!(outbound.next_attempt_at.is_some());
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        assert!(message.contains("From: Owl <owl@example.net>"));
+        assert!(message.contains("Content-Type: text/html"));
+        assert!(!message.contains("multipart"));
     }
 
     #[test]
-    fn dispatch_pending_returns_empty_without_outbox_dir() {
+    fn queue_draft_requires_from_without_list_settings() {
         let (_dir, layout, env, logger) = test_env();
-        fs::remove_dir_all(layout.outbox()).unwrap();
-        let pipeline = OutboxPipeline::new(layout, env, logger);
-        let outcomes = pipeline.dispatch_pending().unwrap();
-        assert!(outcomes.is_empty());
+        fs::write(
+            layout.accepted().join(".settings"),
+            "from=Team <team@example.org>\n",
+        )
+        .unwrap();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let draft_path = layout
+            .drafts()
+            .join(format!("{}.md", crate::util::ulid::generate()));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Hi\nto:\n  - stranger@example.com\n---\nHello\n",
+        )
+        .unwrap();
+        let err = pipeline.queue_draft(&draft_path).unwrap_err();
+        assert!(err.to_string().contains("missing 'from'"));
     }
 
     #[test]
-    fn dispatch_pending_skips_directories_and_non_outbox_entries() {
-        let (_dir, layout, env, logger) = test_env();
-        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
-        let outbox_dir = layout.outbox();
-        create_dir_all(&outbox_dir).unwrap();
-        fs::create_dir(outbox_dir.join("subdir")).unwrap();
-
-        let mut sidecar = MessageSidecar::new(
-            "01ARZ3NDEKTSV4RRFFQ69G5FB0",
-            "draft.eml",
-            "draft",
-            "strict",
-            "draft.html",
-            "deadbeef",
-            HeadersCache::new("Alice", "Draft"),
-        );
-        sidecar.set_plain_render("draft.txt");
-        let sidecar_path = outbox_dir.join("draft.yml");
-        write_atomic(
-            &sidecar_path,
-            serde_yaml::to_string(&sidecar).unwrap().as_bytes(),
+    fn missing_signature_file_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01ARZ3NDEKTSV4RRFFQ69G5FAV.md");
+        fs::write(
+            &path,
+            "---\nsubject: hi\nto:\n  - bob@example.org\n---\nbody\n",
         )
         .unwrap();
-
-        let outcomes = pipeline.dispatch_pending().unwrap();
-        assert!(outcomes.is_empty());
-        assert!(sidecar_path.exists());
+        let mut draft = Draft::from_file(&path).unwrap();
+        let settings = ListSettings {
+            signature: Some("missing.txt".into()),
+            ..ListSettings::default()
+        };
+        let err = draft.apply_settings(&settings, dir.path()).unwrap_err();
+        assert!(err.to_string().contains("reading signature"));
     }
 
     #[test]
-    fn finish_dispatch_moves_related_files() {
-        let (_dir, layout, mut env, logger) = test_env();
-        env.render_mode = "strict".into();
+    fn queue_draft_keeps_bcc_out_of_message() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Quiet\nfrom: owl@example.org\nto:\n  - bob@example.org\nbcc:\n  - Eve <eve@example.net>\n---\nBody\n",
+        )
+        .unwrap();
+
+        pipeline.queue_draft(&draft_path).unwrap();
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        assert!(!message.to_ascii_lowercase().contains("bcc"));
+        assert!(!message.contains("eve@example.net"));
+        let signature = dkim::extract_header(&message, "dkim-signature").unwrap();
+        assert!(!signature.to_ascii_lowercase().contains("bcc"));
+
+        let sidecar: MessageSidecar = serde_yaml::from_str(
+            &fs::read_to_string(layout.outbox().join(outbox_sidecar_filename(&draft_ulid)))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sidecar.headers_cache.bcc, vec!["Eve <eve@example.net>"]);
+        let envelope = build_envelope(&sidecar).unwrap();
+        let recipients: Vec<String> = envelope.to().iter().map(|a| a.to_string()).collect();
+        assert_eq!(recipients, vec!["bob@example.org", "eve@example.net"]);
+    }
+
+    #[test]
+    fn queue_draft_attaches_files_and_blobs() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        fs::write(layout.drafts().join("report.pdf"), b"%PDF-1.4").unwrap();
+        fs::write(layout.drafts().join("résumé.txt"), b"cv").unwrap();
+        let blob = AttachmentStore::new(layout.attachments("accepted"))
+            .store("photo.png", b"png bytes")
+            .unwrap();
+        let blob_name = blob.path.file_name().unwrap().to_str().unwrap().to_string();
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            format!(
+                "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - report.pdf\n  - résumé.txt\n  - {blob_name}\n---\nSee attached.\n"
+            ),
+        )
+        .unwrap();
+
+        pipeline.queue_draft(&draft_path).unwrap();
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        assert!(message.contains("Content-Type: multipart/mixed"));
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.contains("Content-Type: application/pdf"));
+        assert!(message.contains("Content-Type: image/png"));
+        assert!(message.contains("filename*0*=utf-8''r%C3%A9sum%C3%A9.txt"));
+        let parsed = mailparse::parse_mail(message.as_bytes()).unwrap();
+        assert_eq!(parsed.subparts.len(), 4);
+        assert!(
+            parsed.subparts[1]
+                .get_body_raw()
+                .unwrap()
+                .starts_with(b"%PDF-1.4")
+        );
+        let disposition = parsed.subparts[2].get_content_disposition();
+        assert_eq!(
+            disposition.params.get("filename").map(String::as_str),
+            Some("résumé.txt")
+        );
+
+        let sidecar: MessageSidecar = serde_yaml::from_str(
+            &fs::read_to_string(layout.outbox().join(outbox_sidecar_filename(&draft_ulid)))
+                .unwrap(),
+        )
+        .unwrap();
+        let names: Vec<_> = sidecar
+            .attachments
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["report.pdf", "résumé.txt", "photo.png"]);
+        assert_eq!(sidecar.attachments[2].sha256, blob.sha256);
+        assert!(
+            layout
+                .attachments("sent")
+                .join(format!("{}__report.pdf", sidecar.attachments[0].sha256))
+                .exists()
+        );
+    }
+
+    #[test]
+    fn queue_draft_refuses_attachments_outside_drafts() {
+        let (dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        fs::write(dir.path().join(".env"), "smtp_password=secret\n").unwrap();
+        let draft_path = layout
+            .drafts()
+            .join(format!("{}.md", crate::util::ulid::generate()));
+        for entry in ["/etc/passwd", "../.env", "notes/../../.env"] {
+            fs::write(
+                &draft_path,
+                format!(
+                    "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - {entry}\n---\nBody\n"
+                ),
+            )
+            .unwrap();
+            let err = pipeline.queue_draft(&draft_path).unwrap_err();
+            assert!(
+                err.to_string().contains("must be a path inside drafts/"),
+                "{entry}"
+            );
+        }
+
+        // A symlink out of drafts/ is refused too.
+        std::os::unix::fs::symlink(dir.path().join(".env"), layout.drafts().join("env.txt"))
+            .unwrap();
+        fs::write(
+            &draft_path,
+            "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - env.txt\n---\nBody\n",
+        )
+        .unwrap();
+        let err = pipeline.queue_draft(&draft_path).unwrap_err();
+        assert!(err.to_string().contains("must be a path inside drafts/"));
+        assert!(fs::read_dir(layout.outbox()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn queue_draft_resolves_attachments_next_to_drafts_outside_drafts_dir() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        fs::remove_dir_all(layout.drafts()).unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        fs::create_dir(elsewhere.path().join("scans")).unwrap();
+        fs::write(elsewhere.path().join("notes.txt"), b"notes").unwrap();
+        fs::write(elsewhere.path().join("scans/page.pdf"), b"%PDF-1.4").unwrap();
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = elsewhere.path().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - notes.txt\n  - scans/page.pdf\n---\nBody\n",
+        )
+        .unwrap();
+
+        pipeline.queue_draft(&draft_path).unwrap();
+        let sidecar = read_outbox_sidecar(&layout, &draft_ulid);
+        let names: Vec<_> = sidecar
+            .attachments
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["notes.txt", "page.pdf"]);
+    }
+
+    #[test]
+    fn queue_draft_encodes_non_ascii_attachment_names() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        fs::write(layout.drafts().join("räksmörgås.pdf"), b"%PDF-1.4").unwrap();
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - räksmörgås.pdf\n---\nBody\n",
+        )
+        .unwrap();
+
+        pipeline.queue_draft(&draft_path).unwrap();
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        // RFC 2231: percent-encoded UTF-8, no raw 8-bit in the headers.
+        assert!(message.contains("filename*0*=utf-8''r%C3%A4ksm%C3%B6rg%C3%A5s.pdf"));
+        assert!(message.is_ascii());
+        let parsed = mailparse::parse_mail(message.as_bytes()).unwrap();
+        let disposition = parsed.subparts[1].get_content_disposition();
+        assert_eq!(
+            disposition.params.get("filename").map(String::as_str),
+            Some("räksmörgås.pdf")
+        );
+    }
+
+    #[test]
+    fn queue_draft_reports_missing_attachment() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let draft_path = layout
+            .drafts()
+            .join(format!("{}.md", crate::util::ulid::generate()));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Files\nfrom: owl@example.org\nto:\n  - bob@example.org\nattachments:\n  - nope.pdf\n---\nBody\n",
+        )
+        .unwrap();
+        let err = pipeline.queue_draft(&draft_path).unwrap_err();
+        assert!(err.to_string().contains("attachment nope.pdf not found"));
+        assert!(fs::read_dir(layout.outbox()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn queue_draft_carries_threading_headers() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        let meta = DraftFrontMatter {
+            subject: "Re: Plans".into(),
+            from: Some("Owl <owl@example.org>".into()),
+            to: vec!["Bob <bob@example.org>".into()],
+            in_reply_to: Some("<b@example.org>".into()),
+            references: vec!["<a@example.org>".into(), "<b@example.org>".into()],
+            ..DraftFrontMatter::default()
+        };
+        fs::write(&draft_path, format_draft(&meta, "Sounds good.\n").unwrap()).unwrap();
+
+        pipeline.queue_draft(&draft_path).unwrap();
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&draft_ulid))).unwrap();
+        assert!(message.contains("In-Reply-To: <b@example.org>"));
+        assert!(message.contains("References: <a@example.org> <b@example.org>"));
+        let sidecar: MessageSidecar = serde_yaml::from_str(
+            &fs::read_to_string(layout.outbox().join(outbox_sidecar_filename(&draft_ulid)))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            sidecar.headers_cache.in_reply_to.as_deref(),
+            Some("<b@example.org>")
+        );
+        assert_eq!(sidecar.headers_cache.references.len(), 2);
+        assert_eq!(sidecar.thread_id.as_deref(), Some("<a@example.org>"));
+    }
+
+    #[test]
+    fn format_draft_round_trips_through_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join(format!("{}.md", crate::util::ulid::generate()));
+        let meta = DraftFrontMatter {
+            subject: "Fwd: Notes".into(),
+            from: Some("owl@example.org".into()),
+            to: vec!["carol@example.org".into()],
+            ..DraftFrontMatter::default()
+        };
+        let contents = format_draft(&meta, "> quoted\n").unwrap();
+        assert!(!contents.contains("in_reply_to"));
+        fs::write(&path, contents).unwrap();
+        let draft = Draft::from_file(&path).unwrap();
+        assert_eq!(draft.subject, "Fwd: Notes");
+        assert_eq!(draft.body, "> quoted");
+        assert!(draft.in_reply_to.is_none());
+        assert!(draft.references.is_empty());
+    }
+
+    #[test]
+    fn dispatch_moves_successful_message() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(RecordingTransport::success());
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Dispatch\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nBody\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        let outcomes = pipeline.dispatch_pending().unwrap();
+        assert!(matches!(outcomes.first(), Some(DispatchResult::Sent(_))));
+        let sent_path = layout.sent().join(outbox_message_filename(&draft_ulid));
+        assert!(sent_path.exists());
+        assert!(
+            !layout
+                .outbox()
+                .join(outbox_message_filename(&draft_ulid))
+                .exists()
+        );
+    }
+
+    #[test]
+    fn dispatch_records_retry_on_failure() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(RecordingTransport::fail());
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Retry\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nBody\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        let outcomes = pipeline.dispatch_pending().unwrap();
+        assert!(matches!(outcomes.first(), Some(DispatchResult::Retry(_))));
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&draft_ulid));
+        let sidecar: MessageSidecar =
+            serde_yaml::from_str(&fs::read_to_string(sidecar_path).unwrap()).unwrap();
+        let outbound = sidecar.outbound.unwrap();
+        assert_eq!(outbound.attempts, 1);
+        assert!(outbound.last_error.unwrap().contains("forced"));
+        assert!(outbound.next_attempt_at.is_some());
+    }
+
+    fn queue_test_draft(layout: &MailLayout, pipeline: &OutboxPipeline) -> String {
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Retry\nfrom: Owl <owl@example.org>\nto:\n  - Bob <bob@example.org>\n---\nBody\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        draft_ulid
+    }
+
+    fn read_outbox_sidecar(layout: &MailLayout, ulid: &str) -> MessageSidecar {
+        let path = layout.outbox().join(outbox_sidecar_filename(ulid));
+        serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn dispatch_marks_permanent_rejections_failed() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(RecordingTransport::reject());
+        let pipeline =
+            OutboxPipeline::with_transport(layout.clone(), env, logger, transport.clone());
+        let ulid = queue_test_draft(&layout, &pipeline);
+
+        let outcomes = pipeline.dispatch_pending().unwrap();
+        assert_eq!(outcomes, vec![DispatchResult::Failed(ulid.clone())]);
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.status, OutboundStatus::Failed);
+        assert!(outbound.next_attempt_at.is_none());
+        assert!(outbound.last_error.unwrap().contains("5.1.1 no such user"));
+        assert!(
+            layout
+                .outbox()
+                .join(outbox_message_filename(&ulid))
+                .exists()
+        );
+
+        assert!(pipeline.dispatch_pending().unwrap().is_empty());
+        assert_eq!(transport.attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dispatch_fails_transient_errors_after_max_attempts() {
+        let (_dir, layout, mut env, logger) = test_env();
+        env.retry_max_attempts = Some(2);
+        let transport = Arc::new(RecordingTransport::fail());
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        let ulid = queue_test_draft(&layout, &pipeline);
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Retry(ulid.clone())]
+        );
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&ulid));
+        let mut sidecar = read_outbox_sidecar(&layout, &ulid);
+        let outbound = sidecar.outbound_state_mut();
+        outbound.next_attempt_at = None;
+        for rcpt in &mut outbound.recipients {
+            rcpt.next_attempt_at = None;
+        }
+        fs::write(&sidecar_path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.status, OutboundStatus::Failed);
+        assert_eq!(outbound.attempts, 2);
+    }
+
+    #[test]
+    fn dispatch_fails_transient_errors_after_max_age() {
+        let (_dir, layout, mut env, logger) = test_env();
+        env.retry_max_age = Some("1h".into());
+        let transport = Arc::new(RecordingTransport::fail());
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        let ulid = queue_test_draft(&layout, &pipeline);
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&ulid));
+        let mut sidecar = read_outbox_sidecar(&layout, &ulid);
+        let two_hours_ago = (OffsetDateTime::now_utc() - Duration::hours(2))
+            .format(&Rfc3339)
+            .unwrap();
+        sidecar.received_at = two_hours_ago.clone();
+        sidecar.outbound_state_mut().queued_at = Some(two_hours_ago);
+        fs::write(&sidecar_path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+
+        // Resending starts the clock again, so a 4xx is retried later.
+        assert_eq!(
+            pipeline.resend(&ulid).unwrap(),
+            DispatchResult::Retry(ulid.clone())
+        );
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.status, OutboundStatus::Pending);
+        assert!(outbound.next_attempt_at.is_some());
+        let queued_at =
+            OffsetDateTime::parse(outbound.queued_at.as_deref().unwrap(), &Rfc3339).unwrap();
+        assert!(OffsetDateTime::now_utc() - queued_at < Duration::minutes(1));
+    }
+
+    #[test]
+    fn queued_at_defaults_to_received_at_for_older_sidecars() {
+        let (_dir, layout, mut env, logger) = test_env();
+        env.retry_max_age = Some("1h".into());
+        let transport = Arc::new(RecordingTransport::fail());
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, transport);
+        let ulid = queue_test_draft(&layout, &pipeline);
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&ulid));
+        let mut sidecar = read_outbox_sidecar(&layout, &ulid);
+        assert_eq!(
+            sidecar.outbound.as_ref().unwrap().queued_at.as_deref(),
+            Some(sidecar.received_at.as_str())
+        );
+        sidecar.received_at = (OffsetDateTime::now_utc() - Duration::hours(2))
+            .format(&Rfc3339)
+            .unwrap();
+        sidecar.outbound_state_mut().queued_at = None;
+        fs::write(&sidecar_path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+    }
+
+    #[test]
+    fn smtp_relay_classifies_reply_codes() {
+        let (_dir, layout, env, _logger) = test_env();
+        let pipeline = OutboxPipeline::new(
+            layout.clone(),
+            env,
+            Logger::new(layout.root(), LogLevel::Off).unwrap(),
+        );
+        let ulid = queue_test_draft(&layout, &pipeline);
+        let sidecar = read_outbox_sidecar(&layout, &ulid);
+        let message = fs::read(layout.outbox().join(outbox_message_filename(&ulid))).unwrap();
+
+        for (reply, permanent) in [
+            ("550 5.7.1 relaying denied", true),
+            ("451 4.3.0 try later", false),
+        ] {
+            let port = smtp_sink("MAIL", reply);
+            let relay = SmtpRelay::from_env(&EnvConfig {
+                smtp_host: Some("127.0.0.1".into()),
+                smtp_port: port,
+                smtp_starttls: false,
+                ..EnvConfig::default()
+            });
+            let err = relay
+                .send(&message, &build_envelope(&sidecar).unwrap())
+                .unwrap_err();
+            assert_eq!(err.is::<PermanentFailure>(), permanent, "{reply}: {err}");
+            assert!(err.to_string().contains(&reply[4..]), "{err}");
+        }
+
+        for (reply, rejected) in [
+            ("550 5.1.1 no such user", true),
+            ("451 4.3.0 try later", false),
+        ] {
+            let port = smtp_sink("RCPT TO:<NOBODY", reply);
+            let relay = SmtpRelay::from_env(&EnvConfig {
+                smtp_host: Some("127.0.0.1".into()),
+                smtp_port: port,
+                smtp_starttls: false,
+                ..EnvConfig::default()
+            });
+            let envelope = Envelope::new(
+                Some("alice@example.org".parse().unwrap()),
+                ["bob@example.org", "nobody@example.org", "carol@example.org"]
+                    .iter()
+                    .map(|addr| addr.parse().unwrap())
+                    .collect(),
+            )
+            .unwrap();
+            let results = relay.send(&message, &envelope).unwrap();
+            assert_eq!(results.len(), 3);
+            assert_eq!(results[0].outcome, RecipientOutcome::Delivered);
+            assert_eq!(results[2].outcome, RecipientOutcome::Delivered);
+            assert_eq!(results[1].recipient, "nobody@example.org");
+            match &results[1].outcome {
+                RecipientOutcome::Rejected(err) if rejected => assert!(err.contains("5.1.1")),
+                RecipientOutcome::Deferred(err) if !rejected => assert!(err.contains("4.3.0")),
+                other => panic!("{reply}: unexpected {other:?}"),
+            }
+        }
+
+        let mut bad = sidecar.clone();
+        bad.headers_cache.to = vec!["not an address".into()];
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&ulid));
+        fs::write(&sidecar_path, serde_yaml::to_string(&bad).unwrap()).unwrap();
+        assert_eq!(
+            pipeline.retry(&ulid).unwrap(),
+            DispatchResult::Failed(ulid.clone())
+        );
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert!(outbound.last_error.unwrap().contains("invalid recipient"));
+    }
+
+    #[test]
+    fn retry_dispatches_despite_backoff() {
+        let (_dir, layout, env, logger) = test_env();
+        let failing = OutboxPipeline::with_transport(
+            layout.clone(),
+            env.clone(),
+            logger.clone(),
+            Arc::new(RecordingTransport::fail()),
+        );
+        let ulid = queue_test_draft(&layout, &failing);
+        failing.dispatch_pending().unwrap();
+        assert!(failing.dispatch_pending().unwrap().is_empty());
+        assert_eq!(
+            failing.retry(&ulid.to_lowercase()).unwrap(),
+            DispatchResult::Retry(ulid.clone())
+        );
+        let entries = failing.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outbound.as_ref().unwrap().attempts, 2);
+
+        let working = OutboxPipeline::with_transport(
+            layout.clone(),
+            env,
+            logger,
+            Arc::new(RecordingTransport::success()),
+        );
+        assert_eq!(
+            working.retry(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        assert!(working.entries().unwrap().is_empty());
+        let err = working.retry(&ulid).unwrap_err();
+        assert!(err.to_string().contains("not found in outbox"));
+    }
+
+    #[test]
+    fn resend_requeues_failed_and_sent_messages() {
+        let (_dir, layout, env, logger) = test_env();
+        let rejecting = OutboxPipeline::with_transport(
+            layout.clone(),
+            env.clone(),
+            logger.clone(),
+            Arc::new(RecordingTransport::reject()),
+        );
+        let ulid = queue_test_draft(&layout, &rejecting);
+        let pending = queue_test_draft(&layout, &rejecting);
+        rejecting.dispatch_pending().unwrap();
+        assert!(
+            rejecting
+                .retry(&ulid)
+                .unwrap_err()
+                .to_string()
+                .contains("use resend")
+        );
+
+        let working = OutboxPipeline::with_transport(
+            layout.clone(),
+            env,
+            logger,
+            Arc::new(RecordingTransport::success()),
+        );
+        assert_eq!(
+            working.resend(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        let sent_sidecar = layout.sent().join(outbox_sidecar_filename(&ulid));
+        let sidecar: MessageSidecar =
+            serde_yaml::from_str(&fs::read_to_string(&sent_sidecar).unwrap()).unwrap();
+        assert_eq!(sidecar.outbound.unwrap().attempts, 1);
+
+        // A sent copy goes back through the outbox and lands in sent/ again.
+        assert_eq!(
+            working.resend(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        assert!(sent_sidecar.exists());
+        assert!(layout.sent().join(outbox_message_filename(&ulid)).exists());
+        assert!(
+            !layout
+                .outbox()
+                .join(outbox_message_filename(&ulid))
+                .exists()
+        );
+
+        let mut queued = read_outbox_sidecar(&layout, &pending);
+        queued.outbound = Some(OutboundState::default());
+        fs::write(
+            layout.outbox().join(outbox_sidecar_filename(&pending)),
+            serde_yaml::to_string(&queued).unwrap(),
+        )
+        .unwrap();
+        assert!(
+            working
+                .resend(&pending)
+                .unwrap_err()
+                .to_string()
+                .contains("use retry")
+        );
+        assert!(
+            working
+                .resend("01ARZ3NDEKTSV4RRFFQ69G5FZZ")
+                .unwrap_err()
+                .to_string()
+                .contains("not found")
+        );
+    }
+
+    #[test]
+    fn cancel_restores_editable_draft() {
+        let (_dir, layout, env, logger) = test_env();
+        fs::write(layout.accepted().join(".rules"), "bob@example.org\n").unwrap();
+        fs::write(layout.accepted().join(".settings"), "signature=sig.txt\n").unwrap();
+        fs::write(layout.root().join("sig.txt"), "Owl\n").unwrap();
+        fs::write(layout.drafts().join("notes.txt"), "notes").unwrap();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Later\nfrom: owl@example.org\nto:\n  - bob@example.org\nbcc:\n  - eve@example.org\nreply_to: help@example.org\nin_reply_to: <p@example.org>\nattachments:\n  - notes.txt\n---\nFirst line\n\nSecond line\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        fs::remove_file(&draft_path).unwrap();
+
+        let restored = pipeline.cancel(&ulid).unwrap();
+        assert_eq!(restored, draft_path);
+        assert!(fs::read_dir(layout.outbox()).unwrap().next().is_none());
+        let contents = fs::read_to_string(&restored).unwrap();
+        assert!(contents.ends_with("---\nFirst line\n\nSecond line"));
+        let draft = Draft::from_file(&restored).unwrap();
+        assert_eq!(draft.subject, "Later");
+        assert_eq!(draft.bcc.len(), 1);
+        assert_eq!(
+            draft.reply_to.unwrap().email.to_string(),
+            "help@example.org"
+        );
+        assert_eq!(draft.in_reply_to.as_deref(), Some("<p@example.org>"));
+        assert_eq!(draft.attachments.len(), 1);
+        assert!(draft.attachments[0].ends_with("__notes.txt"));
+
+        // The restored draft queues again, blob attachment and all.
+        pipeline.queue_draft(&restored).unwrap();
+        let message =
+            fs::read_to_string(layout.outbox().join(outbox_message_filename(&ulid))).unwrap();
+        // Once in the plain part and once in the HTML part, not doubled.
+        assert_eq!(message.matches("-- \r\nOwl").count(), 2);
+        assert!(message.contains("filename=\"notes.txt\""));
+    }
+
+    #[test]
+    fn cancel_never_overwrites_an_existing_draft() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Sig\nfrom: owl@example.org\nto:\n  - bob@example.org\n---\nHello\n\nAlice, by hand\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        fs::write(&draft_path, "newer draft").unwrap();
+
+        let restored = pipeline.cancel(&ulid).unwrap();
+        assert_ne!(restored, draft_path);
+        assert!(restored.starts_with(layout.drafts()));
+        assert_eq!(fs::read_to_string(&draft_path).unwrap(), "newer draft");
+        let draft = Draft::from_file(&restored).unwrap();
+        assert!(draft.body.ends_with("Alice, by hand"));
+    }
+
+    #[test]
+    fn strip_signature_only_removes_the_appended_block() {
+        let text = "Hi\r\n\r\n-- \r\nnot mine\r\n\r\n-- \r\nOwl team\r\n";
+        assert_eq!(
+            strip_signature(text, Some("Owl team")),
+            "Hi\n\n-- \nnot mine"
+        );
+        assert_eq!(
+            strip_signature(text, Some("Other")),
+            "Hi\n\n-- \nnot mine\n\n-- \nOwl team"
+        );
+        assert_eq!(strip_signature("Hi\n-- \nOwl\n", None), "Hi\n-- \nOwl");
+    }
+
+    /// One-shot SMTP server that answers commands starting with `prefix`
+    /// (compared in upper case) with `reply` and everything else with 250.
+    fn smtp_sink(prefix: &'static str, reply: &'static str) -> u16 {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 sink ready\r\n").unwrap();
+            let mut line = String::new();
+            let mut in_data = false;
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        let _ = writer.write_all(b"250 queued\r\n");
+                    }
+                    line.clear();
+                    continue;
+                }
+                let upper = line.to_ascii_uppercase();
+                let reply = if upper.starts_with(prefix) {
+                    reply
+                } else if upper.starts_with("EHLO") || upper.starts_with("HELO") {
+                    "250 sink"
+                } else if upper.starts_with("DATA") {
+                    in_data = true;
+                    "354 go ahead"
+                } else if upper.starts_with("QUIT") {
+                    let _ = writer.write_all(b"221 bye\r\n");
+                    break;
+                } else {
+                    "250 ok"
+                };
+                if writer.write_all(format!("{reply}\r\n").as_bytes()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn dispatch_pending_returns_empty_without_outbox_dir() {
+        let (_dir, layout, env, logger) = test_env();
+        fs::remove_dir_all(layout.outbox()).unwrap();
+        let pipeline = OutboxPipeline::new(layout, env, logger);
+        let outcomes = pipeline.dispatch_pending().unwrap();
+        assert!(outcomes.is_empty());
+    }
+
+    #[test]
+    fn dispatch_pending_skips_directories_and_non_outbox_entries() {
+        let (_dir, layout, env, logger) = test_env();
+        let pipeline = OutboxPipeline::new(layout.clone(), env, logger);
+        let outbox_dir = layout.outbox();
+        create_dir_all(&outbox_dir).unwrap();
+        fs::create_dir(outbox_dir.join("subdir")).unwrap();
+
+        let mut sidecar = MessageSidecar::new(
+            "01ARZ3NDEKTSV4RRFFQ69G5FB0",
+            "draft.eml",
+            "draft",
+            "strict",
+            "draft.html",
+            "deadbeef",
+            HeadersCache::new("Alice", "Draft"),
+        );
+        sidecar.set_plain_render("draft.txt");
+        let sidecar_path = outbox_dir.join("draft.yml");
+        write_atomic(
+            &sidecar_path,
+            serde_yaml::to_string(&sidecar).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let outcomes = pipeline.dispatch_pending().unwrap();
+        assert!(outcomes.is_empty());
+        assert!(sidecar_path.exists());
+    }
+
+    #[test]
+    fn finish_dispatch_moves_related_files() {
+        let (_dir, layout, mut env, logger) = test_env();
+        env.render_mode = "strict".into();
         let pipeline = OutboxPipeline::new(layout.clone(), env.clone(), logger);
         let outbox_dir = layout.outbox();
         create_dir_all(&outbox_dir).unwrap();
@@ -907,9 +2437,228 @@ mod tests {
         );
     }
 
+    /// Delivers to everyone except the addresses given a scripted outcome,
+    /// and records which recipients each attempt was asked to reach.
+    #[derive(Default)]
+    struct ScriptedTransport {
+        outcomes: parking_lot::Mutex<std::collections::HashMap<String, RecipientOutcome>>,
+        calls: parking_lot::Mutex<Vec<Vec<String>>>,
+    }
+
+    impl ScriptedTransport {
+        fn script(&self, recipient: &str, outcome: Option<RecipientOutcome>) {
+            let mut outcomes = self.outcomes.lock();
+            match outcome {
+                Some(outcome) => outcomes.insert(recipient.to_string(), outcome),
+                None => outcomes.remove(recipient),
+            };
+        }
+    }
+
+    impl MailTransport for ScriptedTransport {
+        fn send(&self, _message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>> {
+            let outcomes = self.outcomes.lock();
+            let results: Vec<RecipientResult> = envelope
+                .to()
+                .iter()
+                .map(|addr| RecipientResult {
+                    recipient: addr.to_string(),
+                    outcome: outcomes
+                        .get(&addr.to_string())
+                        .cloned()
+                        .unwrap_or(RecipientOutcome::Delivered),
+                })
+                .collect();
+            self.calls
+                .lock()
+                .push(results.iter().map(|r| r.recipient.clone()).collect());
+            Ok(results)
+        }
+    }
+
+    fn queue_group_draft(layout: &MailLayout, pipeline: &OutboxPipeline) -> String {
+        let draft_ulid = crate::util::ulid::generate();
+        let draft_path = layout.drafts().join(format!("{draft_ulid}.md"));
+        fs::write(
+            &draft_path,
+            "---\nsubject: Group\nfrom: Owl <owl@example.org>\nto:\n  - bob@example.org\n  - carol@down.example\ncc:\n  - dave@example.net\n---\nBody\n",
+        )
+        .unwrap();
+        pipeline.queue_draft(&draft_path).unwrap();
+        draft_ulid
+    }
+
+    #[test]
+    fn dispatch_retries_only_pending_recipients() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(ScriptedTransport::default());
+        transport.script(
+            "carol@down.example",
+            Some(RecipientOutcome::Deferred(
+                "421 4.4.2 down.example busy".into(),
+            )),
+        );
+        let pipeline =
+            OutboxPipeline::with_transport(layout.clone(), env, logger, transport.clone());
+        let ulid = queue_group_draft(&layout, &pipeline);
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Retry(ulid.clone())]
+        );
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.status, OutboundStatus::Pending);
+        assert_eq!(outbound.recipient_counts(), (2, 1, 0));
+        assert!(outbound.is_partial());
+        let carol = &outbound.recipients[1];
+        assert_eq!(carol.address, "carol@down.example");
+        assert_eq!(carol.attempts, 1);
+        assert!(carol.last_error.as_deref().unwrap().contains("busy"));
+        assert_eq!(carol.next_attempt_at, outbound.next_attempt_at);
+        assert_eq!(
+            outbound.last_error.as_deref(),
+            Some("carol@down.example: 421 4.4.2 down.example busy")
+        );
+
+        transport.script("carol@down.example", None);
+        assert_eq!(
+            pipeline.retry(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        let calls = transport.calls.lock().clone();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].len(), 3);
+        assert_eq!(calls[1], vec!["carol@down.example".to_string()]);
+        let sent: MessageSidecar = serde_yaml::from_str(
+            &fs::read_to_string(layout.sent().join(outbox_sidecar_filename(&ulid))).unwrap(),
+        )
+        .unwrap();
+        let outbound = sent.outbound.unwrap();
+        assert_eq!(outbound.recipient_counts(), (3, 0, 0));
+        assert_eq!(outbound.recipients[1].attempts, 2);
+        assert!(outbound.last_error.is_none());
+    }
+
+    #[test]
+    fn dispatch_skips_recipients_not_yet_due_and_fails_bad_addresses() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(ScriptedTransport::default());
+        transport.script(
+            "carol@down.example",
+            Some(RecipientOutcome::Deferred(
+                "421 4.4.2 down.example busy".into(),
+            )),
+        );
+        let pipeline =
+            OutboxPipeline::with_transport(layout.clone(), env, logger, transport.clone());
+        let ulid = queue_group_draft(&layout, &pipeline);
+        pipeline.dispatch_pending().unwrap();
+
+        // Carol's own backoff still holds even when the message looks due.
+        let sidecar_path = layout.outbox().join(outbox_sidecar_filename(&ulid));
+        let mut sidecar = read_outbox_sidecar(&layout, &ulid);
+        sidecar.outbound_state_mut().next_attempt_at = None;
+        fs::write(&sidecar_path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+        assert!(pipeline.dispatch_pending().unwrap().is_empty());
+        assert_eq!(transport.calls.lock().len(), 1);
+        assert_eq!(
+            read_outbox_sidecar(&layout, &ulid)
+                .outbound
+                .unwrap()
+                .attempts,
+            1
+        );
+
+        let outbound = sidecar.outbound_state_mut();
+        outbound.recipients[1].address = "not an address".into();
+        outbound.recipients[1].next_attempt_at = None;
+        fs::write(&sidecar_path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+        assert_eq!(transport.calls.lock().len(), 1);
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.recipient_counts(), (2, 0, 1));
+        let carol = &outbound.recipients[1];
+        assert_eq!(carol.status, OutboundStatus::Failed);
+        assert!(
+            carol
+                .last_error
+                .as_deref()
+                .unwrap()
+                .contains("invalid recipient address")
+        );
+    }
+
+    #[test]
+    fn relay_dispatch_records_each_rcpt_reply() {
+        let (_dir, layout, env, logger) = test_env();
+        let port = smtp_sink("RCPT TO:<CAROL", "550 5.1.1 no such user");
+        let relay = SmtpRelay::from_env(&EnvConfig {
+            smtp_host: Some("127.0.0.1".into()),
+            smtp_port: port,
+            smtp_starttls: false,
+            ..EnvConfig::default()
+        });
+        let pipeline = OutboxPipeline::with_transport(layout.clone(), env, logger, Arc::new(relay));
+        let ulid = queue_group_draft(&layout, &pipeline);
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.recipient_counts(), (2, 0, 1));
+        let carol = &outbound.recipients[1];
+        assert_eq!(carol.address, "carol@down.example");
+        assert_eq!(carol.status, OutboundStatus::Failed);
+        assert!(carol.last_error.as_deref().unwrap().contains("5.1.1"));
+    }
+
+    #[test]
+    fn resend_after_partial_failure_skips_delivered_recipients() {
+        let (_dir, layout, env, logger) = test_env();
+        let transport = Arc::new(ScriptedTransport::default());
+        transport.script(
+            "dave@example.net",
+            Some(RecipientOutcome::Rejected("550 5.1.1 no such user".into())),
+        );
+        let pipeline =
+            OutboxPipeline::with_transport(layout.clone(), env, logger, transport.clone());
+        let ulid = queue_group_draft(&layout, &pipeline);
+
+        assert_eq!(
+            pipeline.dispatch_pending().unwrap(),
+            vec![DispatchResult::Failed(ulid.clone())]
+        );
+        let outbound = read_outbox_sidecar(&layout, &ulid).outbound.unwrap();
+        assert_eq!(outbound.status, OutboundStatus::Failed);
+        assert_eq!(outbound.recipient_counts(), (2, 0, 1));
+        assert!(pipeline.dispatch_pending().unwrap().is_empty());
+
+        transport.script("dave@example.net", None);
+        assert_eq!(
+            pipeline.resend(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        assert_eq!(
+            transport.calls.lock().last().unwrap(),
+            &vec!["dave@example.net".to_string()]
+        );
+
+        // A fully delivered copy goes to everyone again.
+        assert_eq!(
+            pipeline.resend(&ulid).unwrap(),
+            DispatchResult::Sent(ulid.clone())
+        );
+        assert_eq!(transport.calls.lock().last().unwrap().len(), 3);
+    }
+
     struct RecordingTransport {
         attempts: AtomicUsize,
         fail: bool,
+        permanent: bool,
     }
 
     impl RecordingTransport {
@@ -917,6 +2666,7 @@ mod tests {
             Self {
                 attempts: AtomicUsize::new(0),
                 fail: false,
+                permanent: false,
             }
         }
 
@@ -924,17 +2674,31 @@ mod tests {
             Self {
                 attempts: AtomicUsize::new(0),
                 fail: true,
+                permanent: false,
+            }
+        }
+
+        fn reject() -> Self {
+            Self {
+                attempts: AtomicUsize::new(0),
+                fail: true,
+                permanent: true,
             }
         }
     }
 
     impl MailTransport for RecordingTransport {
-        fn send(&self, _message: &[u8], _sidecar: &MessageSidecar) -> Result<()> {
+        fn send(&self, _message: &[u8], envelope: &Envelope) -> Result<Vec<RecipientResult>> {
             self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.permanent {
+                return Err(
+                    PermanentFailure("permanent error (550): 5.1.1 no such user".into()).into(),
+                );
+            }
             if self.fail {
                 bail!("forced failure");
             }
-            Ok(())
+            Ok(all_recipients(envelope, RecipientOutcome::Delivered))
         }
     }
 
@@ -948,19 +2712,20 @@ mod tests {
             ..EnvConfig::default()
         };
         let relay = SmtpRelay::from_env(&env);
-        // Ensure the builder path executes without panic.
-        let _ = format!("{:?}", relay.inner);
+        assert!(relay.starttls);
+        assert!(relay.credentials.is_some());
     }
 
     #[test]
-    fn smtp_relay_without_starttls_uses_dangerous_builder() {
+    fn smtp_relay_without_starttls_stays_plaintext() {
         let env = EnvConfig {
             smtp_starttls: false,
             smtp_host: Some("smtp.example.org".into()),
             ..EnvConfig::default()
         };
         let relay = SmtpRelay::from_env(&env);
-        let _ = format!("{:?}", relay.inner);
+        assert!(!relay.starttls);
+        assert!(relay.credentials.is_none());
     }
 
     #[test]
@@ -998,7 +2763,9 @@ mod tests {
             "---\nsubject: hi\nto:\n  - bob@example.org\n---\nbody\n",
         )
         .unwrap();
-        let err = Draft::from_file(&path).expect_err("expected missing from failure");
+        // From may still come from list settings, so it is only required once resolved.
+        let draft = Draft::from_file(&path).unwrap();
+        let err = draft.sender().expect_err("expected missing from failure");
         assert!(format!("{err}").contains("missing 'from'"));
     }
 
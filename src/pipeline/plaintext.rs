@@ -0,0 +1,766 @@
+//! HTML to plain text for the `.txt` render, in the spirit of `lynx -dump`:
+//! paragraphs, lists, aligned tables, `>` quoting and `[n]` link footnotes,
+//! wrapped to a fixed width.
+
+use std::cell::RefCell;
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+    states::RawKind,
+};
+
+/// Narrowest wrap width honoured; smaller values would leave no room for
+/// list markers and quote prefixes.
+pub const MIN_WIDTH: usize = 20;
+
+/// Deepest element nesting kept in the tree; tags opened below it are
+/// dropped and their content flows into the innermost kept element, so a
+/// hostile message cannot exhaust the stack in the recursive layout.
+const MAX_DEPTH: usize = 64;
+
+const SKIPPED: &[&str] = &[
+    "head", "script", "style", "title", "template", "noscript", "iframe", "object", "svg", "math",
+];
+
+/// Blocks separated from their neighbours by a blank line; other block
+/// elements (`div`, `center`, ...) only start a new line.
+const PARAGRAPHS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "dl",
+    "blockquote",
+    "pre",
+    "table",
+    "hr",
+    "figure",
+];
+
+const LINE_BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "body",
+    "caption",
+    "center",
+    "details",
+    "div",
+    "figcaption",
+    "footer",
+    "header",
+    "html",
+    "li",
+    "main",
+    "nav",
+    "section",
+    "summary",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "dd",
+    "dt",
+];
+
+pub fn html_to_text(html: &str, width: usize) -> String {
+    let root = parse(html);
+    let mut renderer = Renderer::default();
+    let lines = renderer.flow(&root, width.max(MIN_WIDTH));
+    let mut text = lines
+        .iter()
+        .map(|line| line.replace('\u{a0}', " ").trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !renderer.links.is_empty() {
+        text.push_str("\n\nLinks:\n");
+        for (idx, url) in renderer.links.iter().enumerate() {
+            text.push_str(&format!("[{}] {url}\n", idx + 1));
+        }
+        return text;
+    }
+    text.push('\n');
+    text
+}
+
+/// Rendered lines of one block and whether it wants blank lines around it.
+struct Block {
+    lines: Vec<String>,
+    gap: bool,
+}
+
+#[derive(Default)]
+struct Renderer {
+    links: Vec<String>,
+    list_depth: usize,
+}
+
+impl Renderer {
+    /// Lay out the children of `element` as a sequence of blocks.
+    fn flow(&mut self, element: &Element, width: usize) -> Vec<String> {
+        let mut blocks = Vec::new();
+        let mut inline = String::new();
+        for child in &element.children {
+            match child {
+                Node::Text(text) => push_collapsed(&mut inline, text),
+                Node::Element(child) => {
+                    let tag = child.tag.as_str();
+                    if SKIPPED.contains(&tag) {
+                        continue;
+                    }
+                    if is_block(tag) {
+                        flush(&mut blocks, &mut inline, width);
+                        if let Some(block) = self.block(child, width) {
+                            blocks.push(block);
+                        }
+                    } else {
+                        self.inline(child, &mut inline);
+                    }
+                }
+            }
+        }
+        flush(&mut blocks, &mut inline, width);
+        join_blocks(blocks)
+    }
+
+    fn block(&mut self, element: &Element, width: usize) -> Option<Block> {
+        let tag = element.tag.as_str();
+        let gap = PARAGRAPHS.contains(&tag);
+        let lines = match tag {
+            "h1" | "h2" => {
+                let mut text = String::new();
+                self.inline(element, &mut text);
+                let mut lines = wrap(&text, width);
+                let underline = lines.iter().map(|line| line.chars().count()).max()?;
+                let rule = if tag == "h1" { "=" } else { "-" };
+                lines.push(rule.repeat(underline));
+                lines
+            }
+            "ul" | "ol" => return self.list(element, tag == "ol", width),
+            "blockquote" => self
+                .flow(element, width.saturating_sub(2).max(MIN_WIDTH / 2))
+                .into_iter()
+                .map(|line| {
+                    if line.is_empty() {
+                        ">".to_string()
+                    } else {
+                        format!("> {line}")
+                    }
+                })
+                .collect(),
+            "pre" => {
+                let mut text = String::new();
+                raw_text(element, &mut text);
+                let text = text.strip_prefix('\n').unwrap_or(&text);
+                text.trim_end().lines().map(str::to_string).collect()
+            }
+            "hr" => vec!["-".repeat(width)],
+            "table" => return self.table(element, width),
+            "dd" => self
+                .flow(element, width.saturating_sub(4).max(MIN_WIDTH / 2))
+                .into_iter()
+                .map(|line| indent(&line, "    "))
+                .collect(),
+            _ => self.flow(element, width),
+        };
+        if lines.iter().all(|line| line.trim().is_empty()) {
+            return None;
+        }
+        Some(Block { lines, gap })
+    }
+
+    fn inline(&mut self, element: &Element, out: &mut String) {
+        let tag = element.tag.as_str();
+        match tag {
+            _ if SKIPPED.contains(&tag) => {}
+            "br" => out.push('\n'),
+            "img" => {
+                if let Some(alt) = element.attr("alt")
+                    && !alt.trim().is_empty()
+                {
+                    push_collapsed(out, &format!("[{}]", alt.trim()));
+                }
+            }
+            "a" => {
+                let start = out.len();
+                self.inline_children(element, out);
+                if let Some(href) = element.attr("href")
+                    && let Some(number) = self.footnote(href, &out[start..])
+                {
+                    out.push_str(&format!("[{number}]"));
+                }
+            }
+            _ if is_block(tag) => {
+                push_collapsed(out, " ");
+                self.inline_children(element, out);
+                push_collapsed(out, " ");
+            }
+            _ => self.inline_children(element, out),
+        }
+    }
+
+    fn inline_children(&mut self, element: &Element, out: &mut String) {
+        for child in &element.children {
+            match child {
+                Node::Text(text) => push_collapsed(out, text),
+                Node::Element(child) => self.inline(child, out),
+            }
+        }
+    }
+
+    /// Footnote number for `href`, or `None` when the link text already
+    /// shows the target.
+    fn footnote(&mut self, href: &str, text: &str) -> Option<usize> {
+        let href = href.trim();
+        let lower = href.to_ascii_lowercase();
+        if !(lower.starts_with("http:")
+            || lower.starts_with("https:")
+            || lower.starts_with("mailto:"))
+        {
+            return None;
+        }
+        let shown = text.trim().trim_end_matches('/');
+        let bare = href
+            .split_once(':')
+            .map(|(_, rest)| rest.trim_start_matches("//"))
+            .unwrap_or(href)
+            .trim_end_matches('/');
+        if shown.eq_ignore_ascii_case(href.trim_end_matches('/'))
+            || shown.eq_ignore_ascii_case(bare)
+        {
+            return None;
+        }
+        let number = match self.links.iter().position(|link| link == href) {
+            Some(idx) => idx + 1,
+            None => {
+                self.links.push(href.to_string());
+                self.links.len()
+            }
+        };
+        Some(number)
+    }
+
+    fn list(&mut self, element: &Element, ordered: bool, width: usize) -> Option<Block> {
+        let mut number = element
+            .attr("start")
+            .and_then(|start| start.trim().parse::<i64>().ok())
+            .unwrap_or(1);
+        let items: Vec<&Element> = element.elements().filter(|el| el.tag == "li").collect();
+        let mut markers = Vec::new();
+        for item in &items {
+            if let Some(value) = item
+                .attr("value")
+                .and_then(|v| v.trim().parse::<i64>().ok())
+            {
+                number = value;
+            }
+            markers.push(if ordered {
+                format!("{number}. ")
+            } else {
+                "* ".to_string()
+            });
+            number = number.saturating_add(1);
+        }
+        let marker_width = markers.iter().map(|m| m.chars().count()).max()?;
+        let inner = width.saturating_sub(marker_width).max(MIN_WIDTH / 2);
+        self.list_depth += 1;
+        let mut lines = Vec::new();
+        for (item, marker) in items.iter().zip(markers) {
+            let content = self.flow(item, inner);
+            let padding = " ".repeat(marker_width);
+            for (idx, line) in content.iter().enumerate() {
+                if idx == 0 {
+                    lines.push(format!("{marker:<marker_width$}{line}"));
+                } else {
+                    lines.push(indent(line, &padding));
+                }
+            }
+            if content.is_empty() {
+                lines.push(marker.trim_end().to_string());
+            }
+        }
+        self.list_depth -= 1;
+        Some(Block {
+            lines,
+            gap: self.list_depth == 0,
+        })
+    }
+
+    fn table(&mut self, element: &Element, width: usize) -> Option<Block> {
+        let mut caption = Vec::new();
+        let mut rows: Vec<Vec<(bool, Vec<String>)>> = Vec::new();
+        for child in element.elements() {
+            match child.tag.as_str() {
+                "caption" => caption = self.flow(child, width),
+                "thead" | "tbody" | "tfoot" => {
+                    for row in child.elements().filter(|el| el.tag == "tr") {
+                        rows.push(self.row(row, width));
+                    }
+                }
+                "tr" => rows.push(self.row(child, width)),
+                _ => {}
+            }
+        }
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let single_line = rows.iter().flatten().all(|(_, lines)| lines.len() <= 1);
+        let mut widths = vec![0; columns];
+        for row in &rows {
+            for (idx, (_, lines)) in row.iter().enumerate() {
+                let len = lines.first().map_or(0, |line| line.chars().count());
+                widths[idx] = widths[idx].max(len);
+            }
+        }
+        let total = widths.iter().sum::<usize>() + 2 * columns.saturating_sub(1);
+        let mut lines = caption;
+        if columns > 1 && single_line && total <= width {
+            for (idx, row) in rows.iter().enumerate() {
+                let cells: Vec<String> = row
+                    .iter()
+                    .enumerate()
+                    .map(|(col, (_, lines))| {
+                        let text = lines.first().map(String::as_str).unwrap_or("");
+                        format!("{text:<w$}", w = widths[col])
+                    })
+                    .collect();
+                lines.push(cells.join("  ").trim_end().to_string());
+                if idx == 0 && rows.len() > 1 && row.iter().all(|(header, _)| *header) {
+                    let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+                    lines.push(rule.join("  "));
+                }
+            }
+            if lines.iter().all(|line| line.is_empty()) {
+                return None;
+            }
+            return Some(Block { lines, gap: true });
+        }
+        // Layout tables (one column, or cells holding whole paragraphs) read
+        // better as a plain sequence of blocks.
+        let mut blocks = Vec::new();
+        if !lines.is_empty() {
+            blocks.push(Block { lines, gap: false });
+        }
+        for (_, cell) in rows.into_iter().flatten() {
+            if cell.iter().any(|line| !line.trim().is_empty()) {
+                blocks.push(Block {
+                    lines: cell,
+                    gap: false,
+                });
+            }
+        }
+        let lines = join_blocks(blocks);
+        if lines.is_empty() {
+            return None;
+        }
+        Some(Block { lines, gap: true })
+    }
+
+    fn row(&mut self, element: &Element, width: usize) -> Vec<(bool, Vec<String>)> {
+        element
+            .elements()
+            .filter_map(|cell| match cell.tag.as_str() {
+                "td" => Some((false, self.flow(cell, width))),
+                "th" => Some((true, self.flow(cell, width))),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn flush(blocks: &mut Vec<Block>, inline: &mut String, width: usize) {
+    let lines = wrap(inline, width);
+    inline.clear();
+    if lines.iter().any(|line| !line.is_empty()) {
+        blocks.push(Block { lines, gap: false });
+    }
+}
+
+fn join_blocks(blocks: Vec<Block>) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous_gap = false;
+    for (idx, block) in blocks.into_iter().enumerate() {
+        if idx > 0 && (previous_gap || block.gap) {
+            lines.push(String::new());
+        }
+        previous_gap = block.gap;
+        lines.extend(block.lines);
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Greedy word wrap; `\n` from `<br>` forces a break and words longer than
+/// `width` (URLs, mostly) get a line of their own.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for segment in text.split('\n') {
+        let mut line = String::new();
+        let mut len = 0;
+        for word in segment.split(' ').filter(|word| !word.is_empty()) {
+            let word_len = word.chars().count();
+            if len > 0 && len + 1 + word_len > width {
+                lines.push(std::mem::take(&mut line));
+                len = 0;
+            }
+            if len > 0 {
+                line.push(' ');
+                len += 1;
+            }
+            line.push_str(word);
+            len += word_len;
+        }
+        lines.push(line);
+    }
+    while lines.first().is_some_and(|line| line.is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Append `text` with HTML whitespace collapsed to single spaces.
+fn push_collapsed(out: &mut String, text: &str) {
+    for c in text.chars() {
+        if matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c') {
+            if !out.is_empty() && !out.ends_with([' ', '\n']) {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+fn raw_text(element: &Element, out: &mut String) {
+    for child in &element.children {
+        match child {
+            Node::Text(text) => out.push_str(text),
+            Node::Element(child) if child.tag == "br" => out.push('\n'),
+            Node::Element(child) => raw_text(child, out),
+        }
+    }
+}
+
+fn indent(line: &str, prefix: &str) -> String {
+    if line.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}{line}")
+    }
+}
+
+const VOID: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+struct Element {
+    tag: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(tag: impl Into<String>, attrs: Vec<(String, String)>) -> Self {
+        Self {
+            tag: tag.into(),
+            attrs,
+            children: Vec::new(),
+        }
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+}
+
+/// Element tree built from html5ever's tokenizer. The input is normally the
+/// sanitizer's serialized output, so only the implied end tags common in
+/// hand-written HTML (`<p>`, `<li>`, table cells) are inferred.
+struct TreeBuilder {
+    stack: RefCell<Vec<Element>>,
+    /// Start tags dropped past [`MAX_DEPTH`] whose end tags are still due.
+    dropped: RefCell<usize>,
+}
+
+fn parse(html: &str) -> Element {
+    let builder = TreeBuilder {
+        stack: RefCell::new(vec![Element::new("#root", Vec::new())]),
+        dropped: RefCell::new(0),
+    };
+    let tokenizer = Tokenizer::new(builder, TokenizerOpts::default());
+    let queue = BufferQueue::default();
+    queue.push_back(StrTendril::from(html));
+    let _ = tokenizer.feed(&queue);
+    tokenizer.end();
+    let mut stack = tokenizer.sink.stack.into_inner();
+    while stack.len() > 1 {
+        close_top(&mut stack);
+    }
+    stack.pop().expect("root element")
+}
+
+impl TokenSink for TreeBuilder {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut stack = self.stack.borrow_mut();
+        match token {
+            Token::TagToken(tag) if tag.kind == TagKind::StartTag => {
+                let name = tag.name.to_string();
+                let attrs = tag
+                    .attrs
+                    .iter()
+                    .map(|attr| (attr.name.local.to_string(), attr.value.to_string()))
+                    .collect();
+                imply_end_tags(&mut stack, &name);
+                let element = Element::new(name.as_str(), attrs);
+                if VOID.contains(&name.as_str()) {
+                    append(&mut stack, Node::Element(element));
+                    return TokenSinkResult::Continue;
+                }
+                if stack.len() >= MAX_DEPTH {
+                    *self.dropped.borrow_mut() += 1;
+                    return TokenSinkResult::Continue;
+                }
+                stack.push(element);
+                return match name.as_str() {
+                    "script" => TokenSinkResult::RawData(RawKind::ScriptData),
+                    "style" | "xmp" | "iframe" | "noembed" | "noframes" | "noscript" => {
+                        TokenSinkResult::RawData(RawKind::Rawtext)
+                    }
+                    "title" | "textarea" => TokenSinkResult::RawData(RawKind::Rcdata),
+                    "plaintext" => TokenSinkResult::Plaintext,
+                    _ => TokenSinkResult::Continue,
+                };
+            }
+            Token::TagToken(tag) => {
+                let mut dropped = self.dropped.borrow_mut();
+                if *dropped > 0 {
+                    *dropped -= 1;
+                    return TokenSinkResult::Continue;
+                }
+                if let Some(idx) = stack.iter().rposition(|el| *el.tag == *tag.name)
+                    && idx > 0
+                {
+                    while stack.len() > idx {
+                        close_top(&mut stack);
+                    }
+                }
+            }
+            Token::CharacterTokens(text) => {
+                let top = stack.last_mut().expect("root element");
+                match top.children.last_mut() {
+                    Some(Node::Text(existing)) => existing.push_str(&text),
+                    _ => top.children.push(Node::Text(text.to_string())),
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+fn imply_end_tags(stack: &mut Vec<Element>, tag: &str) {
+    let (targets, scope): (&[&str], &[&str]) = match tag {
+        "li" => (&["li"], &["ul", "ol"]),
+        "dt" | "dd" => (&["dt", "dd"], &["dl"]),
+        "td" | "th" => (&["td", "th"], &["tr", "table"]),
+        "tr" => (&["tr", "td", "th"], &["table", "thead", "tbody", "tfoot"]),
+        _ if is_block(tag) => (&["p"], &[]),
+        _ => return,
+    };
+    for idx in (1..stack.len()).rev() {
+        let open = stack[idx].tag.as_str();
+        if targets.contains(&open) {
+            while stack.len() > idx {
+                close_top(stack);
+            }
+            return;
+        }
+        if scope.contains(&open) || scope.is_empty() {
+            return;
+        }
+    }
+}
+
+fn close_top(stack: &mut Vec<Element>) {
+    if let Some(element) = stack.pop() {
+        append(stack, Node::Element(element));
+    }
+}
+
+fn append(stack: &mut [Element], node: Node) {
+    if let Some(parent) = stack.last_mut() {
+        parent.children.push(node);
+    }
+}
+
+fn is_block(tag: &str) -> bool {
+    PARAGRAPHS.contains(&tag) || LINE_BLOCKS.contains(&tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paragraphs_and_line_breaks() {
+        let text = html_to_text(
+            "<p>First   paragraph\nspans lines.</p><p>Second<br>with a break</p><div>a div</div><div>another</div>",
+            78,
+        );
+        assert_eq!(
+            text,
+            "First paragraph spans lines.\n\nSecond\nwith a break\n\na div\nanother\n"
+        );
+    }
+
+    #[test]
+    fn wraps_to_width() {
+        let text = html_to_text(
+            "<p>the quick brown fox jumps over the lazy dog again and again</p>",
+            20,
+        );
+        assert_eq!(
+            text,
+            "the quick brown fox\njumps over the lazy\ndog again and again\n"
+        );
+    }
+
+    #[test]
+    fn lists_nest_and_number() {
+        let text = html_to_text(
+            "<ul><li>one</li><li>two<ol start=\"9\"><li>nine</li><li>ten</li></ol></li></ul>",
+            78,
+        );
+        assert_eq!(text, "* one\n* two\n  9.  nine\n  10. ten\n");
+    }
+
+    #[test]
+    fn list_numbers_saturate_at_i64_max() {
+        let text = html_to_text(
+            "<ol start=\"9223372036854775807\"><li>last</li><li>after</li></ol>",
+            78,
+        );
+        assert_eq!(
+            text,
+            "9223372036854775807. last\n9223372036854775807. after\n"
+        );
+    }
+
+    #[test]
+    fn blockquotes_are_prefixed() {
+        let text = html_to_text(
+            "<p>Sure.</p><blockquote><p>Can we meet?</p><blockquote>Earlier</blockquote></blockquote>",
+            78,
+        );
+        assert_eq!(text, "Sure.\n\n> Can we meet?\n>\n> > Earlier\n");
+    }
+
+    #[test]
+    fn links_become_footnotes() {
+        let text = html_to_text(
+            concat!(
+                "<p><a href=\"https://example.org/a\">first</a>, ",
+                "<a href=\"https://example.org/\">example.org</a>, ",
+                "<a href=\"https://example.org/a\">again</a> and ",
+                "<a href=\"mailto:bob@example.org\">Bob</a></p>"
+            ),
+            78,
+        );
+        assert_eq!(
+            text,
+            "first[1], example.org, again[1] and Bob[2]\n\nLinks:\n[1] https://example.org/a\n[2] mailto:bob@example.org\n"
+        );
+    }
+
+    #[test]
+    fn data_tables_are_aligned() {
+        let text = html_to_text(
+            "<table><tr><th>Name</th><th>Qty</th></tr><tr><td>Apples</td><td>3</td></tr><tr><td>Kiwi</td><td>12</td></tr></table>",
+            78,
+        );
+        assert_eq!(text, "Name    Qty\n------  ---\nApples  3\nKiwi    12\n");
+    }
+
+    #[test]
+    fn layout_tables_flow_as_blocks() {
+        let text = html_to_text(
+            "<table><tr><td><p>Intro</p><p>More</p></td></tr><tr><td>Footer</td></tr></table>",
+            78,
+        );
+        assert_eq!(text, "Intro\n\nMore\nFooter\n");
+    }
+
+    #[test]
+    fn deep_nesting_keeps_a_minimum_width() {
+        let depth = 60;
+        let mut html = String::new();
+        for tag in ["blockquote", "ul><li", "dl><dd"]
+            .iter()
+            .cycle()
+            .take(depth)
+        {
+            html.push_str(&format!("<{tag}>"));
+        }
+        html.push_str("<hr>deep text");
+        let text = html_to_text(&html, 78);
+        assert!(text.contains("deep text"));
+        let rule = "-".repeat(MIN_WIDTH / 2);
+        assert!(text.lines().any(|line| line.ends_with(&rule)));
+    }
+
+    #[test]
+    fn hostile_nesting_is_flattened() {
+        for tag in ["blockquote", "div"] {
+            let depth = 5000;
+            let html = format!(
+                "{}deep text{}after",
+                format!("<{tag}>").repeat(depth),
+                format!("</{tag}>").repeat(depth)
+            );
+            let text = html_to_text(&html, 78);
+            assert!(text.contains("deep text"));
+            assert!(text.trim_end().ends_with("after"));
+        }
+    }
+
+    #[test]
+    fn pre_keeps_whitespace_and_headings_are_underlined() {
+        let text = html_to_text(
+            "<h1>Title</h1><pre>\n  a  b\n    c</pre><h3>Minor</h3><img alt=\"Logo\"><hr>",
+            20,
+        );
+        assert_eq!(
+            text,
+            "Title\n=====\n\n  a  b\n    c\n\nMinor\n\n[Logo]\n\n--------------------\n"
+        );
+    }
+}
@@ -1,20 +1,453 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use ammonia::{Builder, UrlRelative};
 use anyhow::{Context, Result};
 use duct::cmd;
 
-pub fn sanitize_html(input: &str) -> Result<String> {
-    sanitize_html_with("sanitize-html", &[], input)
+use super::plaintext::html_to_text;
+
+/// Sanitizer policy selected by `render_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderPolicy {
+    /// Document structure, links and images only; no inline CSS.
+    Strict,
+    /// Strict plus rewritten inline CSS and presentational attributes, for
+    /// newsletters laid out with tables.
+    Moderate,
 }
 
-pub fn sanitize_html_with(command: &str, args: &[&str], input: &str) -> Result<String> {
-    cmd(command, args)
-        .stdin_bytes(input.as_bytes())
-        .stderr_to_stdout()
-        .read()
-        .with_context(|| format!("running {command}"))
+impl RenderPolicy {
+    /// `moderate` selects [`RenderPolicy::Moderate`]; anything else is strict.
+    pub fn from_mode(mode: &str) -> Self {
+        if mode.trim().eq_ignore_ascii_case("moderate") {
+            RenderPolicy::Moderate
+        } else {
+            RenderPolicy::Strict
+        }
+    }
 }
 
-pub fn render_plaintext(html: &str) -> Result<String> {
-    render_plaintext_with("lynx", &["-dump", "-stdin"], html)
+const STRICT_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "b",
+    "bdi",
+    "bdo",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "del",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "samp",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "time",
+    "tr",
+    "u",
+    "ul",
+    "var",
+];
+
+const MODERATE_TAGS: &[&str] = &[
+    "address", "article", "aside", "big", "center", "details", "font", "footer", "header", "main",
+    "nav", "section", "summary", "tt",
+];
+
+/// Elements dropped together with their content. Form controls go too, so
+/// a message cannot render something that looks like a login prompt.
+const DROPPED_TAGS: &[&str] = &[
+    "script", "style", "title", "template", "iframe", "object", "svg", "math", "textarea",
+    "select", "noscript",
+];
+
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto", "cid"];
+
+const MODERATE_ATTRIBUTES: &[&str] = &[
+    "style",
+    "align",
+    "valign",
+    "width",
+    "height",
+    "bgcolor",
+    "border",
+    "cellpadding",
+    "cellspacing",
+];
+
+const CSS_PROPERTIES: &[&str] = &[
+    "background",
+    "background-color",
+    "background-image",
+    "border",
+    "border-bottom",
+    "border-collapse",
+    "border-color",
+    "border-left",
+    "border-radius",
+    "border-right",
+    "border-spacing",
+    "border-style",
+    "border-top",
+    "border-width",
+    "color",
+    "direction",
+    "display",
+    "font",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-variant",
+    "font-weight",
+    "height",
+    "letter-spacing",
+    "line-height",
+    "list-style",
+    "list-style-type",
+    "margin",
+    "margin-bottom",
+    "margin-left",
+    "margin-right",
+    "margin-top",
+    "max-width",
+    "min-width",
+    "padding",
+    "padding-bottom",
+    "padding-left",
+    "padding-right",
+    "padding-top",
+    "table-layout",
+    "text-align",
+    "text-decoration",
+    "text-indent",
+    "text-transform",
+    "vertical-align",
+    "white-space",
+    "width",
+    "word-spacing",
+];
+
+const CSS_DISPLAY_VALUES: &[&str] = &[
+    "block",
+    "inline",
+    "inline-block",
+    "list-item",
+    "table",
+    "table-cell",
+    "table-row",
+];
+
+const CSS_FUNCTIONS: &[&str] = &["rgb", "rgba", "hsl", "hsla"];
+
+/// Whether remote resources survive sanitization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteContent {
+    /// Replace remote images and CSS `url()`s with inert placeholders.
+    Block,
+    /// Keep them, for `owl render --allow-remote`.
+    Allow,
+}
+
+/// Stand-in for a blocked remote image: a grey box that loads nothing.
+pub const BLOCKED_IMAGE: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='24' height='24'%3E%3Crect width='24' height='24' fill='%23e5e5e5'/%3E%3C/svg%3E";
+
+/// Where inline part links point, relative to a sender directory.
+pub const ATTACHMENT_LINK_PREFIX: &str = "../attachments/";
+
+/// Output of [`sanitize_html`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SanitizedHtml {
+    pub html: String,
+    /// Remote URLs replaced by placeholders, in document order without
+    /// repeats. Empty when remote content was allowed.
+    pub blocked: Vec<String>,
+}
+
+/// Clean untrusted message HTML against the allowlist for `policy`. Anything
+/// not listed is dropped: scripts, event handlers, forms, `<style>` blocks,
+/// `<link>` stylesheets, relative URLs other than inline part links and every
+/// scheme besides http(s), mailto and cid. With [`RemoteContent::Block`],
+/// http(s) images and CSS `url()`s are swapped for placeholders and reported
+/// in `blocked`.
+pub fn sanitize_html(input: &str, policy: RenderPolicy, remote: RemoteContent) -> SanitizedHtml {
+    sanitize_html_with_inline(input, policy, remote, &HashMap::new())
+}
+
+/// [`sanitize_html`], pointing `cid:` images at the sources in `inline`,
+/// keyed by lowercase Content-ID without angle brackets. References to
+/// parts the message does not carry are left as they are.
+pub fn sanitize_html_with_inline(
+    input: &str,
+    policy: RenderPolicy,
+    remote: RemoteContent,
+    inline: &HashMap<String, String>,
+) -> SanitizedHtml {
+    let blocked = Arc::new(Mutex::new(Vec::new()));
+    let html = builder(policy, remote, inline.clone(), Arc::clone(&blocked))
+        .clean(input)
+        .to_string();
+    let mut blocked = std::mem::take(&mut *blocked.lock().expect("blocked urls lock"));
+    let mut seen = HashSet::new();
+    blocked.retain(|url: &String| seen.insert(url.clone()));
+    SanitizedHtml { html, blocked }
+}
+
+fn builder(
+    policy: RenderPolicy,
+    remote: RemoteContent,
+    inline: HashMap<String, String>,
+    blocked: Arc<Mutex<Vec<String>>>,
+) -> Builder<'static> {
+    let mut tags: HashSet<&str> = STRICT_TAGS.iter().copied().collect();
+    let mut generic: HashSet<&str> = ["title", "lang", "dir"].into_iter().collect();
+    let mut per_tag: HashMap<&str, HashSet<&str>> = HashMap::from([
+        ("a", HashSet::from(["href"])),
+        ("img", HashSet::from(["src", "alt", "width", "height"])),
+        ("td", HashSet::from(["colspan", "rowspan"])),
+        ("th", HashSet::from(["colspan", "rowspan", "scope"])),
+        ("ol", HashSet::from(["start", "type"])),
+        ("li", HashSet::from(["value"])),
+    ]);
+    if policy == RenderPolicy::Moderate {
+        tags.extend(MODERATE_TAGS);
+        generic.extend(MODERATE_ATTRIBUTES);
+        per_tag.insert("font", HashSet::from(["color", "face", "size"]));
+    }
+    let mut builder = Builder::empty();
+    builder
+        .tags(tags)
+        .clean_content_tags(DROPPED_TAGS.iter().copied().collect())
+        .generic_attributes(generic)
+        .tag_attributes(per_tag)
+        .url_schemes(URL_SCHEMES.iter().copied().collect())
+        .url_relative(UrlRelative::Custom(Box::new(attachment_link)))
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .strip_comments(true)
+        .attribute_filter(move |element, attribute, value| {
+            let mut blocked = blocked.lock().expect("blocked urls lock");
+            match (element, attribute) {
+                ("img", "src") if let Some(source) = cid_target(value, &inline) => {
+                    Some(Cow::Owned(source.clone()))
+                }
+                ("img", "src") if remote == RemoteContent::Block && is_remote_url(value) => {
+                    blocked.push(value.to_string());
+                    Some(Cow::Borrowed(BLOCKED_IMAGE))
+                }
+                (_, "style") => {
+                    let css = sanitize_css(value, remote, &mut blocked);
+                    (!css.is_empty()).then_some(Cow::Owned(css))
+                }
+                _ => Some(Cow::Borrowed(value)),
+            }
+        });
+    builder
+}
+
+/// Relative URLs survive only as the `../attachments/<sha256>__<name>` links
+/// written for inline parts, so they cannot leave the list's blob store.
+fn attachment_link(url: &str) -> Option<Cow<'_, str>> {
+    let blob = url.strip_prefix(ATTACHMENT_LINK_PREFIX)?;
+    let (sha256, name) = blob.split_once("__")?;
+    let valid = sha256.len() == 64
+        && sha256.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && !name.is_empty()
+        && !name.contains(['/', '\\']);
+    valid.then_some(Cow::Borrowed(url))
+}
+
+fn cid_target<'a>(value: &str, inline: &'a HashMap<String, String>) -> Option<&'a String> {
+    let (scheme, id) = value.split_once(':')?;
+    if !scheme.eq_ignore_ascii_case("cid") {
+        return None;
+    }
+    inline.get(&percent_decode(id).to_lowercase())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = value.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_remote_url(value: &str) -> bool {
+    value.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+    })
+}
+
+/// Rewrite a `style` attribute down to allowlisted properties whose values
+/// call no functions besides colours and plain http(s) `url()`s;
+/// `expression()`, escapes and positioning never survive. Blocked URLs
+/// become `none` and are pushed onto `blocked`.
+fn sanitize_css(style: &str, remote: RemoteContent, blocked: &mut Vec<String>) -> String {
+    let mut declarations = Vec::new();
+    for declaration in style.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let property = property.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if value.is_empty() || !CSS_PROPERTIES.contains(&property.as_str()) {
+            continue;
+        }
+        let Some(inert) = replace_css_urls(value, |_| "none".to_string()) else {
+            continue;
+        };
+        if !css_value_safe(&inert) {
+            continue;
+        }
+        if property == "display"
+            && !CSS_DISPLAY_VALUES.contains(&value.to_ascii_lowercase().as_str())
+        {
+            continue;
+        }
+        let value = match remote {
+            RemoteContent::Block => replace_css_urls(value, |url| {
+                blocked.push(url.to_string());
+                "none".to_string()
+            })
+            .unwrap_or(inert),
+            RemoteContent::Allow => {
+                replace_css_urls(value, |url| format!("url(\"{url}\")")).unwrap_or(inert)
+            }
+        };
+        declarations.push(format!("{property}: {value}"));
+    }
+    declarations.join("; ")
+}
+
+/// Substitute every `url(...)` in a CSS value with `replace(url)`, or `None`
+/// when one holds anything but a plain http(s) URL.
+fn replace_css_urls(value: &str, mut replace: impl FnMut(&str) -> String) -> Option<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.to_ascii_lowercase().find("url(") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 4..];
+        let end = after.find(')')?;
+        let url = after[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+        if !is_remote_url(url)
+            || url.chars().any(|c| {
+                matches!(c, '"' | '\'' | '(' | ')' | '\\' | '<' | '>')
+                    || c.is_whitespace()
+                    || c.is_control()
+            })
+        {
+            return None;
+        }
+        out.push_str(&replace(url));
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn css_value_safe(value: &str) -> bool {
+    if value
+        .chars()
+        .any(|c| matches!(c, '\\' | '<' | '>' | '@' | '{' | '}') || c.is_control())
+    {
+        return false;
+    }
+    let lower = value.to_ascii_lowercase();
+    let mut rest = lower.as_str();
+    while let Some(open) = rest.find('(') {
+        let name: String = rest[..open]
+            .chars()
+            .rev()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        if !CSS_FUNCTIONS.contains(&name.as_str()) {
+            return false;
+        }
+        rest = &rest[open + 1..];
+    }
+    true
+}
+
+/// Backend for the `.txt` render, selected by `plaintext_renderer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextRenderer {
+    /// In-crate converter; needs no external programs.
+    Native,
+    /// `lynx -dump`, for installs that prefer its layout.
+    Lynx,
+}
+
+impl TextRenderer {
+    /// `lynx` selects [`TextRenderer::Lynx`]; anything else is native.
+    pub fn from_setting(value: &str) -> Self {
+        if value.trim().eq_ignore_ascii_case("lynx") {
+            TextRenderer::Lynx
+        } else {
+            TextRenderer::Native
+        }
+    }
+}
+
+pub fn render_plaintext(html: &str, renderer: TextRenderer, width: usize) -> Result<String> {
+    match renderer {
+        TextRenderer::Native => Ok(html_to_text(html, width)),
+        TextRenderer::Lynx => {
+            let width = format!("-width={width}");
+            render_plaintext_with("lynx", &["-dump", "-stdin", &width], html)
+        }
+    }
 }
 
 pub fn render_plaintext_with(command: &str, args: &[&str], html: &str) -> Result<String> {
@@ -67,31 +500,145 @@ mod tests {
     }
 
     #[test]
-    fn sanitize_via_custom_command() {
-        let dir = tempfile::tempdir().unwrap();
-        let script = write_script(
-            &dir,
-            "custom-sanitize",
-            "#!/bin/sh\nsed 's/<script/[blocked]/g'\n",
+    fn strips_scripts_handlers_and_javascript_urls() {
+        let html = concat!(
+            "<div onclick=\"steal()\"><script>alert(1)</script>",
+            "<a href=\"javascript:alert(1)\">x</a>",
+            "<a href=\"https://example.org/\" target=\"_blank\">ok</a>",
+            "<img src=\"x\" onerror=\"alert(1)\"></div>"
+        );
+        let clean = sanitize_html(html, RenderPolicy::Strict, RemoteContent::Block).html;
+        assert_eq!(
+            clean,
+            concat!(
+                "<div><a rel=\"noopener noreferrer nofollow\">x</a>",
+                "<a href=\"https://example.org/\" rel=\"noopener noreferrer nofollow\">ok</a>",
+                "<img></div>"
+            )
         );
-        let html = "<div><script>alert(1)</script></div>";
-        let sanitized = sanitize_html_with(&script, &[], html).unwrap();
-        assert!(sanitized.contains("[blocked]"));
     }
 
     #[test]
-    #[serial]
-    fn sanitize_html_searches_path() {
-        let dir = tempfile::tempdir().unwrap();
-        let _ = write_script(
-            &dir,
-            "sanitize-html",
-            "#!/bin/sh\ncat | sed 's/<script/SANITIZED/g'\n",
+    fn drops_forms_with_their_controls() {
+        let html = "<form action=\"https://evil.example/\"><p>Password</p><input type=\"password\" name=\"pw\"><textarea>hi</textarea><button>Log in</button></form>";
+        let clean = sanitize_html(html, RenderPolicy::Moderate, RemoteContent::Block).html;
+        assert_eq!(clean, "<p>Password</p>Log in");
+    }
+
+    #[test]
+    fn policy_decides_on_inline_css() {
+        let html = "<p style=\"color: red; position: fixed\" align=\"center\">hi</p>";
+        assert_eq!(
+            sanitize_html(html, RenderPolicy::Strict, RemoteContent::Block).html,
+            "<p>hi</p>"
         );
-        let result = with_prepended_path(&dir, || {
-            sanitize_html("<div><script>alert(1)</script></div>").unwrap()
-        });
-        assert!(result.contains("SANITIZED"));
+        assert_eq!(
+            sanitize_html(html, RenderPolicy::Moderate, RemoteContent::Block).html,
+            "<p style=\"color: red\" align=\"center\">hi</p>"
+        );
+        assert_eq!(RenderPolicy::from_mode("moderate"), RenderPolicy::Moderate);
+        assert_eq!(RenderPolicy::from_mode("strict"), RenderPolicy::Strict);
+        assert_eq!(RenderPolicy::from_mode("bogus"), RenderPolicy::Strict);
+    }
+
+    fn css(style: &str) -> String {
+        sanitize_css(style, RemoteContent::Block, &mut Vec::new())
+    }
+
+    #[test]
+    fn css_rewrite_keeps_safe_declarations_only() {
+        assert_eq!(
+            css(
+                "COLOR: rgb(1, 2, 3); background: url(https://t.example/p.gif); width: expression(alert(1)); font-size: 12px"
+            ),
+            "color: rgb(1, 2, 3); background: none; font-size: 12px"
+        );
+        assert_eq!(css("display: none; display: block"), "display: block");
+        assert_eq!(css("color: \\72 ed; margin: 0 auto"), "margin: 0 auto");
+        assert_eq!(css("behavior: url(x.htc); -moz-binding: x"), "");
+        assert_eq!(
+            css("font-family: \"Tr\u{e9}buchet\", sans-serif"),
+            "font-family: \"Tr\u{e9}buchet\", sans-serif"
+        );
+    }
+
+    #[test]
+    fn remote_images_and_css_urls_become_placeholders() {
+        let html = concat!(
+            "<div style=\"background: #fff url('https://t.example/bg.png') no-repeat\">",
+            "<img src=\"https://t.example/open.gif?id=1\" alt=\"Logo\">",
+            "<img src=\"cid:logo@example\">",
+            "<img src=\"https://t.example/open.gif?id=1\"></div>"
+        );
+        let blocked = sanitize_html(html, RenderPolicy::Moderate, RemoteContent::Block);
+        assert!(!blocked.html.contains("t.example"));
+        assert!(
+            blocked
+                .html
+                .contains(&format!("src=\"{BLOCKED_IMAGE}\" alt=\"Logo\""))
+        );
+        assert!(blocked.html.contains("src=\"cid:logo@example\""));
+        assert!(blocked.html.contains("background: #fff none no-repeat"));
+        assert_eq!(
+            blocked.blocked,
+            vec![
+                "https://t.example/bg.png".to_string(),
+                "https://t.example/open.gif?id=1".to_string()
+            ]
+        );
+
+        let allowed = sanitize_html(html, RenderPolicy::Moderate, RemoteContent::Allow);
+        assert!(allowed.blocked.is_empty());
+        assert!(
+            allowed
+                .html
+                .contains("src=\"https://t.example/open.gif?id=1\"")
+        );
+        assert!(
+            allowed
+                .html
+                .contains("background: #fff url(&quot;https://t.example/bg.png&quot;) no-repeat")
+        );
+    }
+
+    #[test]
+    fn cid_images_point_at_inline_parts() {
+        let link = format!("{ATTACHMENT_LINK_PREFIX}{}__logo.png", "ab".repeat(32));
+        let inline = HashMap::from([("logo@example.org".to_string(), link.clone())]);
+        let html = concat!(
+            "<img src=\"cid:Logo%40example.org\" alt=\"Logo\">",
+            "<img src=\"cid:missing@example.org\">",
+            "<img src=\"../attachments/x__/../../.env\">"
+        );
+        let clean =
+            sanitize_html_with_inline(html, RenderPolicy::Strict, RemoteContent::Block, &inline);
+        assert_eq!(
+            clean.html,
+            format!("<img src=\"{link}\" alt=\"Logo\"><img src=\"cid:missing@example.org\"><img>")
+        );
+        assert!(clean.blocked.is_empty());
+    }
+
+    #[test]
+    fn css_urls_must_be_plain_remote_urls() {
+        let mut blocked = Vec::new();
+        assert_eq!(
+            sanitize_css(
+                "background-image: url(javascript:alert(1)); color: red",
+                RemoteContent::Allow,
+                &mut blocked
+            ),
+            "color: red"
+        );
+        assert_eq!(
+            sanitize_css(
+                "background: url(https://a.example/x\\).png)",
+                RemoteContent::Allow,
+                &mut blocked
+            ),
+            ""
+        );
+        assert!(blocked.is_empty());
     }
 
     #[test]
@@ -100,8 +647,10 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let original = std::env::var_os("PATH");
         unsafe { std::env::remove_var("PATH") };
-        let _ = write_script(&dir, "sanitize-html", "#!/bin/sh\nexec /bin/cat\n");
-        let output = with_prepended_path(&dir, || sanitize_html("<p>ok</p>").unwrap());
+        let _ = write_script(&dir, "lynx", "#!/bin/sh\nexec /bin/cat\n");
+        let output = with_prepended_path(&dir, || {
+            render_plaintext("<p>ok</p>", TextRenderer::Lynx, 78).unwrap()
+        });
         assert_eq!(output.trim(), "<p>ok</p>");
         match original {
             Some(path) => unsafe { std::env::set_var("PATH", path) },
@@ -115,8 +664,10 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let original = std::env::var_os("PATH");
         unsafe { std::env::remove_var("PATH") };
-        let _ = write_script(&dir, "sanitize-html", "#!/bin/sh\nexec /bin/cat\n");
-        with_prepended_path(&dir, || sanitize_html("<p>noop</p>").unwrap());
+        let _ = write_script(&dir, "lynx", "#!/bin/sh\nexec /bin/cat\n");
+        with_prepended_path(&dir, || {
+            render_plaintext("<p>noop</p>", TextRenderer::Lynx, 78).unwrap()
+        });
         assert_eq!(std::env::var("PATH").unwrap(), "");
         match original {
             Some(path) => unsafe { std::env::set_var("PATH", path) },
@@ -124,14 +675,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn sanitize_html_failure_bubbles() {
-        let dir = tempfile::tempdir().unwrap();
-        let script = write_script(&dir, "fail-sanitize", "#!/bin/sh\nexit 2\n");
-        let err = sanitize_html_with(&script, &[], "<div>").unwrap_err();
-        assert!(err.to_string().contains("running"));
-    }
-
     #[test]
     fn render_plaintext_invokes_command() {
         let dir = tempfile::tempdir().unwrap();
@@ -142,11 +685,21 @@ mod tests {
 
     #[test]
     #[serial]
-    fn render_plaintext_default_uses_lynx() {
+    fn render_plaintext_lynx_backend_runs_lynx() {
         let dir = tempfile::tempdir().unwrap();
-        let _ = write_script(&dir, "lynx", "#!/bin/sh\ncat\n");
-        let rendered = with_prepended_path(&dir, || render_plaintext("body").unwrap());
-        assert_eq!(rendered, "body");
+        let _ = write_script(&dir, "lynx", "#!/bin/sh\necho \"$3\"\ncat\n");
+        let rendered = with_prepended_path(&dir, || {
+            render_plaintext("body", TextRenderer::Lynx, 60).unwrap()
+        });
+        assert_eq!(rendered, "-width=60\nbody");
+    }
+
+    #[test]
+    fn render_plaintext_native_needs_no_programs() {
+        let rendered = render_plaintext("<p>body</p>", TextRenderer::Native, 78).unwrap();
+        assert_eq!(rendered, "body\n");
+        assert_eq!(TextRenderer::from_setting("lynx"), TextRenderer::Lynx);
+        assert_eq!(TextRenderer::from_setting("native"), TextRenderer::Native);
     }
 
     #[test]
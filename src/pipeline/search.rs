@@ -0,0 +1,779 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{
+    Date, OffsetDateTime, Time, format_description::well_known::Rfc3339, macros::format_description,
+};
+
+use crate::{
+    fsops::{
+        io_atom::{create_dir_all, write_atomic},
+        layout::MailLayout,
+        scan::{SENDER_LISTS, SkippedSidecar, StoredMessage, load_message, scan_messages},
+    },
+    model::filename::ulid_from_filename,
+};
+
+/// Longest token kept; longer runs are usually encoded junk.
+const MAX_TERM_LEN: usize = 64;
+/// Size at which an add folds the delta log back into the term shards.
+const COMPACT_AFTER_BYTES: u64 = 4 << 20;
+
+/// Inverted index over received mail, kept as plain files under `index/`:
+///
+/// * `docs/<ULID>.yml` holds what a hit displays and filters on, plus the
+///   terms the message was indexed under so it can be removed again.
+/// * `terms/<xx>.idx` holds `term<TAB>ULID ULID…` lines as of the last
+///   compaction, sharded by the first byte of the term's SHA-256.
+/// * `delta.log` gets one `+ULID<TAB>terms…` or `-ULID<TAB>terms…` line per
+///   add or removal since then, so delivery appends a line instead of
+///   rewriting shards. Searches replay it over the shards; a rebuild, or a
+///   log past [`COMPACT_AFTER_BYTES`], folds it in and starts a new one.
+pub struct SearchIndex {
+    root: PathBuf,
+    dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct IndexedDoc {
+    ulid: String,
+    list: String,
+    /// Sidecar path relative to the mail root.
+    sidecar: String,
+    from: String,
+    subject: String,
+    received_at: String,
+    #[serde(default)]
+    has_attachment: bool,
+    terms: Vec<String>,
+}
+
+/// One message matching a [`SearchQuery`].
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SearchHit {
+    pub ulid: String,
+    pub list: String,
+    pub from: String,
+    pub subject: String,
+    pub received_at: String,
+    pub path: PathBuf,
+}
+
+/// Parsed `owl search` query: bare words must all occur; qualifiers narrow
+/// the result further.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    /// `from:` — substring of the From header, case-insensitive.
+    pub from: Option<String>,
+    /// `list:` — one of the received lists.
+    pub list: Option<String>,
+    /// `before:YYYY-MM-DD` — received before that day (UTC).
+    pub before: Option<OffsetDateTime>,
+    /// `has:attachment` — carries a real (not inline) attachment.
+    pub has_attachment: bool,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut parsed = SearchQuery::default();
+        for word in query.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", value)) if !value.is_empty() => {
+                    parsed.from = Some(value.to_lowercase())
+                }
+                Some(("list", value)) => {
+                    let list = value.to_ascii_lowercase();
+                    if !SENDER_LISTS.contains(&list.as_str()) {
+                        bail!("unknown list in search: {value}");
+                    }
+                    parsed.list = Some(list);
+                }
+                Some(("before", value)) => {
+                    let date = Date::parse(value, format_description!("[year]-[month]-[day]"))
+                        .map_err(|_| anyhow!("invalid date in before:{value} (use YYYY-MM-DD)"))?;
+                    parsed.before = Some(date.with_time(Time::MIDNIGHT).assume_utc());
+                }
+                Some(("has", value)) => {
+                    if !matches!(value, "attachment" | "attachments") {
+                        bail!("unsupported search qualifier has:{value}");
+                    }
+                    parsed.has_attachment = true;
+                }
+                _ => parsed.terms.extend(tokenize(word)),
+            }
+        }
+        parsed.terms.sort();
+        parsed.terms.dedup();
+        Ok(parsed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+            && self.from.is_none()
+            && self.list.is_none()
+            && self.before.is_none()
+            && !self.has_attachment
+    }
+}
+
+impl SearchIndex {
+    pub fn new(layout: &MailLayout) -> Self {
+        Self {
+            root: layout.root().to_path_buf(),
+            dir: layout.search_index(),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.dir.join("docs").exists()
+    }
+
+    /// Index (or re-index) a stored message.
+    pub fn add(&self, message: &StoredMessage) -> Result<()> {
+        let _lock = self.lock()?;
+        self.remove_locked(&message.sidecar.ulid)?;
+        let doc = self.document(message)?;
+        write_atomic(
+            &self.doc_path(&doc.ulid),
+            serde_yaml::to_string(&doc)?.as_bytes(),
+        )?;
+        let size = self.append_delta('+', &doc.ulid, &doc.terms)?;
+        if size > COMPACT_AFTER_BYTES {
+            self.compact_locked()?;
+        }
+        Ok(())
+    }
+
+    /// Index the message just delivered to `message_path` (its `.eml`).
+    /// Does nothing until the index has been built: the first search builds
+    /// it from the whole tree.
+    pub fn add_delivered(&self, message_path: &Path) -> Result<()> {
+        if !self.exists() {
+            return Ok(());
+        }
+        let relative = message_path
+            .strip_prefix(&self.root)
+            .with_context(|| format!("{} is outside the mail root", message_path.display()))?;
+        let list = relative
+            .components()
+            .next()
+            .and_then(|component| component.as_os_str().to_str())
+            .ok_or_else(|| anyhow!("no list in {}", message_path.display()))?;
+        let stem = message_path
+            .file_stem()
+            .ok_or_else(|| anyhow!("no file name in {}", message_path.display()))?
+            .to_string_lossy();
+        let sidecar_path = message_path.with_file_name(format!(".{stem}.yml"));
+        self.add(&load_message(list, sidecar_path)?)
+    }
+
+    /// Drop a message from the index; `false` when it was not indexed.
+    pub fn remove(&self, ulid: &str) -> Result<bool> {
+        let _lock = self.lock()?;
+        self.remove_locked(ulid)
+    }
+
+    /// Drop the messages whose sidecars were at `sidecar_paths`, e.g. after
+    /// retention deleted them. Returns how many were indexed.
+    pub fn remove_sidecars(&self, sidecar_paths: &[PathBuf]) -> Result<usize> {
+        let mut removed = 0;
+        if !self.exists() {
+            return Ok(removed);
+        }
+        for path in sidecar_paths {
+            let Some(ulid) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(ulid_from_filename)
+            else {
+                continue;
+            };
+            if self.remove(ulid)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Throw the index away and index every received message again.
+    /// Returns how many messages were indexed and the sidecars that could
+    /// not be read.
+    pub fn rebuild(&self, layout: &MailLayout) -> Result<(usize, Vec<SkippedSidecar>)> {
+        let _lock = self.lock()?;
+        for sub in ["docs", "terms"] {
+            let path = self.dir.join(sub);
+            if path.exists() {
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+            }
+        }
+        self.remove_delta()?;
+        create_dir_all(&self.dir.join("docs"))?;
+        let scan = scan_messages(layout)?;
+        let mut shards: BTreeMap<String, HashMap<String, BTreeSet<String>>> = BTreeMap::new();
+        let mut count = 0;
+        for message in &scan.messages {
+            if !SENDER_LISTS.contains(&message.list.as_str()) {
+                continue;
+            }
+            let doc = self.document(message)?;
+            for term in &doc.terms {
+                shards
+                    .entry(shard_of(term))
+                    .or_default()
+                    .entry(term.clone())
+                    .or_default()
+                    .insert(doc.ulid.clone());
+            }
+            write_atomic(
+                &self.doc_path(&doc.ulid),
+                serde_yaml::to_string(&doc)?.as_bytes(),
+            )?;
+            count += 1;
+        }
+        for (shard, postings) in shards {
+            self.save_shard(&shard, &postings)?;
+        }
+        Ok((count, scan.skipped))
+    }
+
+    /// Messages matching `query`, newest first. Entries whose sidecar has
+    /// gone since they were indexed are skipped.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        let candidates: Vec<String> = if query.terms.is_empty() {
+            self.all_ulids()?
+        } else {
+            let deltas = self.load_delta()?;
+            let mut matched: Option<BTreeSet<String>> = None;
+            for term in &query.terms {
+                let mut postings = self
+                    .load_shard(&shard_of(term))?
+                    .remove(term)
+                    .unwrap_or_default();
+                for delta in &deltas {
+                    delta.apply(term, &mut postings);
+                }
+                matched = Some(match matched {
+                    Some(current) => current.intersection(&postings).cloned().collect(),
+                    None => postings,
+                });
+            }
+            matched.unwrap_or_default().into_iter().collect()
+        };
+        let mut hits = Vec::new();
+        for ulid in candidates {
+            let Some(doc) = self.load_doc(&ulid)? else {
+                continue;
+            };
+            if !matches_filters(&doc, query) {
+                continue;
+            }
+            let path = self.root.join(&doc.sidecar);
+            if !path.exists() {
+                continue;
+            }
+            hits.push(SearchHit {
+                ulid: doc.ulid,
+                list: doc.list,
+                from: doc.from,
+                subject: doc.subject,
+                received_at: doc.received_at,
+                path,
+            });
+        }
+        hits.sort_by(|a, b| b.received_at.cmp(&a.received_at));
+        Ok(hits)
+    }
+
+    fn document(&self, message: &StoredMessage) -> Result<IndexedDoc> {
+        let sidecar = &message.sidecar;
+        let headers = &sidecar.headers_cache;
+        let mut text = vec![
+            headers.subject.clone(),
+            headers.from.clone(),
+            headers.to.join(" "),
+            headers.cc.join(" "),
+        ];
+        text.extend(sidecar.attachments.iter().map(|a| a.name.clone()));
+        if let Some(plain) = message.plain_path()
+            && plain.exists()
+        {
+            text.push(
+                fs::read_to_string(&plain)
+                    .with_context(|| format!("reading {}", plain.display()))?,
+            );
+        }
+        let terms: BTreeSet<String> = text.iter().flat_map(|field| tokenize(field)).collect();
+        let relative = message
+            .sidecar_path
+            .strip_prefix(&self.root)
+            .unwrap_or(&message.sidecar_path);
+        Ok(IndexedDoc {
+            ulid: sidecar.ulid.clone(),
+            list: message.list.clone(),
+            sidecar: relative.to_string_lossy().into_owned(),
+            from: headers.from.clone(),
+            subject: headers.subject.clone(),
+            received_at: sidecar.received_at.clone(),
+            has_attachment: sidecar.attachments.iter().any(|a| !a.inline),
+            terms: terms.into_iter().collect(),
+        })
+    }
+
+    fn remove_locked(&self, ulid: &str) -> Result<bool> {
+        let Some(doc) = self.load_doc(ulid)? else {
+            return Ok(false);
+        };
+        self.append_delta('-', &doc.ulid, &doc.terms)?;
+        fs::remove_file(self.doc_path(&doc.ulid))?;
+        Ok(true)
+    }
+
+    /// Fold the delta log into the term shards it touches and drop it.
+    fn compact_locked(&self) -> Result<()> {
+        let deltas = self.load_delta()?;
+        let mut shards: BTreeMap<String, HashMap<String, BTreeSet<String>>> = BTreeMap::new();
+        for delta in &deltas {
+            for term in &delta.terms {
+                let shard = shard_of(term);
+                if !shards.contains_key(&shard) {
+                    let postings = self.load_shard(&shard)?;
+                    shards.insert(shard.clone(), postings);
+                }
+                let postings = shards.get_mut(&shard).expect("shard loaded above");
+                let ulids = postings.entry(term.clone()).or_default();
+                delta.apply(term, ulids);
+                if ulids.is_empty() {
+                    postings.remove(term);
+                }
+            }
+        }
+        for (shard, postings) in shards {
+            self.save_shard(&shard, &postings)?;
+        }
+        self.remove_delta()
+    }
+
+    fn delta_path(&self) -> PathBuf {
+        self.dir.join("delta.log")
+    }
+
+    /// Append one `<op>ULID<TAB>terms…` line; returns the log's new size.
+    fn append_delta(&self, op: char, ulid: &str, terms: &[String]) -> Result<u64> {
+        let path = self.delta_path();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let line = format!("{op}{ulid}\t{}\n", terms.join(" "));
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(file.metadata()?.len())
+    }
+
+    /// Complete lines of the delta log, oldest first. A line still being
+    /// appended by another process is left for the next search.
+    fn load_delta(&self) -> Result<Vec<Delta>> {
+        let path = self.delta_path();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let data =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(data
+            .split_inclusive('\n')
+            .filter_map(|line| Delta::parse(line.strip_suffix('\n')?))
+            .collect())
+    }
+
+    fn remove_delta(&self) -> Result<()> {
+        let path = self.delta_path();
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<fs::File> {
+        create_dir_all(&self.dir)?;
+        let path = self.dir.join("lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        file.lock_exclusive()
+            .with_context(|| format!("locking {}", path.display()))?;
+        Ok(file)
+    }
+
+    fn doc_path(&self, ulid: &str) -> PathBuf {
+        self.dir
+            .join("docs")
+            .join(format!("{}.yml", ulid.to_ascii_uppercase()))
+    }
+
+    fn load_doc(&self, ulid: &str) -> Result<Option<IndexedDoc>> {
+        let path = self.doc_path(ulid);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let yaml =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let doc =
+            serde_yaml::from_str(&yaml).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(doc))
+    }
+
+    fn all_ulids(&self) -> Result<Vec<String>> {
+        let dir = self.dir.join("docs");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ulids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("yml")
+                && let Some(stem) = path.file_stem().and_then(|stem| stem.to_str())
+            {
+                ulids.push(stem.to_string());
+            }
+        }
+        Ok(ulids)
+    }
+
+    fn shard_path(&self, shard: &str) -> PathBuf {
+        self.dir.join("terms").join(format!("{shard}.idx"))
+    }
+
+    fn load_shard(&self, shard: &str) -> Result<HashMap<String, BTreeSet<String>>> {
+        let path = self.shard_path(shard);
+        let mut postings = HashMap::new();
+        if !path.exists() {
+            return Ok(postings);
+        }
+        let data =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        for line in data.lines() {
+            if let Some((term, ulids)) = line.split_once('\t') {
+                postings.insert(
+                    term.to_string(),
+                    ulids.split_whitespace().map(str::to_string).collect(),
+                );
+            }
+        }
+        Ok(postings)
+    }
+
+    fn save_shard(&self, shard: &str, postings: &HashMap<String, BTreeSet<String>>) -> Result<()> {
+        let path = self.shard_path(shard);
+        if postings.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        let mut terms: Vec<_> = postings.iter().collect();
+        terms.sort();
+        let mut data = String::new();
+        for (term, ulids) in terms {
+            data.push_str(term);
+            data.push('\t');
+            data.push_str(&ulids.iter().cloned().collect::<Vec<_>>().join(" "));
+            data.push('\n');
+        }
+        write_atomic(&path, data.as_bytes())
+    }
+}
+
+/// One line of the delta log: a message indexed under, or dropped from,
+/// `terms`.
+struct Delta {
+    added: bool,
+    ulid: String,
+    terms: BTreeSet<String>,
+}
+
+impl Delta {
+    fn parse(line: &str) -> Option<Self> {
+        let (head, terms) = line.split_once('\t')?;
+        let added = match head.chars().next()? {
+            '+' => true,
+            '-' => false,
+            _ => return None,
+        };
+        Some(Self {
+            added,
+            ulid: head[1..].to_string(),
+            terms: terms.split_whitespace().map(str::to_string).collect(),
+        })
+    }
+
+    /// Bring the postings of `term` up to date with this line.
+    fn apply(&self, term: &str, postings: &mut BTreeSet<String>) {
+        if !self.terms.contains(term) {
+            return;
+        }
+        if self.added {
+            postings.insert(self.ulid.clone());
+        } else {
+            postings.remove(&self.ulid);
+        }
+    }
+}
+
+fn matches_filters(doc: &IndexedDoc, query: &SearchQuery) -> bool {
+    if let Some(from) = &query.from
+        && !doc.from.to_lowercase().contains(from)
+    {
+        return false;
+    }
+    if let Some(list) = &query.list
+        && &doc.list != list
+    {
+        return false;
+    }
+    if let Some(before) = query.before {
+        match OffsetDateTime::parse(&doc.received_at, &Rfc3339) {
+            Ok(received) if received < before => {}
+            _ => return false,
+        }
+    }
+    !query.has_attachment || doc.has_attachment
+}
+
+/// Lowercased alphanumeric runs of two or more characters.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| token.chars().nth(1).is_some() && token.len() <= MAX_TERM_LEN)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn shard_of(term: &str) -> String {
+    hex::encode(&Sha256::digest(term.as_bytes())[..1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        filename::{html_filename, message_filename, sidecar_filename},
+        message::{HeadersCache, MessageSidecar},
+    };
+
+    fn store(
+        layout: &MailLayout,
+        list: &str,
+        from: &str,
+        subject: &str,
+        ulid: &str,
+        body: &str,
+    ) -> PathBuf {
+        let dir = layout.root().join(list).join(from);
+        fs::create_dir_all(&dir).unwrap();
+        let mut sidecar = MessageSidecar::new(
+            ulid,
+            message_filename(subject, ulid),
+            list,
+            "strict",
+            html_filename(subject, ulid),
+            "hash",
+            HeadersCache::new(from, subject),
+        );
+        let txt = format!(".{subject} ({ulid}).txt");
+        sidecar.set_plain_render(txt.clone());
+        if subject.contains("invoice") {
+            sidecar.add_attachment("aa", "invoice-march.pdf");
+        }
+        sidecar.add_inline_attachment("bb", "logo.png", "logo@example.org");
+        let received = if ulid.ends_with('1') {
+            "2024-12-30T10:00:00Z"
+        } else {
+            "2025-02-01T10:00:00Z"
+        };
+        sidecar.received_at = received.into();
+        fs::write(dir.join(&txt), body).unwrap();
+        let path = dir.join(sidecar_filename(subject, ulid));
+        fs::write(&path, serde_yaml::to_string(&sidecar).unwrap()).unwrap();
+        dir.join(message_filename(subject, ulid))
+    }
+
+    fn ulids(index: &SearchIndex, query: &str) -> Vec<String> {
+        index
+            .search(&SearchQuery::parse(query).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|hit| hit.ulid)
+            .collect()
+    }
+
+    #[test]
+    fn indexes_headers_body_and_attachment_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let index = SearchIndex::new(&layout);
+        assert_eq!(index.rebuild(&layout).unwrap().0, 0);
+        let first = "01ARZ3NDEKTSV4RRFFQ69G5FA1";
+        let second = "01ARZ3NDEKTSV4RRFFQ69G5FA2";
+        let path = store(
+            &layout,
+            "accepted",
+            "alice@example.org",
+            "March invoice",
+            first,
+            "Payment is due on Friday.",
+        );
+        index.add_delivered(&path).unwrap();
+        let path = store(
+            &layout,
+            "spam",
+            "bob@example.net",
+            "Lunch",
+            second,
+            "Friday lunch at noon?",
+        );
+        index.add_delivered(&path).unwrap();
+
+        assert_eq!(ulids(&index, "friday"), vec![second, first]);
+        assert_eq!(ulids(&index, "Friday PAYMENT"), vec![first]);
+        assert_eq!(ulids(&index, "invoice-march.pdf"), vec![first]);
+        assert_eq!(ulids(&index, "example.net"), vec![second]);
+        assert_eq!(ulids(&index, "friday from:alice"), vec![first]);
+        assert_eq!(ulids(&index, "list:spam"), vec![second]);
+        assert_eq!(ulids(&index, "before:2025-01-01"), vec![first]);
+        assert_eq!(ulids(&index, "has:attachment"), vec![first]);
+        assert_eq!(ulids(&index, "logo.png"), vec![second, first]);
+        assert_eq!(ulids(&index, "logo.png has:attachment"), vec![first]);
+        assert!(ulids(&index, "nothing").is_empty());
+
+        assert!(index.remove(first).unwrap());
+        assert_eq!(ulids(&index, "friday"), vec![second]);
+        assert!(!index.remove(first).unwrap());
+        // Updates only append to the delta log; the shards wait for a rebuild.
+        assert!(!dir.path().join("index/terms").exists());
+        let log = fs::read_to_string(dir.path().join("index/delta.log")).unwrap();
+        assert_eq!(log.lines().count(), 3);
+        assert!(
+            log.lines()
+                .last()
+                .unwrap()
+                .starts_with(&format!("-{first}\t"))
+        );
+    }
+
+    #[test]
+    fn compaction_folds_the_delta_log_into_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let index = SearchIndex::new(&layout);
+        let first = "01ARZ3NDEKTSV4RRFFQ69G5FC1";
+        let second = "01ARZ3NDEKTSV4RRFFQ69G5FC2";
+        store(
+            &layout,
+            "accepted",
+            "alice@example.org",
+            "Minutes",
+            first,
+            "budget review",
+        );
+        assert_eq!(index.rebuild(&layout).unwrap().0, 1);
+        assert!(!dir.path().join("index/delta.log").exists());
+        let shards = fs::read_dir(dir.path().join("index/terms"))
+            .unwrap()
+            .count();
+        assert!(shards > 0);
+
+        // Re-indexing under new terms must hide the old ones before and
+        // after compaction.
+        fs::write(
+            layout
+                .accepted()
+                .join("alice@example.org")
+                .join(format!(".Minutes ({first}).txt")),
+            "travel plans",
+        )
+        .unwrap();
+        let path = layout
+            .accepted()
+            .join("alice@example.org")
+            .join(message_filename("Minutes", first));
+        index.add_delivered(&path).unwrap();
+        let path = store(
+            &layout,
+            "accepted",
+            "alice@example.org",
+            "Budget",
+            second,
+            "budget draft",
+        );
+        index.add_delivered(&path).unwrap();
+        // Half a line from an append still in progress is ignored.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("index/delta.log"))
+            .unwrap();
+        log.write_all(format!("+{first}\tbudg").as_bytes()).unwrap();
+        assert_eq!(ulids(&index, "budget"), vec![second]);
+        assert_eq!(ulids(&index, "travel"), vec![first]);
+
+        {
+            let _lock = index.lock().unwrap();
+            index.compact_locked().unwrap();
+        }
+        assert!(!dir.path().join("index/delta.log").exists());
+        assert_eq!(ulids(&index, "budget"), vec![second]);
+        assert_eq!(ulids(&index, "travel"), vec![first]);
+        assert!(ulids(&index, "review").is_empty());
+    }
+
+    #[test]
+    fn rebuild_and_retention_removal_keep_index_in_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let index = SearchIndex::new(&layout);
+        let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        store(
+            &layout,
+            "quarantine",
+            "eve@example.org",
+            "Hello",
+            ulid,
+            "hi",
+        );
+        assert!(!index.exists());
+        assert_eq!(index.rebuild(&layout).unwrap().0, 1);
+        assert_eq!(ulids(&index, "hello"), vec![ulid]);
+
+        let sidecar = layout
+            .quarantine()
+            .join("eve@example.org")
+            .join(sidecar_filename("Hello", ulid));
+        fs::remove_file(&sidecar).unwrap();
+        assert!(ulids(&index, "hello").is_empty());
+        assert_eq!(index.remove_sidecars(&[sidecar]).unwrap(), 1);
+        assert!(!dir.path().join(format!("index/docs/{ulid}.yml")).exists());
+    }
+
+    #[test]
+    fn query_parsing_validates_qualifiers() {
+        let query = SearchQuery::parse("from:Alice list:accepted has:attachment Big-Deal").unwrap();
+        assert_eq!(query.terms, vec!["big", "deal"]);
+        assert_eq!(query.from.as_deref(), Some("alice"));
+        assert_eq!(query.list.as_deref(), Some("accepted"));
+        assert!(query.has_attachment);
+        assert!(SearchQuery::parse("list:drafts").is_err());
+        assert!(SearchQuery::parse("before:yesterday").is_err());
+        assert!(SearchQuery::parse("has:stars").is_err());
+        assert!(SearchQuery::parse("  ").unwrap().is_empty());
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Result, anyhow, bail};
-use mailparse::{DispositionType, ParsedMail, parse_mail};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use mailparse::{
+    DispositionType, MailAddr, MailHeaderMap, ParsedMail, addrparse_header, parse_mail,
+};
 use sha2::{Digest, Sha256};
 
 use crate::{
@@ -14,11 +18,21 @@ use crate::{
     model::{
         address::Address,
         filename::{html_filename, message_filename, sidecar_filename},
-        message::{HeadersCache, MessageSidecar, RspamdSummary},
+        message::{
+            AuthResults, HeadersCache, MessageSidecar, RspamdSummary, normalize_date,
+            parse_message_ids,
+        },
+    },
+    pipeline::{
+        auth::Authenticator,
+        render::{
+            ATTACHMENT_LINK_PREFIX, RemoteContent, RenderPolicy, TextRenderer, render_plaintext,
+            sanitize_html_with_inline,
+        },
+        thread::{assign_thread_id, record_thread},
     },
-    pipeline::render::{render_plaintext, sanitize_html},
     ruleset::eval::Route,
-    util::{size::parse_size, ulid},
+    util::{logging::Logger, size::parse_size, ulid},
 };
 
 pub struct InboundPipeline {
@@ -26,6 +40,8 @@ pub struct InboundPipeline {
     env: EnvConfig,
     approved_limit: u64,
     quarantine_limit: u64,
+    authenticator: Option<Authenticator>,
+    logger: Option<Logger>,
 }
 
 impl InboundPipeline {
@@ -37,14 +53,50 @@ impl InboundPipeline {
             env,
             approved_limit,
             quarantine_limit,
+            authenticator: None,
+            logger: None,
         })
     }
 
+    /// Verify sender authentication on delivery; without one (e.g. for
+    /// imports) messages carry no `auth` block.
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Log problems that do not stop a delivery, such as a thread lookup
+    /// that failed.
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    pub fn authenticator(&self) -> Option<&Authenticator> {
+        self.authenticator.as_ref()
+    }
+
+    /// Largest message any route will accept, used for up-front size checks.
+    pub fn max_message_size(&self) -> u64 {
+        self.approved_limit.max(self.quarantine_limit)
+    }
+
     pub fn deliver_quarantine(
         &self,
         sender: &Address,
         subject: &str,
         body: &[u8],
+    ) -> Result<PathBuf> {
+        self.quarantine_with_auth(sender, subject, body, None, &[])
+    }
+
+    fn quarantine_with_auth(
+        &self,
+        sender: &Address,
+        subject: &str,
+        body: &[u8],
+        auth: Option<&AuthResults>,
+        recipients: &[String],
     ) -> Result<PathBuf> {
         self.ensure_within_limit(
             body.len(),
@@ -59,6 +111,8 @@ impl InboundPipeline {
             sender,
             subject,
             body,
+            auth,
+            recipients,
         )
     }
 
@@ -68,6 +122,20 @@ impl InboundPipeline {
         sender: &Address,
         subject: &str,
         body: &[u8],
+    ) -> Result<PathBuf> {
+        self.deliver_with_auth(route, sender, subject, body, None, &[])
+    }
+
+    /// [`Self::deliver_to_route`], recording `auth` and the envelope
+    /// `recipients` in the sidecar.
+    pub fn deliver_with_auth(
+        &self,
+        route: Route,
+        sender: &Address,
+        subject: &str,
+        body: &[u8],
+        auth: Option<&AuthResults>,
+        recipients: &[String],
     ) -> Result<PathBuf> {
         match route {
             Route::Accepted => {
@@ -84,6 +152,8 @@ impl InboundPipeline {
                     sender,
                     subject,
                     body,
+                    auth,
+                    recipients,
                 )
             }
             Route::Spam => {
@@ -100,6 +170,8 @@ impl InboundPipeline {
                     sender,
                     subject,
                     body,
+                    auth,
+                    recipients,
                 )
             }
             Route::Banned => {
@@ -116,12 +188,15 @@ impl InboundPipeline {
                     sender,
                     subject,
                     body,
+                    auth,
+                    recipients,
                 )
             }
-            Route::Quarantine => self.deliver_quarantine(sender, subject, body),
+            Route::Quarantine => self.quarantine_with_auth(sender, subject, body, auth, recipients),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn deliver_to_dir(
         &self,
         base: &Path,
@@ -130,6 +205,8 @@ impl InboundPipeline {
         sender: &Address,
         subject: &str,
         body: &[u8],
+        auth: Option<&AuthResults>,
+        recipients: &[String],
     ) -> Result<PathBuf> {
         create_dir_all(base)?;
         let dir = base.join(sender.canonical());
@@ -150,16 +227,21 @@ impl InboundPipeline {
             text_body,
             attachments,
             rspamd,
+            header_fields,
         } = parse_email(body)?;
-        let text_for_plain = text_body.clone();
-        let html_input = html_body
-            .or_else(|| text_body.clone().map(|text| plaintext_to_html(&text)))
-            .unwrap_or_else(|| "<pre></pre>".to_string());
-        let sanitized_html = sanitize_html(&html_input)?;
-        let plain_render = render_plaintext(&sanitized_html)
-            .unwrap_or_else(|_| text_for_plain.unwrap_or_default());
-
-        let headers = HeadersCache::new(sender.to_string(), subject.to_string());
+        let policy = RenderPolicy::from_mode(&self.env.render_mode);
+        let inline = inline_sources(&attachments, policy, attachments_list.is_some());
+        let rendered = render_views(
+            html_body,
+            text_body,
+            &inline,
+            &self.env,
+            policy,
+            RemoteContent::Block,
+        );
+
+        let mut headers = HeadersCache::new(sender.to_string(), subject.to_string());
+        header_fields.apply(&mut headers);
         let mut sidecar = MessageSidecar::new(
             ulid,
             message_name.clone(),
@@ -169,25 +251,39 @@ impl InboundPipeline {
             hash,
             headers,
         );
+        sidecar.thread_id = Some(assign_thread_id(
+            &self.layout,
+            &sidecar,
+            self.logger.as_ref(),
+        ));
         if let Some(summary) = rspamd {
             sidecar.set_rspamd(summary);
         }
+        sidecar.auth = auth.cloned();
+        sidecar.delivered_to = recipients.to_vec();
         let txt_name = format!(
             ".{}",
             html_name.trim_start_matches('.').replace(".html", ".txt")
         );
         sidecar.set_plain_render(txt_name.clone());
+        sidecar.render.blocked_remote = rendered.blocked_remote;
         if let Some(list) = attachments_list {
             let store = AttachmentStore::new(self.layout.attachments(list));
             for attachment in attachments {
                 let stored = store.store(&attachment.name, &attachment.data)?;
-                sidecar.add_attachment(stored.sha256, attachment.name);
+                match attachment.content_id {
+                    Some(content_id) => {
+                        sidecar.add_inline_attachment(stored.sha256, attachment.name, content_id)
+                    }
+                    None => sidecar.add_attachment(stored.sha256, attachment.name),
+                }
             }
         }
         let yaml = serde_yaml::to_string(&sidecar)?;
         write_atomic(&dir.join(&sidecar_name), yaml.as_bytes())?;
-        write_atomic(&dir.join(&html_name), sanitized_html.as_bytes())?;
-        write_atomic(&dir.join(&txt_name), plain_render.as_bytes())?;
+        write_atomic(&dir.join(&html_name), rendered.html.as_bytes())?;
+        write_atomic(&dir.join(&txt_name), rendered.plain.as_bytes())?;
+        record_thread(&self.layout, &sidecar, self.logger.as_ref());
         Ok(message_path)
     }
 
@@ -200,29 +296,235 @@ impl InboundPipeline {
     ) -> Result<()> {
         let size = length as u64;
         if size > limit {
-            bail!("message size {size} bytes exceeds {label} limit ({configured})");
+            return Err(SizeLimitError {
+                size,
+                label: label.to_string(),
+                configured: configured.to_string(),
+            }
+            .into());
         }
         Ok(())
     }
 }
 
+/// Raised when a message exceeds the configured size cap for its destination.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("message size {size} bytes exceeds {label} limit ({configured})")]
+pub struct SizeLimitError {
+    pub size: u64,
+    pub label: String,
+    pub configured: String,
+}
+
+/// Raised when a message or its envelope cannot be parsed, so delivering it
+/// again would fail the same way.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{reason}")]
+pub struct MalformedMessageError {
+    pub reason: String,
+}
+
+impl MalformedMessageError {
+    pub fn new(reason: impl std::fmt::Display) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
 #[derive(Default)]
 struct ParsedEmail {
     html_body: Option<String>,
     text_body: Option<String>,
     attachments: Vec<EmailAttachment>,
     rspamd: Option<RspamdSummary>,
+    header_fields: HeaderFields,
+}
+
+/// Header values copied into the sidecar's [`HeadersCache`].
+#[derive(Default)]
+struct HeaderFields {
+    to: Vec<String>,
+    cc: Vec<String>,
+    date: Option<String>,
+    message_id: Option<String>,
+    in_reply_to: Option<String>,
+    references: Vec<String>,
+}
+
+impl HeaderFields {
+    fn from_parsed(parsed: &ParsedMail) -> Self {
+        let headers = &parsed.headers;
+        Self {
+            to: collect_mailboxes(parsed, "To"),
+            cc: collect_mailboxes(parsed, "Cc"),
+            date: headers
+                .get_first_value("Date")
+                .and_then(|value| normalize_date(&value)),
+            message_id: headers
+                .get_first_value("Message-ID")
+                .and_then(|value| parse_message_ids(&value).into_iter().next()),
+            in_reply_to: headers
+                .get_first_value("In-Reply-To")
+                .and_then(|value| parse_message_ids(&value).into_iter().next()),
+            references: headers
+                .get_first_value("References")
+                .map(|value| parse_message_ids(&value))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn apply(self, cache: &mut HeadersCache) {
+        cache.to = self.to;
+        cache.cc = self.cc;
+        if let Some(date) = self.date {
+            cache.date = date;
+        }
+        cache.message_id = self.message_id;
+        cache.in_reply_to = self.in_reply_to;
+        cache.references = self.references;
+    }
+}
+
+/// Decode every mailbox in the named address headers (RFC 2047 display
+/// names included), flattening groups. Unparseable headers are kept verbatim.
+pub fn collect_mailboxes(parsed: &ParsedMail, name: &str) -> Vec<String> {
+    let mut mailboxes = Vec::new();
+    for header in parsed.headers.get_all_headers(name) {
+        match addrparse_header(header) {
+            Ok(list) => {
+                for addr in list.iter() {
+                    match addr {
+                        MailAddr::Single(info) => mailboxes.push(info.to_string()),
+                        MailAddr::Group(group) => {
+                            mailboxes.extend(group.addrs.iter().map(|info| info.to_string()))
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                let value = header.get_value();
+                if !value.trim().is_empty() {
+                    mailboxes.push(value.trim().to_string());
+                }
+            }
+        }
+    }
+    mailboxes
+}
+
+/// The `.html` and `.txt` views of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMessage {
+    pub html: String,
+    pub plain: String,
+    pub blocked_remote: Vec<String>,
+}
+
+/// Render a raw message's views again, e.g. with remote content allowed.
+/// The plaintext backend and width come from `env`; `stored_attachments`
+/// says whether its list keeps blobs that inline images can link to.
+pub fn render_message(
+    body: &[u8],
+    env: &EnvConfig,
+    policy: RenderPolicy,
+    remote: RemoteContent,
+    stored_attachments: bool,
+) -> Result<RenderedMessage> {
+    let ParsedEmail {
+        html_body,
+        text_body,
+        attachments,
+        ..
+    } = parse_email(body)?;
+    let inline = inline_sources(&attachments, policy, stored_attachments);
+    Ok(render_views(
+        html_body, text_body, &inline, env, policy, remote,
+    ))
+}
+
+/// Map each inline part's Content-ID to what its `cid:` references become:
+/// a link into the list's blob store, or a data URI in strict mode and for
+/// lists that keep no attachments.
+fn inline_sources(
+    attachments: &[EmailAttachment],
+    policy: RenderPolicy,
+    stored: bool,
+) -> HashMap<String, String> {
+    let link = stored && policy == RenderPolicy::Moderate;
+    attachments
+        .iter()
+        .filter_map(|attachment| {
+            let content_id = attachment.content_id.as_ref()?;
+            let source = if link {
+                let sha256 = hex::encode(Sha256::digest(&attachment.data));
+                format!(
+                    "{ATTACHMENT_LINK_PREFIX}{sha256}__{}",
+                    encode_path_segment(&attachment.name)
+                )
+            } else {
+                format!(
+                    "data:{};base64,{}",
+                    attachment.mimetype,
+                    STANDARD.encode(&attachment.data)
+                )
+            };
+            Some((content_id.to_lowercase(), source))
+        })
+        .collect()
+}
+
+fn encode_path_segment(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn render_views(
+    html_body: Option<String>,
+    text_body: Option<String>,
+    inline: &HashMap<String, String>,
+    env: &EnvConfig,
+    policy: RenderPolicy,
+    remote: RemoteContent,
+) -> RenderedMessage {
+    let text_for_plain = text_body.clone();
+    let html_input = html_body
+        .or_else(|| text_body.map(|text| plaintext_to_html(&text)))
+        .unwrap_or_else(|| "<pre></pre>".to_string());
+    let sanitized = sanitize_html_with_inline(&html_input, policy, remote, inline);
+    let plain = render_plaintext(
+        &sanitized.html,
+        TextRenderer::from_setting(&env.plaintext_renderer),
+        env.plaintext_width,
+    )
+    .unwrap_or_else(|_| text_for_plain.unwrap_or_default());
+    RenderedMessage {
+        html: sanitized.html,
+        plain,
+        blocked_remote: sanitized.blocked,
+    }
 }
 
 struct EmailAttachment {
     name: String,
     data: Vec<u8>,
+    mimetype: String,
+    /// Set for parts the HTML embeds as `cid:`, without angle brackets.
+    content_id: Option<String>,
 }
 
 fn parse_email(body: &[u8]) -> Result<ParsedEmail> {
     let parsed = parse_mail(body).map_err(|err| anyhow!(err.to_string()))?;
     let mut result = ParsedEmail {
         rspamd: extract_rspamd(&parsed),
+        header_fields: HeaderFields::from_parsed(&parsed),
         ..ParsedEmail::default()
     };
     collect_parts(&parsed, &mut result)?;
@@ -248,14 +550,31 @@ fn collect_parts(part: &ParsedMail, acc: &mut ParsedEmail) -> Result<()> {
     }
 
     let disposition = part.get_content_disposition();
+    let is_text = ctype.starts_with("text/");
+    let content_id = part
+        .headers
+        .get_first_value("Content-ID")
+        .map(|value| {
+            value
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        })
+        .filter(|value| !value.is_empty())
+        .filter(|_| !is_text && !matches!(disposition.disposition, DispositionType::Attachment));
     let mut filename = disposition.params.get("filename").cloned();
     if filename.is_none()
         && let Some(name) = part.ctype.params.get("name")
     {
         filename = Some(name.clone());
     }
+    if filename.is_none()
+        && let Some(content_id) = &content_id
+    {
+        filename = Some(inline_name(content_id));
+    }
     if let Some(name) = filename {
-        let is_text = ctype.starts_with("text/");
         let treat_as_attachment = matches!(disposition.disposition, DispositionType::Attachment)
             || (!is_text
                 && matches!(
@@ -266,14 +585,34 @@ fn collect_parts(part: &ParsedMail, acc: &mut ParsedEmail) -> Result<()> {
             let data = part
                 .get_body_raw()
                 .map_err(|err| anyhow!(err.to_string()))?;
-            acc.attachments.push(EmailAttachment { name, data });
+            acc.attachments.push(EmailAttachment {
+                name,
+                data,
+                mimetype: ctype,
+                content_id,
+            });
         }
     }
 
     Ok(())
 }
 
-fn plaintext_to_html(text: &str) -> String {
+/// File name for an inline part that carries none: its Content-ID with
+/// anything unsafe in a path replaced.
+fn inline_name(content_id: &str) -> String {
+    content_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn plaintext_to_html(text: &str) -> String {
     let mut escaped = String::with_capacity(text.len());
     for ch in text.chars() {
         match ch {
@@ -316,39 +655,9 @@ fn extract_rspamd(parsed: &ParsedMail) -> Option<RspamdSummary> {
 mod tests {
     use super::*;
     use crate::ruleset::eval::Route;
+    use crate::util::logging::LogLevel;
     use serial_test::serial;
     use sha2::{Digest, Sha256};
-    use std::fs;
-    use std::os::unix::fs::PermissionsExt;
-    use std::panic;
-
-    fn with_fake_sanitizer<T>(script_body: &str, f: impl FnOnce() -> T) -> T {
-        let script_dir = tempfile::tempdir().unwrap();
-        let script_path = script_dir.path().join("sanitize-html");
-        fs::write(&script_path, script_body).unwrap();
-        let mut perms = fs::metadata(&script_path).unwrap().permissions();
-        perms.set_mode(0o755);
-        // Ignore permission errors on systems that don't support it
-        let _ = fs::set_permissions(&script_path, perms);
-
-        let original = std::env::var_os("PATH");
-        let mut new_path = std::ffi::OsString::from(script_dir.path());
-        if let Some(ref orig) = original {
-            new_path.push(":");
-            new_path.push(orig);
-        }
-        unsafe { std::env::set_var("PATH", &new_path) };
-        let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
-        if let Some(orig) = original {
-            unsafe { std::env::set_var("PATH", orig) };
-        } else {
-            unsafe { std::env::remove_var("PATH") };
-        }
-        match result {
-            Ok(value) => value,
-            Err(err) => panic::resume_unwind(err),
-        }
-    }
 
     fn plain_message(body: &str) -> Vec<u8> {
         format!(
@@ -361,191 +670,358 @@ mod tests {
     #[test]
     #[serial]
     fn deliver_writes_files() {
-        with_fake_sanitizer("#!/bin/sh\ncat\n", || {
-            let dir = tempfile::tempdir().unwrap();
-            let layout = MailLayout::new(dir.path());
-            let env = EnvConfig::default();
-            let pipeline = InboundPipeline::new(layout.clone(), env).unwrap();
-            let sender = Address::parse("alice@example.org", false).unwrap();
-            let body = plain_message("line1 & <test> \"quote\"\nline2's");
-            let path = pipeline
-                .deliver_quarantine(&sender, "Hello", &body)
-                .unwrap();
-            assert!(path.exists());
-            let stem = path.file_stem().unwrap().to_string_lossy();
-            let html_path = path.with_file_name(format!(".{stem}.html"));
-            assert!(html_path.exists());
-            let html = std::fs::read_to_string(&html_path).unwrap();
-            assert!(html.contains("line1"));
-            assert!(html.contains("&amp;"));
-            assert!(html.contains("&lt;test&gt;"));
-            assert!(html.contains("&quot;quote&quot;"));
-            assert!(html.contains("line2"));
-            assert!(html.contains("&#39;s"));
-            let sidecar: MessageSidecar = serde_yaml::from_str(
-                &std::fs::read_to_string(path.with_file_name(format!(".{stem}.yml"))).unwrap(),
-            )
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let env = EnvConfig::default();
+        let pipeline = InboundPipeline::new(layout.clone(), env).unwrap();
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let body = plain_message("line1 & <test> \"quote\"\nline2's");
+        let path = pipeline
+            .deliver_quarantine(&sender, "Hello", &body)
             .unwrap();
-            assert!(sidecar.attachments.is_empty());
-            let plain_path = path.with_file_name(format!(".{stem}.txt"));
-            assert!(plain_path.exists());
-            assert_eq!(sidecar.render.plain.unwrap(), format!(".{stem}.txt"));
-        });
+        assert!(path.exists());
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let html_path = path.with_file_name(format!(".{stem}.html"));
+        assert!(html_path.exists());
+        let html = std::fs::read_to_string(&html_path).unwrap();
+        assert!(html.contains("line1"));
+        assert!(html.contains("&amp;"));
+        assert!(html.contains("&lt;test&gt;"));
+        assert!(html.contains("\"quote\""));
+        assert!(html.contains("line2's"));
+        let sidecar: MessageSidecar = serde_yaml::from_str(
+            &std::fs::read_to_string(path.with_file_name(format!(".{stem}.yml"))).unwrap(),
+        )
+        .unwrap();
+        assert!(sidecar.attachments.is_empty());
+        let plain_path = path.with_file_name(format!(".{stem}.txt"));
+        assert!(plain_path.exists());
+        assert_eq!(sidecar.render.plain.unwrap(), format!(".{stem}.txt"));
     }
 
     #[test]
     #[serial]
     fn deliver_to_route_sets_status_and_path() {
-        with_fake_sanitizer("#!/bin/sh\nsed 's/<script/[blocked]/g'\n", || {
-            let dir = tempfile::tempdir().unwrap();
-            let layout = MailLayout::new(dir.path());
-            let env = EnvConfig::default();
-            let pipeline = InboundPipeline::new(layout.clone(), env).unwrap();
-            let sender = Address::parse("carol@example.org", false).unwrap();
-            let accepted_body = b"Subject: Hi\r\nX-Spam-Score: 0.0\r\nX-Spam-Symbols: BAYES_GOOD\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=BOUND\r\n\r\n--BOUND\r\nContent-Type: text/html; charset=utf-8\r\n\r\n<html><body>Hello<script>alert(1)</script></body></html>\r\n--BOUND\r\nContent-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"note.txt\"\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8=\r\n--BOUND--\r\n";
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let env = EnvConfig::default();
+        let pipeline = InboundPipeline::new(layout.clone(), env).unwrap();
+        let sender = Address::parse("carol@example.org", false).unwrap();
+        let accepted_body = b"Subject: Hi\r\nX-Spam-Score: 0.0\r\nX-Spam-Symbols: BAYES_GOOD\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=BOUND\r\n\r\n--BOUND\r\nContent-Type: text/html; charset=utf-8\r\n\r\n<html><body>Hello<script>alert(1)</script></body></html>\r\n--BOUND\r\nContent-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"note.txt\"\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8=\r\n--BOUND--\r\n";
+        let path = pipeline
+            .deliver_to_route(Route::Accepted, &sender, "Greetings", accepted_body)
+            .unwrap();
+        assert!(path.starts_with(dir.path().join("accepted")));
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let sidecar_path = path.with_file_name(format!(".{stem}.yml"));
+        let yaml = std::fs::read_to_string(&sidecar_path).unwrap();
+        let sidecar: MessageSidecar = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(sidecar.status_shadow, "accepted");
+        assert_eq!(sidecar.render.html, format!(".{stem}.html"));
+        let expected_plain = format!(".{stem}.txt");
+        assert_eq!(
+            sidecar.render.plain.as_deref(),
+            Some(expected_plain.as_str())
+        );
+        assert_eq!(sidecar.attachments.len(), 1);
+        assert_eq!(sidecar.attachments[0].name, "note.txt");
+        let rspamd = sidecar.rspamd.expect("rspamd metadata");
+        assert!(rspamd.score.abs() < 0.0001);
+        assert_eq!(rspamd.symbols, vec!["BAYES_GOOD".to_string()]);
+        let html_path = path.with_file_name(format!(".{stem}.html"));
+        let html = std::fs::read_to_string(&html_path).unwrap();
+        assert!(html.contains("Hello"));
+        assert!(!html.contains("script"));
+        let mut digest = Sha256::new();
+        digest.update(accepted_body);
+        assert_eq!(sidecar.hash_sha256, hex::encode(digest.finalize()));
+
+        let attachments_dir = layout.attachments("accepted");
+        let mut entries = std::fs::read_dir(&attachments_dir).unwrap();
+        let stored = entries.next().unwrap().unwrap();
+        let stored_name = stored.file_name().into_string().unwrap();
+        assert!(stored_name.ends_with("__note.txt"));
+        assert!(stored_name.starts_with(&sidecar.attachments[0].sha256));
+
+        let spam_body = plain_message("spam");
+        let spam_path = pipeline
+            .deliver_to_route(Route::Spam, &sender, "Spam", &spam_body)
+            .unwrap();
+        assert!(spam_path.starts_with(dir.path().join("spam")));
+        let banned_body = plain_message("banned");
+        let banned_path = pipeline
+            .deliver_to_route(Route::Banned, &sender, "Banned", &banned_body)
+            .unwrap();
+        assert!(banned_path.starts_with(dir.path().join("banned")));
+
+        let quarantine_body = plain_message("quarantine");
+        let quarantine_path = pipeline
+            .deliver_to_route(Route::Quarantine, &sender, "Quarantine", &quarantine_body)
+            .unwrap();
+        assert!(quarantine_path.starts_with(dir.path().join("quarantine")));
+
+        let inline_body = b"Subject: Inline\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=BOUND2\r\n\r\n--BOUND2\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nbody\r\n--BOUND2\r\nContent-Type: image/png\r\nContent-Disposition: inline; filename=\"logo.png\"\r\nContent-Transfer-Encoding: base64\r\n\r\naGVsbG8=\r\n--BOUND2\r\nContent-Type: application/octet-stream; name=\"report.pdf\"\r\nContent-Disposition: attachment\r\nContent-Transfer-Encoding: base64\r\n\r\nc29tZQ==\r\n--BOUND2--\r\n";
+        let inline_path = pipeline
+            .deliver_to_route(Route::Accepted, &sender, "Inline", inline_body)
+            .unwrap();
+        let inline_stem = inline_path.file_stem().unwrap().to_string_lossy();
+        let inline_sidecar_path = inline_path.with_file_name(format!(".{inline_stem}.yml"));
+        let inline_sidecar: MessageSidecar =
+            serde_yaml::from_str(&std::fs::read_to_string(&inline_sidecar_path).unwrap()).unwrap();
+        assert!(
+            inline_sidecar
+                .attachments
+                .iter()
+                .any(|a| a.name == "logo.png")
+        );
+        assert!(
+            inline_sidecar
+                .attachments
+                .iter()
+                .any(|a| a.name == "report.pdf")
+        );
+        let attachment_names: Vec<String> = std::fs::read_dir(layout.attachments("accepted"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(
+            attachment_names
+                .iter()
+                .any(|name| name.ends_with("__logo.png"))
+        );
+        assert!(
+            attachment_names
+                .iter()
+                .any(|name| name.ends_with("__report.pdf"))
+        );
+    }
+
+    fn related_message() -> &'static [u8] {
+        concat!(
+            "Subject: Contract\r\nMIME-Version: 1.0\r\n",
+            "Content-Type: multipart/mixed; boundary=OUTER\r\n\r\n",
+            "--OUTER\r\nContent-Type: multipart/related; boundary=REL\r\n\r\n",
+            "--REL\r\nContent-Type: text/html; charset=utf-8\r\n\r\n",
+            "<p><img src=\"cid:sig.1@example.org\" alt=\"Signature\"></p>\r\n",
+            "--REL\r\nContent-Type: image/png\r\nContent-ID: <sig.1@example.org>\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\naGVsbG8=\r\n",
+            "--REL--\r\n",
+            "--OUTER\r\nContent-Type: application/pdf; name=\"contract.pdf\"\r\n",
+            "Content-Disposition: attachment\r\nContent-ID: <pdf@example.org>\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\nc29tZQ==\r\n",
+            "--OUTER--\r\n"
+        )
+        .as_bytes()
+    }
+
+    fn delivered_views(path: &Path) -> (MessageSidecar, String) {
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let sidecar = serde_yaml::from_str(
+            &std::fs::read_to_string(path.with_file_name(format!(".{stem}.yml"))).unwrap(),
+        )
+        .unwrap();
+        let html = std::fs::read_to_string(path.with_file_name(format!(".{stem}.html"))).unwrap();
+        (sidecar, html)
+    }
+
+    #[test]
+    #[serial]
+    fn delivery_stores_the_conversation_thread_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let sender = Address::parse("dana@example.org", false).unwrap();
+        let pipeline = InboundPipeline::new(layout, EnvConfig::default()).unwrap();
+        let mut thread_ids = Vec::new();
+        for (id, parent) in [
+            ("<a@x>", None),
+            ("<b@x>", Some("<a@x>")),
+            ("<c@x>", Some("<b@x>")),
+        ] {
+            let mut body = format!("From: dana@example.org\r\nMessage-ID: {id}\r\n");
+            if let Some(parent) = parent {
+                body.push_str(&format!("In-Reply-To: {parent}\r\n"));
+            }
+            body.push_str("Subject: Plans\r\n\r\nHi\r\n");
             let path = pipeline
-                .deliver_to_route(Route::Accepted, &sender, "Greetings", accepted_body)
-                .unwrap();
-            assert!(path.starts_with(dir.path().join("accepted")));
-            let stem = path.file_stem().unwrap().to_string_lossy();
-            let sidecar_path = path.with_file_name(format!(".{stem}.yml"));
-            let yaml = std::fs::read_to_string(&sidecar_path).unwrap();
-            let sidecar: MessageSidecar = serde_yaml::from_str(&yaml).unwrap();
-            assert_eq!(sidecar.status_shadow, "accepted");
-            assert_eq!(sidecar.render.html, format!(".{stem}.html"));
-            let expected_plain = format!(".{stem}.txt");
-            assert_eq!(
-                sidecar.render.plain.as_deref(),
-                Some(expected_plain.as_str())
-            );
-            assert_eq!(sidecar.attachments.len(), 1);
-            assert_eq!(sidecar.attachments[0].name, "note.txt");
-            let rspamd = sidecar.rspamd.expect("rspamd metadata");
-            assert!(rspamd.score.abs() < 0.0001);
-            assert_eq!(rspamd.symbols, vec!["BAYES_GOOD".to_string()]);
-            let html_path = path.with_file_name(format!(".{stem}.html"));
-            let html = std::fs::read_to_string(&html_path).unwrap();
-            assert!(html.contains("[blocked]"));
-            let mut digest = Sha256::new();
-            digest.update(accepted_body);
-            assert_eq!(sidecar.hash_sha256, hex::encode(digest.finalize()));
-
-            let attachments_dir = layout.attachments("accepted");
-            let mut entries = std::fs::read_dir(&attachments_dir).unwrap();
-            let stored = entries.next().unwrap().unwrap();
-            let stored_name = stored.file_name().into_string().unwrap();
-            assert!(stored_name.ends_with("__note.txt"));
-            assert!(stored_name.starts_with(&sidecar.attachments[0].sha256));
-
-            let spam_body = plain_message("spam");
-            let spam_path = pipeline
-                .deliver_to_route(Route::Spam, &sender, "Spam", &spam_body)
-                .unwrap();
-            assert!(spam_path.starts_with(dir.path().join("spam")));
-            let banned_body = plain_message("banned");
-            let banned_path = pipeline
-                .deliver_to_route(Route::Banned, &sender, "Banned", &banned_body)
+                .deliver_to_route(Route::Accepted, &sender, "Plans", body.as_bytes())
                 .unwrap();
-            assert!(banned_path.starts_with(dir.path().join("banned")));
+            thread_ids.push(delivered_views(&path).0.thread_id);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        // The last reply only names its parent but still joins the root.
+        assert!(thread_ids.iter().all(|id| id.as_deref() == Some("<a@x>")));
+    }
 
-            let quarantine_body = plain_message("quarantine");
-            let quarantine_path = pipeline
-                .deliver_to_route(Route::Quarantine, &sender, "Quarantine", &quarantine_body)
-                .unwrap();
-            assert!(quarantine_path.starts_with(dir.path().join("quarantine")));
+    #[test]
+    #[serial]
+    fn broken_thread_keys_do_not_fail_delivery() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        std::fs::create_dir_all(layout.search_index()).unwrap();
+        std::fs::write(layout.thread_keys(), "not a directory").unwrap();
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let sender = Address::parse("dana@example.org", false).unwrap();
+        let pipeline = InboundPipeline::new(layout, EnvConfig::default())
+            .unwrap()
+            .with_logger(logger.clone());
+        let body = "From: dana@example.org\r\nMessage-ID: <b@x>\r\nIn-Reply-To: <a@x>\r\n\
+                    Subject: Plans\r\n\r\nHi\r\n";
+        let path = pipeline
+            .deliver_to_route(Route::Accepted, &sender, "Plans", body.as_bytes())
+            .unwrap();
+        assert_eq!(delivered_views(&path).0.thread_id.as_deref(), Some("<a@x>"));
+        let log = std::fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("thread.lookup_error"));
+        assert!(log.contains("thread.record_error"));
+    }
 
-            let inline_body = b"Subject: Inline\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=BOUND2\r\n\r\n--BOUND2\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nbody\r\n--BOUND2\r\nContent-Type: image/png\r\nContent-Disposition: inline; filename=\"logo.png\"\r\nContent-Transfer-Encoding: base64\r\n\r\naGVsbG8=\r\n--BOUND2\r\nContent-Type: application/octet-stream; name=\"report.pdf\"\r\nContent-Disposition: attachment\r\nContent-Transfer-Encoding: base64\r\n\r\nc29tZQ==\r\n--BOUND2--\r\n";
-            let inline_path = pipeline
-                .deliver_to_route(Route::Accepted, &sender, "Inline", inline_body)
-                .unwrap();
-            let inline_stem = inline_path.file_stem().unwrap().to_string_lossy();
-            let inline_sidecar_path = inline_path.with_file_name(format!(".{inline_stem}.yml"));
-            let inline_sidecar: MessageSidecar =
-                serde_yaml::from_str(&std::fs::read_to_string(&inline_sidecar_path).unwrap())
-                    .unwrap();
-            assert!(
-                inline_sidecar
-                    .attachments
-                    .iter()
-                    .any(|a| a.name == "logo.png")
-            );
-            assert!(
-                inline_sidecar
-                    .attachments
-                    .iter()
-                    .any(|a| a.name == "report.pdf")
-            );
-            let attachment_names: Vec<String> = std::fs::read_dir(layout.attachments("accepted"))
-                .unwrap()
-                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
-                .collect();
-            assert!(
-                attachment_names
-                    .iter()
-                    .any(|name| name.ends_with("__logo.png"))
-            );
-            assert!(
-                attachment_names
-                    .iter()
-                    .any(|name| name.ends_with("__report.pdf"))
-            );
-        });
+    #[test]
+    #[serial]
+    fn cid_images_resolve_to_inline_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let sender = Address::parse("dana@example.org", false).unwrap();
+        let strict = InboundPipeline::new(layout.clone(), EnvConfig::default()).unwrap();
+        let path = strict
+            .deliver_to_route(Route::Accepted, &sender, "Contract", related_message())
+            .unwrap();
+        let (sidecar, html) = delivered_views(&path);
+        assert!(html.contains("src=\"data:image/png;base64,aGVsbG8=\" alt=\"Signature\""));
+        assert_eq!(sidecar.attachments.len(), 2);
+        let signature = &sidecar.attachments[0];
+        assert!(signature.inline);
+        assert_eq!(signature.name, "sig.1@example.org");
+        assert_eq!(signature.content_id.as_deref(), Some("sig.1@example.org"));
+        let contract = &sidecar.attachments[1];
+        assert!(!contract.inline);
+        assert_eq!(contract.name, "contract.pdf");
+        assert_eq!(contract.content_id, None);
+
+        let moderate = InboundPipeline::new(
+            layout.clone(),
+            EnvConfig {
+                render_mode: "moderate".into(),
+                ..EnvConfig::default()
+            },
+        )
+        .unwrap();
+        let path = moderate
+            .deliver_to_route(Route::Accepted, &sender, "Contract", related_message())
+            .unwrap();
+        let (sidecar, html) = delivered_views(&path);
+        let link = format!(
+            "../attachments/{}__sig.1%40example.org",
+            sidecar.attachments[0].sha256
+        );
+        assert!(html.contains(&format!("src=\"{link}\"")));
+        let blob = layout.attachments("accepted").join(format!(
+            "{}__sig.1@example.org",
+            sidecar.attachments[0].sha256
+        ));
+        assert_eq!(std::fs::read(blob).unwrap(), b"hello");
+
+        let path = moderate
+            .deliver_to_route(Route::Quarantine, &sender, "Contract", related_message())
+            .unwrap();
+        let (sidecar, html) = delivered_views(&path);
+        assert!(sidecar.attachments.is_empty());
+        assert!(html.contains("src=\"data:image/png;base64,aGVsbG8=\""));
     }
 
     #[test]
     #[serial]
     fn quarantine_limit_enforced() {
-        with_fake_sanitizer("#!/bin/sh\ncat\n", || {
-            let dir = tempfile::tempdir().unwrap();
-            let layout = MailLayout::new(dir.path());
-            let env = EnvConfig {
-                max_size_quarantine: "16".into(),
-                ..EnvConfig::default()
-            };
-            let pipeline = InboundPipeline::new(layout, env).unwrap();
-            let sender = Address::parse("dave@example.org", false).unwrap();
-            let mut body = plain_message(&"A".repeat(32));
-            body.extend_from_slice(&[b'X'; 64]);
-            let err = pipeline
-                .deliver_quarantine(&sender, "Big", &body)
-                .unwrap_err();
-            assert!(err.to_string().contains("limit"));
-        });
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let env = EnvConfig {
+            max_size_quarantine: "16".into(),
+            ..EnvConfig::default()
+        };
+        let pipeline = InboundPipeline::new(layout, env).unwrap();
+        let sender = Address::parse("dave@example.org", false).unwrap();
+        let mut body = plain_message(&"A".repeat(32));
+        body.extend_from_slice(&[b'X'; 64]);
+        let err = pipeline
+            .deliver_quarantine(&sender, "Big", &body)
+            .unwrap_err();
+        assert!(err.to_string().contains("limit"));
+        let typed = err.downcast_ref::<SizeLimitError>().expect("typed error");
+        assert_eq!(typed.label, "quarantine");
+        assert_eq!(typed.configured, "16");
     }
 
     #[test]
     #[serial]
     fn approved_limit_enforced() {
-        with_fake_sanitizer("#!/bin/sh\ncat\n", || {
-            let dir = tempfile::tempdir().unwrap();
-            let layout = MailLayout::new(dir.path());
-            let env = EnvConfig {
-                max_size_approved_default: "32".into(),
-                ..EnvConfig::default()
-            };
-            let pipeline = InboundPipeline::new(layout, env).unwrap();
-            let sender = Address::parse("erin@example.org", false).unwrap();
-            let mut body = plain_message(&"B".repeat(64));
-            body.extend_from_slice(&[b'Y'; 64]);
-            let err = pipeline
-                .deliver_to_route(Route::Accepted, &sender, "Big", &body)
-                .unwrap_err();
-            assert!(err.to_string().contains("limit"));
-        });
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let env = EnvConfig {
+            max_size_approved_default: "32".into(),
+            ..EnvConfig::default()
+        };
+        let pipeline = InboundPipeline::new(layout, env).unwrap();
+        let sender = Address::parse("erin@example.org", false).unwrap();
+        let mut body = plain_message(&"B".repeat(64));
+        body.extend_from_slice(&[b'Y'; 64]);
+        let err = pipeline
+            .deliver_to_route(Route::Accepted, &sender, "Big", &body)
+            .unwrap_err();
+        assert!(err.to_string().contains("limit"));
     }
 
     #[test]
     #[serial]
-    fn with_fake_sanitizer_restores_missing_path() {
-        let original = std::env::var_os("PATH");
-        unsafe { std::env::remove_var("PATH") };
-        with_fake_sanitizer("#!/bin/sh\ncat\n", || {
-            assert!(std::env::var_os("PATH").is_some());
-        });
-        assert!(std::env::var_os("PATH").is_none());
-        if let Some(path) = original {
-            unsafe { std::env::set_var("PATH", path) };
-        }
+    fn deliver_populates_headers_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let pipeline = InboundPipeline::new(layout, EnvConfig::default()).unwrap();
+        let sender = Address::parse("alice@example.org", false).unwrap();
+        let body = b"From: Alice <alice@example.org>\r\n\
+To: =?UTF-8?Q?J=C3=B6rg?= <jorg@example.org>, bob@example.org\r\n\
+Cc: Team: carol@example.org, dave@example.org;\r\n\
+Date: Tue, 1 Jul 2025 10:00:00 +0200\r\n\
+Message-ID: <msg-2@example.org>\r\n\
+In-Reply-To: <msg-1@example.org>\r\n\
+References: <root@example.org>\r\n <msg-1@example.org>\r\n\
+Subject: Re: Plans\r\n\r\nBody\r\n";
+        let path = pipeline
+            .deliver_quarantine(&sender, "Re: Plans", body)
+            .unwrap();
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let sidecar: MessageSidecar = serde_yaml::from_str(
+            &std::fs::read_to_string(path.with_file_name(format!(".{stem}.yml"))).unwrap(),
+        )
+        .unwrap();
+        let headers = sidecar.headers_cache;
+        assert_eq!(
+            headers.to,
+            vec!["\"Jörg\" <jorg@example.org>", "bob@example.org"]
+        );
+        assert_eq!(headers.cc, vec!["carol@example.org", "dave@example.org"]);
+        assert_eq!(headers.date, "2025-07-01T10:00:00+02:00");
+        assert_eq!(headers.message_id.as_deref(), Some("<msg-2@example.org>"));
+        assert_eq!(headers.in_reply_to.as_deref(), Some("<msg-1@example.org>"));
+        assert_eq!(
+            headers.references,
+            vec!["<root@example.org>", "<msg-1@example.org>"]
+        );
+    }
+
+    #[test]
+    fn header_fields_fall_back_when_missing_or_invalid() {
+        let parsed = parse_mail(b"To: undisclosed-recipients:;\r\nDate:   \r\n\r\nx").unwrap();
+        let fields = HeaderFields::from_parsed(&parsed);
+        assert!(fields.to.is_empty());
+        assert!(fields.date.is_none());
+        let mut cache = HeadersCache::new("a", "s");
+        let delivery_date = cache.date.clone();
+        fields.apply(&mut cache);
+        assert_eq!(cache.date, delivery_date);
+        assert!(cache.message_id.is_none());
+
+        let broken = parse_mail(b"To: <<<\r\n\r\nx").unwrap();
+        assert_eq!(collect_mailboxes(&broken, "To"), vec!["<<<"]);
     }
 
     #[test]
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    fsops::{
+        io_atom::write_atomic,
+        layout::MailLayout,
+        scan::{SkippedSidecar, StoredMessage, scan_messages},
+    },
+    model::message::MessageSidecar,
+    util::logging::{LogLevel, Logger},
+};
+
+/// Conversation index over every sidecar in the mail root.
+///
+/// Messages are linked when they share any Message-ID, In-Reply-To,
+/// References entry, or `thread_id`, so replies in `sent/` join the inbound
+/// mail they answer even when one side has an incomplete References chain.
+pub struct ThreadIndex {
+    messages: Vec<StoredMessage>,
+    components: Vec<usize>,
+    skipped: Vec<SkippedSidecar>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ThreadView {
+    /// The oldest stored message's `thread_id`, so every message in the
+    /// conversation reports the same id.
+    pub thread_id: String,
+    pub messages: Vec<ThreadEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ThreadEntry {
+    pub ulid: String,
+    pub list: String,
+    pub from: String,
+    pub subject: String,
+    pub date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+}
+
+impl ThreadIndex {
+    pub fn build(layout: &MailLayout) -> Result<Self> {
+        let scan = scan_messages(layout)?;
+        let mut index = Self::from_messages(scan.messages);
+        index.skipped = scan.skipped;
+        Ok(index)
+    }
+
+    pub fn from_messages(messages: Vec<StoredMessage>) -> Self {
+        let mut keys: HashMap<String, usize> = HashMap::new();
+        let mut parent: Vec<usize> = Vec::new();
+        let mut message_nodes = Vec::with_capacity(messages.len());
+        for message in &messages {
+            let ids = thread_keys(&message.sidecar);
+            let mut node = None;
+            for id in ids {
+                let next = *keys.entry(id).or_insert_with(|| {
+                    parent.push(parent.len());
+                    parent.len() - 1
+                });
+                match node {
+                    Some(existing) => union(&mut parent, existing, next),
+                    None => node = Some(next),
+                }
+            }
+            message_nodes.push(node.expect("thread_keys is never empty"));
+        }
+        let components = message_nodes
+            .into_iter()
+            .map(|node| find(&mut parent, node))
+            .collect();
+        Self {
+            messages,
+            components,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Sidecars left out of the index because they could not be read.
+    pub fn skipped(&self) -> &[SkippedSidecar] {
+        &self.skipped
+    }
+
+    /// The conversation containing `ulid`, oldest message first.
+    pub fn thread_for(&self, ulid: &str) -> Option<ThreadView> {
+        let position = self
+            .messages
+            .iter()
+            .position(|message| message.sidecar.ulid.eq_ignore_ascii_case(ulid))?;
+        let component = self.components[position];
+        let mut members: Vec<&StoredMessage> = self
+            .messages
+            .iter()
+            .zip(&self.components)
+            .filter(|(_, c)| **c == component)
+            .map(|(message, _)| message)
+            .collect();
+        members.sort_by(|a, b| a.sidecar.ulid.cmp(&b.sidecar.ulid));
+        let thread_id = own_thread_id(&members[0].sidecar);
+        Some(ThreadView {
+            thread_id,
+            messages: members
+                .into_iter()
+                .map(|message| ThreadEntry {
+                    ulid: message.sidecar.ulid.clone(),
+                    list: message.list.clone(),
+                    from: message.sidecar.headers_cache.from.clone(),
+                    subject: message.sidecar.headers_cache.subject.clone(),
+                    date: message.sidecar.headers_cache.date.clone(),
+                    message_id: message.sidecar.headers_cache.message_id.clone(),
+                    in_reply_to: message.sidecar.headers_cache.in_reply_to.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Message-ID to `thread_id` map kept under `index/threads/`, one small
+/// file per id named by its SHA-256, so storing a message only touches the
+/// ids it carries however large the tree grows.
+///
+/// The first message to name an id decides its thread, and entries are
+/// never rewritten: when a later message joins two conversations, the
+/// sidecars already stored keep their ids. `thread_id` is therefore a hint
+/// for tools reading the tree; `owl thread` links the whole tree again.
+pub struct ThreadKeys {
+    dir: PathBuf,
+}
+
+impl ThreadKeys {
+    pub fn new(layout: &MailLayout) -> Self {
+        Self {
+            dir: layout.thread_keys(),
+        }
+    }
+
+    /// The thread of the earliest id `sidecar` names that is already known:
+    /// References first (root first), then In-Reply-To, then its own
+    /// Message-ID.
+    pub fn lookup(&self, sidecar: &MessageSidecar) -> Result<Option<String>> {
+        let headers = &sidecar.headers_cache;
+        let ids = headers
+            .references
+            .iter()
+            .chain(headers.in_reply_to.iter())
+            .chain(headers.message_id.iter());
+        for id in ids {
+            let path = self.path_for(id);
+            match fs::read_to_string(&path) {
+                Ok(thread_id) if !thread_id.trim().is_empty() => {
+                    return Ok(Some(thread_id.trim().to_string()));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("reading {}", path.display()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record `sidecar`'s `thread_id` under each id it names that has no
+    /// entry yet.
+    pub fn record(&self, sidecar: &MessageSidecar) -> Result<()> {
+        let Some(thread_id) = &sidecar.thread_id else {
+            return Ok(());
+        };
+        let headers = &sidecar.headers_cache;
+        for id in headers
+            .message_id
+            .iter()
+            .chain(headers.in_reply_to.iter())
+            .chain(headers.references.iter())
+        {
+            let path = self.path_for(id);
+            if !path.exists() {
+                write_atomic(&path, format!("{thread_id}\n").as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn path_for(&self, id: &str) -> PathBuf {
+        let digest = hex::encode(Sha256::digest(id.as_bytes()));
+        self.dir.join(&digest[..2]).join(digest)
+    }
+}
+
+/// The `thread_id` a message about to be stored should carry: the thread
+/// [`ThreadKeys`] knows for one of its ids, or its own root. A lookup
+/// failure only costs the link, so it is logged rather than failing the
+/// delivery.
+pub fn assign_thread_id(
+    layout: &MailLayout,
+    sidecar: &MessageSidecar,
+    logger: Option<&Logger>,
+) -> String {
+    match ThreadKeys::new(layout).lookup(sidecar) {
+        Ok(Some(thread_id)) => thread_id,
+        Ok(None) => own_thread_id(sidecar),
+        Err(err) => {
+            log_thread_error(logger, "thread.lookup_error", &sidecar.ulid, &err);
+            own_thread_id(sidecar)
+        }
+    }
+}
+
+/// Make a stored message's ids findable by later replies; failures are
+/// logged, never fatal.
+pub fn record_thread(layout: &MailLayout, sidecar: &MessageSidecar, logger: Option<&Logger>) {
+    if let Err(err) = ThreadKeys::new(layout).record(sidecar) {
+        log_thread_error(logger, "thread.record_error", &sidecar.ulid, &err);
+    }
+}
+
+fn log_thread_error(logger: Option<&Logger>, event: &str, ulid: &str, err: &anyhow::Error) {
+    if let Some(logger) = logger {
+        let _ = logger.log(
+            LogLevel::Minimal,
+            event,
+            Some(&format!("ulid={ulid} error={err:#}")),
+        );
+    }
+}
+
+fn own_thread_id(sidecar: &MessageSidecar) -> String {
+    sidecar
+        .thread_id
+        .clone()
+        .or_else(|| sidecar.headers_cache.thread_root())
+        .unwrap_or_else(|| sidecar.ulid.clone())
+}
+
+fn thread_keys(sidecar: &MessageSidecar) -> Vec<String> {
+    let headers = &sidecar.headers_cache;
+    let mut keys: Vec<String> = headers
+        .message_id
+        .iter()
+        .chain(headers.in_reply_to.iter())
+        .chain(headers.references.iter())
+        .chain(sidecar.thread_id.iter())
+        .cloned()
+        .collect();
+    if keys.is_empty() {
+        keys.push(sidecar.ulid.clone());
+    }
+    keys
+}
+
+fn find(parent: &mut [usize], node: usize) -> usize {
+    let mut root = node;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut current = node;
+    while parent[current] != root {
+        let next = parent[current];
+        parent[current] = root;
+        current = next;
+    }
+    root
+}
+
+fn union(parent: &mut [usize], a: usize, b: usize) {
+    let root_a = find(parent, a);
+    let root_b = find(parent, b);
+    if root_a != root_b {
+        parent[root_b] = root_a;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::message::HeadersCache;
+    use std::path::PathBuf;
+
+    fn stored(
+        list: &str,
+        ulid: &str,
+        message_id: Option<&str>,
+        in_reply_to: Option<&str>,
+        references: &[&str],
+    ) -> StoredMessage {
+        let mut headers = HeadersCache::new("alice@example.org", format!("Subject {ulid}"));
+        headers.message_id = message_id.map(str::to_string);
+        headers.in_reply_to = in_reply_to.map(str::to_string);
+        headers.references = references.iter().map(|id| id.to_string()).collect();
+        StoredMessage {
+            list: list.to_string(),
+            dir: PathBuf::from(list),
+            sidecar_path: PathBuf::from(format!("{list}/.{ulid}.yml")),
+            sidecar: MessageSidecar::new(
+                ulid,
+                format!("{ulid}.eml"),
+                list,
+                "strict",
+                format!(".{ulid}.html"),
+                "hash",
+                headers,
+            ),
+        }
+    }
+
+    #[test]
+    fn links_inbound_and_sent_replies() {
+        let index = ThreadIndex::from_messages(vec![
+            stored(
+                "sent",
+                "01C",
+                Some("<c@me>"),
+                Some("<b@x>"),
+                &["<a@x>", "<b@x>"],
+            ),
+            stored("accepted", "01A", Some("<a@x>"), None, &[]),
+            stored("quarantine", "01B", Some("<b@x>"), Some("<a@x>"), &[]),
+            stored("accepted", "01D", Some("<d@x>"), None, &[]),
+        ]);
+        let thread = index.thread_for("01b").unwrap();
+        assert_eq!(thread.thread_id, "<a@x>");
+        let ulids: Vec<_> = thread.messages.iter().map(|m| m.ulid.as_str()).collect();
+        assert_eq!(ulids, vec!["01A", "01B", "01C"]);
+        assert_eq!(thread.messages[2].list, "sent");
+
+        let lonely = index.thread_for("01D").unwrap();
+        assert_eq!(lonely.messages.len(), 1);
+        assert!(index.thread_for("01Z").is_none());
+    }
+
+    #[test]
+    fn joins_branches_through_shared_references() {
+        // B only knows its parent; C only knows the root. Both join via A.
+        let index = ThreadIndex::from_messages(vec![
+            stored("accepted", "01B", Some("<b@x>"), Some("<a@x>"), &[]),
+            stored("accepted", "01C", Some("<c@x>"), None, &["<a@x>"]),
+            stored("accepted", "01E", None, None, &[]),
+        ]);
+        assert_eq!(index.thread_for("01C").unwrap().messages.len(), 2);
+        let orphan = index.thread_for("01E").unwrap();
+        assert_eq!(orphan.thread_id, "01E");
+        assert_eq!(orphan.messages.len(), 1);
+    }
+
+    #[test]
+    fn thread_id_is_shared_by_every_member() {
+        // D only names its parent, so its own root would be <c@x>.
+        let index = ThreadIndex::from_messages(vec![
+            stored("accepted", "01A", Some("<a@x>"), None, &[]),
+            stored("sent", "01C", Some("<c@x>"), Some("<a@x>"), &["<a@x>"]),
+            stored("accepted", "01D", Some("<d@x>"), Some("<c@x>"), &[]),
+        ]);
+        for ulid in ["01A", "01C", "01D"] {
+            assert_eq!(index.thread_for(ulid).unwrap().thread_id, "<a@x>");
+        }
+    }
+
+    #[test]
+    fn thread_keys_follow_the_first_recorded_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let keys = ThreadKeys::new(&layout);
+        let mut root = stored("accepted", "01A", Some("<a@x>"), None, &[]);
+        root.sidecar.thread_id = Some("01A".into());
+        keys.record(&root.sidecar).unwrap();
+        let mut sent = stored("sent", "01C", Some("<c@x>"), Some("<a@x>"), &["<a@x>"]);
+        sent.sidecar.thread_id = keys.lookup(&sent.sidecar).unwrap();
+        assert_eq!(sent.sidecar.thread_id.as_deref(), Some("01A"));
+        keys.record(&sent.sidecar).unwrap();
+
+        // Only names C, but C's conversation was started by A.
+        let reply = stored("accepted", "01D", Some("<d@x>"), Some("<c@x>"), &[]);
+        assert_eq!(assign_thread_id(&layout, &reply.sidecar, None), "01A");
+        let fresh = stored("accepted", "01E", Some("<e@x>"), None, &[]);
+        assert_eq!(assign_thread_id(&layout, &fresh.sidecar, None), "<e@x>");
+
+        // A message joining two conversations does not move the second one.
+        let mut other = stored("accepted", "01F", Some("<f@x>"), None, &[]);
+        other.sidecar.thread_id = Some("<f@x>".into());
+        keys.record(&other.sidecar).unwrap();
+        let mut joining = stored("accepted", "01G", Some("<g@x>"), None, &["<a@x>", "<f@x>"]);
+        joining.sidecar.thread_id = keys.lookup(&joining.sidecar).unwrap();
+        keys.record(&joining.sidecar).unwrap();
+        assert_eq!(joining.sidecar.thread_id.as_deref(), Some("01A"));
+        assert_eq!(
+            keys.lookup(&other.sidecar).unwrap().as_deref(),
+            Some("<f@x>")
+        );
+    }
+
+    #[test]
+    fn unreadable_thread_keys_fall_back_to_own_root_and_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        let logger = Logger::new(dir.path(), LogLevel::Minimal).unwrap();
+        let reply = stored("accepted", "01D", Some("<d@x>"), Some("<c@x>"), &[]);
+        // A directory where the entry for <c@x> should be cannot be read.
+        std::fs::create_dir_all(ThreadKeys::new(&layout).path_for("<c@x>")).unwrap();
+        assert_eq!(
+            assign_thread_id(&layout, &reply.sidecar, Some(&logger)),
+            "<c@x>"
+        );
+        let log = std::fs::read_to_string(logger.log_path()).unwrap();
+        assert!(log.contains("thread.lookup_error"));
+    }
+
+    #[test]
+    fn build_scans_mail_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = MailLayout::new(dir.path());
+        layout.ensure().unwrap();
+        let message = stored("sent", "01S", Some("<s@me>"), None, &[]);
+        std::fs::write(
+            layout.sent().join(".01S.yml"),
+            serde_yaml::to_string(&message.sidecar).unwrap(),
+        )
+        .unwrap();
+        let index = ThreadIndex::build(&layout).unwrap();
+        assert_eq!(index.thread_for("01S").unwrap().thread_id, "<s@me>");
+    }
+}
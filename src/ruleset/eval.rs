@@ -1,4 +1,4 @@
-use crate::model::{address::Address, rules::RuleSet};
+use crate::model::rules::{MessageContext, RuleSet};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Route {
@@ -8,12 +8,28 @@ pub enum Route {
     Quarantine,
 }
 
-pub fn evaluate(address: &Address, rules: &RuleSet, spam: &RuleSet, banned: &RuleSet) -> Route {
-    if banned.evaluate(address).is_some() {
+impl Route {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Route::Banned => "banned",
+            Route::Spam => "spam",
+            Route::Accepted => "accepted",
+            Route::Quarantine => "quarantine",
+        }
+    }
+}
+
+pub fn evaluate(
+    message: &MessageContext,
+    rules: &RuleSet,
+    spam: &RuleSet,
+    banned: &RuleSet,
+) -> Route {
+    if banned.evaluate(message).is_some() {
         Route::Banned
-    } else if spam.evaluate(address).is_some() {
+    } else if spam.evaluate(message).is_some() {
         Route::Spam
-    } else if rules.evaluate(address).is_some() {
+    } else if rules.evaluate(message).is_some() {
         Route::Accepted
     } else {
         Route::Quarantine
@@ -23,15 +39,27 @@ pub fn evaluate(address: &Address, rules: &RuleSet, spam: &RuleSet, banned: &Rul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::address::Address;
     use proptest::prelude::*;
 
+    #[test]
+    fn route_names_match_list_directories() {
+        assert_eq!(Route::Banned.as_str(), "banned");
+        assert_eq!(Route::Spam.as_str(), "spam");
+        assert_eq!(Route::Accepted.as_str(), "accepted");
+        assert_eq!(Route::Quarantine.as_str(), "quarantine");
+    }
+
     #[test]
     fn precedence_applies() {
         let addr = Address::parse("foo@bar.com", false).unwrap();
         let banned = RuleSet::parse("@bar.com").unwrap();
         let spam = RuleSet::default();
         let accepted = RuleSet::default();
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Banned);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Banned
+        );
     }
 
     #[test]
@@ -40,7 +68,10 @@ mod tests {
         let banned = RuleSet::default();
         let spam = RuleSet::default();
         let accepted = RuleSet::parse("@example.com").unwrap();
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Accepted);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Accepted
+        );
     }
 
     #[test]
@@ -49,7 +80,10 @@ mod tests {
         let banned = RuleSet::default();
         let spam = RuleSet::parse("@spam.org").unwrap();
         let accepted = RuleSet::default();
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Spam);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Spam
+        );
     }
 
     #[test]
@@ -59,7 +93,7 @@ mod tests {
         let spam = RuleSet::default();
         let accepted = RuleSet::default();
         assert_eq!(
-            evaluate(&addr, &accepted, &spam, &banned),
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
             Route::Quarantine
         );
     }
@@ -72,7 +106,7 @@ mod tests {
             let accepted = RuleSet::parse(&format!("@{}", domain)).unwrap();
             let spam = RuleSet::parse(&format!("{}@{}", local, domain)).unwrap();
             let banned = RuleSet::parse(&format!("@{}", domain)).unwrap();
-            prop_assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Banned);
+            prop_assert_eq!(evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned), Route::Banned);
         }
 
         #[test]
@@ -82,7 +116,7 @@ mod tests {
             let accepted = RuleSet::parse(&format!("@{}", domain)).unwrap();
             let spam = RuleSet::parse(&format!("{}@{}", local, domain)).unwrap();
             let banned = RuleSet::default();
-            prop_assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Spam);
+            prop_assert_eq!(evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned), Route::Spam);
         }
     }
 
@@ -96,7 +130,10 @@ mod tests {
         let spam = RuleSet::parse("user@example.org").unwrap();
         let banned = RuleSet::parse("/user/").unwrap();
 
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Banned);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Banned
+        );
     }
 
     #[test]
@@ -108,7 +145,10 @@ mod tests {
         let spam = RuleSet::parse("user@spam.org").unwrap();
         let banned = RuleSet::default();
 
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Spam);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Spam
+        );
     }
 
     #[test]
@@ -121,7 +161,7 @@ mod tests {
         let banned = RuleSet::parse("@banned.org").unwrap();
 
         assert_eq!(
-            evaluate(&addr, &accepted, &spam, &banned),
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
             Route::Quarantine
         );
     }
@@ -136,7 +176,7 @@ mod tests {
         let banned = RuleSet::default();
 
         assert_eq!(
-            evaluate(&addr, &accepted, &spam, &banned),
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
             Route::Quarantine
         );
     }
@@ -149,7 +189,10 @@ mod tests {
         let spam = RuleSet::default();
         let banned = RuleSet::parse("/^admin@/").unwrap();
 
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Banned);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Banned
+        );
     }
 
     #[test]
@@ -162,7 +205,10 @@ mod tests {
         let spam = RuleSet::default();
         let banned = RuleSet::default();
 
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Accepted);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Accepted
+        );
     }
 
     #[test]
@@ -175,7 +221,7 @@ mod tests {
         let banned = RuleSet::default();
 
         assert_eq!(
-            evaluate(&addr, &accepted, &spam, &banned),
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
             Route::Quarantine
         );
     }
@@ -189,6 +235,9 @@ mod tests {
         let spam = RuleSet::default();
         let banned = RuleSet::default();
 
-        assert_eq!(evaluate(&addr, &accepted, &spam, &banned), Route::Accepted);
+        assert_eq!(
+            evaluate(&MessageContext::new(&addr), &accepted, &spam, &banned),
+            Route::Accepted
+        );
     }
 }
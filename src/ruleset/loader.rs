@@ -1,9 +1,12 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use crate::model::{rules::RuleSet, settings::ListSettings};
+use crate::{
+    model::{rules::RuleSet, settings::ListSettings},
+    ruleset::sieve::SieveScript,
+};
 
 #[derive(Debug, Clone)]
 pub struct RulesetLoader {
@@ -20,9 +23,21 @@ impl RulesetLoader {
             accepted: self.load_list("accepted")?,
             spam: self.load_list("spam")?,
             banned: self.load_list("banned")?,
+            sieve: self.load_sieve()?,
         })
     }
 
+    fn load_sieve(&self) -> Result<Option<SieveScript>> {
+        let path = self.root.join(".sieve");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&path)?;
+        let script =
+            SieveScript::parse(&data).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(script))
+    }
+
     fn load_list(&self, name: &str) -> Result<LoadedList> {
         let dir = self.root.join(name);
         let rules = self.load_rules(&dir)?;
@@ -74,6 +89,8 @@ pub struct LoadedRules {
     pub accepted: LoadedList,
     pub spam: LoadedList,
     pub banned: LoadedList,
+    /// `.sieve` at the mail root, consulted before the lists' `.rules`.
+    pub sieve: Option<SieveScript>,
 }
 
 impl Default for LoadedRules {
@@ -91,6 +108,7 @@ impl Default for LoadedRules {
                 rules: RuleSet::default(),
                 settings: default_settings_for("banned"),
             },
+            sieve: None,
         }
     }
 }
@@ -108,6 +126,21 @@ mod tests {
         assert_eq!(rules.accepted.settings.list_status, "accepted");
     }
 
+    #[test]
+    fn loads_sieve_script_and_reports_parse_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let loader = RulesetLoader::new(dir.path());
+        assert!(loader.load().unwrap().sieve.is_none());
+
+        std::fs::write(dir.path().join(".sieve"), "keep;\n").unwrap();
+        assert!(loader.load().unwrap().sieve.is_some());
+
+        std::fs::write(dir.path().join(".sieve"), "keep;\nfileinto \"spam\";\n").unwrap();
+        let err = format!("{:#}", loader.load().unwrap_err());
+        assert!(err.contains(".sieve"));
+        assert!(err.contains("line 2: missing require \"fileinto\""));
+    }
+
     #[test]
     fn loads_present_rules() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,1199 @@
+//! The Sieve (RFC 5228) subset read from `.sieve` at the mail root:
+//! `if`/`elsif`/`else` over the `header`, `address`, `envelope`, `exists`,
+//! `size` and `true`/`false`/`not`/`allof`/`anyof` tests, the `fileinto`,
+//! `keep`, `discard` and `stop` actions, and the `variables` (RFC 5229) and
+//! `regex` extensions. `fileinto` names an Owl list instead of a mailbox.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, anyhow, bail};
+use regex::RegexBuilder;
+
+use crate::{model::rules::MessageContext, ruleset::eval::Route};
+
+const EXTENSIONS: &[&str] = &[
+    "fileinto",
+    "envelope",
+    "variables",
+    "regex",
+    "comparator-i;octet",
+    "comparator-i;ascii-casemap",
+];
+
+/// What a script decided for one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SieveOutcome {
+    /// Implicit or explicit keep: the `.rules` decide.
+    Keep,
+    /// `fileinto` a list, before that list's `list_status` is applied.
+    FileInto(Route),
+    /// `discard`. Owl never drops mail unseen, so this routes to `banned/`.
+    Discard,
+}
+
+#[derive(Debug, Clone)]
+pub struct SieveScript {
+    commands: Vec<Command>,
+    variables: bool,
+}
+
+impl SieveScript {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: lex(source)?,
+            pos: 0,
+            required: HashSet::new(),
+            started: false,
+        };
+        let commands = parser.block(true)?;
+        Ok(Self {
+            commands,
+            variables: parser.required.contains("variables"),
+        })
+    }
+
+    pub fn evaluate(&self, message: &MessageContext) -> SieveOutcome {
+        let mut run = Run {
+            message,
+            expand: self.variables,
+            variables: HashMap::new(),
+            captures: Vec::new(),
+            filed: Vec::new(),
+            keep: false,
+            discard: false,
+        };
+        run.commands(&self.commands);
+        let strongest = run.filed.iter().copied().min_by_key(|route| match route {
+            Route::Banned => 0,
+            Route::Spam => 1,
+            Route::Accepted => 2,
+            Route::Quarantine => 3,
+        });
+        match strongest {
+            Some(route) => SieveOutcome::FileInto(route),
+            None if run.discard && !run.keep => SieveOutcome::Discard,
+            None => SieveOutcome::Keep,
+        }
+    }
+}
+
+/// `Some(None)` is `INBOX`, which Sieve scripts use for keep.
+fn fileinto_target(name: &str) -> Option<Option<Route>> {
+    let route = match name.to_ascii_lowercase().as_str() {
+        "inbox" => return Some(None),
+        "accepted" => Route::Accepted,
+        "spam" => Route::Spam,
+        "banned" => Route::Banned,
+        "quarantine" => Route::Quarantine,
+        _ => return None,
+    };
+    Some(Some(route))
+}
+
+#[derive(Debug, Clone)]
+enum Command {
+    If {
+        branches: Vec<(Test, Vec<Command>)>,
+        otherwise: Vec<Command>,
+    },
+    FileInto(String),
+    Keep,
+    Discard,
+    Stop,
+    Set {
+        modifiers: Vec<String>,
+        name: String,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Exists(Vec<String>),
+    Header {
+        matcher: Matcher,
+        names: Vec<String>,
+        keys: Vec<String>,
+    },
+    Address {
+        matcher: Matcher,
+        part: AddressPart,
+        headers: Vec<String>,
+        keys: Vec<String>,
+    },
+    Envelope {
+        matcher: Matcher,
+        part: AddressPart,
+        fields: Vec<String>,
+        keys: Vec<String>,
+    },
+    Size {
+        over: bool,
+        limit: u64,
+    },
+    String {
+        matcher: Matcher,
+        sources: Vec<String>,
+        keys: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+    Regex,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Matcher {
+    kind: MatchType,
+    /// `i;ascii-casemap`, the default comparator; `i;octet` is exact.
+    casemap: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Tag(String),
+    Number(u64),
+    Str(String),
+    Punct(char),
+}
+
+fn lex(source: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = line;
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                loop {
+                    match chars.get(i) {
+                        None => bail!("line {start}: unterminated comment"),
+                        Some('*') if chars.get(i + 1) == Some(&'/') => break,
+                        Some('\n') => line += 1,
+                        Some(_) => {}
+                    }
+                    i += 1;
+                }
+                i += 2;
+            }
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => bail!("line {start}: unterminated string"),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            i += 1;
+                            value.push(chars[i]);
+                        }
+                        Some(other) => value.push(*other),
+                    }
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+                tokens.push((Token::Str(value), start));
+            }
+            '0'..='9' => {
+                let mut number: u64 = 0;
+                while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+                    number = number
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add(u64::from(digit)))
+                        .ok_or_else(|| anyhow!("line {start}: number too large"))?;
+                    i += 1;
+                }
+                let scale: u64 = match chars.get(i).map(|c| c.to_ascii_uppercase()) {
+                    Some('K') => 1 << 10,
+                    Some('M') => 1 << 20,
+                    Some('G') => 1 << 30,
+                    _ => 1,
+                };
+                if scale > 1 {
+                    i += 1;
+                }
+                let number = number
+                    .checked_mul(scale)
+                    .ok_or_else(|| anyhow!("line {start}: number too large"))?;
+                tokens.push((Token::Number(number), start));
+            }
+            ':' if chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic()) => {
+                i += 1;
+                let name = identifier(&chars, &mut i);
+                tokens.push((Token::Tag(format!(":{}", name.to_ascii_lowercase())), start));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let name = identifier(&chars, &mut i);
+                if name.eq_ignore_ascii_case("text") && chars.get(i) == Some(&':') {
+                    i += 1;
+                    let value = multiline(&chars, &mut i, &mut line)?;
+                    tokens.push((Token::Str(value), start));
+                } else {
+                    tokens.push((Token::Ident(name.to_ascii_lowercase()), start));
+                }
+            }
+            '[' | ']' | '{' | '}' | '(' | ')' | ',' | ';' => {
+                tokens.push((Token::Punct(c), start));
+                i += 1;
+            }
+            other => bail!("line {line}: unexpected character {other:?}"),
+        }
+    }
+    Ok(tokens)
+}
+
+fn identifier(chars: &[char], i: &mut usize) -> String {
+    let start = *i;
+    while *i < chars.len() && (chars[*i].is_ascii_alphanumeric() || chars[*i] == '_') {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect()
+}
+
+/// `text:` up to a line holding a single `.`, with leading `..` unstuffed.
+fn multiline(chars: &[char], i: &mut usize, line: &mut usize) -> Result<String> {
+    let start = *line;
+    while *i < chars.len() && chars[*i] != '\n' {
+        if chars[*i] == '#' {
+            while *i < chars.len() && chars[*i] != '\n' {
+                *i += 1;
+            }
+            break;
+        }
+        if !chars[*i].is_whitespace() {
+            bail!("line {start}: text: must end its line");
+        }
+        *i += 1;
+    }
+    let mut value = String::new();
+    loop {
+        if *i >= chars.len() {
+            bail!("line {start}: unterminated text: string");
+        }
+        *i += 1;
+        *line += 1;
+        let begin = *i;
+        while *i < chars.len() && chars[*i] != '\n' {
+            *i += 1;
+        }
+        let text: String = chars[begin..*i].iter().collect();
+        let text = text.strip_suffix('\r').unwrap_or(&text);
+        if text == "." {
+            return Ok(value);
+        }
+        value.push_str(
+            text.strip_prefix('.')
+                .filter(|_| text.starts_with(".."))
+                .unwrap_or(text),
+        );
+        value.push('\n');
+    }
+}
+
+enum Arg {
+    Tag(String),
+    Number(u64),
+    Strings(Vec<String>),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    required: HashSet<String>,
+    started: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map(|(_, line)| *line)
+            .unwrap_or(1)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Punct(found)) if found == punct => Ok(()),
+            _ => bail!("line {line}: expected '{punct}'"),
+        }
+    }
+
+    fn require(&self, extension: &str, line: usize) -> Result<()> {
+        if !self.required.contains(extension) {
+            bail!("line {line}: missing require \"{extension}\"");
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, top: bool) -> Result<Vec<Command>> {
+        let mut commands = Vec::new();
+        loop {
+            match self.peek() {
+                None if top => return Ok(commands),
+                None => bail!("line {}: expected '}}'", self.line()),
+                Some(Token::Punct('}')) if !top => {
+                    self.pos += 1;
+                    return Ok(commands);
+                }
+                _ => {
+                    if let Some(command) = self.command()? {
+                        commands.push(command);
+                    }
+                }
+            }
+        }
+    }
+
+    fn command(&mut self) -> Result<Option<Command>> {
+        let line = self.line();
+        let Some(Token::Ident(name)) = self.next() else {
+            bail!("line {line}: expected a command");
+        };
+        if name == "require" {
+            if self.started {
+                bail!("line {line}: require must come before other commands");
+            }
+            for extension in self.strings()? {
+                let extension = extension.to_ascii_lowercase();
+                if !EXTENSIONS.contains(&extension.as_str()) {
+                    bail!("line {line}: unsupported extension \"{extension}\"");
+                }
+                self.required.insert(extension);
+            }
+            self.expect(';')?;
+            return Ok(None);
+        }
+        self.started = true;
+        let command = match name.as_str() {
+            "if" => {
+                let mut branches = vec![(self.test()?, self.braced()?)];
+                let mut otherwise = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::Ident(next)) if next == "elsif" => {
+                            self.pos += 1;
+                            branches.push((self.test()?, self.braced()?));
+                        }
+                        Some(Token::Ident(next)) if next == "else" => {
+                            self.pos += 1;
+                            otherwise = self.braced()?;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                return Ok(Some(Command::If {
+                    branches,
+                    otherwise,
+                }));
+            }
+            "elsif" | "else" => bail!("line {line}: {name} without if"),
+            "keep" => Command::Keep,
+            "discard" => Command::Discard,
+            "stop" => Command::Stop,
+            "fileinto" => {
+                self.require("fileinto", line)?;
+                let target = self.string()?;
+                if !target.contains("${") && fileinto_target(&target).is_none() {
+                    bail!(
+                        "line {line}: fileinto \"{target}\" is not a list (accepted, spam, banned, quarantine or INBOX)"
+                    );
+                }
+                Command::FileInto(target)
+            }
+            "set" => {
+                self.require("variables", line)?;
+                let mut modifiers = Vec::new();
+                let mut values = Vec::new();
+                for arg in self.args()? {
+                    match arg {
+                        Arg::Tag(tag) if SET_MODIFIERS.contains(&tag.as_str()) => {
+                            modifiers.push(tag)
+                        }
+                        Arg::Strings(mut list) if list.len() == 1 => values.push(list.remove(0)),
+                        _ => bail!("line {line}: set takes modifiers, a name and a value"),
+                    }
+                }
+                let [name, value] = <[String; 2]>::try_from(values)
+                    .map_err(|_| anyhow!("line {line}: set takes a name and a value"))?;
+                if name.is_empty()
+                    || name.starts_with(|c: char| c.is_ascii_digit())
+                    || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    bail!("line {line}: invalid variable name \"{name}\"");
+                }
+                Command::Set {
+                    modifiers,
+                    name: name.to_ascii_lowercase(),
+                    value,
+                }
+            }
+            other => bail!("line {line}: unsupported command \"{other}\""),
+        };
+        self.expect(';')?;
+        Ok(Some(command))
+    }
+
+    fn braced(&mut self) -> Result<Vec<Command>> {
+        self.expect('{')?;
+        self.block(false)
+    }
+
+    fn args(&mut self) -> Result<Vec<Arg>> {
+        let mut args = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Tag(tag)) => {
+                    args.push(Arg::Tag(tag.clone()));
+                    self.pos += 1;
+                }
+                Some(Token::Number(number)) => {
+                    args.push(Arg::Number(*number));
+                    self.pos += 1;
+                }
+                Some(Token::Str(_) | Token::Punct('[')) => args.push(Arg::Strings(self.strings()?)),
+                _ => return Ok(args),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Str(value)) => Ok(value),
+            _ => bail!("line {line}: expected a string"),
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        if self.peek() != Some(&Token::Punct('[')) {
+            return Ok(vec![self.string()?]);
+        }
+        self.pos += 1;
+        let mut values = vec![self.string()?];
+        loop {
+            let line = self.line();
+            match self.next() {
+                Some(Token::Punct(',')) => values.push(self.string()?),
+                Some(Token::Punct(']')) => return Ok(values),
+                _ => bail!("line {line}: expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn test(&mut self) -> Result<Test> {
+        let line = self.line();
+        let Some(Token::Ident(name)) = self.next() else {
+            bail!("line {line}: expected a test");
+        };
+        let test = match name.as_str() {
+            "true" => Test::True,
+            "false" => Test::False,
+            "not" => Test::Not(Box::new(self.test()?)),
+            "allof" | "anyof" => {
+                self.expect('(')?;
+                let mut tests = vec![self.test()?];
+                loop {
+                    let line = self.line();
+                    match self.next() {
+                        Some(Token::Punct(',')) => tests.push(self.test()?),
+                        Some(Token::Punct(')')) => break,
+                        _ => bail!("line {line}: expected ',' or ')'"),
+                    }
+                }
+                if name == "allof" {
+                    Test::AllOf(tests)
+                } else {
+                    Test::AnyOf(tests)
+                }
+            }
+            "exists" => {
+                let args = self.args()?;
+                match <[Arg; 1]>::try_from(args) {
+                    Ok([Arg::Strings(names)]) => Test::Exists(names),
+                    _ => bail!("line {line}: exists takes a list of header names"),
+                }
+            }
+            "size" => match <[Arg; 2]>::try_from(self.args()?) {
+                Ok([Arg::Tag(tag), Arg::Number(limit)]) if tag == ":over" || tag == ":under" => {
+                    Test::Size {
+                        over: tag == ":over",
+                        limit,
+                    }
+                }
+                _ => bail!("line {line}: size takes :over or :under and a number"),
+            },
+            "header" | "address" | "envelope" | "string" => {
+                if name == "envelope" {
+                    self.require("envelope", line)?;
+                }
+                if name == "string" {
+                    self.require("variables", line)?;
+                }
+                let args = self.args()?;
+                let addresses = name == "address" || name == "envelope";
+                let (matcher, part, [first, keys]) =
+                    self.match_args(&name, args, addresses, line)?;
+                match name.as_str() {
+                    "header" => Test::Header {
+                        matcher,
+                        names: first,
+                        keys,
+                    },
+                    "address" => Test::Address {
+                        matcher,
+                        part,
+                        headers: first,
+                        keys,
+                    },
+                    "envelope" => {
+                        if let Some(field) = first.iter().find(|field| {
+                            !field.eq_ignore_ascii_case("from") && !field.eq_ignore_ascii_case("to")
+                        }) {
+                            bail!("line {line}: unsupported envelope part \"{field}\"");
+                        }
+                        Test::Envelope {
+                            matcher,
+                            part,
+                            fields: first,
+                            keys,
+                        }
+                    }
+                    _ => Test::String {
+                        matcher,
+                        sources: first,
+                        keys,
+                    },
+                }
+            }
+            other => bail!("line {line}: unsupported test \"{other}\""),
+        };
+        Ok(test)
+    }
+
+    fn match_args(
+        &self,
+        test: &str,
+        args: Vec<Arg>,
+        addresses: bool,
+        line: usize,
+    ) -> Result<(Matcher, AddressPart, [Vec<String>; 2])> {
+        let mut kind = None;
+        let mut casemap = true;
+        let mut part = None;
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let tag = match arg {
+                Arg::Tag(tag) => tag,
+                Arg::Strings(values) => {
+                    positional.push(values);
+                    continue;
+                }
+                Arg::Number(_) => bail!("line {line}: unexpected number in {test}"),
+            };
+            match tag.as_str() {
+                ":is" | ":contains" | ":matches" | ":regex" => {
+                    if kind.is_some() {
+                        bail!("line {line}: {test} takes one match type");
+                    }
+                    kind = Some(match tag.as_str() {
+                        ":is" => MatchType::Is,
+                        ":contains" => MatchType::Contains,
+                        ":matches" => MatchType::Matches,
+                        _ => {
+                            self.require("regex", line)?;
+                            MatchType::Regex
+                        }
+                    });
+                }
+                ":comparator" => {
+                    casemap = match args.next() {
+                        Some(Arg::Strings(values)) if values.len() == 1 => {
+                            match values[0].as_str() {
+                                "i;ascii-casemap" => true,
+                                "i;octet" => false,
+                                other => {
+                                    bail!("line {line}: unsupported comparator \"{other}\"")
+                                }
+                            }
+                        }
+                        _ => bail!("line {line}: :comparator takes a string"),
+                    };
+                }
+                ":all" | ":localpart" | ":domain" if addresses => {
+                    if part.is_some() {
+                        bail!("line {line}: {test} takes one address part");
+                    }
+                    part = Some(match tag.as_str() {
+                        ":all" => AddressPart::All,
+                        ":localpart" => AddressPart::LocalPart,
+                        _ => AddressPart::Domain,
+                    });
+                }
+                other => bail!("line {line}: unsupported tag {other} for {test}"),
+            }
+        }
+        let positional = <[Vec<String>; 2]>::try_from(positional)
+            .map_err(|_| anyhow!("line {line}: {test} takes two string lists"))?;
+        let matcher = Matcher {
+            kind: kind.unwrap_or(MatchType::Is),
+            casemap,
+        };
+        if matcher.kind == MatchType::Regex {
+            for key in positional[1].iter().filter(|key| !key.contains("${")) {
+                matcher
+                    .regex(key)
+                    .map_err(|err| anyhow!("line {line}: invalid regex: {err}"))?;
+            }
+        }
+        Ok((matcher, part.unwrap_or(AddressPart::All), positional))
+    }
+}
+
+const SET_MODIFIERS: &[&str] = &[
+    ":lower",
+    ":upper",
+    ":lowerfirst",
+    ":upperfirst",
+    ":quotewildcard",
+    ":length",
+];
+
+impl Matcher {
+    fn regex(&self, pattern: &str) -> Result<regex::Regex, regex::Error> {
+        RegexBuilder::new(pattern)
+            .case_insensitive(self.casemap)
+            .build()
+    }
+
+    /// The match variables on a match: `${0}` is the whole value, then
+    /// one per wildcard or group.
+    fn matches(&self, value: &str, key: &str) -> Option<Vec<String>> {
+        let fold = |text: &str| {
+            if self.casemap {
+                text.to_ascii_lowercase()
+            } else {
+                text.to_string()
+            }
+        };
+        let pattern = match self.kind {
+            MatchType::Is => return (fold(value) == fold(key)).then(Vec::new),
+            MatchType::Contains => return fold(value).contains(&fold(key)).then(Vec::new),
+            MatchType::Matches => wildcard_regex(key),
+            MatchType::Regex => key.to_string(),
+        };
+        let captures = self.regex(&pattern).ok()?.captures(value)?;
+        Some(
+            captures
+                .iter()
+                .map(|group| group.map(|m| m.as_str().to_string()).unwrap_or_default())
+                .collect(),
+        )
+    }
+}
+
+/// `*` and `?` wildcards, each captured; `\` escapes the next character.
+fn wildcard_regex(key: &str) -> String {
+    let mut pattern = String::from("(?s)^");
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => pattern.push_str("(.*?)"),
+            '?' => pattern.push_str("(.)"),
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    pattern.push_str(&regex::escape(&next.to_string()));
+                }
+            }
+            other => pattern.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+fn address_part(address: &str, part: AddressPart) -> String {
+    let (local, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+    match part {
+        AddressPart::All => address.to_string(),
+        AddressPart::LocalPart => local.to_string(),
+        AddressPart::Domain => domain.to_string(),
+    }
+}
+
+fn header_addresses(value: &str) -> Vec<String> {
+    let Ok(addresses) = mailparse::addrparse(value) else {
+        return Vec::new();
+    };
+    addresses
+        .iter()
+        .flat_map(|addr| match addr {
+            mailparse::MailAddr::Single(info) => vec![info.addr.clone()],
+            mailparse::MailAddr::Group(group) => {
+                group.addrs.iter().map(|info| info.addr.clone()).collect()
+            }
+        })
+        .collect()
+}
+
+enum Flow {
+    Continue,
+    Stop,
+}
+
+struct Run<'m, 'a> {
+    message: &'m MessageContext<'a>,
+    expand: bool,
+    variables: HashMap<String, String>,
+    captures: Vec<String>,
+    filed: Vec<Route>,
+    keep: bool,
+    discard: bool,
+}
+
+impl Run<'_, '_> {
+    fn commands(&mut self, commands: &[Command]) -> Flow {
+        for command in commands {
+            let flow = match command {
+                Command::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let chosen = branches
+                        .iter()
+                        .find(|(test, _)| self.test(test))
+                        .map(|(_, block)| block)
+                        .unwrap_or(otherwise);
+                    self.commands(chosen)
+                }
+                Command::FileInto(target) => match fileinto_target(&self.expand(target)) {
+                    Some(Some(route)) => {
+                        self.filed.push(route);
+                        Flow::Continue
+                    }
+                    Some(None) => {
+                        self.keep = true;
+                        Flow::Continue
+                    }
+                    // A run-time error: RFC 5228 falls back to keep.
+                    None => {
+                        self.keep = true;
+                        Flow::Stop
+                    }
+                },
+                Command::Keep => {
+                    self.keep = true;
+                    Flow::Continue
+                }
+                Command::Discard => {
+                    self.discard = true;
+                    Flow::Continue
+                }
+                Command::Stop => Flow::Stop,
+                Command::Set {
+                    modifiers,
+                    name,
+                    value,
+                } => {
+                    let value = apply_modifiers(modifiers, self.expand(value));
+                    self.variables.insert(name.clone(), value);
+                    Flow::Continue
+                }
+            };
+            if let Flow::Stop = flow {
+                return Flow::Stop;
+            }
+        }
+        Flow::Continue
+    }
+
+    fn test(&mut self, test: &Test) -> bool {
+        let message = self.message;
+        match test {
+            Test::True => true,
+            Test::False => false,
+            Test::Not(inner) => !self.test(inner),
+            Test::AllOf(tests) => tests.iter().all(|test| self.test(test)),
+            Test::AnyOf(tests) => tests.iter().any(|test| self.test(test)),
+            Test::Exists(names) => names
+                .iter()
+                .all(|name| message.header_values(name).next().is_some()),
+            Test::Size { over, limit } => {
+                let size = message.size() as u64;
+                if *over { size > *limit } else { size < *limit }
+            }
+            Test::Header {
+                matcher,
+                names,
+                keys,
+            } => {
+                let values: Vec<String> = names
+                    .iter()
+                    .flat_map(|name| message.header_values(name))
+                    .map(str::to_string)
+                    .collect();
+                self.compare(matcher, &values, keys)
+            }
+            Test::Address {
+                matcher,
+                part,
+                headers,
+                keys,
+            } => {
+                let values: Vec<String> = headers
+                    .iter()
+                    .flat_map(|name| message.header_values(name))
+                    .flat_map(header_addresses)
+                    .map(|address| address_part(&address, *part))
+                    .collect();
+                self.compare(matcher, &values, keys)
+            }
+            Test::Envelope {
+                matcher,
+                part,
+                fields,
+                keys,
+            } => {
+                let mut values = Vec::new();
+                for field in fields {
+                    if field.eq_ignore_ascii_case("from") {
+                        // The null reverse-path compares as the empty string.
+                        values.extend(message.envelope_sender().map(|sender| {
+                            if sender.is_empty() {
+                                String::new()
+                            } else {
+                                address_part(sender, *part)
+                            }
+                        }));
+                    } else {
+                        values.extend(
+                            message
+                                .recipients()
+                                .iter()
+                                .map(|rcpt| address_part(rcpt.canonical(), *part)),
+                        );
+                    }
+                }
+                self.compare(matcher, &values, keys)
+            }
+            Test::String {
+                matcher,
+                sources,
+                keys,
+            } => {
+                let values: Vec<String> =
+                    sources.iter().map(|source| self.expand(source)).collect();
+                self.compare(matcher, &values, keys)
+            }
+        }
+    }
+
+    fn compare(&mut self, matcher: &Matcher, values: &[String], keys: &[String]) -> bool {
+        for key in keys {
+            let key = self.expand(key);
+            for value in values {
+                if let Some(captures) = matcher.matches(value, &key) {
+                    if matches!(matcher.kind, MatchType::Matches | MatchType::Regex) {
+                        self.captures = captures;
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Replace `${name}` and `${N}` when the script requires `variables`;
+    /// unknown names expand to nothing and malformed references stay as-is.
+    fn expand(&self, text: &str) -> String {
+        if !self.expand {
+            return text.to_string();
+        }
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let reference = after.find('}').map(|end| &after[..end]).filter(|name| {
+                let numeric = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+                let named = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                numeric || named
+            });
+            match reference {
+                Some(name) => {
+                    let value = match name.parse::<usize>() {
+                        Ok(index) => self.captures.get(index).cloned(),
+                        Err(_) => self.variables.get(&name.to_ascii_lowercase()).cloned(),
+                    };
+                    out.push_str(&value.unwrap_or_default());
+                    rest = &after[name.len() + 1..];
+                }
+                None => {
+                    out.push_str("${");
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// RFC 5229 order: case, then first-letter case, then quoting, then length.
+fn apply_modifiers(modifiers: &[String], mut value: String) -> String {
+    let has = |name: &str| modifiers.iter().any(|modifier| modifier == name);
+    if has(":lower") {
+        value = value.to_lowercase();
+    }
+    if has(":upper") {
+        value = value.to_uppercase();
+    }
+    if has(":lowerfirst") || has(":upperfirst") {
+        let mut chars = value.chars();
+        if let Some(first) = chars.next() {
+            let first: String = if has(":upperfirst") {
+                first.to_uppercase().collect()
+            } else {
+                first.to_lowercase().collect()
+            };
+            value = first + chars.as_str();
+        }
+    }
+    if has(":quotewildcard") {
+        value = value
+            .chars()
+            .flat_map(|c| match c {
+                '*' | '?' | '\\' => vec!['\\', c],
+                other => vec![other],
+            })
+            .collect();
+    }
+    if has(":length") {
+        value = value.chars().count().to_string();
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::address::Address;
+
+    fn outcome(script: &str, message: &MessageContext) -> SieveOutcome {
+        SieveScript::parse(script).unwrap().evaluate(message)
+    }
+
+    fn newsletter(sender: &Address) -> MessageContext<'_> {
+        MessageContext::new(sender)
+            .with_envelope_sender("bounces+123@lists.example.org")
+            .with_recipients(&["Me+News@example.org"])
+            .with_header("From", "Weekly News <news@lists.example.org>")
+            .with_header("To", "me+news@example.org, Other <other@example.net>")
+            .with_header("Subject", "[weekly] Issue 42")
+            .with_header("List-Id", "Weekly <weekly.lists.example.org>")
+            .with_size(12_000)
+    }
+
+    #[test]
+    fn tests_and_actions_route_messages() {
+        let sender = Address::parse("news@lists.example.org", false).unwrap();
+        let message = newsletter(&sender);
+        let script = r#"
+            require ["fileinto", "envelope"];
+            # Mailing lists first.
+            if header :contains "list-id" "lists.example.org" {
+                fileinto "accepted";
+                stop;
+            }
+            fileinto "spam";
+        "#;
+        assert_eq!(
+            outcome(script, &message),
+            SieveOutcome::FileInto(Route::Accepted)
+        );
+
+        let cases = [
+            (
+                r#"require "fileinto"; if address :domain "to" "example.net" { fileinto "Spam"; }"#,
+                SieveOutcome::FileInto(Route::Spam),
+            ),
+            (
+                r#"require ["envelope", "fileinto"];
+                   if envelope :localpart :matches "to" "*+news" { fileinto "accepted"; }"#,
+                SieveOutcome::FileInto(Route::Accepted),
+            ),
+            (
+                r#"require "envelope"; if envelope :is "from" "bounces+123@lists.example.org" { discard; }"#,
+                SieveOutcome::Discard,
+            ),
+            (
+                r#"if size :over 10K { discard; } if size :under 1M { keep; }"#,
+                SieveOutcome::Keep,
+            ),
+            (
+                r#"if allof (exists ["Subject", "List-Id"], not size :over 20k) { discard; }"#,
+                SieveOutcome::Discard,
+            ),
+            (
+                r#"if anyof (false, header :is "subject" "other") { discard; } else { keep; }"#,
+                SieveOutcome::Keep,
+            ),
+            (
+                // Several lists: the usual precedence picks one.
+                r#"require "fileinto"; fileinto "accepted"; fileinto "banned"; fileinto "spam";"#,
+                SieveOutcome::FileInto(Route::Banned),
+            ),
+            (
+                r#"require "fileinto"; fileinto "INBOX"; discard;"#,
+                SieveOutcome::Keep,
+            ),
+            (
+                r#"if header :comparator "i;octet" :is "subject" "[WEEKLY] ISSUE 42" { discard; }"#,
+                SieveOutcome::Keep,
+            ),
+        ];
+        for (script, expected) in cases {
+            assert_eq!(outcome(script, &message), expected, "{script}");
+        }
+    }
+
+    #[test]
+    fn variables_and_regex_extensions() {
+        let sender = Address::parse("news@lists.example.org", false).unwrap();
+        let message = newsletter(&sender);
+        let script = r#"
+            require ["fileinto", "variables", "regex"];
+            if header :regex "subject" "^\\[([a-z]+)\\]" {
+                set :upper "tag" "${1}";
+            }
+            if string :is "${tag}" "WEEKLY" {
+                set "list" "accepted";
+            }
+            fileinto "${list}";
+        "#;
+        assert_eq!(
+            outcome(script, &message),
+            SieveOutcome::FileInto(Route::Accepted)
+        );
+
+        let script = r#"
+            require ["fileinto", "variables"];
+            if address :matches "from" "*@*" { set "domain" "${2}"; }
+            set :length "size" "${domain}";
+            if string :is "${size}" "17" { fileinto "spam"; }
+        "#;
+        assert_eq!(
+            outcome(script, &message),
+            SieveOutcome::FileInto(Route::Spam)
+        );
+
+        // A target that expands to no list is a run-time error: keep.
+        let script = r#"require ["fileinto", "variables"]; fileinto "${missing}"; discard;"#;
+        assert_eq!(outcome(script, &message), SieveOutcome::Keep);
+
+        // Without the extension `${...}` is literal text.
+        let script = r#"if header :is "subject" "${x}" { discard; }"#;
+        assert_eq!(outcome(script, &message), SieveOutcome::Keep);
+        assert_eq!(
+            apply_modifiers(&[":quotewildcard".into()], "a*b?".into()),
+            "a\\*b\\?"
+        );
+    }
+
+    #[test]
+    fn multiline_strings_and_comments_parse() {
+        let sender = Address::parse("news@lists.example.org", false).unwrap();
+        let message = newsletter(&sender);
+        let script = "/* block\n comment */\nrequire \"variables\";\nset \"body\" text: # note\n..dot\nline\n.\n;\nif string :is \"${body}\" \".dot\nline\n\" { discard; }\n";
+        assert_eq!(outcome(script, &message), SieveOutcome::Discard);
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let cases = [
+            ("fileinto \"spam\";", "line 1: missing require \"fileinto\""),
+            (
+                "require \"fileinto\";\nfileinto \"Archive\";",
+                "line 2: fileinto \"Archive\" is not a list",
+            ),
+            (
+                "require \"vacation\";",
+                "unsupported extension \"vacation\"",
+            ),
+            (
+                "keep;\nrequire \"regex\";",
+                "line 2: require must come before",
+            ),
+            (
+                "if header :regex \"a\" \"b\" { keep; }",
+                "missing require \"regex\"",
+            ),
+            (
+                "require \"regex\";\nif header :regex \"a\" \"[\" { keep; }",
+                "line 2: invalid regex",
+            ),
+            ("reject \"no\";", "unsupported command \"reject\""),
+            ("if header :is \"a\" { keep; }", "takes two string lists"),
+            ("if size 10 { keep; }", "size takes :over or :under"),
+            ("if true { keep;", "expected '}'"),
+            ("keep", "expected ';'"),
+            ("\"open", "unterminated string"),
+            ("else { keep; }", "else without if"),
+            (
+                "require \"envelope\";\nif envelope \"auth\" \"x\" { keep; }",
+                "unsupported envelope part \"auth\"",
+            ),
+        ];
+        for (script, expected) in cases {
+            let err = SieveScript::parse(script).unwrap_err().to_string();
+            assert!(err.contains(expected), "{script}: {err}");
+        }
+    }
+}
@@ -3,15 +3,51 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, anyhow, bail};
 use base64::{Engine as _, engine::general_purpose::STANDARD};
+use openssl::{pkey::PKey, rsa::Rsa};
 use ring::{
     rand::SystemRandom,
-    signature::{Ed25519KeyPair, KeyPair},
+    signature::{Ed25519KeyPair, KeyPair, RSA_PKCS1_SHA256, RsaKeyPair},
 };
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
 
 use crate::fsops::io_atom::{create_dir_all, write_atomic};
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimKeyType {
+    #[default]
+    Ed25519,
+    Rsa,
+}
+
+impl DkimKeyType {
+    /// Value of the signature's `a=` tag.
+    pub fn algorithm(self) -> &'static str {
+        match self {
+            DkimKeyType::Ed25519 => "ed25519-sha256",
+            DkimKeyType::Rsa => "rsa-sha256",
+        }
+    }
+
+    /// Value of the DNS record's `k=` tag.
+    pub fn dns_key_type(self) -> &'static str {
+        match self {
+            DkimKeyType::Ed25519 => "ed25519",
+            DkimKeyType::Rsa => "rsa",
+        }
+    }
+
+    /// The `.env` key naming the selector for this key type.
+    pub fn env_key(self) -> &'static str {
+        match self {
+            DkimKeyType::Ed25519 => "dkim_selector",
+            DkimKeyType::Rsa => "dkim_rsa_selector",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DkimMaterial {
     pub private_key_path: PathBuf,
@@ -19,9 +55,37 @@ pub struct DkimMaterial {
     pub dns_record_path: PathBuf,
     pub public_key: String,
     pub selector: String,
+    pub key_type: DkimKeyType,
 }
 
 pub fn ensure_ed25519_keypair(dir: &Path, selector: &str) -> Result<DkimMaterial> {
+    ensure_keypair(dir, selector, DkimKeyType::Ed25519)
+}
+
+/// RSA-2048 counterpart of [`ensure_ed25519_keypair`] for receivers that do
+/// not verify Ed25519 signatures yet.
+pub fn ensure_rsa_keypair(dir: &Path, selector: &str) -> Result<DkimMaterial> {
+    ensure_keypair(dir, selector, DkimKeyType::Rsa)
+}
+
+/// The Ed25519 key plus, when `rsa_selector` is set, the RSA key signed
+/// alongside it (RFC 8463 dual signing).
+pub fn ensure_keypairs(
+    dir: &Path,
+    ed25519_selector: &str,
+    rsa_selector: Option<&str>,
+) -> Result<Vec<DkimMaterial>> {
+    let mut materials = vec![ensure_ed25519_keypair(dir, ed25519_selector)?];
+    if let Some(rsa_selector) = rsa_selector {
+        if rsa_selector == ed25519_selector {
+            bail!("dkim_rsa_selector must differ from dkim_selector ({ed25519_selector})");
+        }
+        materials.push(ensure_rsa_keypair(dir, rsa_selector)?);
+    }
+    Ok(materials)
+}
+
+fn ensure_keypair(dir: &Path, selector: &str, key_type: DkimKeyType) -> Result<DkimMaterial> {
     create_dir_all(dir)?;
     let private = dir.join(format!("{selector}.private"));
     let public = dir.join(format!("{selector}.public"));
@@ -29,14 +93,19 @@ pub fn ensure_ed25519_keypair(dir: &Path, selector: &str) -> Result<DkimMaterial
 
     let mut generated = false;
     if !private.exists() || !public.exists() {
-        let rng = SystemRandom::new();
-        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
-            .map_err(|err| anyhow!("failed to generate ed25519 DKIM keypair: {err:?}"))?;
-        write_atomic(&private, pkcs8.as_ref())?;
+        if dir
+            .join("archive")
+            .join(format!("{selector}.private"))
+            .exists()
+        {
+            bail!(
+                "DKIM selector {selector} was retired to {}; refusing to generate a new key for it",
+                dir.join("archive").display()
+            );
+        }
+        let (pkcs8, public_b64) = generate_keypair(key_type)?;
+        write_atomic(&private, &pkcs8)?;
         set_private_permissions(&private)?;
-        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
-            .map_err(|err| anyhow!("generated DKIM keypair invalid: {err}"))?;
-        let public_b64 = STANDARD.encode(keypair.public_key().as_ref());
         write_atomic(&public, public_b64.as_bytes())?;
         generated = true;
     }
@@ -45,7 +114,7 @@ pub fn ensure_ed25519_keypair(dir: &Path, selector: &str) -> Result<DkimMaterial
         .with_context(|| format!("reading {}", public.display()))?
         .trim()
         .to_string();
-    let dns_value = format!("v=DKIM1; k=ed25519; p={public_key}");
+    let dns_value = format!("v=DKIM1; k={}; p={public_key}", key_type.dns_key_type());
 
     if generated || !dns.exists() {
         write_atomic(&dns, dns_value.as_bytes())?;
@@ -65,69 +134,421 @@ pub fn ensure_ed25519_keypair(dir: &Path, selector: &str) -> Result<DkimMaterial
         dns_record_path: dns,
         public_key,
         selector: selector.to_string(),
+        key_type,
     })
 }
 
+/// A PKCS#8 private key and the base64 public key for the DNS `p=` tag: the
+/// raw 32-byte key for Ed25519 (RFC 8463), SubjectPublicKeyInfo DER for RSA.
+fn generate_keypair(key_type: DkimKeyType) -> Result<(Vec<u8>, String)> {
+    match key_type {
+        DkimKeyType::Ed25519 => {
+            let rng = SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                .map_err(|err| anyhow!("failed to generate ed25519 DKIM keypair: {err:?}"))?;
+            let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .map_err(|err| anyhow!("generated DKIM keypair invalid: {err}"))?;
+            let public_b64 = STANDARD.encode(keypair.public_key().as_ref());
+            Ok((pkcs8.as_ref().to_vec(), public_b64))
+        }
+        DkimKeyType::Rsa => {
+            let generate = || -> std::result::Result<_, openssl::error::ErrorStack> {
+                let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+                Ok((key.private_key_to_pkcs8()?, key.public_key_to_der()?))
+            };
+            let (pkcs8, spki) =
+                generate().map_err(|err| anyhow!("failed to generate RSA DKIM keypair: {err}"))?;
+            Ok((pkcs8, STANDARD.encode(spki)))
+        }
+    }
+}
+
+/// A selector change started by `owl dkim rotate`, kept in
+/// `dkim/rotation.yml` until it is finished.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rotation {
+    /// Which key is being replaced; older files only rotated Ed25519.
+    #[serde(default)]
+    pub key_type: DkimKeyType,
+    pub from: String,
+    pub to: String,
+    pub started_at: String,
+    /// When signing moves to `to`; RFC 3339.
+    pub switch_at: String,
+}
+
+impl Rotation {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("rotation.yml")
+    }
+
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let rotation =
+            serde_yaml::from_str(&data).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(rotation))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        create_dir_all(dir)?;
+        write_atomic(&Self::path(dir), serde_yaml::to_string(self)?.as_bytes())
+    }
+
+    pub fn switch_time(&self) -> Result<OffsetDateTime> {
+        OffsetDateTime::parse(&self.switch_at, &Rfc3339)
+            .with_context(|| format!("invalid switch_at in DKIM rotation: {}", self.switch_at))
+    }
+
+    pub fn is_due(&self, now: OffsetDateTime) -> Result<bool> {
+        Ok(self.switch_time()? <= now)
+    }
+}
+
+/// The `key_type` selector to sign with: the rotation's new selector once its
+/// overlap has passed, even before `.env` is updated, else `configured`.
+pub fn signing_selector(
+    dir: &Path,
+    key_type: DkimKeyType,
+    configured: &str,
+    now: OffsetDateTime,
+) -> Result<String> {
+    match Rotation::load(dir)? {
+        Some(rotation) if rotation.key_type == key_type && rotation.is_due(now)? => Ok(rotation.to),
+        _ => Ok(configured.to_string()),
+    }
+}
+
+#[derive(Debug)]
+enum SigningKey {
+    Ed25519(Ed25519KeyPair),
+    Rsa(RsaKeyPair),
+}
+
+/// Header/body canonicalization algorithms from RFC 6376 §3.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    fn as_str(self) -> &'static str {
+        match self {
+            Canonicalization::Simple => "simple",
+            Canonicalization::Relaxed => "relaxed",
+        }
+    }
+
+    /// Parse a `c=` style value such as `relaxed/relaxed` or `relaxed`; a
+    /// missing body algorithm means `simple` (RFC 6376 §3.5).
+    pub fn parse_pair(value: &str) -> Result<(Self, Self)> {
+        let parse = |name: &str| match name.trim().to_ascii_lowercase().as_str() {
+            "simple" => Ok(Canonicalization::Simple),
+            "relaxed" => Ok(Canonicalization::Relaxed),
+            other => Err(anyhow!("unknown DKIM canonicalization: {other}")),
+        };
+        match value.split_once('/') {
+            Some((header, body)) => Ok((parse(header)?, parse(body)?)),
+            None => Ok((parse(value)?, Canonicalization::Simple)),
+        }
+    }
+}
+
+/// Headers signed when `dkim_signed_headers` is not set, in `h=` order.
+pub const DEFAULT_SIGNED_HEADERS: &[&str] = &[
+    "from",
+    "to",
+    "cc",
+    "subject",
+    "date",
+    "message-id",
+    "reply-to",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+];
+
+/// Headers oversigned when `dkim_oversign_headers` is not set.
+pub const DEFAULT_OVERSIGN_HEADERS: &[&str] = &["from", "to", "cc", "subject", "reply-to"];
+
+/// What a signature covers and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignOptions {
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    /// Headers listed in `h=` once per occurrence; absent ones are skipped.
+    pub headers: Vec<String>,
+    /// Headers listed one extra time so nothing can be added or prepended
+    /// without breaking the signature (RFC 6376 §8.15).
+    pub oversign: Vec<String>,
+    /// Sets `x=` this far after `t=`.
+    pub expiry: Option<Duration>,
+}
+
+impl Default for SignOptions {
+    fn default() -> Self {
+        Self {
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+            headers: DEFAULT_SIGNED_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            oversign: DEFAULT_OVERSIGN_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            expiry: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DkimSigner {
-    selector: String,
-    keypair: Ed25519KeyPair,
+    keys: Vec<(String, SigningKey)>,
+    options: SignOptions,
 }
 
 impl DkimSigner {
     pub fn from_material(material: &DkimMaterial) -> Result<Self> {
-        let pkcs8 = fs::read(&material.private_key_path)
-            .with_context(|| format!("reading {}", material.private_key_path.display()))?;
-        let keypair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+        Self::from_materials(std::slice::from_ref(material))
+    }
+
+    /// Sign with every key in `materials`, one `DKIM-Signature` each.
+    pub fn from_materials(materials: &[DkimMaterial]) -> Result<Self> {
+        let mut keys = Vec::with_capacity(materials.len());
+        for material in materials {
+            let pkcs8 = fs::read(&material.private_key_path)
+                .with_context(|| format!("reading {}", material.private_key_path.display()))?;
+            let key = match material.key_type {
+                DkimKeyType::Ed25519 => Ed25519KeyPair::from_pkcs8(&pkcs8).map(SigningKey::Ed25519),
+                DkimKeyType::Rsa => RsaKeyPair::from_pkcs8(&pkcs8).map(SigningKey::Rsa),
+            }
             .map_err(|err| anyhow!("failed to parse DKIM private key: {err}"))?;
+            keys.push((material.selector.clone(), key));
+        }
+        if keys.is_empty() {
+            bail!("no DKIM keys to sign with");
+        }
         Ok(Self {
-            selector: material.selector.clone(),
-            keypair,
+            keys,
+            options: SignOptions::default(),
         })
     }
 
-    pub fn sign(
-        &self,
-        domain: &str,
-        headers_raw: &str,
-        body: &[u8],
-        header_names: &[&str],
-    ) -> Result<String> {
-        let canonical_headers = collect_signed_headers(headers_raw, header_names)?;
-        let canonical_body = canonicalize_body_simple(body);
-        let mut hasher = Sha256::new();
-        hasher.update(&canonical_body);
-        let body_hash = STANDARD.encode(hasher.finalize());
-        let timestamp = OffsetDateTime::now_utc().unix_timestamp();
-        let header_list = header_names.join(":");
-        let mut value = format!(
-            "v=1; a=ed25519-sha256; d={domain}; s={}; c=simple/simple; q=dns/txt; t={timestamp}; h={header_list}; bh={body_hash}; b=",
-            self.selector
+    pub fn with_options(mut self, options: SignOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// One `DKIM-Signature` header value per key, in key order. Signs the
+    /// configured headers that are present in `headers_raw`, plus the
+    /// over-signed ones.
+    pub fn sign(&self, domain: &str, headers_raw: &str, body: &[u8]) -> Result<Vec<String>> {
+        let options = &self.options;
+        let header_list = signed_header_list(headers_raw, &options.headers, &options.oversign);
+        if header_fields(headers_raw, "from").is_empty() {
+            bail!("header from missing for DKIM signing");
+        }
+        if !header_list.iter().any(|name| name == "from") {
+            bail!("DKIM signed headers must include from");
+        }
+        let canonical_headers =
+            canonical_signed_headers(headers_raw, &header_list, options.header_canonicalization);
+        let canonical_body = canonicalize_body(body, options.body_canonicalization);
+        let body_hash = STANDARD.encode(Sha256::digest(&canonical_body));
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut times = format!("t={now};");
+        if let Some(expiry) = options.expiry {
+            times.push_str(&format!(" x={};", now + expiry.whole_seconds()));
+        }
+        let header_list = header_list.join(":");
+        let canon = format!(
+            "{}/{}",
+            options.header_canonicalization.as_str(),
+            options.body_canonicalization.as_str()
         );
 
-        let mut to_sign = Vec::new();
-        for header in &canonical_headers {
-            to_sign.extend_from_slice(header.as_bytes());
+        let mut values = Vec::with_capacity(self.keys.len());
+        for (selector, key) in &self.keys {
+            let algorithm = match key {
+                SigningKey::Ed25519(_) => DkimKeyType::Ed25519.algorithm(),
+                SigningKey::Rsa(_) => DkimKeyType::Rsa.algorithm(),
+            };
+            let mut value = format!(
+                "v=1; a={algorithm}; d={domain}; s={selector}; c={canon}; q=dns/txt; {times} h={header_list}; bh={body_hash}; b="
+            );
+
+            // The signature header itself goes last, empty `b=` and no CRLF.
+            let mut to_sign = canonical_headers.clone();
+            let dkim_header = canonicalize_header(
+                &format!("DKIM-Signature: {value}"),
+                options.header_canonicalization,
+            );
+            to_sign.extend_from_slice(dkim_header.trim_end_matches("\r\n").as_bytes());
+
+            let signature = match key {
+                // RFC 8463: Ed25519 signs the SHA-256 digest of the data.
+                SigningKey::Ed25519(keypair) => {
+                    keypair.sign(&Sha256::digest(&to_sign)).as_ref().to_vec()
+                }
+                SigningKey::Rsa(keypair) => {
+                    let mut signature = vec![0; keypair.public().modulus_len()];
+                    keypair
+                        .sign(
+                            &RSA_PKCS1_SHA256,
+                            &SystemRandom::new(),
+                            &to_sign,
+                            &mut signature,
+                        )
+                        .map_err(|err| anyhow!("RSA DKIM signing failed: {err}"))?;
+                    signature
+                }
+            };
+            value.push_str(&STANDARD.encode(signature));
+            values.push(value);
+        }
+        Ok(values)
+    }
+}
+
+/// The `h=` list: each configured header once per occurrence, and each
+/// over-signed header one extra time (even when absent).
+pub fn signed_header_list(
+    headers_raw: &str,
+    headers: &[String],
+    oversign: &[String],
+) -> Vec<String> {
+    let mut list = Vec::new();
+    for name in headers {
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty() || list.contains(&name) {
+            continue;
         }
-        let dkim_header = format!("DKIM-Signature: {value}");
-        to_sign.extend_from_slice(dkim_header.as_bytes());
-        to_sign.extend_from_slice(b"\r\n");
+        let count = header_fields(headers_raw, &name).len();
+        let extra = usize::from(oversign.iter().any(|o| o.eq_ignore_ascii_case(&name)));
+        list.extend(std::iter::repeat_n(name, count + extra));
+    }
+    list
+}
 
-        let signature = self.keypair.sign(&to_sign);
-        value.push_str(&STANDARD.encode(signature.as_ref()));
-        Ok(value)
+/// Canonicalized header fields for an `h=` list. Repeated names consume
+/// occurrences from the bottom up; names with no occurrence left contribute
+/// nothing (RFC 6376 §5.4.2).
+pub fn canonical_signed_headers(
+    headers_raw: &str,
+    header_list: &[impl AsRef<str>],
+    canonicalization: Canonicalization,
+) -> Vec<u8> {
+    let mut used: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut out = Vec::new();
+    for name in header_list {
+        let name = name.as_ref().trim().to_ascii_lowercase();
+        let fields = header_fields(headers_raw, &name);
+        let taken = used.entry(name).or_default();
+        if *taken < fields.len() {
+            let field = &fields[fields.len() - 1 - *taken];
+            out.extend_from_slice(canonicalize_header(field, canonicalization).as_bytes());
+        }
+        *taken += 1;
     }
+    out
 }
 
-pub fn collect_signed_headers(headers_raw: &str, header_names: &[&str]) -> Result<Vec<String>> {
-    let mut result = Vec::with_capacity(header_names.len());
-    for name in header_names {
-        let Some(header) = extract_header(headers_raw, name) else {
-            bail!("header {name} missing for DKIM signing");
-        };
-        result.push(header);
+/// Every occurrence of header `name`, top to bottom, each with its folded
+/// continuation lines and a trailing CRLF.
+pub fn header_fields(headers_raw: &str, name: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+    for line in headers_raw.split("\r\n") {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some(field) = current.as_mut() {
+                field.push_str(line);
+                field.push_str("\r\n");
+            }
+            continue;
+        }
+        fields.extend(current.take());
+        if line
+            .split_once(':')
+            .is_some_and(|(field, _)| field.trim_end().eq_ignore_ascii_case(name))
+        {
+            current = Some(format!("{line}\r\n"));
+        }
     }
-    Ok(result)
+    fields.extend(current);
+    fields
+}
+
+/// Canonicalize one header field (including its trailing CRLF).
+pub fn canonicalize_header(field: &str, canonicalization: Canonicalization) -> String {
+    match canonicalization {
+        Canonicalization::Simple => {
+            if field.ends_with("\r\n") {
+                field.to_string()
+            } else {
+                format!("{field}\r\n")
+            }
+        }
+        Canonicalization::Relaxed => {
+            let (name, value) = field.split_once(':').unwrap_or((field, ""));
+            let unfolded = value.replace("\r\n", "");
+            let value = unfolded
+                .split([' ', '\t'])
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!("{}:{value}\r\n", name.trim().to_ascii_lowercase())
+        }
+    }
+}
+
+pub fn canonicalize_body(body: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    match canonicalization {
+        Canonicalization::Simple => canonicalize_body_simple(body),
+        Canonicalization::Relaxed => canonicalize_body_relaxed(body),
+    }
+}
+
+/// RFC 6376 §3.4.4: collapse whitespace runs, drop trailing whitespace on
+/// each line and trailing empty lines. An empty body stays empty.
+pub fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    for line in body.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut out = Vec::with_capacity(line.len());
+        let mut pending_space = false;
+        for &byte in line {
+            if byte == b' ' || byte == b'\t' {
+                pending_space = true;
+                continue;
+            }
+            if pending_space {
+                out.push(b' ');
+                pending_space = false;
+            }
+            out.push(byte);
+        }
+        lines.push(out);
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    let mut canonical = Vec::new();
+    for line in lines {
+        canonical.extend_from_slice(&line);
+        canonical.extend_from_slice(b"\r\n");
+    }
+    canonical
 }
 
 pub fn extract_header(headers_raw: &str, name: &str) -> Option<String> {
@@ -203,12 +624,6 @@ fn set_private_permissions(_path: &Path) -> Result<()> {
 mod tests {
     use super::*;
 
-    #[test]
-    fn collect_signed_headers_errors_on_missing_field() {
-        let err = collect_signed_headers("Subject: hi\r\n\r\n", &["from"]).unwrap_err();
-        assert!(err.to_string().contains("header from missing"));
-    }
-
     #[test]
     fn extract_header_stops_on_blank_lines() {
         let raw = "Subject: hi\r\n\r\nX-Test: value\r\n";
@@ -255,6 +670,7 @@ mod tests {
             dns_record_path: dns,
             public_key: String::new(),
             selector: "selector".into(),
+            key_type: DkimKeyType::Ed25519,
         };
         let err = DkimSigner::from_material(&material).unwrap_err();
         assert!(err.to_string().contains("failed to parse DKIM private key"));
@@ -282,6 +698,19 @@ mod tests {
         assert_ne!(dns_contents.trim(), "old record");
     }
 
+    #[test]
+    fn ensure_keypair_refuses_archived_selector() {
+        let dir = tempfile::tempdir().unwrap();
+        let material = ensure_ed25519_keypair(dir.path(), "old").unwrap();
+        let archive = dir.path().join("archive");
+        fs::create_dir_all(&archive).unwrap();
+        fs::rename(&material.private_key_path, archive.join("old.private")).unwrap();
+        fs::remove_file(&material.public_key_path).unwrap();
+        let err = ensure_keypairs(dir.path(), "old", None).unwrap_err();
+        assert!(err.to_string().contains("was retired"));
+        assert!(!material.private_key_path.exists());
+    }
+
     #[test]
     fn generates_and_persists_keys() {
         let dir = tempfile::tempdir().unwrap();
@@ -295,6 +724,41 @@ mod tests {
         assert!(material.public_key.len() > 40);
     }
 
+    #[test]
+    fn generates_rsa_keypair_with_own_dns_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let material = ensure_rsa_keypair(dir.path(), "mail-rsa").unwrap();
+        assert_eq!(material.key_type, DkimKeyType::Rsa);
+        let dns = fs::read_to_string(&material.dns_record_path).unwrap();
+        assert_eq!(dns, format!("v=DKIM1; k=rsa; p={}", material.public_key));
+        let spki = STANDARD.decode(&material.public_key).unwrap();
+        let key = PKey::public_key_from_der(&spki).unwrap();
+        assert_eq!(key.bits(), 2048);
+        let again = ensure_rsa_keypair(dir.path(), "mail-rsa").unwrap();
+        assert_eq!(again.public_key, material.public_key);
+    }
+
+    #[test]
+    fn ensure_keypairs_dual_signs_with_distinct_selectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = ensure_keypairs(dir.path(), "mail", Some("mail")).unwrap_err();
+        assert!(err.to_string().contains("must differ"));
+        let single = ensure_keypairs(dir.path(), "mail", None).unwrap();
+        assert_eq!(single.len(), 1);
+
+        let materials = ensure_keypairs(dir.path(), "mail", Some("mail-rsa")).unwrap();
+        assert!(dir.path().join("mail.dns").exists());
+        assert!(dir.path().join("mail-rsa.dns").exists());
+        let signer = DkimSigner::from_materials(&materials).unwrap();
+        let values = signer
+            .sign("example.org", "From: a@example.org\r\n", b"hi\r\n")
+            .unwrap();
+        assert_eq!(values.len(), 2);
+        assert!(values[0].contains("a=ed25519-sha256; d=example.org; s=mail;"));
+        assert!(values[1].contains("a=rsa-sha256; d=example.org; s=mail-rsa;"));
+        assert!(DkimSigner::from_materials(&[]).is_err());
+    }
+
     #[test]
     fn reuses_existing_keys() {
         let dir = tempfile::tempdir().unwrap();
@@ -311,7 +775,6 @@ mod tests {
     fn signer_builds_header_and_signature() {
         let dir = tempfile::tempdir().unwrap();
         let material = ensure_ed25519_keypair(dir.path(), "mail").unwrap();
-        let signer = DkimSigner::from_material(&material).unwrap();
         let headers = "From: Test <test@example.org>\r\nTo: Bob <bob@example.org>\r\nSubject: Hi\r\nDate: Tue, 16 Sep 2025 23:12:33 -0700\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n";
         let body = b"hello world\r\n";
         let header_names = [
@@ -323,9 +786,13 @@ mod tests {
             "content-type",
             "content-transfer-encoding",
         ];
-        let header_value = signer
-            .sign("example.org", headers, body, &header_names)
-            .unwrap();
+        let signer = DkimSigner::from_material(&material)
+            .unwrap()
+            .with_options(SignOptions {
+                headers: header_names.into_iter().map(String::from).collect(),
+                ..SignOptions::default()
+            });
+        let header_value = signer.sign("example.org", headers, body).unwrap().remove(0);
         assert!(header_value.contains("v=1"));
         assert!(header_value.contains("d=example.org"));
         assert!(header_value.contains("bh="));
@@ -370,23 +837,6 @@ mod tests {
         assert!(extract_header(raw, "subject").is_none());
     }
 
-    #[test]
-    fn collect_signed_headers_with_all_required() {
-        let raw = "From: alice@example.org\r\nTo: bob@example.org\r\nSubject: test\r\n\r\n";
-        let headers = collect_signed_headers(raw, &["from", "to", "subject"]).unwrap();
-        assert_eq!(headers.len(), 3);
-    }
-
-    #[test]
-    fn collect_signed_headers_preserves_order() {
-        let raw = "Subject: test\r\nFrom: alice@example.org\r\nTo: bob@example.org\r\n\r\n";
-        let headers = collect_signed_headers(raw, &["subject", "from", "to"]).unwrap();
-        // Should preserve the order requested
-        assert!(headers[0].starts_with("Subject:"));
-        assert!(headers[1].starts_with("From:"));
-        assert!(headers[2].starts_with("To:"));
-    }
-
     #[test]
     fn signer_with_empty_body() {
         let dir = tempfile::tempdir().unwrap();
@@ -395,9 +845,7 @@ mod tests {
         let headers = "From: alice@example.org\r\n\r\n";
         let body = b"";
 
-        let dkim_header = signer
-            .sign("example.org", headers, body, &["from"])
-            .unwrap();
+        let dkim_header = signer.sign("example.org", headers, body).unwrap().remove(0);
 
         assert!(dkim_header.contains("bh="));
     }
@@ -413,8 +861,9 @@ mod tests {
         let large_body = vec![b'x'; 100 * 1024];
 
         let dkim_header = signer
-            .sign("example.org", headers, &large_body, &["from"])
-            .unwrap();
+            .sign("example.org", headers, &large_body)
+            .unwrap()
+            .remove(0);
 
         assert!(dkim_header.contains("b="));
     }
@@ -452,9 +901,83 @@ mod tests {
         let binary_body = vec![0xFF, 0xFE, 0x00, 0x01, 0xAB, 0xCD];
 
         let dkim_header = signer
-            .sign("example.org", headers, &binary_body, &["from"])
-            .unwrap();
+            .sign("example.org", headers, &binary_body)
+            .unwrap()
+            .remove(0);
 
         assert!(dkim_header.contains("b="));
     }
+
+    #[test]
+    fn relaxed_canonicalization_matches_rfc_example() {
+        // RFC 6376 §3.4.5.
+        let headers = "A: X\r\nB : Y\t\r\n\tZ  \r\n";
+        let canonical = canonical_signed_headers(headers, &["a", "b"], Canonicalization::Relaxed);
+        assert_eq!(canonical, b"a:X\r\nb:Y Z\r\n");
+        let simple = canonical_signed_headers(headers, &["a", "b"], Canonicalization::Simple);
+        assert_eq!(simple, headers.as_bytes());
+
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(canonicalize_body_relaxed(body), b" C\r\nD E\r\n");
+        assert!(canonicalize_body_relaxed(b"\r\n\r\n").is_empty());
+        assert_eq!(
+            Canonicalization::parse_pair("relaxed").unwrap(),
+            (Canonicalization::Relaxed, Canonicalization::Simple)
+        );
+        assert!(Canonicalization::parse_pair("loose/simple").is_err());
+    }
+
+    #[test]
+    fn oversigns_headers_and_takes_instances_bottom_up() {
+        let headers =
+            "Received: x\r\nTo: a@example.org\r\nFrom: me@example.org\r\nTo: b@example.org\r\n";
+        let names: Vec<String> = ["from", "to", "cc", "date"].map(String::from).into();
+        let list = signed_header_list(headers, &names, &["to".into(), "cc".into()]);
+        assert_eq!(list, ["from", "to", "to", "to", "cc"]);
+        let canonical = canonical_signed_headers(headers, &list, Canonicalization::Relaxed);
+        assert_eq!(
+            String::from_utf8(canonical).unwrap(),
+            "from:me@example.org\r\nto:b@example.org\r\nto:a@example.org\r\n"
+        );
+    }
+
+    #[test]
+    fn signer_sets_expiry_and_requires_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let material = ensure_ed25519_keypair(dir.path(), "mail").unwrap();
+        let signer = DkimSigner::from_material(&material)
+            .unwrap()
+            .with_options(SignOptions {
+                expiry: Some(Duration::days(7)),
+                ..SignOptions::default()
+            });
+        let value = signer
+            .sign(
+                "example.org",
+                "From: a@example.org\r\nSubject: hi\r\n",
+                b"hi\r\n",
+            )
+            .unwrap()
+            .remove(0);
+        assert!(value.contains("c=relaxed/relaxed;"));
+        assert!(value.contains("h=from:from:to:cc:subject:subject:reply-to;"));
+        let t: i64 = parse_tag(&value, "t").parse().unwrap();
+        let x: i64 = parse_tag(&value, "x").parse().unwrap();
+        assert_eq!(x - t, 7 * 86_400);
+
+        let err = signer
+            .sign("example.org", "Subject: hi\r\n", b"hi\r\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("from missing"));
+    }
+
+    fn parse_tag(value: &str, tag: &str) -> String {
+        value
+            .split(';')
+            .find_map(|part| {
+                let (key, val) = part.trim().split_once('=')?;
+                (key == tag).then(|| val.to_string())
+            })
+            .unwrap()
+    }
 }
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use anyhow::{Result, anyhow};
+use hickory_resolver::{Resolver, error::ResolveErrorKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MxRecord {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+/// DNS lookups used by owl. A name with no records yields an empty list;
+/// `Err` is reserved for lookups that may succeed when retried.
+pub trait DnsResolver: Send + Sync {
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>>;
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>>;
+    /// TXT records, each with its character-strings concatenated.
+    fn txt(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Resolver backed by the system configuration (`/etc/resolv.conf`). A
+/// configuration that cannot be loaded makes every lookup fail, so queued
+/// mail is deferred rather than dropped.
+pub struct SystemResolver {
+    inner: std::result::Result<Resolver, String>,
+}
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        let inner = Resolver::from_system_conf()
+            .map_err(|err| format!("loading system resolver config: {err}"));
+        Self { inner }
+    }
+
+    fn resolver(&self) -> Result<&Resolver> {
+        self.inner.as_ref().map_err(|err| anyhow!("{err}"))
+    }
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsResolver for SystemResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>> {
+        match self.resolver()?.mx_lookup(fqdn(domain)) {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|mx| MxRecord {
+                    preference: mx.preference(),
+                    exchange: normalize(&mx.exchange().to_ascii()),
+                })
+                .collect()),
+            Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                Ok(Vec::new())
+            }
+            Err(err) => Err(anyhow!("MX lookup for {domain} failed: {err}")),
+        }
+    }
+
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>> {
+        match self.resolver()?.lookup_ip(fqdn(host)) {
+            Ok(lookup) => Ok(lookup.iter().collect()),
+            Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                Ok(Vec::new())
+            }
+            Err(err) => Err(anyhow!("address lookup for {host} failed: {err}")),
+        }
+    }
+
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        match self.resolver()?.txt_lookup(fqdn(name)) {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|part| String::from_utf8_lossy(part))
+                        .collect()
+                })
+                .collect()),
+            Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                Ok(Vec::new())
+            }
+            Err(err) => Err(anyhow!("TXT lookup for {name} failed: {err}")),
+        }
+    }
+}
+
+/// Fixed answers for tests and offline setups. Names without entries have
+/// no records; names added with [`StaticResolver::fail`] return an error.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<MxRecord>>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    txt: HashMap<String, Vec<String>>,
+    failing: Vec<String>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+        self.mx
+            .entry(normalize(domain))
+            .or_default()
+            .push(MxRecord {
+                preference,
+                exchange: normalize(exchange),
+            });
+        self
+    }
+
+    pub fn with_host(mut self, host: &str, addr: IpAddr) -> Self {
+        self.hosts.entry(normalize(host)).or_default().push(addr);
+        self
+    }
+
+    pub fn with_txt(mut self, name: &str, value: &str) -> Self {
+        self.txt
+            .entry(normalize(name))
+            .or_default()
+            .push(value.to_string());
+        self
+    }
+
+    pub fn fail(mut self, name: &str) -> Self {
+        self.failing.push(normalize(name));
+        self
+    }
+
+    fn check(&self, name: &str) -> Result<String> {
+        let name = normalize(name);
+        if self.failing.contains(&name) {
+            return Err(anyhow!("lookup for {name} failed: SERVFAIL"));
+        }
+        Ok(name)
+    }
+}
+
+impl DnsResolver for StaticResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>> {
+        let name = self.check(domain)?;
+        Ok(self.mx.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let name = self.check(host)?;
+        Ok(self.hosts.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let name = self.check(name)?;
+        Ok(self.txt.get(&name).cloned().unwrap_or_default())
+    }
+}
+
+/// Lowercase a DNS name and drop the trailing root dot. The root itself
+/// (a null MX target) becomes the empty string.
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", normalize(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_lowercases_and_strips_root() {
+        assert_eq!(normalize("MX1.Example.ORG."), "mx1.example.org");
+        assert_eq!(normalize("."), "");
+    }
+
+    #[test]
+    fn static_resolver_answers_and_fails() {
+        let resolver = StaticResolver::new()
+            .with_mx("Example.org", 10, "mx.example.org.")
+            .with_host("mx.example.org", "127.0.0.1".parse().unwrap())
+            .with_txt("Example.org", "v=spf1 -all")
+            .fail("broken.example");
+        let mx = resolver.mx("example.org.").unwrap();
+        assert_eq!(
+            mx,
+            vec![MxRecord {
+                preference: 10,
+                exchange: "mx.example.org".into()
+            }]
+        );
+        assert_eq!(resolver.addresses("MX.example.org").unwrap().len(), 1);
+        assert!(resolver.mx("missing.example").unwrap().is_empty());
+        assert!(resolver.mx("broken.example").is_err());
+        assert_eq!(resolver.txt("example.org").unwrap(), ["v=spf1 -all"]);
+        assert!(resolver.txt("broken.example").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use crate::pipeline::smtp_in::{MalformedMessageError, SizeLimitError};
+
+/// Input data was incorrect in some way; the MTA bounces the message.
+pub const EX_DATAERR: i32 = 65;
+/// Temporary failure; the MTA keeps the message queued and retries later.
+pub const EX_TEMPFAIL: i32 = 75;
+
+/// Map a delivery error onto the sysexits code an MTA pipe transport expects.
+///
+/// Oversized messages, unparseable mail and invalid addresses are permanent
+/// failures. Everything else (I/O, missing render tools, unreadable rules) is
+/// treated as temporary so mail is deferred rather than bounced.
+pub fn delivery_exit_code(err: &anyhow::Error) -> i32 {
+    if err
+        .chain()
+        .any(|cause| cause.is::<SizeLimitError>() || cause.is::<MalformedMessageError>())
+    {
+        EX_DATAERR
+    } else {
+        EX_TEMPFAIL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn size_limit_maps_to_dataerr() {
+        let err = anyhow::Error::new(SizeLimitError {
+            size: 10,
+            label: "quarantine".into(),
+            configured: "1".into(),
+        });
+        assert_eq!(delivery_exit_code(&err), EX_DATAERR);
+    }
+
+    #[test]
+    fn wrapped_size_limit_maps_to_dataerr() {
+        let result: anyhow::Result<()> = Err(SizeLimitError {
+            size: 10,
+            label: "accepted".into(),
+            configured: "1".into(),
+        }
+        .into());
+        let err = result.context("delivering").unwrap_err();
+        assert_eq!(delivery_exit_code(&err), EX_DATAERR);
+    }
+
+    #[test]
+    fn malformed_message_maps_to_dataerr() {
+        let result: anyhow::Result<()> = Err(MalformedMessageError::new("missing @").into());
+        let err = result.context("invalid recipient x").unwrap_err();
+        assert_eq!(delivery_exit_code(&err), EX_DATAERR);
+    }
+
+    #[test]
+    fn io_errors_map_to_tempfail() {
+        let err = anyhow::Error::new(std::io::Error::other("disk full"));
+        assert_eq!(delivery_exit_code(&err), EX_TEMPFAIL);
+    }
+
+    #[test]
+    fn other_errors_map_to_tempfail() {
+        let err = anyhow::anyhow!("rules unreadable");
+        assert_eq!(delivery_exit_code(&err), EX_TEMPFAIL);
+    }
+}
@@ -49,3 +49,70 @@ alice@example.org\n\
     assert!(settings.contains("list_status="));
     assert!(settings.contains("body_format="));
 }
+
+#[test]
+fn cli_deliver_files_message_from_stdin() {
+    let temp = tempfile::tempdir().unwrap();
+    let env_path = temp.path().join(".env");
+    std::fs::write(&env_path, "logging=minimal\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("owl").unwrap();
+    cmd.args([
+        "--env",
+        env_path.to_str().unwrap(),
+        "deliver",
+        "--sender",
+        "alice@example.org",
+        "--recipient",
+        "me@example.org",
+    ])
+    .write_stdin("From: Alice <alice@example.org>\r\nSubject: Piped\r\n\r\nHello\r\n")
+    .assert()
+    .success();
+
+    assert!(temp.path().join("quarantine/alice@example.org").exists());
+}
+
+#[test]
+fn cli_deliver_exits_dataerr_when_oversized() {
+    let temp = tempfile::tempdir().unwrap();
+    let env_path = temp.path().join(".env");
+    std::fs::write(&env_path, "logging=minimal\nmax_size_quarantine=8\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("owl").unwrap();
+    cmd.args([
+        "--env",
+        env_path.to_str().unwrap(),
+        "deliver",
+        "--sender",
+        "alice@example.org",
+        "--recipient",
+        "me@example.org",
+    ])
+    .write_stdin("From: Alice <alice@example.org>\r\nSubject: Big\r\n\r\nHello\r\n")
+    .assert()
+    .code(65);
+}
+
+#[test]
+fn cli_deliver_exits_tempfail_on_io_error() {
+    let temp = tempfile::tempdir().unwrap();
+    let env_path = temp.path().join(".env");
+    std::fs::write(&env_path, "logging=minimal\n").unwrap();
+    // A plain file where the quarantine directory belongs forces a write failure.
+    std::fs::write(temp.path().join("quarantine"), "").unwrap();
+
+    let mut cmd = Command::cargo_bin("owl").unwrap();
+    cmd.args([
+        "--env",
+        env_path.to_str().unwrap(),
+        "deliver",
+        "--sender",
+        "alice@example.org",
+        "--recipient",
+        "me@example.org",
+    ])
+    .write_stdin("From: Alice <alice@example.org>\r\nSubject: Hi\r\n\r\nHello\r\n")
+    .assert()
+    .code(75);
+}
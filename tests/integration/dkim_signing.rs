@@ -1,9 +1,10 @@
 use base64::{Engine as _, engine::general_purpose::STANDARD};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Verifier};
 use owl::{
     envcfg::EnvConfig,
     fsops::layout::MailLayout,
     pipeline::outbox::OutboxPipeline,
-    util::dkim::{self, canonicalize_body_simple},
+    util::dkim::{self, Canonicalization},
     util::logging::{LogLevel, Logger},
 };
 use ring::signature::UnparsedPublicKey;
@@ -45,32 +46,82 @@ fn queued_message_has_valid_dkim_signature() {
     let message_bytes = std::fs::read(&message_path).unwrap();
     let message = String::from_utf8(message_bytes.clone()).unwrap();
     let (headers_section, body_section) = message.split_once("\r\n\r\n").unwrap();
-    let dkim_header = dkim::extract_header(headers_section, "dkim-signature").unwrap();
-    let value = dkim_header.strip_prefix("DKIM-Signature:").unwrap().trim();
-    let signature_b64 = parse_tag(value, "b").unwrap();
-    let body_hash_b64 = parse_tag(value, "bh").unwrap();
-    let header_list = parse_tag(value, "h").unwrap();
-    let header_names: Vec<&str> = header_list.split(':').collect();
+    let dkim_headers: Vec<&str> = headers_section
+        .split("\r\n")
+        .filter(|line| line.starts_with("DKIM-Signature:"))
+        .collect();
+    assert_eq!(dkim_headers.len(), 2, "expected Ed25519 and RSA signatures");
 
-    let canonical_body = canonicalize_body_simple(body_section.as_bytes());
-    let computed_hash = Sha256::digest(&canonical_body);
-    assert_eq!(STANDARD.encode(computed_hash), body_hash_b64);
+    let canonical_body = dkim::canonicalize_body_relaxed(body_section.as_bytes());
+    let computed_hash = STANDARD.encode(Sha256::digest(&canonical_body));
 
-    let signed_headers = dkim::collect_signed_headers(headers_section, &header_names).unwrap();
-    let mut to_verify = Vec::new();
-    for header in signed_headers {
-        to_verify.extend_from_slice(header.as_bytes());
+    let mut algorithms = Vec::new();
+    for dkim_header in dkim_headers {
+        let value = dkim_header.strip_prefix("DKIM-Signature:").unwrap().trim();
+        let algorithm = parse_tag(value, "a").unwrap();
+        let selector = parse_tag(value, "s").unwrap();
+        let signature_b64 = parse_tag(value, "b").unwrap();
+        assert_eq!(parse_tag(value, "bh").unwrap(), computed_hash);
+        assert_eq!(parse_tag(value, "c").unwrap(), "relaxed/relaxed");
+        let header_list = parse_tag(value, "h").unwrap();
+        let header_names: Vec<&str> = header_list.split(':').collect();
+        // From is over-signed so a second From header would break the signature.
+        assert_eq!(
+            header_names.iter().filter(|name| **name == "from").count(),
+            2
+        );
+        assert!(header_names.contains(&"message-id"));
+
+        // Verify over the headers as stored, minus the signatures themselves.
+        let unsigned_headers: String = headers_section
+            .split("\r\n")
+            .filter(|line| !line.starts_with("DKIM-Signature:"))
+            .map(|line| format!("{line}\r\n"))
+            .collect();
+        let mut to_verify = dkim::canonical_signed_headers(
+            &unsigned_headers,
+            &header_names,
+            Canonicalization::Relaxed,
+        );
+        let sig_index = dkim_header.rfind("b=").unwrap();
+        let unsigned =
+            dkim::canonicalize_header(&dkim_header[..sig_index + 2], Canonicalization::Relaxed);
+        to_verify.extend_from_slice(unsigned.trim_end_matches("\r\n").as_bytes());
+
+        let public_key_b64 = std::fs::read_to_string(layout.dkim_public_key(&selector))
+            .unwrap()
+            .trim()
+            .to_string();
+        let public_key = STANDARD.decode(public_key_b64).unwrap();
+        let signature = STANDARD.decode(signature_b64.trim()).unwrap();
+        match algorithm.as_str() {
+            "ed25519-sha256" => {
+                assert_eq!(selector, env.dkim_selector);
+                // RFC 8463 signs the SHA-256 digest of the header data.
+                let verifier = UnparsedPublicKey::new(&ring::signature::ED25519, public_key);
+                verifier
+                    .verify(&Sha256::digest(&to_verify), &signature)
+                    .unwrap();
+            }
+            "rsa-sha256" => {
+                assert_eq!(Some(selector), env.dkim_rsa_selector);
+                let key = PKey::public_key_from_der(&public_key).unwrap();
+                assert_eq!(key.bits(), 2048);
+                let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+                verifier.update(&to_verify).unwrap();
+                assert!(verifier.verify(&signature).unwrap());
+            }
+            other => panic!("unexpected algorithm {other}"),
+        }
+        algorithms.push(algorithm);
     }
-    let sig_index = dkim_header.find("b=").unwrap();
-    let unsigned = format!("{}\r\n", &dkim_header[..sig_index + 2]);
-    to_verify.extend_from_slice(unsigned.as_bytes());
+    algorithms.sort();
+    assert_eq!(algorithms, ["ed25519-sha256", "rsa-sha256"]);
 
-    let public_key_b64 = std::fs::read_to_string(layout.dkim_public_key(&env.dkim_selector))
-        .unwrap()
-        .trim()
-        .to_string();
-    let public_key = STANDARD.decode(public_key_b64).unwrap();
-    let signature = STANDARD.decode(signature_b64.trim()).unwrap();
-    let verifier = UnparsedPublicKey::new(&ring::signature::ED25519, public_key);
-    verifier.verify(&to_verify, &signature).unwrap();
+    let rsa_dns =
+        std::fs::read_to_string(layout.dkim_dns_record(env.dkim_rsa_selector.as_deref().unwrap()))
+            .unwrap();
+    assert!(rsa_dns.starts_with("v=DKIM1; k=rsa; p="));
+    let ed_dns = std::fs::read_to_string(layout.dkim_dns_record(&env.dkim_selector)).unwrap();
+    assert!(ed_dns.starts_with("v=DKIM1; k=ed25519; p="));
 }
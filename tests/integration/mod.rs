@@ -6,3 +6,4 @@ mod main_smoke;
 mod outbox_retry;
 mod retention;
 mod routing;
+mod sanitize_corpus;
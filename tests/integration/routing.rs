@@ -1,5 +1,4 @@
 use owl::{
-    envcfg::EnvConfig,
     model::{address::Address, rules::RuleSet},
     pipeline::inbound::determine_route,
     ruleset::{eval::Route, loader::LoadedRules},
@@ -10,7 +9,7 @@ fn routing_precedence() {
     let sender = Address::parse("eve@malicious.example", false).unwrap();
     let mut loaded = LoadedRules::default();
     loaded.banned.rules = RuleSet::parse("@malicious.example").unwrap();
-    let route = determine_route(&sender, &loaded, &EnvConfig::default()).unwrap();
+    let route = determine_route(&sender, &loaded).unwrap();
     assert_eq!(route, Route::Banned);
 }
 
@@ -20,6 +19,6 @@ fn list_settings_change_route() {
     let mut loaded = LoadedRules::default();
     loaded.spam.rules = RuleSet::parse("@example.org").unwrap();
     loaded.spam.settings.list_status = "accepted".into();
-    let route = determine_route(&sender, &loaded, &EnvConfig::default()).unwrap();
+    let route = determine_route(&sender, &loaded).unwrap();
     assert_eq!(route, Route::Accepted);
 }
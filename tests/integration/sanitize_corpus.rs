@@ -0,0 +1,104 @@
+use owl::pipeline::render::{RemoteContent, RenderPolicy, sanitize_html};
+use std::fs;
+use std::path::Path;
+
+fn corpus() -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/html");
+    let mut entries: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+        .collect();
+    entries.sort();
+    entries
+        .into_iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn corpus_matches_snapshots() {
+    let corpus = corpus();
+    assert!(!corpus.is_empty());
+    for (name, html) in corpus {
+        for (mode, policy) in [
+            ("strict", RenderPolicy::Strict),
+            ("moderate", RenderPolicy::Moderate),
+        ] {
+            let clean = sanitize_html(&html, policy, RemoteContent::Block);
+            insta::assert_snapshot!(format!("{name}_{mode}"), clean.html);
+        }
+    }
+}
+
+#[test]
+fn corpus_output_has_no_active_content() {
+    let handler = regex::Regex::new(r"\son[a-z]+\s*=").unwrap();
+    for (name, html) in corpus() {
+        for policy in [RenderPolicy::Strict, RenderPolicy::Moderate] {
+            let clean = sanitize_html(&html, policy, RemoteContent::Block)
+                .html
+                .to_ascii_lowercase();
+            for needle in [
+                "<script",
+                "<style",
+                "<form",
+                "<input",
+                "<iframe",
+                "<svg",
+                "<base",
+                "<meta",
+                "javascript:",
+                "vbscript:",
+                "expression(",
+                "url(",
+                "src=\"http",
+            ] {
+                assert!(
+                    !clean.contains(needle),
+                    "{name} ({policy:?}) still contains {needle:?}"
+                );
+            }
+            assert!(
+                !handler.is_match(&clean),
+                "{name} ({policy:?}) kept a handler"
+            );
+        }
+    }
+}
+
+#[test]
+fn corpus_blocked_urls_are_listed() {
+    let html = corpus()
+        .into_iter()
+        .find(|(name, _)| name == "newsletter")
+        .unwrap()
+        .1;
+    let strict = sanitize_html(&html, RenderPolicy::Strict, RemoteContent::Block);
+    assert_eq!(
+        strict.blocked,
+        [
+            "https://example.org/banner.png",
+            "https://track.example/open.gif?id=42"
+        ]
+    );
+    let moderate = sanitize_html(&html, RenderPolicy::Moderate, RemoteContent::Block);
+    assert_eq!(
+        moderate.blocked,
+        [
+            "https://track.example/p.gif",
+            "https://example.org/banner.png",
+            "https://track.example/open.gif?id=42"
+        ]
+    );
+    let allowed = sanitize_html(&html, RenderPolicy::Moderate, RemoteContent::Allow);
+    assert!(allowed.blocked.is_empty());
+    assert!(
+        allowed
+            .html
+            .contains("src=\"https://example.org/banner.png\"")
+    );
+}